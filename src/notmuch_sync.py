@@ -5,28 +5,324 @@ local and remote systems."""
 
 import argparse
 import asyncio
+import contextlib
+import fcntl
+import fnmatch
 import hashlib
+import importlib.metadata
+import io
 import json
 import logging
+import math
 import os
+import random
+import re
 import shlex
 import shutil
+import socket
+import ssl
+import stat
 import struct
 import subprocess
 import sys
-
-from typing import Any, Dict, List, Tuple, Callable, IO
+import tempfile
+import threading
+import time
+import uuid
+import zlib
+
+from collections import deque
+from concurrent.futures import ThreadPoolExecutor
+from contextvars import ContextVar
+from dataclasses import asdict, dataclass, field
+from typing import Any, Deque, Dict, List, Tuple, Callable, IO, Iterator, NoReturn
 
 from pathlib import Path
-from select import select
 
 import notmuch2
 import xapian
 
-logging.basicConfig(format="[{asctime}] {message}", style="{")
+# optional compression algorithms
+try:
+    import zstandard
+except ImportError:
+    zstandard = None
+try:
+    import lz4.frame as lz4frame
+except ImportError:
+    lz4frame = None
+# optional binary encoding of changes
+try:
+    import msgpack
+except ImportError:
+    msgpack = None
+# configuration files, tomllib is only part of Python 3.11 and later
+try:
+    import tomllib
+except ImportError:
+    try:
+        import tomli as tomllib
+    except ImportError:
+        tomllib = None
+
+# ID of the sync session, shared by local and remote, the protocol version
+# both sides speak (0 until negotiated), the compression
+# algorithm (and level) frames are compressed with, if any, the
+# content-addressed store files are hardlinked to, if any, whether this is
+# a dry run that only records what it would change, the limit of the rate
+# data is sent at, if any, the number of messages changed in one atomic
+# transaction (0 for all), the directory deleted files are moved to, if any,
+# the journal changes are recorded in to undo the sync, if any, and whether
+# maildir flags are synchronized with tags (maildir.synchronize_flags), the
+# encoding changes are sent in, "json" or "msgpack" if both sides have it,
+# whether frames are followed by a CRC32 checksum (--frame-checksum), and the
+# round-trip time in seconds and throughput in bytes per second measured in
+# the session, if any; reset by start_session() at the start of each session
+SESSION_DEFAULTS: Dict[str, Any] = {"id": "", "protocol": 0, "compress": "", "store": None, "dry_run": False,
+                                    "rate": None, "batch": 0, "trash": None, "journal": None, "flags": True,
+                                    "encoding": "json", "crc": False, "rtt": None, "throughput": None}
+session: Dict[str, Any] = dict(SESSION_DEFAULTS)
+
+
+def start_session(**values: Any) -> None:
+    """
+    Start a new sync session: reset the session state to the defaults, so that
+    nothing carries over from a previous session in the same process (with
+    several clients of --listen, several remotes, or --watch), and set the
+    given values.
+
+    Args:
+        values: Session state to set, by key.
+    """
+    session.clear()
+    session.update(SESSION_DEFAULTS, **values)
+
+
+def add_session(record: logging.LogRecord) -> bool:
+    """
+    Add the session ID, if known, to a log record.
+
+    Args:
+        record: Log record to add the session ID to.
+
+    Returns:
+        bool: Always True, i.e. the record is logged.
+    """
+    record.session = f"[{session['id']}] " if session["id"] else ""
+    return True
+
+
+handler = logging.StreamHandler()
+handler.addFilter(add_session)
+logging.basicConfig(format="[{asctime}] {session}{message}", style="{", handlers=[handler])
 logger = logging.getLogger(__name__)
 
-transfer = {"read": 0, "write": 0}
+
+@dataclass
+class SyncStats:
+    """
+    Statistics of one sync session on one side -- bytes transferred, changes,
+    files not received because the sync ran out of time, received files
+    skipped because they conflict with existing files, new messages, new
+    files, and deleted messages per maildir folder, IDs of messages that are
+    new or had their tags changed, files renamed to match their tags, whether
+    the file transfer was stopped early on either side, the changes a dry run
+    would have made, and the exit code the sync finished with.
+    """
+    read: int = 0
+    write: int = 0
+    messages: int = 0
+    files: int = 0
+    moved: int = 0
+    deleted_files: int = 0
+    tags: int = 0
+    deleted_messages: int = 0
+    remaining: int = 0
+    conflicts: int = 0
+    folders: Dict[str, Dict[str, int]] = field(default_factory=dict)
+    message_ids: set[str] = field(default_factory=set)
+    renamed: List[Tuple[str, str]] = field(default_factory=list)
+    incomplete: bool = False
+    actions: List[str] = field(default_factory=list)
+    status: int = 0
+
+    def changes(self) -> Dict[str, Any]:
+        """
+        Get the changes to send to the other side, i.e. everything but the
+        bytes transferred, message IDs, renamed files, whether the sync is
+        incomplete, the changes of a dry run, and the exit code.
+
+        Returns:
+            dict: Number of changes by name and changes per folder.
+        """
+        changes = asdict(self)
+        del changes["read"], changes["write"], changes["message_ids"], changes["renamed"], changes["incomplete"]
+        del changes["actions"], changes["status"]
+        return changes
+
+
+def dry_run(action: str) -> bool:
+    """
+    Check whether this is a dry run, and if so record the given change in the
+    stats instead of making it.
+
+    Args:
+        action (str): Description of the change, e.g. "delete foo".
+
+    Returns:
+        bool: Whether the change is to be skipped.
+    """
+    if not session["dry_run"]:
+        return False
+    current_stats.get().actions.append(action)
+    return True
+
+
+# statistics of the current session, set at the start of each session so that
+# several sessions in one process don't share counters; there is no default, so
+# that counting outside of a session fails instead of going unnoticed
+current_stats: ContextVar[SyncStats] = ContextVar("current_stats")
+
+# data up to this size is copied to be written in one go with its length prefix
+COALESCE_SIZE = 64 * 1024
+# frames smaller than this are never compressed
+COMPRESS_MIN = 32
+# size of the start of a frame that is compressed to check whether compressing
+# the whole frame is worth it, and the ratio it has to be compressed to
+COMPRESS_SAMPLE = 4096
+COMPRESS_RATIO = 0.9
+# preset dictionary for compressing frames -- common mail headers and the JSON
+# of changes, most common last as that is where zlib finds matches cheapest
+COMPRESS_DICT = (
+    b'Content-Disposition: attachment; filename="Content-Type: application/pdf; name="'
+    b'Content-Type: multipart/alternative; boundary="Content-Type: multipart/mixed; boundary="'
+    b'Content-Type: text/html; charset="utf-8"\nContent-Transfer-Encoding: base64\n'
+    b'List-Unsubscribe: <mailto:List-Id: <List-Post: <mailto:Precedence: list\n'
+    b'ARC-Seal: i=1; a=rsa-sha256; t=ARC-Message-Signature: i=1; a=rsa-sha256; c=relaxed/relaxed; d='
+    b'ARC-Authentication-Results: i=1; Authentication-Results: dkim=pass header.i=@; spf=pass smtp.mailfrom='
+    b'DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed; d=; s=; h=From:To:Subject:Date:Message-ID; bh=; b='
+    b'Received: from by with ESMTPS id for <>; (version=TLS1_3 cipher=TLS_AES_256_GCM_SHA384 bits=256/256)\n'
+    b'Return-Path: <Delivered-To: X-Original-To: X-Mailer: User-Agent: X-TUID: \n'
+    b'Content-Type: text/plain; charset="utf-8"\nContent-Transfer-Encoding: quoted-printable\n'
+    b'MIME-Version: 1.0\nIn-Reply-To: <References: <Reply-To: Cc: To: From: Subject: Re: Date: Mon, Tue, Wed, Thu, Fri, Sat, Sun, '
+    b'Message-ID: <@mail.gmail.com>\n'
+    b'{"stamp": "", "vector": {}, "receive_only": false, "session": ""}'
+    b'"attachment", "replied", "flagged", "signed", "sent", "draft", "inbox", "unread"], '
+    b'/cur/:2,RS", "/new/", "/cur/:2,S"]}, "": {"tags": ["inbox", "unread"], "files": ["'
+)
+# length prefix that marks an error frame instead of data
+ERROR_MARKER = struct.pack("!I", 0xFFFFFFFF)
+# start of a session, followed by the protocol version and the UUID; the
+# session uses the lower protocol version of the two sides, which must not be
+# lower than the oldest one either side still speaks
+HANDSHAKE = b"notmuch-sync"
+PROTOCOL_VERSION = 5
+PROTOCOL_MIN = 1
+# length prefix that marks a file streamed in frames of up to STREAM_CHUNK
+# bytes, followed by the size of the file and ended by an empty frame; files
+# larger than one chunk are streamed from protocol version 2 on
+STREAM_MARKER = struct.pack("!I", 0xFFFFFFFE)
+STREAM_CHUNK = 64 * 1024
+# from protocol version 5 on, files are streamed in chunks of the
+# bandwidth-delay product measured in the session, within these bounds
+MIN_CHUNK = 16 * 1024
+MAX_CHUNK = 4 * 1024 * 1024
+# length prefix without data that a side sends every HEARTBEAT_INTERVAL seconds
+# while it is busy without sending anything else, from protocol version 4 on,
+# so that the connection doesn't look dead; skipped when reading frames
+HEARTBEAT_MARKER = struct.pack("!I", 0xFFFFFFFD)
+HEARTBEAT_INTERVAL = 10
+# largest length of a frame that doesn't collide with a marker; larger data
+# must be streamed, which older peers can't receive for files
+MAX_FRAME = 0xFFFFFFFC
+# supported SSH clients and their default commands -- dropbear's dbclient has
+# none of OpenSSH's -C, -a, -x, and -q, and plink never prompts with -batch
+SSH_CLIENTS = {
+    "openssh": "ssh -CTaxq",
+    "plink": "plink -batch -C -T -a -x",
+    "dropbear": "dbclient -T",
+}
+# number of files sent before waiting for the other side to acknowledge them
+FILES_WINDOW = 100
+FILES_ACK = b"ack"
+# acknowledgement of the last window of files when out of time
+FILES_STOP = b"stop"
+# number of messages whose changes are sent in one frame
+CHANGES_CHUNK = 10000
+# default number of messages changed in one atomic notmuch transaction
+ATOMIC_BATCH = 1000
+# with --delta, files are sent as differences to a local file of the same
+# message on the receiving side, matched in blocks of DELTA_BLOCK bytes; the
+# sender marks whether a file comes as a delta or in full
+DELTA_BLOCK = 1024
+DELTA_MIN = 4 * DELTA_BLOCK
+DELTA_MAX = 16 * 1024 * 1024
+DELTA_MARK = b"delta"
+DELTA_FULL = b"full"
+# answers of the local side to whether to apply the changes with --confirm
+CONFIRM_YES = b"yes"
+CONFIRM_NO = b"no"
+# number of the most common tag changes shown when asking for confirmation
+CONFIRM_TAGS = 5
+# file in each day's directory in the trash directory that records where the
+# files moved there came from
+TRASH_MANIFEST = "notmuch-sync-manifest"
+# journal of the changes of the last sync on the local side, next to the sync
+# state files, and the directory the files it deleted are kept in to undo it
+JOURNAL = "notmuch-sync-journal"
+JOURNAL_FILES = "notmuch-sync-journal-files"
+# version of the final stats sent by the remote; fields unknown to the other
+# side are ignored and missing ones count as zero
+STATS_VERSION = 1
+# acknowledgement of the final stats that ends a session
+GOODBYE = b"bye"
+
+# message properties for observed-remove sets of tags
+ORSET_ADD = "notmuch-sync-add"
+ORSET_RM = "notmuch-sync-rm"
+# message property for times of tag changes
+TAG_TIME = "notmuch-sync-time"
+# prefixes of message properties that --properties doesn't sync: those of
+# notmuch-sync itself and those notmuch keeps about how a message was indexed
+LOCAL_PROPERTIES = ("notmuch-sync-", "index.")
+# minimum length of mail bodies to identify the same mail with different message
+# IDs by, shorter ones (e.g. "Thanks!") are too common
+REWRITE_MIN_BODY = 200
+# tags corresponding to maildir flags, as in notmuch; "unread" is set if the
+# "S" flag is *not* present
+MAILDIR_FLAGS = {"D": "draft", "F": "flagged", "P": "passed", "R": "replied", "S": "unread"}
+
+
+class AtomicBatches:
+    """
+    Context manager grouping changes to messages in the notmuch database into
+    atomic transactions of session["batch"] messages each, so that the changes
+    are committed in fewer, larger steps and an interrupted sync leaves whole
+    batches changed rather than parts of messages.
+    """
+    def __init__(self, db: notmuch2.Database):
+        self.db = db
+        self.count = 0
+        self.ctx: Any = None
+
+    def __enter__(self) -> "AtomicBatches":
+        self.ctx = self.db.atomic()
+        self.ctx.__enter__()
+        return self
+
+    def __exit__(self, *exc) -> None:
+        self.ctx.__exit__(*exc)
+
+    def done(self) -> None:
+        """
+        Count a changed message and start a new transaction after each batch.
+        """
+        self.count += 1
+        if session["batch"] and self.count % session["batch"] == 0:
+            self.ctx.__exit__(None, None, None)
+            self.ctx = self.db.atomic()
+            self.ctx.__enter__()
+
 
 def digest(data: bytes) -> str:
     """
@@ -54,861 +350,6309 @@ def digest(data: bytes) -> str:
     return hashlib.new("sha256", to_digest).hexdigest()
 
 
-def write(data: bytes, stream: IO[bytes] | None) -> None:
+def body_digest(data: bytes) -> str | None:
     """
-    Write data to a stream with a 4-byte length prefix.
+    Compute SHA256 digest of the body of a mail, i.e. everything after the
+    headers, to identify the same mail with rewritten headers. Headers end at
+    the first empty line, with LF or CRLF line endings.
 
     Args:
-        data (bytes): The data to write.
-        stream: A writable stream supporting .write() and .flush().
-    """
-    if stream is None:
-        return
-    stream.write(struct.pack("!I", len(data)))
-    transfer["write"] += 4
-    written = stream.write(data)
-    if written < len(data):
-        raise ValueError(f"Tried to write {len(data)} bytes, but wrote only {written}, aborting...")
-    transfer["write"] += len(data)
-    stream.flush()
+        data (bytes): The mail.
 
-
-def read(stream: IO[bytes] | None) -> bytes:
+    Returns:
+        The computed checksum, or None if the body is too short to identify the
+        mail.
     """
-    Read 4-byte length-prefixed data from a stream.
+    end = re.search(rb"\r?\n\r?\n", data)
+    body = data[end.end():] if end else b""
+    if len(body.strip()) < REWRITE_MIN_BODY:
+        return None
+    return hashlib.new("sha256", body).hexdigest()
+
+
+def digest_files(
+    fnames: List[str],
+    jobs: int | None = None,
+    cache: Dict[str, List[Any]] | None = None,
+    body: bool = False
+) -> List[str]:
+    """
+    Read files and compute their digests in parallel worker threads. If a cache
+    is given, files whose size, mtime, and inode match the cached ones are not
+    read again, and the cache is updated with the digests of all other files.
 
     Args:
-        stream: A readable stream supporting .read().
+        fnames (list): Paths of the files to digest.
+        jobs (int): Number of worker threads, defaults to the number of
+                    available CPUs.
+        cache (dict): Mapping of paths to size, mtime in nanoseconds, inode,
+                      digest, and, if computed, body digest.
+        body (bool): Whether to add the body digests to the cache as well.
 
     Returns:
-        bytes: The data read from the stream.
+        list: Digests of the files in the same order.
     """
-    if stream is None:
-        return b''
-    size_data = stream.read(4)
-    transfer["read"] += 4
-    size = struct.unpack("!I", size_data)[0]
-    data = stream.read(size)
-    if len(data) < size:
-        raise ValueError(f"Tried to read {size} bytes, but read only {len(data)}, aborting...")
-    transfer["read"] += size
-    return data
+    def _digest(fname):
+        if cache is None:
+            return digest(Path(fname).read_bytes())
+        st = os.stat(fname)
+        key = [st.st_size, st.st_mtime_ns, st.st_ino]
+        if cache.get(fname, [])[:3] != key or (body and len(cache[fname]) < 5):
+            data = Path(fname).read_bytes()
+            cache[fname] = key + [digest(data)] + ([body_digest(data)] if body else [])
+        return cache[fname][3]
 
+    with ThreadPoolExecutor(max_workers=jobs or os.cpu_count() or 1) as pool:
+        return list(pool.map(_digest, fnames))
 
-def run_async(m1: Callable[[], Any], m2: Callable[[], Any]) -> None:
+
+def maildir_flag_tags(fname: str) -> set[str] | None:
     """
-    Run two functions async. Used to read/write to streams at the same time.
+    Get the tags implied by the maildir flags of a file name, as notmuch
+    interprets them.
 
     Args:
-        m1: One function.
-        m2: Other function.
-    """
-    async def _tmp():
-        await asyncio.gather(asyncio.to_thread(m1), asyncio.to_thread(m2))
+        fname (str): Name of the file.
 
-    asyncio.run(_tmp())
+    Returns:
+        set: Tags corresponding to maildir flags (of those in MAILDIR_FLAGS)
+        that the file name implies, or None if the file has no flag information,
+        i.e. is not in a "cur" directory with a ":2," suffix.
+    """
+    if os.path.basename(os.path.dirname(fname)) != "cur" or ":2," not in fname:
+        return None
+    flags = fname.rsplit(":2,", 1)[1]
+    tags = {tag for flag, tag in MAILDIR_FLAGS.items() if flag != "S" and flag in flags}
+    if "S" not in flags:
+        tags.add("unread")
+    return tags
 
 
-def get_changes(
-    db: notmuch2.Database,
-    revision: notmuch2.DbRevision,
-    prefix: str,
-    sync_file: str
-) -> Dict[str, Dict[str, Any]]:
+def flags_only_rename(f1: str, f2: str) -> bool:
     """
-    Get changes that happened since the last sync, or everything in the DB if no previous sync.
+    Check whether two file names only differ in their maildir flags, i.e. one is
+    a rename of the other that notmuch or a mail client does when tags change.
 
     Args:
-        db: An open notmuch2.Database object.
-        revision: Database revision object, must have .uuid and .rev.
-        prefix (str): Prefix path for filenames (notmuch config database.path).
-        sync_file (str): Path to the file storing the sync state.
+        f1 (str): First file name.
+        f2 (str): Second file name.
 
     Returns:
-        dict: Mapping of message IDs to their tags and files.
+        bool: Whether the file names are the same file in the same folder with
+        different maildir flags.
     """
-    rev_prev = -1
-    try:
-        with open(sync_file, 'r', encoding="utf-8") as f:
-            tmp = f.read().strip('\n\r').split(' ')
-            uuid = revision.uuid.decode()
-            try:
-                if tmp[1] != uuid:
-                    raise ValueError(f"Last sync with UUID {tmp[1]}, but notmuch DB has UUID {uuid}, aborting...")
-                rev_prev = int(tmp[0])
-                if rev_prev > revision.rev:
-                    raise ValueError(f"Last sync revision {rev_prev} larger than current DB revision {revision.rev}, aborting...")
-            except (AttributeError, IndexError, UnicodeError) as e:
-                raise ValueError(f"Sync state file '{sync_file}' corrupted, delete to sync from scratch.") from e
-    except FileNotFoundError:
-        # no previous sync or sync file broken, leave rev_prev at -1 as this will sync entire DB
-        pass
-
-    logger.info("Previous sync revision %s, current revision %s.", rev_prev, revision.rev)
-    return {msg.messageid: {"tags": list(msg.tags),
-                            "files": [str(f).removeprefix(prefix) for f in msg.filenames()]}
-                            for msg in db.messages(f"lastmod:{rev_prev + 1}..")}
+    if f1 == f2 or os.path.dirname(f1) != os.path.dirname(f2):
+        return False
+    if ":2," not in f1 or ":2," not in f2:
+        return False
+    return f1.rsplit(":2,", 1)[0] == f2.rsplit(":2,", 1)[0]
 
 
-def sync_tags(
-    db: notmuch2.Database,
-    changes_mine: Dict[str, Dict[str, Any]],
-    changes_theirs: Dict[str, Dict[str, Any]]
-) -> int:
+def maildir_rename(f1: str, f2: str) -> bool:
     """
-    Synchronize tags between local and remote changes. Applies tags from all
-    remotely changed IDs to local messages with the same ID, overwriting any
-    local tags. If an ID appears both in remote and local changes, take the
-    union of all tags. If a message is not found locally, do nothing (will be
-    synced later).
+    Check whether a file name is one notmuch may rename another file to when
+    changing the maildir flags to match the tags of its message, i.e. the same
+    file in the same folder with different flags, or moved from "new" to "cur".
 
     Args:
-        db: An open notmuch2.Database object.
-        changes_mine (dict): Local changes, mapping message IDs to tags.
-        changes_theirs (dict): Remote changes, mapping message IDs to tags.
+        f1 (str): Old file name.
+        f2 (str): New file name.
 
     Returns:
-        int: Number of tag changes made.
+        bool: Whether the second file name is a rename of the first.
     """
-    changes = 0
-    for mid in changes_theirs:
-        tags = changes_theirs[mid]["tags"]
-        if mid in changes_mine:
-            tags = set(tags) | set(changes_mine[mid]["tags"])
-        tags = set(tags)
-        try:
-            msg = db.find(mid)
-            if msg.ghost:
-                continue
-            if tags != set(msg.tags):
-                logger.info("Setting tags %s for %s.", sorted(list(tags)), mid)
-                with msg.frozen():
-                    changes += 1
-                    msg.tags.clear()
-                    for tag in sorted(list(tags)):
-                        msg.tags.add(tag)
-                    msg.tags.to_maildir_flags()
-        except LookupError:
-            # we don't have this message on our side, it will be added later
-            # when syncing files
-            pass
-
-    return changes
+    if flags_only_rename(f1, f2):
+        return True
+    d1, d2 = os.path.dirname(f1), os.path.dirname(f2)
+    return os.path.basename(d1) == "new" and os.path.basename(d2) == "cur" and \
+        os.path.dirname(d1) == os.path.dirname(d2) and \
+        os.path.basename(f1).split(":2,")[0] == os.path.basename(f2).split(":2,")[0]
 
 
-def record_sync(fname: str, revision: notmuch2.DbRevision) -> None:
+def flags_match(fname: str, msg: notmuch2.Message) -> bool:
     """
-    Record last sync revision.
+    Check whether the maildir flags of a file agree with the tags of its
+    message, i.e. notmuch wouldn't rename it when applying the tags. Files in
+    "new" directories never agree, notmuch moves them to "cur".
 
     Args:
-        fname: File to write to.
-        revision: Revision/UUID to record.
+        fname (str): Name of the file.
+        msg (notmuch2.Message): Message of the file.
+
+    Returns:
+        bool: Whether the flags agree with the tags.
     """
-    with open(fname, 'w', encoding="utf-8") as f:
-        logger.info("Writing last sync revision %s.", revision.rev)
-        f.write(f"{revision.rev} {revision.uuid.decode()}")
+    if os.path.basename(os.path.dirname(fname)) == "new":
+        return False
+    flag_tags = maildir_flag_tags(fname)
+    return flag_tags is None or flag_tags == set(msg.tags) & set(MAILDIR_FLAGS.values())
 
 
-def initial_sync(
-    dbw: notmuch2.Database,
-    prefix: str,
-    from_stream: IO[bytes] | None,
-    to_stream: IO[bytes] | None
-) -> Tuple[Dict[str, Dict[str, Any]], Dict[str, Dict[str, Any]], int, str]:
+def reconcile_flags(msg: notmuch2.Message) -> List[Tuple[str, str]]:
     """
-    Perform the initial synchronization of UUIDs and tag changes, which includes
-    applying any remote tag changes to messages that exist locally. UUIDs and
-    changes are communicated to/from the remote over the respective streams.
+    Rename the files of a message whose maildir flags don't match its tags,
+    as notmuch does when changing tags, unless maildir.synchronize_flags is
+    turned off (session["flags"]). Renames are recorded in the journal and the
+    stats, to tell the other side about them.
 
     Args:
-        dbw: An open writable notmuch2.Database object.
-        prefix (str): Prefix path for filenames (notmuch config database.path).
-        from_stream: Stream to read from the remote.
-        to_stream: Stream to write to the remote.
+        msg (notmuch2.Message): Message to rename the files of.
 
     Returns:
-        tuple: (local changes dict, remote changes dict, number of tag changes,
-                name of sync file)
+        list: Old and new names of the renamed files.
     """
-    revision = dbw.revision()
-    uuids = {}
-    uuids["mine"] = revision.uuid.decode()
-
-    def _send_uuid():
-        logger.info("Sending UUID %s...", uuids["mine"])
-        to_stream.write(uuids["mine"].encode("utf-8"))
-        transfer["write"] += 36
-        to_stream.flush()
-
-    def _recv_uuid():
-        logger.info("Receiving UUID...")
-        uuids["theirs"] = from_stream.read(36).decode("utf-8")
-        transfer["read"] += 36
+    if not session["flags"]:
+        return []
+    before = [str(f) for f in msg.filenames()]
+    msg.tags.to_maildir_flags()
+    after = [str(f) for f in msg.filenames()]
+    renames = []
+    for old in set(before) - set(after):
+        new = next((f for f in after if f not in before and maildir_rename(old, f)), None)
+        if new is not None:
+            logger.debug("Renamed %s to %s to match the tags of %s.", old, new, msg.messageid)
+            journal("move", **{"from": old, "to": new, "id": msg.messageid})
+            renames.append((old, new))
+    current_stats.get().renamed.extend(renames)
+    return renames
+
+
+def load_digests(fname: str) -> Dict[str, List[Any]]:
+    """
+    Load the digests of files cached during previous syncs.
 
-    run_async(_send_uuid, _recv_uuid)
+    Args:
+        fname (str): File the cache is kept in.
 
-    logger.info("UUIDs synced.")
-    logger.debug("Local UUID %s, remote UUID %s.", uuids["mine"], uuids["theirs"])
-    fname = os.path.join(prefix, ".notmuch", "notmuch-sync-" + uuids["theirs"])
+    Returns:
+        dict: Mapping of paths to size, mtime in nanoseconds, inode, and
+              digest; empty if there is no (valid) cache.
+    """
+    try:
+        return json.loads(Path(fname).read_text(encoding="utf-8"))
+    except (OSError, ValueError):
+        return {}
 
-    changes = {}
-    logger.info("Computing local changes...")
-    changes["mine"] = get_changes(dbw, revision, prefix, fname)
 
-    def _send_changes():
-        logger.info("Sending local changes...")
-        write(json.dumps(changes["mine"]).encode("utf-8"), to_stream)
+def save_digests(fname: str, cache: Dict[str, List[Any]]) -> None:
+    """
+    Save the digests of files for the next sync, dropping files that do not
+    exist anymore.
 
-    def _recv_changes():
-        logger.info("Receiving remote changes...")
-        changes["theirs"] = json.loads(read(from_stream).decode("utf-8"))
+    Args:
+        fname (str): File to keep the cache in.
+        cache (dict): Mapping of paths to size, mtime in nanoseconds, inode,
+                      and digest.
+    """
+    Path(fname).write_text(json.dumps({f: v for f, v in cache.items() if os.path.exists(f)}),
+                           encoding="utf-8")
 
-    run_async(_send_changes, _recv_changes)
 
-    logger.info("Changes synced.")
-    logger.debug("Local changes %s, remote changes %s.", changes["mine"], changes["theirs"])
-    tchanges = sync_tags(dbw, changes["mine"], changes["theirs"])
-    logger.info("Tags synced.")
+def index_digests(
+    db: notmuch2.Database,
+    cache: Dict[str, List[Any]],
+    jobs: int | None = None,
+    body: bool = False
+) -> None:
+    """
+    Add the digests of all files in the notmuch database to the cache, so that
+    it can be used to look up files by content. Only new and changed files are
+    read.
 
-    return (changes["mine"], changes["theirs"], tchanges, fname)
+    Args:
+        db: An open notmuch2.Database object.
+        cache (dict): Mapping of paths to size, mtime in nanoseconds, inode,
+                      and digest, see digest_files().
+        jobs (int): Number of worker threads.
+        body (bool): Whether to add the body digests as well.
+    """
+    logger.info("Indexing digests of all files...")
+    fnames = [str(f) for msg in db.messages("*") for f in msg.filenames()]
+    digest_files([f for f in fnames if os.path.exists(f)], jobs, cache, body)
 
 
-def get_missing_files(
-    dbw: notmuch2.Database,
-    prefix: str,
-    changes_mine: Dict[str, Dict[str, Any]],
-    changes_theirs: Dict[str, Dict[str, Any]],
-    from_stream: IO[bytes] | None,
-    to_stream: IO[bytes] | None,
-    move_on_change: bool = False
-) -> Tuple[Dict[str, Dict[str, Any]], int, int]:
+def find_digest(cache: Dict[str, List[Any]], by_digest: Dict[str, List[str]], sha: str) -> str | None:
     """
-    Determine which files are missing locally compared to the remote, and handle
-    file moves/copies based on SHA256 checksums. Delete any files that aren't
-    there on the remote anymore. This never deletes a message, only duplicate
-    files for a message.
+    Find an unchanged local file with the given digest.
 
     Args:
-        dbw: An open writable notmuch2.Database object.
-        prefix (str): Prefix path for filenames (notmuch config database.path).
-        changes_mine (dict): Local changes.
-        changes_theirs (dict): Remote changes.
-        from_stream: Stream to read from the remote.
-        to_stream: Stream to write to the remote.
-        move_on_change: Whether to move file that has local and remote changes.
-        This flag is used to prevent infinite loops where local has one file
-        name and remote another file name (e.g. when running mbsync independently).
+        cache (dict): Mapping of paths to size, mtime in nanoseconds, inode,
+                      and digest, see digest_files().
+        by_digest (dict): Mapping of digests to paths in the cache.
+        sha (str): Digest to look for.
 
     Returns:
-        tuple: (dict of missing files, number of local moves/copies, number of
-                local deletions)
+        str: Path of a file with the digest whose size, mtime, and inode are
+        the same as when it was digested, or None if there is no such file.
     """
-    ret = {}
-    mcchanges = 0
-    dchanges = 0
-    hashes: dict[str, List[str]] = {}
-    # check which files we need to get digests for to determine if they've
-    # been moved/copied
-    hashes["req_mine"] = []
-    for mid in changes_theirs:
+    for fname in by_digest.get(sha, []):
         try:
-            msg = dbw.find(mid)
-            if msg.ghost:
-                continue
-            fnames_theirs = changes_theirs[mid]["files"]
-            fnames_mine = [ str(f).removeprefix(prefix) for f in msg.filenames() ]
-            missing_mine = set(fnames_theirs) - set(fnames_mine)
-            if len(missing_mine) > 0:
-                hashes["req_mine"].extend(fnames_theirs)
-        except LookupError:
+            st = os.stat(fname)
+        except OSError:
             continue
+        if cache[fname][:3] == [st.st_size, st.st_mtime_ns, st.st_ino]:
+            return fname
+    return None
 
-    def _send_hashes_req():
-        logger.info("Requesting %s hashes from remote...", len(hashes["req_mine"]))
-        logger.debug("Requesting hashes %s", hashes["req_mine"])
-        write(json.dumps(hashes["req_mine"]).encode("utf-8"), to_stream)
 
-    def _recv_hashes_req():
-        logger.info("Receiving hash requests from remote...")
-        hashes["req_theirs"] = json.loads(read(from_stream).decode("utf-8"))
-        logger.debug("Hashes requested by remote %s", hashes["req_theirs"])
+def maildir_folder(fname: str) -> str:
+    """
+    Get the maildir folder of a file, i.e. its directory without any
+    cur/new/tmp.
 
-    run_async(_send_hashes_req, _recv_hashes_req)
+    Args:
+        fname (str): File name relative to the prefix.
 
-    def _send_hashes():
-        logger.info("Hashing %s requested files and sending to remote...",
-                    len(hashes["req_theirs"]))
-        tmp = [digest(Path(os.path.join(prefix, f)).read_bytes()) for f in hashes["req_theirs"]]
-        write(json.dumps(tmp).encode("utf-8"), to_stream)
+    Returns:
+        str: Folder name, "." for the top level.
+    """
+    name = os.path.dirname(fname)
+    if os.path.basename(name) in ("cur", "new", "tmp"):
+        name = os.path.dirname(name)
+    return name or "."
 
-    def _recv_hashes():
-        logger.info("Receiving hashes from remote...")
-        tmp = json.loads(read(from_stream).decode("utf-8"))
-        hashes["theirs"] = dict(zip(hashes["req_mine"], tmp))
 
-    run_async(_send_hashes, _recv_hashes)
+def count_folders(prefix: str, fnames: List[str], key: str) -> None:
+    """
+    Count a change for the maildir folders of the given files, i.e. their
+    directories relative to the prefix without any cur/new/tmp.
 
-    # now actually determine changes and move/copy
-    for mid in changes_theirs:
-        try:
-            msg = dbw.find(mid)
-            if msg.ghost:
-                ret[mid] = changes_theirs[mid]
-                continue
-            fnames_theirs = changes_theirs[mid]["files"]
-            fnames_mine = [ str(f).removeprefix(prefix) for f in msg.filenames() ]
-            missing_mine = set(fnames_theirs) - set(fnames_mine)
-            if len(missing_mine) > 0:
-                hashes_mine = {str(f).removeprefix(prefix): digest(Path(f).read_bytes()) for f in msg.filenames()}
-                for f in changes_theirs[mid]["files"]:
-                    if f in missing_mine:
-                        # check if it has been moved/copied
-                        matches = [x[0] for x in hashes_mine.items() if hashes["theirs"][f] == x[1]]
-                        if len(matches) > 0:
-                            src = os.path.join(prefix, matches[0])
-                            dst = os.path.join(prefix, f)
-                            if matches[0] in changes_theirs[mid]["files"]:
-                                mcchanges += 1
-                                logger.info("Copying %s to %s.", src, dst)
-                                Path(dst).parent.mkdir(parents=True, exist_ok=True)
-                                shutil.copy(src, dst)
-                                fnames_mine.append(f)
-                                dbw.add(dst)
-                            elif mid not in changes_mine or move_on_change:
-                                mcchanges += 1
-                                logger.info("Moving %s to %s.", src, dst)
-                                Path(dst).parent.mkdir(parents=True, exist_ok=True)
-                                shutil.move(src, dst)
-                                fnames_mine.append(f)
-                                fnames_mine.remove(matches[0])
-                                hashes_mine[f] = hashes_mine[matches[0]]
-                                del hashes_mine[matches[0]]
-                                dbw.add(dst)
-                                logger.info("Removing %s from DB.", src)
-                                dbw.remove(src)
-                            missing_mine.remove(f)
-            # check which ones are still missing
-            if len(missing_mine) > 0:
-                ret[mid] = {"files": [f for f in changes_theirs[mid]["files"] if f in missing_mine]}
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        fnames (list): Files of the changed message, or a single new file.
+        key (str): "messages", "files", or "deleted".
+    """
+    for name in {maildir_folder(os.path.relpath(fname, prefix)) for fname in fnames}:
+        current_stats.get().folders.setdefault(name, {"messages": 0, "files": 0, "deleted": 0})[key] += 1
 
-            # delete any files that are not there remotely after copy/move
-            if mid not in changes_mine:
-                if len(set(fnames_mine).intersection(fnames_theirs)) == 0:
-                    raise ValueError(f"Message '{mid}' has {fnames_theirs} on remote and different {fnames_mine} locally!")
-                to_delete = set(fnames_mine) - set(fnames_theirs)
-                for f in to_delete:
-                    fname = os.path.join(prefix, f)
-                    dchanges += 1
-                    logger.info("Removing %s from DB and deleting file.", fname)
-                    dbw.remove(fname)
-                    Path(fname).unlink()
-        except LookupError:
-            # don't have this message; all files missing
-            ret[mid] = changes_theirs[mid]
 
-    return (ret, mcchanges, dchanges)
+class SyncError(ValueError):
+    """
+    Error that stops a sync, with the exit code of its category and whether
+    trying again later may succeed. Subclasses ValueError, which all errors
+    that stop a sync are.
+    """
+    exit_code = 5
+    retryable = False
 
 
-def send_file(fname: str, stream: IO[bytes]) -> None:
+class ProtocolError(SyncError):
     """
-    Send a file's contents to a stream with 4-byte length prefix.
+    The other side doesn't speak the protocol, runs it with different options,
+    or sent something unexpected.
+    """
+    exit_code = 3
 
-    Args:
-        fname (str): Path to the file to send.
-        stream: Writable stream.
+
+class ConnectionLost(SyncError):
     """
-    with open(fname, "rb") as f:
-        write(f.read(), stream)
+    Connecting to the other side failed or the connection broke off.
+    """
+    exit_code = 4
+    retryable = True
 
 
-def recv_file(
+class BusyError(SyncError):
+    """
+    Another sync with the notmuch database is in progress.
+    """
+    retryable = True
+
+
+class StateError(SyncError):
+    """
+    The sync state file is corrupted or belongs to another notmuch database.
+    """
+
+
+class ConflictError(SyncError):
+    """
+    Files on both sides conflict and can't be reconciled automatically.
+    """
+    exit_code = 2
+
+
+class RemoteError(Exception):
+    """
+    Error the remote reported in an error frame. Its exit code is that of a
+    protocol error or conflict on the remote, otherwise 4, and it is retryable
+    if the error on the remote is.
+
+    Args:
+        kind (str): Type of the error on the remote.
+        message (str): Error message on the remote.
+    """
+    def __init__(self, kind: str, message: str):
+        super().__init__(f"{kind}: {message}")
+        self.kind = kind
+        self.message = message
+        error = next((cls for cls in [ProtocolError, ConnectionLost, BusyError, StateError, ConflictError]
+                      if cls.__name__ == kind), None)
+        self.exit_code = error.exit_code if error in [ProtocolError, ConflictError] else ConnectionLost.exit_code
+        self.retryable = error is not None and error.retryable
+
+
+class VerifyRequested(Exception):
+    """
+    The local side verifies instead of syncing, which the remote switches to,
+    see run_verify().
+    """
+
+
+def available_compression() -> List[str]:
+    """
+    Get the compression algorithms available on this side.
+
+    Returns:
+        list: Names of the algorithms; zlib is always available.
+    """
+    return ["zlib"] + (["zstd"] if zstandard else []) + (["lz4"] if lz4frame else [])
+
+
+def parse_compress(spec: str) -> str:
+    """
+    Check a compression specification of the form ALGORITHM[:LEVEL], where
+    ALGORITHM is none, zlib, zstd, or lz4 and LEVEL the compression level (1-9
+    for zlib, 1-22 for zstd, none for lz4). gzip is accepted for zlib, which
+    compresses the same way without the gzip header.
+
+    Args:
+        spec (str): Compression specification.
+
+    Returns:
+        str: The specification, with gzip replaced by zlib.
+
+    Raises:
+        argparse.ArgumentTypeError: If the specification is not valid.
+    """
+    levels = {"none": None, "zlib": range(1, 10), "zstd": range(1, 23), "lz4": None}
+    algo, _, level = spec.partition(":")
+    if algo == "gzip":
+        algo = "zlib"
+        spec = algo + spec[len("gzip"):]
+    if algo not in levels:
+        raise argparse.ArgumentTypeError(f"unknown compression '{algo}', choose from {', '.join(levels)}")
+    if level:
+        valid = levels[algo]
+        if valid is None or not level.isdigit() or int(level) not in valid:
+            raise argparse.ArgumentTypeError(f"invalid level '{level}' for compression '{algo}'")
+    return spec
+
+
+def negotiate_compression(spec: str, theirs: List[str]) -> str:
+    """
+    Choose the compression to use for frames sent to the other side after the
+    sync information has been exchanged. Until then, and if the requested
+    algorithm isn't available on both sides, zlib is used.
+
+    Args:
+        spec (str): Requested compression, see parse_compress().
+        theirs (list): Algorithms available on the other side.
+
+    Returns:
+        str: Compression to use.
+    """
+    algo = spec.partition(":")[0]
+    if algo not in available_compression():
+        logger.warning("%s compression not available here, using zlib.", algo)
+        return "zlib"
+    if algo not in theirs:
+        logger.warning("%s compression not available on the other side, using zlib.", algo)
+        return "zlib"
+    return spec
+
+
+def compress_frame(data: bytes, spec: str = "zlib") -> bytes:
+    """
+    Compress the data of a frame, unless it doesn't compress well, e.g. because
+    it is a compressed attachment. This is checked by compressing a sample from
+    the start of the data quickly first.
+
+    Args:
+        data (bytes): The data to compress.
+        spec (str): Compression algorithm and level, see parse_compress().
+
+    Returns:
+        bytes: 2 followed by the data compressed with zlib and the preset
+               dictionary, 3 followed by the data compressed with zstd, 4
+               followed by the data compressed with lz4, or 0 followed by the
+               data.
+    """
+    if len(data) >= COMPRESS_MIN:
+        sample = data[:COMPRESS_SAMPLE]
+        compressor = zlib.compressobj(1, zdict=COMPRESS_DICT)
+        if len(compressor.compress(sample) + compressor.flush()) < COMPRESS_RATIO * len(sample):
+            algo, _, level = spec.partition(":")
+            if algo == "zstd":
+                flag = b"\x03"
+                compressed = zstandard.ZstdCompressor(level=int(level or 3)).compress(data)
+            elif algo == "lz4":
+                flag = b"\x04"
+                compressed = lz4frame.compress(data)
+            else:
+                flag = b"\x02"
+                compressor = zlib.compressobj(int(level or -1), zdict=COMPRESS_DICT)
+                compressed = compressor.compress(data) + compressor.flush()
+            if len(compressed) < len(data):
+                return flag + compressed
+    return b"\x00" + data
+
+
+def decompress_frame(data: bytes) -> bytes:
+    """
+    Decompress the data of a frame compressed with compress_frame(), with any
+    of the algorithms.
+
+    Args:
+        data (bytes): The data to decompress.
+
+    Returns:
+        bytes: The decompressed data.
+
+    Raises:
+        ValueError: If the data is neither compressed nor raw, or compressed
+        with an algorithm that isn't available.
+    """
+    if data[:1] == b"\x01":
+        return zlib.decompress(data[1:])
+    if data[:1] == b"\x02":
+        decompressor = zlib.decompressobj(zdict=COMPRESS_DICT)
+        return decompressor.decompress(data[1:]) + decompressor.flush()
+    if data[:1] == b"\x03" and zstandard:
+        return zstandard.ZstdDecompressor().decompress(data[1:])
+    if data[:1] == b"\x04" and lz4frame:
+        return lz4frame.decompress(data[1:])
+    if data[:1] == b"\x00":
+        return data[1:]
+    raise ProtocolError(f"Unknown frame compression {data[:1]!r}, aborting...")
+
+
+class RateLimit:
+    """
+    Token bucket that limits the rate data is sent at. Up to a second's worth
+    of data can be sent at once after a pause, after that senders are made to
+    wait until the data they sent is within the limit again.
+    """
+    def __init__(self, rate: float):
+        self.rate = rate
+        # time at which all data sent so far is within the limit, starting
+        # with a full second's worth that can be sent at once
+        self.until = time.monotonic() - 1
+        self.lock = threading.Lock()
+
+    def take(self, size: int) -> None:
+        """
+        Account for sent data and wait until it is within the limit.
+
+        Args:
+            size (int): Number of bytes sent.
+        """
+        with self.lock:
+            now = time.monotonic()
+            self.until = max(self.until, now - 1) + size / self.rate
+            wait = self.until - now
+        if wait > 0:
+            time.sleep(wait)
+
+
+@contextlib.contextmanager
+def heartbeat(stream: IO[bytes] | None, interval: float = HEARTBEAT_INTERVAL) -> Iterator[None]:
+    """
+    Send heartbeats to the other side every interval seconds while this side
+    is busy, e.g. hashing files, so that the other side, SSH, or anything in
+    between doesn't give up on the connection. Nothing else may be written to
+    the stream until the context is left.
+
+    Args:
+        stream: Stream to the other side.
+        interval (float): Seconds between heartbeats.
+    """
+    if stream is None or session["protocol"] < 4:
+        yield
+        return
+    stats = current_stats.get()
+    stop = threading.Event()
+
+    def _beat():
+        while not stop.wait(interval):
+            try:
+                stream.write(HEARTBEAT_MARKER)
+                stream.flush()
+            except (OSError, ValueError):
+                return
+            stats.write += 4
+
+    thread = threading.Thread(target=_beat, daemon=True)
+    thread.start()
+    try:
+        yield
+    finally:
+        stop.set()
+        thread.join()
+
+
+class ReadTimeout:
+    """
+    Stream that gives up on the other side if a read from it gets no data for
+    longer than a timeout, by aborting the connection, which ends the read.
+    Heartbeats count as data.
+    """
+    def __init__(self, stream: IO[bytes], timeout: float, abort: Callable[[], None]):
+        self.stream = stream
+        self.timeout = timeout
+        self.abort = abort
+        # time.monotonic() when the current read started, if any
+        self.since: float | None = None
+        self.timed_out = False
+        self.stop = threading.Event()
+        self.thread = threading.Thread(target=self._watch, daemon=True)
+        self.thread.start()
+
+    def _watch(self) -> None:
+        while not self.stop.wait(min(1, self.timeout / 4)):
+            since = self.since
+            if since is not None and time.monotonic() - since > self.timeout:
+                self.timed_out = True
+                with contextlib.suppress(OSError):
+                    self.abort()
+                return
+
+    def read(self, size: int = -1) -> bytes:
+        """
+        Read from the stream.
+
+        Args:
+            size (int): Number of bytes to read, all if negative.
+
+        Returns:
+            bytes: Data read.
+
+        Raises:
+            ConnectionLost: If the read timed out.
+        """
+        self.since = time.monotonic()
+        try:
+            data = self.stream.read(size)
+        except (OSError, ValueError):
+            if not self.timed_out:
+                raise
+            data = b''
+        finally:
+            self.since = None
+        if self.timed_out:
+            raise ConnectionLost(f"No data from remote for {self.timeout:g}s, aborting...")
+        return data
+
+    def close(self) -> None:
+        """
+        Stop watching and close the stream.
+        """
+        self.stop.set()
+        self.stream.close()
+
+    @property
+    def closed(self) -> bool:
+        """
+        Whether the stream is closed.
+        """
+        return self.stream.closed
+
+
+def parse_bwlimit(spec: str) -> float:
+    """
+    Parse a bandwidth limit in KiB/s.
+
+    Args:
+        spec (str): Limit.
+
+    Returns:
+        float: Limit in bytes per second.
+
+    Raises:
+        argparse.ArgumentTypeError: If the limit is not a finite positive number.
+    """
+    try:
+        rate = float(spec)
+    except ValueError as e:
+        raise argparse.ArgumentTypeError(f"invalid bandwidth limit '{spec}'") from e
+    if not math.isfinite(rate) or rate <= 0:
+        raise argparse.ArgumentTypeError(f"invalid bandwidth limit '{spec}'")
+    return rate * 1024
+
+
+def write(data: bytes, stream: IO[bytes] | None, flush: bool = True) -> None:
+    """
+    Write data to a stream with a 4-byte length prefix, and a 4-byte CRC32
+    checksum of the data after it with --frame-checksum. Small data is written
+    together with its length in one go. With a rate limit, waits until the data
+    is within it afterwards.
+
+    Args:
+        data (bytes): The data to write.
+        stream: A writable stream supporting .write() and .flush().
+        flush (bool): Whether to flush the stream afterwards; only to be
+                      skipped when more data follows before the other side
+                      is waited for.
+    """
+    if stream is None:
+        return
+    if session["compress"]:
+        data = compress_frame(data, session["compress"])
+    if len(data) > MAX_FRAME:
+        raise ProtocolError(f"Tried to write {len(data)} bytes in one frame, but frames hold at most {MAX_FRAME}, "
+                            "aborting...")
+    parts = [struct.pack("!I", len(data)), data]
+    if session["crc"]:
+        parts.append(struct.pack("!I", zlib.crc32(data)))
+    for chunk in ([b"".join(parts)] if len(data) < COALESCE_SIZE else parts):
+        written = stream.write(chunk)
+        if written < len(chunk):
+            raise ConnectionLost(f"Tried to write {len(chunk)} bytes, but wrote only {written}, aborting...")
+    size = sum(len(part) for part in parts)
+    current_stats.get().write += size
+    if flush:
+        stream.flush()
+    if session["rate"] is not None:
+        session["rate"].take(size)
+
+
+def read(stream: IO[bytes] | None, size_data: bytes | None = None) -> bytes:
+    """
+    Read 4-byte length-prefixed data from a stream, checking the CRC32
+    checksum after it with --frame-checksum.
+
+    Args:
+        stream: A readable stream supporting .read().
+        size_data: Length prefix that has already been read from the stream.
+
+    Returns:
+        bytes: The data read from the stream.
+
+    Raises:
+        ProtocolError: If the checksum doesn't match the data.
+    """
+    if stream is None:
+        return b''
+    if size_data is None:
+        size_data = stream.read(4)
+    current_stats.get().read += 4
+    while size_data == HEARTBEAT_MARKER:
+        size_data = stream.read(4)
+        current_stats.get().read += 4
+    if size_data == ERROR_MARKER:
+        read_error(stream)
+    size = struct.unpack("!I", size_data)[0]
+    data = stream.read(size)
+    if len(data) < size:
+        raise ConnectionLost(f"Tried to read {size} bytes, but read only {len(data)}, aborting...")
+    current_stats.get().read += size
+    if session["crc"]:
+        crc_data = stream.read(4)
+        if len(crc_data) < 4:
+            raise ConnectionLost(f"Tried to read checksum of {size} bytes, but read only {len(crc_data)} bytes "
+                                 "of it, aborting...")
+        current_stats.get().read += 4
+        if struct.unpack("!I", crc_data)[0] != zlib.crc32(data):
+            raise ProtocolError(f"Checksum of frame of {size} bytes doesn't match, data was corrupted in transit, "
+                                "aborting...")
+    if session["compress"]:
+        return decompress_frame(data)
+    return data
+
+
+def write_error(e: Exception, stream: IO[bytes]) -> None:
+    """
+    Write an error frame -- the error marker followed by a frame with the type
+    and message of the error as JSON, which is never compressed.
+
+    Args:
+        e: The error.
+        stream: A writable stream supporting .write() and .flush().
+    """
+    data = json.dumps({"type": type(e).__name__, "message": str(e)}).encode("utf-8")
+    stream.write(ERROR_MARKER + struct.pack("!I", len(data)) + data)
+    current_stats.get().write += 8 + len(data)
+    stream.flush()
+
+
+def read_error(stream: IO[bytes], data: bytes = b'') -> NoReturn:
+    """
+    Read the rest of an error frame after the error marker and raise the error.
+
+    Args:
+        stream: A readable stream supporting .read().
+        data: Data of the error frame after the marker that has already been
+              read from the stream.
+
+    Raises:
+        RemoteError: The error in the frame.
+    """
+    if len(data) < 4:
+        data += stream.read(4 - len(data))
+    size = struct.unpack("!I", data[:4])[0]
+    payload = data[4:4 + size]
+    payload += stream.read(size - len(payload))
+    current_stats.get().read += 4 + size
+    error = json.loads(payload.decode("utf-8"))
+    raise RemoteError(error["type"], error["message"])
+
+
+def run_async(m1: Callable[[], Any], m2: Callable[[], Any]) -> None:
+    """
+    Run two functions async. Used to read/write to streams at the same time.
+    If both fail, an error reported by the remote takes precedence, as the
+    other one is usually just the broken pipe to the remote that aborted.
+
+    Args:
+        m1: One function.
+        m2: Other function.
+    """
+    async def _tmp():
+        return await asyncio.gather(asyncio.to_thread(m1), asyncio.to_thread(m2), return_exceptions=True)
+
+    errors = [r for r in asyncio.run(_tmp()) if isinstance(r, BaseException)]
+    errors.sort(key=lambda e: not isinstance(e, RemoteError))
+    if errors:
+        raise errors[0]
+
+
+def last_sync_revision(sync_file: str, revision: notmuch2.DbRevision) -> int:
+    """
+    Get the revision of the last sync from the sync state file and check that
+    it belongs to the database.
+
+    Args:
+        sync_file (str): Path to the file storing the sync state.
+        revision: Database revision object, must have .uuid and .rev.
+
+    Returns:
+        int: Revision of the last sync, -1 if there was none.
+
+    Raises:
+        StateError: If the sync state file is corrupted or doesn't match the
+        database.
+    """
+    rev_prev = -1
+    try:
+        with open(sync_file, 'r', encoding="utf-8") as f:
+            tmp = f.read().strip('\n\r').split(' ')
+            uuid = revision.uuid.decode()
+            try:
+                if tmp[1] != uuid:
+                    raise StateError(f"Last sync with UUID {tmp[1]}, but notmuch DB has UUID {uuid}, aborting...")
+                rev_prev = int(tmp[0])
+                if rev_prev > revision.rev:
+                    raise StateError(f"Last sync revision {rev_prev} larger than current DB revision {revision.rev}, aborting...")
+            except (AttributeError, IndexError, UnicodeError) as e:
+                raise StateError(f"Sync state file '{sync_file}' corrupted, delete to sync from scratch.") from e
+    except FileNotFoundError:
+        # no previous sync or sync file broken, leave rev_prev at -1 as this will sync entire DB
+        pass
+    return rev_prev
+
+
+def get_changes(
+    db: notmuch2.Database,
+    revision: notmuch2.DbRevision,
+    prefix: str,
+    sync_file: str,
+    orset: bool = False,
+    tag_times: bool = False,
+    properties: bool = False
+) -> Dict[str, Dict[str, Any]]:
+    """
+    Get changes that happened since the last sync, or everything in the DB if no previous sync.
+    Tags are interned, see intern_tags().
+
+    Args:
+        db: An open notmuch2.Database object.
+        revision: Database revision object, must have .uuid and .rev.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        sync_file (str): Path to the file storing the sync state.
+        orset: Whether to record tag changes in the observed-remove set of
+        each changed message and include it in the changes.
+        tag_times: Whether to record the times of tag changes of each changed
+        message and include them in the changes.
+        properties: Whether to include the properties of each changed message,
+        see get_properties().
+
+    Returns:
+        dict: Mapping of message IDs to their tags and files (and observed-remove
+        set or tag change times, and properties).
+    """
+    rev_prev = last_sync_revision(sync_file, revision)
+    logger.info("Previous sync revision %s, current revision %s.", rev_prev, revision.rev)
+    changes = {}
+    for msg in db.messages(f"lastmod:{rev_prev + 1}.."):
+        changes[msg.messageid] = {"tags": [sys.intern(t) for t in msg.tags],
+                                  "files": [str(f).removeprefix(prefix) for f in msg.filenames()]}
+        if orset:
+            changes[msg.messageid]["orset"] = observe_orset(msg, f"{revision.uuid.decode()}:{revision.rev}")
+        if tag_times:
+            changes[msg.messageid]["times"] = observe_times(msg, int(time.time()))
+        if properties:
+            changes[msg.messageid]["properties"] = get_properties(msg)
+    return changes
+
+
+def intern_tags(obj: Dict[str, Any]) -> Dict[str, Any]:
+    """
+    Intern the tags of a message in changes decoded from JSON, so that the same
+    tag is kept in memory only once instead of once per message, which makes a
+    big difference when syncing millions of messages.
+
+    Args:
+        obj (dict): Decoded JSON object.
+
+    Returns:
+        dict: The object with its tags interned, if it has any.
+    """
+    if isinstance(obj.get("tags"), list):
+        obj["tags"] = [sys.intern(t) for t in obj["tags"]]
+    return obj
+
+
+def write_changes(changes: Dict[str, Dict[str, Any]], stream: IO[bytes] | None) -> None:
+    """
+    Write changes in chunks of CHANGES_CHUNK messages, each in its own frame,
+    followed by an empty frame. This keeps only one chunk at a time encoded in
+    memory on either side, instead of all changes of an initial sync. Chunks
+    are encoded as JSON, or MessagePack if negotiated (session["encoding"]).
+
+    Args:
+        changes (dict): Changes to write.
+        stream: A writable stream supporting .write() and .flush().
+    """
+    mids = list(changes)
+    for start in range(0, len(mids), CHANGES_CHUNK):
+        chunk = {mid: changes[mid] for mid in mids[start:start + CHANGES_CHUNK]}
+        if session["encoding"] == "msgpack":
+            write(msgpack.packb(chunk), stream, False)
+        else:
+            write(json.dumps(chunk).encode("utf-8"), stream, False)
+    write(b"", stream)
+
+
+def read_changes(stream: IO[bytes] | None) -> Dict[str, Dict[str, Any]]:
+    """
+    Read changes written by write_changes(), with tags interned.
+
+    Args:
+        stream: A readable stream supporting .read().
+
+    Returns:
+        dict: The changes.
+    """
+    changes: Dict[str, Dict[str, Any]] = {}
+    while chunk := read(stream):
+        if session["encoding"] == "msgpack":
+            changes.update(msgpack.unpackb(chunk, object_hook=intern_tags))
+        else:
+            changes.update(json.loads(chunk, object_hook=intern_tags))
+    return changes
+
+
+def get_orset(msg: notmuch2.Message) -> Dict[str, List[str]]:
+    """
+    Get the observed-remove set of tags of a message, stored in its properties.
+    Each element is a tag with a unique identifier ("<UUID>:<revision> <tag>")
+    of where and when it was added; removed elements are kept as tombstones.
+
+    Args:
+        msg: A notmuch2.Message object.
+
+    Returns:
+        dict: Added elements ("add") and removed elements ("rm").
+    """
+    return {"add": sorted(v for _, v in msg.properties.getall(ORSET_ADD, exact=True)),
+            "rm": sorted(v for _, v in msg.properties.getall(ORSET_RM, exact=True))}
+
+
+def set_orset(msg: notmuch2.Message, orset: Dict[str, List[str]]) -> None:
+    """
+    Store the observed-remove set of tags of a message in its properties.
+
+    Args:
+        msg: A notmuch2.Message object.
+        orset (dict): Added elements ("add") and removed elements ("rm").
+    """
+    with msg.frozen():
+        del msg.properties[ORSET_ADD]
+        del msg.properties[ORSET_RM]
+        for e in orset["add"]:
+            msg.properties.add(ORSET_ADD, e)
+        for e in orset["rm"]:
+            msg.properties.add(ORSET_RM, e)
+
+
+def orset_tags(orset: Dict[str, List[str]]) -> set[str]:
+    """
+    Get the tags in an observed-remove set.
+
+    Args:
+        orset (dict): Added elements ("add") and removed elements ("rm").
+
+    Returns:
+        set: The tags that have been added and not removed.
+    """
+    return {e.split(' ', 1)[1] for e in orset["add"]}
+
+
+def merge_orsets(
+    orset_mine: Dict[str, List[str]],
+    orset_theirs: Dict[str, List[str]]
+) -> Dict[str, List[str]]:
+    """
+    Merge two observed-remove sets: an element is in the result if it has been
+    added on either side and not removed on either side. This is commutative,
+    associative, and idempotent, so that any number of copies converge to the
+    same tags regardless of the order in which they are synced.
+
+    Args:
+        orset_mine (dict): Local observed-remove set.
+        orset_theirs (dict): Remote observed-remove set.
+
+    Returns:
+        dict: Merged observed-remove set.
+    """
+    rm = set(orset_mine["rm"]) | set(orset_theirs["rm"])
+    return {"add": sorted((set(orset_mine["add"]) | set(orset_theirs["add"])) - rm),
+            "rm": sorted(rm)}
+
+
+def observe_orset(msg: notmuch2.Message, dot: str) -> Dict[str, List[str]]:
+    """
+    Record local tag changes in the observed-remove set of a message: tags that
+    are not in the set are added with the given identifier, all elements of
+    tags that are in the set but not on the message anymore are removed.
+
+    Args:
+        msg: A notmuch2.Message object.
+        dot (str): Unique identifier for added tags ("<UUID>:<revision>").
+
+    Returns:
+        dict: The updated observed-remove set.
+    """
+    orset = get_orset(msg)
+    tags = set(msg.tags)
+    add = set(orset["add"]) | {f"{dot} {t}" for t in tags - orset_tags(orset)}
+    rm = {e for e in add if e.split(' ', 1)[1] not in tags}
+    updated = {"add": sorted(add - rm), "rm": sorted(set(orset["rm"]) | rm)}
+    if updated != orset:
+        set_orset(msg, updated)
+    return updated
+
+
+def get_state_dir(prefix: str, uuid: str, state_dir: str | None = None) -> str:
+    """
+    Get the directory with the sync state files of the notmuch database with
+    the given UUID. This is the .notmuch directory of the database, unless
+    another directory is given, which has a subdirectory for each database so
+    that several databases for the same mail store can share it.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        uuid (str): UUID of the notmuch database.
+        state_dir (str): Directory to keep sync state in instead of .notmuch.
+
+    Returns:
+        str: Directory with sync state files.
+    """
+    if state_dir is None:
+        return os.path.join(prefix, ".notmuch")
+    return os.path.join(os.path.expanduser(state_dir), uuid)
+
+
+def get_vector(
+    prefix: str,
+    revision: notmuch2.DbRevision,
+    state_dir: str | None = None
+) -> Dict[str, int]:
+    """
+    Get the version vector of the notmuch database, i.e. for each database UUID
+    the latest revision of that database whose changes have been incorporated
+    here, either directly or through syncs with other peers. This is the
+    element-wise maximum of the vectors recorded in all sync state files. The
+    entry for our own UUID is only advanced if there have been changes since
+    the last sync with any peer, so that changes received through syncs do not
+    make us look newer than we are.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        revision: Database revision object, must have .uuid and .rev.
+        state_dir (str): Directory to keep sync state in instead of .notmuch.
+
+    Returns:
+        dict: Mapping of database UUIDs to revisions.
+    """
+    uuid = revision.uuid.decode()
+    vector: Dict[str, int] = {}
+    rev_last = -1
+    for f in Path(get_state_dir(prefix, uuid, state_dir)).glob("notmuch-sync-" + "?" * 36):
+        tmp = f.read_text(encoding="utf-8").strip('\n\r').split(' ')
+        if len(tmp) < 2 or tmp[1] != uuid:
+            continue
+        try:
+            rev_last = max(rev_last, int(tmp[0]))
+            if len(tmp) > 2:
+                for k, v in json.loads(tmp[2]).items():
+                    vector[k] = max(vector.get(k, 0), v)
+        except (AttributeError, ValueError) as e:
+            raise StateError(f"Sync state file '{f}' corrupted, delete to sync from scratch.") from e
+    if revision.rev > rev_last:
+        vector[uuid] = revision.rev
+    return vector
+
+
+def compare_vectors(
+    vector_mine: Dict[str, int],
+    vector_theirs: Dict[str, int]
+) -> str | None:
+    """
+    Compare two version vectors to determine whether one side has seen
+    everything the other side has seen.
+
+    Args:
+        vector_mine (dict): Local version vector.
+        vector_theirs (dict): Remote version vector.
+
+    Returns:
+        "mine" if the remote side is stale (everything it has seen has been
+        seen locally), "theirs" if the local side is stale, None if the two
+        sides have changed concurrently or are identical.
+    """
+    uuids = set(vector_mine) | set(vector_theirs)
+    newer_mine = any(vector_mine.get(u, 0) > vector_theirs.get(u, 0) for u in uuids)
+    newer_theirs = any(vector_theirs.get(u, 0) > vector_mine.get(u, 0) for u in uuids)
+    if newer_mine and not newer_theirs:
+        return "mine"
+    if newer_theirs and not newer_mine:
+        return "theirs"
+    return None
+
+
+def get_times(msg: notmuch2.Message) -> Dict[str, List[Any]]:
+    """
+    Get the times of the last changes of the tags of a message, stored in its
+    properties as "<time> +<tag>" for additions and "<time> -<tag>" for
+    removals.
+
+    Args:
+        msg: A notmuch2.Message object.
+
+    Returns:
+        dict: Mapping of tags to time of last change and whether the tag was
+        added (True) or removed (False).
+    """
+    times = {}
+    for _, v in msg.properties.getall(TAG_TIME, exact=True):
+        t, tag = v.split(' ', 1)
+        times[tag[1:]] = [int(t), tag[0] == '+']
+    return times
+
+
+def set_times(msg: notmuch2.Message, times: Dict[str, List[Any]]) -> None:
+    """
+    Store the times of the last changes of the tags of a message in its
+    properties.
+
+    Args:
+        msg: A notmuch2.Message object.
+        times (dict): Mapping of tags to time of last change and whether the
+        tag was added.
+    """
+    with msg.frozen():
+        del msg.properties[TAG_TIME]
+        for tag in sorted(times):
+            t, present = times[tag]
+            msg.properties.add(TAG_TIME, f"{t} {'+' if present else '-'}{tag}")
+
+
+def times_tags(times: Dict[str, List[Any]]) -> set[str]:
+    """
+    Get the tags that have been added last.
+
+    Args:
+        times (dict): Mapping of tags to time of last change and whether the
+        tag was added.
+
+    Returns:
+        set: The tags whose last change was an addition.
+    """
+    return {tag for tag, (_, present) in times.items() if present}
+
+
+def merge_times(
+    times_mine: Dict[str, List[Any]],
+    times_theirs: Dict[str, List[Any]]
+) -> Dict[str, List[Any]]:
+    """
+    Merge the times of the last changes of tags by taking the most recent
+    change for each tag. If both sides changed a tag at the same time, the
+    addition wins.
+
+    Args:
+        times_mine (dict): Local tag change times.
+        times_theirs (dict): Remote tag change times.
+
+    Returns:
+        dict: Merged tag change times.
+    """
+    return {tag: list(max(tuple(t) for t in (times_mine.get(tag), times_theirs.get(tag)) if t is not None))
+            for tag in set(times_mine) | set(times_theirs)}
+
+
+def observe_times(msg: notmuch2.Message, now: int) -> Dict[str, List[Any]]:
+    """
+    Record the current time for all tags of a message that have been added or
+    removed since the last time the tags of the message were recorded.
+
+    Args:
+        msg: A notmuch2.Message object.
+        now (int): Current time (seconds since the epoch).
+
+    Returns:
+        dict: The updated tag change times.
+    """
+    times = get_times(msg)
+    tags = set(msg.tags)
+    updated = {tag: times[tag] if tag in times and times[tag][1] == (tag in tags) else [now, tag in tags]
+               for tag in tags | set(times)}
+    if updated != times:
+        set_times(msg, updated)
+    return updated
+
+
+def get_properties(msg: notmuch2.Message) -> Dict[str, List[str]]:
+    """
+    Get the properties of a message to sync, i.e. all except those with a
+    prefix in LOCAL_PROPERTIES.
+
+    Args:
+        msg: A notmuch2.Message object.
+
+    Returns:
+        dict: Mapping of property keys to their sorted values.
+    """
+    props: Dict[str, List[str]] = {}
+    for key, value in msg.properties.getall():
+        if not key.startswith(LOCAL_PROPERTIES):
+            props.setdefault(key, []).append(value)
+    return {key: sorted(values) for key, values in props.items()}
+
+
+def set_properties(msg: notmuch2.Message, props: Dict[str, List[str]]) -> None:
+    """
+    Set the properties of a message to sync, removing those not given; see
+    get_properties().
+
+    Args:
+        msg: A notmuch2.Message object.
+        props (dict): Mapping of property keys to their values.
+    """
+    props_old = get_properties(msg)
+    with msg.frozen():
+        for key in sorted(set(props_old) | set(props)):
+            if sorted(props.get(key, [])) == props_old.get(key, []):
+                continue
+            del msg.properties[key]
+            for value in props.get(key, []):
+                msg.properties.add(key, value)
+
+
+def merge_tags(
+    changes_mine: Dict[str, Dict[str, Any]],
+    changes_theirs: Dict[str, Dict[str, Any]],
+    prefer: str | None = None,
+    policies: List[Tuple[str, str]] | None = None,
+    base: Dict[str, List[str]] | None = None
+) -> Dict[str, List[str]]:
+    """
+    Decide the tags for all messages that have been changed on both sides since
+    the last sync. If the changes include observed-remove sets, the tags of the
+    merged sets are taken. If the changes include tag change times, the most
+    recent change of each tag is taken. Otherwise, if one side is stale (see
+    compare_vectors()), its changes were already seen by the other side and the
+    tags of the other side are taken. Otherwise, if the tags of the message at
+    the last sync are known, tags added on either side since then are added and
+    tags removed on either side removed, otherwise the union of all tags is
+    taken. Tags that match a merge policy are merged according to the first
+    matching policy instead; the "newest" policy takes the most recent change
+    of the tag.
+
+    Args:
+        changes_mine (dict): Local changes, mapping message IDs to tags.
+        changes_theirs (dict): Remote changes, mapping message IDs to tags.
+        prefer: "mine" or "theirs" to take the tags of that side, None to take
+        the union.
+        policies (list): Per-tag merge policies as returned by
+        parse_tag_policies().
+        base (dict): Tags of messages at the last sync, see record_tags().
+
+    Returns:
+        dict: Mapping of conflicting message IDs to their merged tags.
+    """
+    decisions = {}
+    for mid in changes_theirs:
+        if mid not in changes_mine:
+            continue
+        if "orset" in changes_mine[mid] and "orset" in changes_theirs[mid]:
+            tags = orset_tags(merge_orsets(changes_mine[mid]["orset"], changes_theirs[mid]["orset"]))
+        elif "times" in changes_mine[mid] and "times" in changes_theirs[mid]:
+            tags = times_tags(merge_times(changes_mine[mid]["times"], changes_theirs[mid]["times"]))
+        elif prefer == "mine":
+            tags = set(changes_mine[mid]["tags"])
+        elif prefer == "theirs":
+            tags = set(changes_theirs[mid]["tags"])
+        elif base is not None and mid in base:
+            # three-way merge: tags both sides still have, and tags added since
+            # the last sync on either side
+            mine, theirs = set(changes_mine[mid]["tags"]), set(changes_theirs[mid]["tags"])
+            tags = (mine & theirs) | ((mine | theirs) - set(base[mid]))
+        else:
+            tags = set(changes_theirs[mid]["tags"]) | set(changes_mine[mid]["tags"])
+        newest = None
+        for tag in set(changes_theirs[mid]["tags"]) | set(changes_mine[mid]["tags"]):
+            policy = next((p for pat, p in policies or [] if fnmatch.fnmatchcase(tag, pat)), None)
+            if policy == "newest" and "times" in changes_mine[mid] and "times" in changes_theirs[mid]:
+                if newest is None:
+                    newest = times_tags(merge_times(changes_mine[mid]["times"], changes_theirs[mid]["times"]))
+                if tag in newest:
+                    tags.add(tag)
+                else:
+                    tags.discard(tag)
+            elif policy == "union":
+                tags.add(tag)
+            elif policy == "mine":
+                if tag in changes_mine[mid]["tags"]:
+                    tags.add(tag)
+                else:
+                    tags.discard(tag)
+            elif policy == "theirs":
+                if tag in changes_theirs[mid]["tags"]:
+                    tags.add(tag)
+                else:
+                    tags.discard(tag)
+        decisions[mid] = sorted(tags)
+    return decisions
+
+
+def parse_duration(spec: str) -> float:
+    """
+    Parse a duration given as a number with an optional unit (s, m, h, or d),
+    e.g. "90", "10m", or "1.5h".
+
+    Args:
+        spec (str): Duration.
+
+    Returns:
+        float: Duration in seconds.
+
+    Raises:
+        argparse.ArgumentTypeError: If the duration is not valid.
+    """
+    units = {"s": 1, "m": 60, "h": 3600, "d": 86400}
+    num, unit = (spec[:-1], spec[-1]) if spec and spec[-1] in units else (spec, "s")
+    try:
+        secs = float(num) * units[unit]
+    except ValueError as e:
+        raise argparse.ArgumentTypeError(f"invalid duration '{spec}'") from e
+    if not math.isfinite(secs) or secs <= 0:
+        raise argparse.ArgumentTypeError(f"invalid duration '{spec}'")
+    return secs
+
+
+def parse_percent(spec: str) -> float:
+    """
+    Parse a percentage greater than 0 and at most 100.
+
+    Args:
+        spec (str): Percentage.
+
+    Returns:
+        float: Percentage.
+
+    Raises:
+        argparse.ArgumentTypeError: If the percentage is not valid.
+    """
+    try:
+        percent = float(spec)
+    except ValueError as e:
+        raise argparse.ArgumentTypeError(f"invalid percentage '{spec}'") from e
+    if not 0 < percent <= 100:
+        raise argparse.ArgumentTypeError(f"invalid percentage '{spec}', must be greater than 0 and at most 100")
+    return percent
+
+
+def parse_count(spec: str, minimum: int = 1) -> int:
+    """
+    Parse a whole number of at least the given minimum.
+
+    Args:
+        spec (str): Number.
+        minimum (int): Smallest number allowed.
+
+    Returns:
+        int: Number.
+
+    Raises:
+        argparse.ArgumentTypeError: If the number is not valid.
+    """
+    try:
+        count = int(spec)
+    except ValueError as e:
+        raise argparse.ArgumentTypeError(f"invalid number '{spec}'") from e
+    if count < minimum:
+        raise argparse.ArgumentTypeError(f"invalid number '{spec}', must be at least {minimum}")
+    return count
+
+
+def parse_time_of_day(spec: str) -> Tuple[int, int]:
+    """
+    Parse a time of day given as hours and minutes, e.g. "03:00".
+
+    Args:
+        spec (str): Time of day.
+
+    Returns:
+        tuple: Hour and minute.
+
+    Raises:
+        argparse.ArgumentTypeError: If the time is not valid.
+    """
+    hour, _, minute = spec.partition(":")
+    try:
+        at = (int(hour), int(minute))
+    except ValueError as e:
+        raise argparse.ArgumentTypeError(f"invalid time '{spec}', must be HH:MM") from e
+    if not (0 <= at[0] < 24 and 0 <= at[1] < 60):
+        raise argparse.ArgumentTypeError(f"invalid time '{spec}', must be HH:MM")
+    return at
+
+
+def parse_tag_policies(
+    specs: List[str],
+    local: bool,
+    default: str | None = None,
+    times: bool = False
+) -> List[Tuple[str, str]]:
+    """
+    Parse per-tag merge policies of the form "<tag glob>:<policy>", where policy
+    is "union", "prefer-local", "prefer-remote", or "newest". "local" and
+    "remote" are always from the point of view of the side notmuch-sync was
+    started on. The default policy, if any, applies to all tags that match no
+    other policy.
+
+    Args:
+        specs (list): Policies as given on the command line.
+        local (bool): Whether this is the local side.
+        default (str): Policy for all other tags, if any.
+        times (bool): Whether tag change times are kept, which "newest" needs.
+
+    Returns:
+        list: Tuples of tag glob and "union", "mine", "theirs", or "newest".
+
+    Raises:
+        SyncError: If a policy is unknown, or "newest" without tag change
+        times.
+    """
+    names = {"union": "union",
+             "prefer-local": "mine" if local else "theirs",
+             "prefer-remote": "theirs" if local else "mine",
+             "newest": "newest"}
+    policies = []
+    for spec in specs + ([f"*:{default}"] if default else []):
+        pat, _, name = spec.rpartition(':')
+        if not pat or name not in names:
+            raise SyncError(f"Invalid tag merge policy '{spec}', must be <tag>:<union|prefer-local|prefer-remote|newest>.")
+        if name == "newest" and not times:
+            raise SyncError(f"Tag merge policy '{spec}' needs --tag-times.")
+        policies.append((pat, names[name]))
+    return policies
+
+
+def merge_properties(
+    changes_mine: Dict[str, Dict[str, Any]],
+    changes_theirs: Dict[str, Dict[str, Any]],
+    prefer: str | None = None,
+    policies: List[Tuple[str, str]] | None = None
+) -> Dict[str, Dict[str, List[str]]]:
+    """
+    Decide the properties for all messages that have been changed on both sides
+    since the last sync, like merge_tags() does for tags: for each key, the
+    values of the side given by the first matching merge policy are taken,
+    otherwise those of the preferred side, if any, otherwise the values of the
+    key on either side.
+
+    Args:
+        changes_mine (dict): Local changes, mapping message IDs to properties.
+        changes_theirs (dict): Remote changes, mapping message IDs to properties.
+        prefer: "mine" or "theirs" to take the properties of that side, None to
+        take the union.
+        policies (list): Per-key merge policies as returned by
+        parse_property_policies().
+
+    Returns:
+        dict: Mapping of conflicting message IDs to their merged properties.
+    """
+    decisions = {}
+    for mid in changes_theirs:
+        if mid not in changes_mine or "properties" not in changes_mine[mid] or \
+                "properties" not in changes_theirs[mid]:
+            continue
+        sides = {"mine": changes_mine[mid]["properties"], "theirs": changes_theirs[mid]["properties"]}
+        props = {}
+        for key in set(sides["mine"]) | set(sides["theirs"]):
+            policy = next((p for pat, p in policies or [] if fnmatch.fnmatchcase(key, pat)), prefer)
+            if policy in sides:
+                values = sides[policy].get(key, [])
+            else:
+                values = sorted(set(sides["mine"].get(key, [])) | set(sides["theirs"].get(key, [])))
+            if values:
+                props[key] = values
+        decisions[mid] = props
+    return decisions
+
+
+def parse_property_policies(specs: List[str], local: bool) -> List[Tuple[str, str]]:
+    """
+    Parse per-property merge policies of the form "<key glob>:<policy>", where
+    policy is "union", "prefer-local", or "prefer-remote", as for tags (see
+    parse_tag_policies()).
+
+    Args:
+        specs (list): Policies as given on the command line.
+        local (bool): Whether this is the local side.
+
+    Returns:
+        list: Tuples of key glob and "union", "mine", or "theirs".
+
+    Raises:
+        SyncError: If a policy is unknown.
+    """
+    names = {"union": "union",
+             "prefer-local": "mine" if local else "theirs",
+             "prefer-remote": "theirs" if local else "mine"}
+    policies = []
+    for spec in specs:
+        pat, _, name = spec.rpartition(':')
+        if not pat or name not in names:
+            raise SyncError(f"Invalid property merge policy '{spec}', must be "
+                            "<key>:<union|prefer-local|prefer-remote>.")
+        policies.append((pat, names[name]))
+    return policies
+
+
+def protect_tags(
+    mid: str,
+    tags_new: set[str],
+    tags_old: set[str],
+    protected: List[str]
+) -> set[str]:
+    """
+    Undo any changes to protected tags, logging each of them.
+
+    Args:
+        mid (str): Message ID, for logging.
+        tags_new (set): Tags to be set.
+        tags_old (set): Current tags.
+        protected (list): Globs of tags that must not be changed.
+
+    Returns:
+        set: Tags to be set with protected tags as they were.
+    """
+    tags = set(tags_new)
+    for tag in sorted(tags_new ^ tags_old):
+        if any(fnmatch.fnmatchcase(tag, pat) for pat in protected):
+            if tag in tags_new:
+                logger.warning("Not adding protected tag %s to %s.", tag, mid)
+                tags.discard(tag)
+            else:
+                logger.warning("Not removing protected tag %s from %s.", tag, mid)
+                tags.add(tag)
+    return tags
+
+
+def batch_encode(term: str) -> str:
+    """
+    Hex-encode all characters of a tag or message ID that are not allowed
+    unencoded in notmuch batch tagging syntax, like notmuch dump does.
+
+    Args:
+        term (str): Tag or message ID to encode.
+
+    Returns:
+        str: Encoded tag or message ID.
+    """
+    safe = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+-_@=.,"
+    return "".join(c if c in safe else "".join(f"%{b:02x}" for b in c.encode("utf-8")) for c in term)
+
+
+def tag_diff(
+    mid: str,
+    tags_old: set[str],
+    tags_new: set[str],
+    diff_format: str = "text"
+) -> str:
+    """
+    Format the changes from old to new tags of a message, either as text
+    ("+flagged -unread") or as a line in notmuch batch tagging syntax that can
+    be applied with "notmuch tag --batch".
+
+    Args:
+        mid (str): Message ID.
+        tags_old (set): Current tags.
+        tags_new (set): Tags to be set.
+        diff_format (str): "text" or "patch".
+
+    Returns:
+        str: Formatted tag changes.
+    """
+    ops = [("+", tag) for tag in sorted(tags_new - tags_old)] + \
+          [("-", tag) for tag in sorted(tags_old - tags_new)]
+    if diff_format == "patch":
+        return " ".join(op + batch_encode(tag) for op, tag in ops) + f" -- id:{batch_encode(mid)}"
+    return f"Tag changes for {mid}: {' '.join(op + tag for op, tag in ops)}."
+
+
+def sync_tags(
+    db: notmuch2.Database,
+    changes_mine: Dict[str, Dict[str, Any]],
+    changes_theirs: Dict[str, Dict[str, Any]],
+    decisions: Dict[str, List[str]] | None = None,
+    protected: List[str] | None = None,
+    diff_format: str = "text",
+    property_decisions: Dict[str, Dict[str, List[str]]] | None = None
+) -> int:
+    """
+    Synchronize tags between local and remote changes. Applies tags from all
+    remotely changed IDs to local messages with the same ID, overwriting any
+    local tags. If an ID appears both in remote and local changes, apply the
+    merge decision for it (the union of all tags unless decided otherwise by
+    the remote). If remote changes include observed-remove sets, they are
+    merged into the local ones and the tags of the merged sets applied;
+    likewise for tag change times. If remote changes include properties, they
+    are set in the same way, i.e. from the remote changes or the property merge
+    decisions. Protected tags are never added or removed.
+    If a message is not found locally, do nothing (will be synced later).
+    Tags are changed in atomic transactions of session["batch"] messages.
+    With debug logging, the changes to the tags of each message are logged in
+    the given format instead of the resulting tags.
+
+    Args:
+        db: An open notmuch2.Database object.
+        changes_mine (dict): Local changes, mapping message IDs to tags.
+        changes_theirs (dict): Remote changes, mapping message IDs to tags.
+        decisions (dict): Merged tags for message IDs changed on both sides, as
+        computed by merge_tags(). Computed locally if not given.
+        protected (list): Globs of tags that must not be changed.
+        diff_format (str): Format of logged tag changes, "text" or "patch".
+        property_decisions (dict): Merged properties for message IDs changed
+        on both sides, as computed by merge_properties(). Computed locally if
+        not given.
+
+    Returns:
+        int: Number of tag and property changes made.
+    """
+    if decisions is None:
+        decisions = merge_tags(changes_mine, changes_theirs)
+    if property_decisions is None:
+        property_decisions = merge_properties(changes_mine, changes_theirs)
+    changes = 0
+    with AtomicBatches(db) as batches:
+        for mid in changes_theirs:
+            tags = set(decisions[mid] if mid in decisions else changes_theirs[mid]["tags"])
+            try:
+                msg = db.find(mid)
+                if msg.ghost:
+                    continue
+                if "orset" in changes_theirs[mid]:
+                    orset_mine = get_orset(msg)
+                    orset = merge_orsets(orset_mine, changes_theirs[mid]["orset"])
+                    if mid not in decisions:
+                        tags = orset_tags(orset)
+                    if orset != orset_mine:
+                        set_orset(msg, orset)
+                if "times" in changes_theirs[mid]:
+                    times_mine = get_times(msg)
+                    times = merge_times(times_mine, changes_theirs[mid]["times"])
+                    if mid not in decisions:
+                        tags = times_tags(times)
+                    if times != times_mine:
+                        set_times(msg, times)
+                # a message counts once, whether its properties, tags, or both change
+                changed = False
+                if "properties" in changes_theirs[mid]:
+                    props = property_decisions.get(mid, changes_theirs[mid]["properties"])
+                    props_mine = get_properties(msg)
+                    if props != props_mine:
+                        logger.info("Setting properties %s for %s.", props, mid)
+                        changed = True
+                        if not dry_run(f"change properties of {mid}"):
+                            journal("properties", id=mid, before=props_mine, after=props)
+                            set_properties(msg, props)
+                            current_stats.get().message_ids.add(mid)
+                if protected:
+                    tags = protect_tags(mid, tags, set(msg.tags), protected)
+                if tags != set(msg.tags):
+                    if logger.isEnabledFor(logging.DEBUG):
+                        logger.debug("%s", tag_diff(mid, set(msg.tags), tags, diff_format))
+                    else:
+                        logger.info("Setting tags %s for %s.", sorted(list(tags)), mid)
+                    ops = [f"+{tag}" for tag in sorted(tags - set(msg.tags))] + [f"-{tag}" for tag in sorted(set(msg.tags) - tags)]
+                    changed = True
+                    if not dry_run(f"change tags of {mid}: {' '.join(ops)}"):
+                        journal("tags", id=mid, before=sorted(msg.tags), after=sorted(tags))
+                        with msg.frozen():
+                            current_stats.get().message_ids.add(mid)
+                            msg.tags.clear()
+                            for tag in sorted(list(tags)):
+                                msg.tags.add(tag)
+                            reconcile_flags(msg)
+                if changed:
+                    changes += 1
+                    batches.done()
+            except LookupError:
+                # we don't have this message on our side, it will be added later
+                # when syncing files
+                pass
+
+    return changes
+
+
+def preview_changes(
+    db: notmuch2.Database,
+    prefix: str,
+    changes_mine: Dict[str, Dict[str, Any]],
+    changes_theirs: Dict[str, Dict[str, Any]],
+    decisions: Dict[str, List[str]],
+    protected: List[str] | None = None,
+    include: List[str] | None = None,
+    exclude: List[str] | None = None
+) -> Dict[str, Any]:
+    """
+    Determine what a sync would change on this side without changing anything:
+    the tag changes sync_tags() would make and, per maildir folder, the files
+    that would be added and removed. Moved files show up as removed and added.
+    Files are determined from the names in the changes only, i.e. without
+    checking whether files to add exist under other names already.
+
+    Args:
+        db: An open notmuch2.Database object.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        changes_mine (dict): Local changes.
+        changes_theirs (dict): Remote changes.
+        decisions (dict): Merged tags for message IDs changed on both sides.
+        protected (list): Globs of tags that must not be changed.
+        include (list): Globs of file names of files to add, if any.
+        exclude (list): Globs of file names of files not to add.
+
+    Returns:
+        dict: "tags" mapping message IDs to old and new tags, and "folders"
+        mapping folder names to "added" and "removed" files.
+    """
+    preview: Dict[str, Any] = {"tags": {}, "folders": {}}
+
+    def _file(fname, key):
+        folder = preview["folders"].setdefault(maildir_folder(fname), {"added": [], "removed": []})
+        folder[key].append(fname)
+
+    for mid in changes_theirs:
+        tags = set(decisions[mid] if mid in decisions else changes_theirs[mid]["tags"])
+        theirs = set(changes_theirs[mid]["files"])
+        try:
+            msg = db.find(mid)
+            if msg.ghost:
+                raise LookupError
+        except LookupError:
+            for f in sorted(theirs):
+                if included(f, include, exclude):
+                    _file(f, "added")
+            continue
+        tags_mine = set(msg.tags)
+        if protected:
+            tags = protect_tags(mid, tags, tags_mine, protected)
+        if tags != tags_mine:
+            preview["tags"][mid] = [sorted(tags_mine), sorted(tags)]
+        mine = {str(f).removeprefix(prefix) for f in msg.filenames()}
+        for f in sorted(theirs - mine):
+            if included(f, include, exclude):
+                _file(f, "added")
+        if mid not in changes_mine and all(included(f, include, exclude) for f in theirs - mine):
+            for f in sorted(mine - theirs):
+                _file(f, "removed")
+    return preview
+
+
+def format_preview(preview: Dict[str, Any], side: str, diff_format: str = "text") -> str:
+    """
+    Format the changes determined by preview_changes() as a unified diff of the
+    state of a side before and after the sync, with a hunk for each folder with
+    files to add or remove and one for each message with tag changes.
+
+    Args:
+        preview (dict): Changes to format.
+        side (str): Name of the side, "local" or "remote".
+        diff_format (str): Format of tag changes, "text" for a line per added
+                           or removed tag, "patch" for notmuch batch tagging
+                           syntax.
+
+    Returns:
+        str: Formatted changes, empty if there are none.
+    """
+    if not preview["tags"] and not preview["folders"]:
+        return ""
+    lines = [f"--- {side}", f"+++ {side} after sync"]
+    for name in sorted(preview["folders"]):
+        folder = preview["folders"][name]
+        lines.append(f"@@ folder {name} -{len(folder['removed'])} +{len(folder['added'])} @@")
+        lines += [f"-{f}" for f in folder["removed"]] + [f"+{f}" for f in folder["added"]]
+    for mid in sorted(preview["tags"]):
+        old, new = (set(tags) for tags in preview["tags"][mid])
+        lines.append(f"@@ id:{mid} @@")
+        if diff_format == "patch":
+            lines.append(tag_diff(mid, old, new, diff_format))
+        else:
+            lines += [f"-{tag}" for tag in sorted(old - new)] + [f"+{tag}" for tag in sorted(new - old)]
+    return "\n".join(lines) + "\n"
+
+
+def confirm_summary(preview: Dict[str, Any]) -> Dict[str, Any]:
+    """
+    Summarize the changes determined by preview_changes() to ask for
+    confirmation: the number of messages with tag changes, the number of files
+    to remove, and the most common tag changes.
+
+    Args:
+        preview (dict): Changes to summarize.
+
+    Returns:
+        dict: "tags", "removed", and "common" with pairs of tag change (e.g.
+        "-unread") and number of messages.
+    """
+    ops: Dict[str, int] = {}
+    for old, new in preview["tags"].values():
+        for op in [f"+{tag}" for tag in set(new) - set(old)] + [f"-{tag}" for tag in set(old) - set(new)]:
+            ops[op] = ops.get(op, 0) + 1
+    return {"tags": len(preview["tags"]),
+            "removed": sum(len(folder["removed"]) for folder in preview["folders"].values()),
+            "common": sorted(ops.items(), key=lambda item: (-item[1], item[0]))[:CONFIRM_TAGS]}
+
+
+def format_confirm(summary: Dict[str, Any], side: str) -> List[str]:
+    """
+    Format a summary as returned by confirm_summary() for the user.
+
+    Args:
+        summary (dict): Summary of the changes.
+        side (str): Name of the side, e.g. "local".
+
+    Returns:
+        list: Lines of output.
+    """
+    lines = [f"{summary['tags']} messages with tag changes, {summary['removed']} files to remove"]
+    lines += [f"  {op} on {count} messages" for op, count in summary["common"]]
+    return [f"{side + ':':8}{line}" for line in lines]
+
+
+def ask_confirm(lines: List[str], yes: bool = False) -> bool:
+    """
+    Show what a sync is about to change on stderr and ask whether to go ahead.
+
+    Args:
+        lines (list): What is about to change.
+        yes (bool): Whether to go ahead without asking.
+
+    Returns:
+        bool: Whether to go ahead.
+
+    Raises:
+        SyncError: If there is no terminal to ask on.
+    """
+    sys.stderr.write("".join(line + "\n" for line in lines))
+    sys.stderr.flush()
+    if yes:
+        logger.info("Going ahead without asking (--yes).")
+        return True
+    if not sys.stdin.isatty():
+        raise SyncError("No terminal to ask for confirmation on, aborting (use --yes to go ahead without asking)...")
+    return input("Go ahead? [y/N] ").strip().lower() in ("y", "yes")
+
+
+def record_sync(
+    fname: str,
+    revision: notmuch2.DbRevision,
+    vector: Dict[str, int] | None = None
+) -> None:
+    """
+    Record last sync revision and, if given, the version vector after the sync.
+
+    Args:
+        fname: File to write to.
+        revision: Revision/UUID to record.
+        vector: Version vector to record.
+    """
+    with open(fname, 'w', encoding="utf-8") as f:
+        logger.info("Writing last sync revision %s.", revision.rev)
+        if vector is None:
+            f.write(f"{revision.rev} {revision.uuid.decode()}")
+        else:
+            f.write(f"{revision.rev} {revision.uuid.decode()} {json.dumps(vector, separators=(',', ':'))}")
+
+
+def load_tags(fname: str) -> Dict[str, List[str]] | None:
+    """
+    Load the tags of all messages at the last sync with the other side.
+
+    Args:
+        fname (str): File the tags are kept in.
+
+    Returns:
+        dict: Mapping of message IDs to tags, None if there is no (valid)
+              file.
+    """
+    try:
+        return json.loads(zlib.decompress(Path(fname).read_bytes()))
+    except (OSError, ValueError, zlib.error):
+        return None
+
+
+def record_tags(fname: str, db: notmuch2.Database, mids: List[str]) -> None:
+    """
+    Record the tags of messages after a sync, for a three-way merge of the tags
+    of messages changed on both sides in the next one. Only the tags of the
+    given (changed) messages are updated, unless there is no record yet, in
+    which case the tags of all messages are recorded.
+
+    Args:
+        fname (str): File to keep the tags in, zlib-compressed.
+        db: An open notmuch2.Database object.
+        mids (list): IDs of the messages changed during the sync.
+    """
+    tags = load_tags(fname)
+    if tags is None:
+        logger.info("Recording tags of all messages.")
+        tags = {msg.messageid: sorted(msg.tags) for msg in db.messages("*")}
+    else:
+        for mid in mids:
+            try:
+                msg = db.find(mid)
+                if msg.ghost:
+                    raise LookupError
+                tags[mid] = sorted(msg.tags)
+            except LookupError:
+                tags.pop(mid, None)
+    with open(fname + ".tmp", "wb") as f:
+        f.write(zlib.compress(json.dumps(tags, separators=(',', ':')).encode("utf-8")))
+    os.replace(fname + ".tmp", fname)
+
+
+def check_changes(
+    dbw: notmuch2.Database,
+    changes_theirs: Dict[str, Dict[str, Any]],
+    fname: str,
+    max_change: float | None = None
+) -> None:
+    """
+    Check that the remote changes do not alter more than the given fraction of
+    all messages in the local database. The first sync with a remote is exempt.
+
+    Args:
+        dbw: An open writable notmuch2.Database object.
+        changes_theirs (dict): Remote changes to be applied.
+        fname: Name of sync file for the remote.
+        max_change: Maximum fraction of messages to change, None for no limit.
+
+    Raises:
+        SyncError: If more than the given fraction of messages would change.
+    """
+    if max_change is None or not os.path.exists(fname):
+        return
+    total = dbw.count_messages("*")
+    if len(changes_theirs) > max_change * max(total, 1):
+        raise SyncError(f"Sync would change {len(changes_theirs)} of {total} messages, more than allowed by --max-change {max_change}, aborting (use --force to sync anyway)...")
+
+
+def exchange_uuids(uuid: str, from_stream: IO[bytes] | None, to_stream: IO[bytes] | None) -> str:
+    """
+    Start a session with the handshake: exchange protocol versions and database
+    UUIDs with the other side and agree on the protocol version to use. From
+    protocol version 5 on, both sides then send an empty frame and another one
+    when the other side's comes in, to measure the round-trip time.
+
+    Args:
+        uuid (str): UUID of the notmuch database on this side.
+        from_stream: Stream to read from the other side.
+        to_stream: Stream to write to the other side.
+
+    Returns:
+        str: UUID of the notmuch database on the other side.
+
+    Raises:
+        ProtocolError: If the other side doesn't speak the protocol.
+        ConnectionLost: If the other side went away before the handshake.
+    """
+    uuids = {"mine": uuid}
+    size = len(HANDSHAKE) + 4 + 36
+
+    def _send_uuid():
+        logger.info("Sending protocol version %s and UUID %s...", PROTOCOL_VERSION, uuids["mine"])
+        to_stream.write(HANDSHAKE + struct.pack("!I", PROTOCOL_VERSION) + uuids["mine"].encode("utf-8"))
+        current_stats.get().write += size
+        to_stream.flush()
+
+    def _recv_uuid():
+        logger.info("Receiving protocol version and UUID...")
+        data = from_stream.read(size)
+        current_stats.get().read += size
+        if data.startswith(ERROR_MARKER):
+            current_stats.get().read -= size - 4
+            read_error(from_stream, data[4:])
+        if len(data) == 0:
+            raise ConnectionLost("Remote closed the connection before sending its UUID, aborting (if SSH asks "
+                             "for a password or second factor, use --interactive-auth)...")
+        if not data.startswith(HANDSHAKE) or len(data) < size:
+            raise ProtocolError(f"Remote sent {data!r} instead of the notmuch-sync handshake, aborting (make sure that "
+                             "nothing on the remote, e.g. shell startup files, writes to stdout, and that "
+                             "notmuch-sync is up to date on both sides)...")
+        version = struct.unpack("!I", data[len(HANDSHAKE):len(HANDSHAKE) + 4])[0]
+        if min(version, PROTOCOL_VERSION) < PROTOCOL_MIN:
+            raise ProtocolError(f"Remote speaks protocol version {version}, but this side needs at least "
+                             f"{PROTOCOL_MIN}, aborting (update notmuch-sync on the remote)...")
+        session["protocol"] = min(version, PROTOCOL_VERSION)
+        uuids["theirs"] = data[len(HANDSHAKE) + 4:].decode("utf-8")
+
+    run_async(_send_uuid, _recv_uuid)
+    if session["protocol"] >= 5:
+        start = time.monotonic()
+        write(b"", to_stream)
+        read(from_stream)
+        # the other side sends this one once it has got the first one
+        write(b"", to_stream)
+        read(from_stream)
+        session["rtt"] = time.monotonic() - start
+        logger.debug("Round-trip time %.1fms.", session["rtt"] * 1000)
+    return uuids["theirs"]
+
+
+def initial_sync(
+    dbw: notmuch2.Database,
+    prefix: str,
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None,
+    arbiter: bool = False,
+    orset: bool = False,
+    tag_times: bool = False,
+    policies: List[Tuple[str, str]] | None = None,
+    protected: List[str] | None = None,
+    receive_only: bool = False,
+    max_change: float | None = None,
+    diff_format: str = "text",
+    state_dir: str | None = None,
+    compress: str = "",
+    preview: bool = False,
+    phases: List[str] | None = None,
+    patterns: Dict[str, List[str]] | None = None,
+    confirm: bool = False,
+    yes: bool = False,
+    properties: bool = False,
+    property_policies: List[Tuple[str, str]] | None = None
+) -> Tuple[Dict[str, Dict[str, Any]], Dict[str, Dict[str, Any]], int, str, Dict[str, Any]]:
+    """
+    Perform the initial synchronization of UUIDs, sync information (version
+    vectors, whether a side is receive-only, and the session ID chosen by the
+    local side), and tag changes, which includes applying any remote tag
+    changes to messages that exist locally. UUIDs, sync information, and
+    changes are communicated to/from the remote over the respective streams.
+    Tags of messages that were changed on both sides are decided by the arbiter
+    (the remote side, which acts as the hub when several clients sync against
+    it) and adopted as-is by the other side, so that both sides always converge
+    to the same tags. A receive-only side does not send any local changes.
+    Nothing is applied if the remote changes would alter more than the given
+    fraction of local messages. With confirm, the arbiter sends a summary of
+    the changes on its side and waits for the other side, which asks whether to
+    go ahead, to send the answer. With properties, message properties are
+    synced and merged like tags.
+
+    Args:
+        dbw: An open writable notmuch2.Database object.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        from_stream: Stream to read from the remote.
+        to_stream: Stream to write to the remote.
+        arbiter: Whether this side decides on tags of conflicting messages.
+        orset: Whether to merge tags as observed-remove sets.
+        tag_times: Whether to merge tags by taking the most recent change.
+        policies (list): Per-tag merge policies used by the arbiter.
+        protected (list): Globs of tags that must not be changed.
+        receive_only: Whether this side only receives changes.
+        max_change: Maximum fraction of messages to change, None for no limit.
+        diff_format (str): Format of logged tag changes, "text" or "patch".
+        state_dir (str): Directory to keep sync state in instead of .notmuch.
+        compress (str): Requested compression, negotiated with the other side
+                        for all frames after the sync information.
+        preview (bool): Whether to only exchange changes and merge decisions
+                        without applying anything; the decisions are returned
+                        in the info dict.
+        phases (list): Optional parts of the protocol this side runs, see
+                       protocol_phases(); must be the same on both sides.
+        patterns (dict): Globs of file names to "include" and "exclude" in
+                         file transfers; must be the same on both sides.
+        confirm (bool): Whether to ask for confirmation before applying
+                        anything; must be given on both sides or neither.
+        yes (bool): Whether to go ahead without asking on the local side.
+        properties (bool): Whether to sync message properties; must be given
+                           on both sides or neither.
+        property_policies (list): Per-property merge policies used by the
+                                  arbiter.
+
+    Returns:
+        tuple: (local changes dict, remote changes dict, number of tag changes,
+                name of sync file, dict with merged version vector to record
+                and whether the remote is receive-only)
+
+    Raises:
+        ValueError: If the other side doesn't speak the protocol, runs different
+        optional parts of it or uses different include/exclude globs, or more
+        than the given fraction of messages would change.
+        SyncError: If the changes weren't confirmed.
+        VerifyRequested: If this is the arbiter and the other side verifies
+        instead of syncing.
+    """
+    revision = dbw.revision()
+    uuids = {}
+    uuids["mine"] = revision.uuid.decode()
+    uuids["theirs"] = exchange_uuids(uuids["mine"], from_stream, to_stream)
+
+    logger.info("UUIDs synced, using protocol version %s.", session["protocol"])
+    logger.debug("Local UUID %s, remote UUID %s.", uuids["mine"], uuids["theirs"])
+    fname = os.path.join(get_state_dir(prefix, uuids["mine"], state_dir), "notmuch-sync-" + uuids["theirs"])
+    if state_dir is not None:
+        os.makedirs(os.path.dirname(fname), exist_ok=True)
+
+    infos = {}
+    infos["mine"] = {"vector": get_vector(prefix, revision, state_dir), "receive_only": receive_only,
+                     "session": session["id"]}
+    if compress:
+        infos["mine"]["compress"] = available_compression()
+    if msgpack:
+        infos["mine"]["encodings"] = ["msgpack"]
+    if phases is not None:
+        infos["mine"]["phases"] = phases
+    if patterns is not None:
+        infos["mine"]["patterns"] = {k: sorted(v) for k, v in patterns.items()}
+    if arbiter:
+        # a local side verifying gets the messages to verify instead
+        infos["mine"]["verifies"] = True
+
+    def _send_info():
+        logger.info("Sending sync information...")
+        write(json.dumps(infos["mine"]).encode("utf-8"), to_stream)
+
+    def _recv_info():
+        logger.info("Receiving sync information...")
+        infos["theirs"] = json.loads(read(from_stream).decode("utf-8"))
+
+    run_async(_send_info, _recv_info)
+
+    if arbiter and infos["theirs"].get("verify"):
+        raise VerifyRequested()
+    if infos["theirs"].get("session"):
+        # remote side, adopt session ID of local (which gets its own back)
+        session["id"] = infos["theirs"]["session"]
+    if phases is not None and "phases" in infos["theirs"] and set(infos["theirs"]["phases"]) != set(phases):
+        differ = sorted(set(phases) ^ set(infos["theirs"]["phases"]))
+        raise ProtocolError(f"Local and remote side differ in options {', '.join('--' + p for p in differ)}, "
+                         "aborting (give the same options on both sides when using --remote-cmd)...")
+    if patterns is not None and "patterns" in infos["theirs"] and infos["theirs"]["patterns"] != infos["mine"]["patterns"]:
+        raise ProtocolError("Local and remote side differ in --include-pattern or --exclude-pattern, "
+                         "aborting (give the same options on both sides when using --remote-cmd)...")
+    if compress:
+        session["compress"] = negotiate_compression(compress, infos["theirs"].get("compress", ["zlib"]))
+        logger.debug("Using %s compression.", session["compress"])
+    session["encoding"] = "msgpack" if msgpack and "msgpack" in infos["theirs"].get("encodings", []) else "json"
+    logger.debug("Sending changes as %s.", session["encoding"])
+    vectors = {k: v["vector"] for k, v in infos.items()}
+    logger.debug("Local version vector %s, remote version vector %s.", vectors["mine"], vectors["theirs"])
+    info = {"vector": {u: max(vectors["mine"].get(u, 0), vectors["theirs"].get(u, 0))
+                       for u in set(vectors["mine"]) | set(vectors["theirs"])},
+            "receive_only": infos["theirs"]["receive_only"]}
+    if info["receive_only"]:
+        logger.info("Remote is receive-only.")
+
+    changes = {}
+    if receive_only:
+        logger.info("Receive-only, not sending local changes.")
+        changes["mine"] = {}
+    else:
+        logger.info("Computing local changes...")
+        with heartbeat(to_stream):
+            changes["mine"] = get_changes(dbw, revision, prefix, fname, orset, tag_times, properties)
+
+    def _send_changes():
+        logger.info("Sending local changes...")
+        write_changes(changes["mine"], to_stream)
+
+    def _recv_changes():
+        logger.info("Receiving remote changes...")
+        changes["theirs"] = read_changes(from_stream)
+
+    run_async(_send_changes, _recv_changes)
+
+    logger.info("Changes synced.")
+    logger.debug("Local changes %s, remote changes %s.", changes["mine"], changes["theirs"])
+
+    if arbiter:
+        prefer = compare_vectors(vectors["mine"], vectors["theirs"])
+        if prefer is not None:
+            logger.info("Preferring %s tags for messages changed on both sides.", prefer)
+        decisions = merge_tags(changes["mine"], changes["theirs"], prefer, policies, load_tags(fname + "-tags"))
+        property_decisions = merge_properties(changes["mine"], changes["theirs"], prefer, property_policies)
+        stamp = f"{uuids['mine']}:{revision.rev}"
+        logger.info("Sending %s merge decisions...", len(decisions))
+        merged = {"stamp": stamp, "tags": decisions}
+        if properties:
+            merged["properties"] = property_decisions
+        write(json.dumps(merged).encode("utf-8"), to_stream)
+    else:
+        logger.info("Receiving merge decisions from remote...")
+        tmp = json.loads(read(from_stream).decode("utf-8"))
+        decisions = tmp["tags"]
+        property_decisions = tmp.get("properties", {})
+        logger.debug("Merge decisions %s stamped %s.", decisions, tmp["stamp"])
+    if preview:
+        info["decisions"] = decisions
+        return (changes["mine"], changes["theirs"], 0, fname, info)
+    check_changes(dbw, changes["theirs"], fname, max_change)
+    if confirm:
+        patterns = patterns or {}
+        summary = confirm_summary(preview_changes(dbw, prefix, changes["mine"], changes["theirs"], decisions, protected,
+                                                  patterns.get("include"), patterns.get("exclude")))
+        if arbiter:
+            write(json.dumps(summary).encode("utf-8"), to_stream)
+            if read(from_stream) != CONFIRM_YES:
+                raise SyncError("Changes not confirmed on local, aborting...")
+        else:
+            summary_theirs = json.loads(read(from_stream).decode("utf-8"))
+            ok = (not (summary["tags"] or summary["removed"] or summary_theirs["tags"] or summary_theirs["removed"]) or
+                  ask_confirm(format_confirm(summary, "local") + format_confirm(summary_theirs, "remote"), yes))
+            write(CONFIRM_YES if ok else CONFIRM_NO, to_stream)
+            if not ok:
+                raise SyncError("Changes not confirmed, aborting...")
+    with heartbeat(to_stream):
+        tchanges = sync_tags(dbw, changes["mine"], changes["theirs"], decisions, protected, diff_format,
+                             property_decisions)
+    logger.info("Tags synced.")
+
+    return (changes["mine"], changes["theirs"], tchanges, fname, info)
+
+
+def check_unindexed(
+    dbw: notmuch2.Database,
+    prefix: str,
+    changes_mine: Dict[str, Dict[str, Any]],
+    changes_theirs: Dict[str, Dict[str, Any]],
+    index: bool = False
+) -> int:
+    """
+    Find files that are not in the notmuch database in all folders with files
+    in the local or remote changes (e.g. mails copied there manually), which
+    means that the files to sync are determined on incomplete information.
+    Either warn about or index them. For maildirs, both cur and new are
+    checked.
+
+    Args:
+        dbw: An open writable notmuch2.Database object.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        changes_mine (dict): Local changes.
+        changes_theirs (dict): Remote changes.
+        index (bool): Whether to add unindexed files to the database.
+
+    Returns:
+        int: Number of unindexed files.
+    """
+    dirs = set()
+    for changes in (changes_mine, changes_theirs):
+        for mid in changes:
+            for f in changes[mid]["files"]:
+                d = os.path.dirname(f)
+                if os.path.basename(d) in ("cur", "new"):
+                    dirs |= {os.path.join(os.path.dirname(d), "cur"), os.path.join(os.path.dirname(d), "new")}
+                else:
+                    dirs.add(d)
+
+    unindexed = 0
+    for d in sorted(dirs):
+        path = os.path.join(prefix, d)
+        if not os.path.isdir(path):
+            continue
+        for fname in sorted(os.listdir(path)):
+            full = os.path.join(path, fname)
+            if fname.startswith(".") or not os.path.isfile(full):
+                continue
+            try:
+                dbw.get(full)
+            except LookupError:
+                unindexed += 1
+                if index and not dry_run(f"index {full}"):
+                    logger.warning("Adding unindexed file %s to DB.", full)
+                    try:
+                        dbw.add(full)
+                    except notmuch2.FileNotEmailError:
+                        logger.warning("Not adding %s, not an email.", full)
+                else:
+                    logger.warning("File %s is not in the notmuch database, sync may be incomplete.", full)
+    return unindexed
+
+
+def included(fname: str, include: List[str] | None, exclude: List[str] | None) -> bool:
+    """
+    Check whether a file is to be transferred according to include and exclude
+    globs.
+
+    Args:
+        fname (str): File name relative to the mail root.
+        include (list): Globs of which a file must match at least one, if any.
+        exclude (list): Globs of which a file must match none.
+
+    Returns:
+        bool: Whether the file is to be transferred.
+    """
+    if include and not any(fnmatch.fnmatchcase(fname, pat) for pat in include):
+        return False
+    return not any(fnmatch.fnmatchcase(fname, pat) for pat in exclude or [])
+
+
+def get_missing_files(
+    dbw: notmuch2.Database,
+    prefix: str,
+    changes_mine: Dict[str, Dict[str, Any]],
+    changes_theirs: Dict[str, Dict[str, Any]],
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None,
+    move_on_change: bool = False,
+    receive_only: bool = False,
+    jobs: int | None = None,
+    digests: Dict[str, List[Any]] | None = None,
+    include: List[str] | None = None,
+    exclude: List[str] | None = None
+) -> Tuple[Dict[str, Dict[str, Any]], int, int]:
+    """
+    Determine which files are missing locally compared to the remote, and handle
+    file moves/copies based on SHA256 checksums. Delete any files that aren't
+    there on the remote anymore. This never deletes a message, only duplicate
+    files for a message.
+
+    Args:
+        dbw: An open writable notmuch2.Database object.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        changes_mine (dict): Local changes.
+        changes_theirs (dict): Remote changes.
+        from_stream: Stream to read from the remote.
+        to_stream: Stream to write to the remote.
+        move_on_change: Whether to move file that has local and remote changes.
+        This flag is used to prevent infinite loops where local has one file
+        name and remote another file name (e.g. when running mbsync independently).
+        receive_only: Whether this side only receives changes, i.e. rejects
+        any hash requests from the remote.
+        jobs: Number of worker threads to read and digest files with, defaults
+        to the number of available CPUs.
+        digests: Cache of digests of unchanged files, see digest_files(); all
+        files are hashed if not given.
+        include (list): Globs of file names of which files missing locally must
+        match at least one to be copied, moved, or requested, if any.
+        exclude (list): Globs of file names that files missing locally must not
+        match to be copied, moved, or requested.
+
+    Returns:
+        tuple: (dict of missing files, number of local moves/copies, number of
+                local deletions)
+
+    Raises:
+        ProtocolError: If this side is receive-only and the remote requested
+        hashes.
+    """
+    ret = {}
+    mcchanges = 0
+    dchanges = 0
+    hashes: dict[str, List[str]] = {}
+    # check which files we need to get digests for to determine if they've
+    # been moved/copied
+    hashes["req_mine"] = []
+    # local files of messages with missing files, to digest in one go
+    hashes["files_mine"] = []
+    for mid in changes_theirs:
+        try:
+            msg = dbw.find(mid)
+            if msg.ghost:
+                continue
+            fnames_theirs = changes_theirs[mid]["files"]
+            files_mine = [ str(f) for f in msg.filenames() ]
+            fnames_mine = [ f.removeprefix(prefix) for f in files_mine ]
+            missing_mine = {f for f in set(fnames_theirs) - set(fnames_mine) if included(f, include, exclude)}
+            if len(missing_mine) > 0:
+                # only the digests of missing files are needed, in particular
+                # none of files left out by include/exclude globs
+                hashes["req_mine"].extend(f for f in fnames_theirs if f in missing_mine)
+                hashes["files_mine"].extend(files_mine)
+        except LookupError:
+            continue
+
+    def _send_hashes_req():
+        logger.info("Requesting %s hashes from remote...", len(hashes["req_mine"]))
+        logger.debug("Requesting hashes %s", hashes["req_mine"])
+        write(json.dumps(hashes["req_mine"]).encode("utf-8"), to_stream)
+
+    def _recv_hashes_req():
+        logger.info("Receiving hash requests from remote...")
+        hashes["req_theirs"] = json.loads(read(from_stream).decode("utf-8"))
+        logger.debug("Hashes requested by remote %s", hashes["req_theirs"])
+
+    run_async(_send_hashes_req, _recv_hashes_req)
+
+    if receive_only and len(hashes["req_theirs"]) > 0:
+        raise ProtocolError(f"Remote requested {len(hashes['req_theirs'])} hashes from receive-only side, aborting...")
+
+    def _send_hashes():
+        logger.info("Hashing %s requested files and sending to remote...",
+                    len(hashes["req_theirs"]))
+        with heartbeat(to_stream):
+            tmp = digest_files([os.path.join(prefix, f) for f in hashes["req_theirs"]], jobs, digests)
+        write(json.dumps(tmp).encode("utf-8"), to_stream)
+
+    def _recv_hashes():
+        # hash local files while the remote hashes the requested ones rather
+        # than after
+        logger.info("Hashing %s local files...", len(hashes["files_mine"]))
+        hashes["mine"] = digest_files(hashes["files_mine"], jobs, digests)
+        logger.info("Receiving hashes from remote...")
+        tmp = json.loads(read(from_stream).decode("utf-8"))
+        hashes["theirs"] = dict(zip(hashes["req_mine"], tmp))
+
+    run_async(_send_hashes, _recv_hashes)
+    digests_mine = dict(zip(hashes["files_mine"], hashes["mine"]))
+
+    # now actually determine changes and move/copy
+    for mid in changes_theirs:
+        try:
+            msg = dbw.find(mid)
+            if msg.ghost:
+                ret[mid] = changes_theirs[mid]
+                continue
+            fnames_theirs = changes_theirs[mid]["files"]
+            theirs = set(fnames_theirs)
+            # ordered set of local file names
+            fnames_mine = dict.fromkeys(str(f).removeprefix(prefix) for f in msg.filenames())
+            missing_mine = {f for f in theirs - fnames_mine.keys() if included(f, include, exclude)}
+            kept: set[str] = set()
+            if len(missing_mine) > 0:
+                # group local files by digest (and by digest and name without
+                # maildir flags) once so that messages with many duplicate
+                # files, e.g. mailing list mails delivered to many folders,
+                # take linear time
+                by_hash: Dict[str, Deque[str]] = {}
+                by_stem: Dict[Tuple[str, str], List[str]] = {}
+                for f in fnames_mine:
+                    h = digests_mine[os.path.join(prefix, f)]
+                    by_hash.setdefault(h, deque()).append(f)
+                    if ":2," in f:
+                        by_stem.setdefault((h, f.rsplit(":2,", 1)[0]), []).append(f)
+                for f in fnames_theirs:
+                    if f in missing_mine:
+                        # check if it has been moved/copied
+                        h = hashes["theirs"][f]
+                        matches = by_hash.get(h, deque())
+                        # the same file with different flags is the result of
+                        # tag changes on either side that the tag sync already
+                        # reconciled -- moving it would undo the renaming to the
+                        # synced tags here and on the next sync on the other side
+                        renames = [x for x in by_stem.get((h, f.rsplit(":2,", 1)[0]), [])
+                                   if x in fnames_mine and x not in theirs and flags_only_rename(x, f)]
+                        if len(renames) > 0:
+                            logger.info("%s is %s with different maildir flags, keeping.",
+                                        renames[0], f)
+                            kept.add(renames[0])
+                            missing_mine.remove(f)
+                        elif len(matches) > 0:
+                            src = os.path.join(prefix, matches[0])
+                            dst = os.path.join(prefix, f)
+                            if matches[0] in theirs:
+                                mcchanges += 1
+                                logger.info("Copying %s to %s.", src, dst)
+                                if not dry_run(f"copy {src} to {dst}"):
+                                    copy_file(src, dst)
+                                    dbw.add(dst)
+                                    journal("add", file=dst, id=mid, new=False)
+                                fnames_mine[f] = None
+                            elif mid not in changes_mine or move_on_change:
+                                mcchanges += 1
+                                logger.info("Moving %s to %s.", src, dst)
+                                if not dry_run(f"move {src} to {dst}"):
+                                    Path(dst).parent.mkdir(parents=True, exist_ok=True)
+                                    shutil.move(src, dst)
+                                    dbw.add(dst)
+                                    logger.info("Removing %s from DB.", src)
+                                    dbw.remove(src)
+                                    journal("move", **{"from": src, "to": dst, "id": mid})
+                                fnames_mine[f] = None
+                                del fnames_mine[matches[0]]
+                                matches.popleft()
+                                matches.append(f)
+                            missing_mine.remove(f)
+            # check which ones are still missing
+            if len(missing_mine) > 0:
+                ret[mid] = {"files": [f for f in changes_theirs[mid]["files"] if f in missing_mine]}
+
+            # delete any files that are not there remotely after copy/move,
+            # unless files were left out because of include/exclude globs
+            # (e.g. a file moved to an excluded name on the remote)
+            if mid not in changes_mine and all(included(f, include, exclude) for f in theirs - fnames_mine.keys()):
+                if len(fnames_mine.keys() & (theirs | kept)) == 0:
+                    raise ConflictError(f"Message '{mid}' has {fnames_theirs} on remote and different {list(fnames_mine)} locally!")
+                to_delete = fnames_mine.keys() - theirs - kept
+                for f in to_delete:
+                    fname = os.path.join(prefix, f)
+                    dchanges += 1
+                    logger.info("Removing %s from DB and deleting file.", fname)
+                    if not dry_run(f"delete {fname}"):
+                        dbw.remove(fname)
+                        delete_file(fname, prefix, mid)
+        except LookupError:
+            # don't have this message; all files missing
+            ret[mid] = changes_theirs[mid]
+
+    return (ret, mcchanges, dchanges)
+
+
+def send_file(fname: str, stream: IO[bytes], flush: bool = True) -> None:
+    """
+    Send a file's contents to a stream with 4-byte length prefix. Large files
+    are sent with sendfile() on Linux, which flushes the stream, unless frames
+    are checksummed. If the other side speaks protocol version 2, files larger
+    than STREAM_CHUNK are streamed in chunks instead, so that neither side
+    needs to keep them in memory, see stream_chunk() for their size. Older
+    peers can't receive files larger than MAX_FRAME at all.
+
+    Args:
+        fname (str): Path to the file to send.
+        stream: Writable stream.
+        flush (bool): Whether to flush the stream afterwards.
+
+    Raises:
+        ProtocolError: If the file is too large for the other side's protocol
+        version.
+    """
+    with open(fname, "rb") as f:
+        size = os.fstat(f.fileno()).st_size
+        if size > MAX_FRAME and session["protocol"] < 2:
+            raise ProtocolError(f"'{fname}' has {size} bytes, but the other side speaks protocol version "
+                                f"{session['protocol']}, which can't receive files larger than {MAX_FRAME} bytes "
+                                "(update notmuch-sync there), aborting...")
+        # checksums need the data, which sendfile() never reads
+        out_fd = None if session["crc"] else stream_fd(stream)
+        if size > STREAM_CHUNK and session["protocol"] >= 2:
+            stream.write(STREAM_MARKER + struct.pack("!Q", size))
+            current_stats.get().write += 12
+            offset = 0
+            while offset < size:
+                n = min(stream_chunk(), size - offset)
+                start = time.monotonic()
+                if out_fd is None or session["compress"]:
+                    write(f.read(n), stream, False)
+                else:
+                    stream.write(struct.pack("!I", n))
+                    stream.flush()
+                    send_range(out_fd, f.fileno(), offset, n, fname)
+                    current_stats.get().write += 4 + n
+                measure_throughput(n, time.monotonic() - start)
+                offset += n
+            write(b"", stream, flush)
+            return
+        if out_fd is None or size < COALESCE_SIZE or session["compress"]:
+            write(f.read(), stream, flush)
+            return
+        # large file on Linux -- have the kernel copy it straight into the
+        # stream instead of reading it into memory first
+        stream.write(struct.pack("!I", size))
+        stream.flush()
+        send_range(out_fd, f.fileno(), 0, size, fname)
+        current_stats.get().write += 4 + size
+
+
+def stream_chunk() -> int:
+    """
+    Get the size of the next chunk to stream a file in: the bandwidth-delay
+    product of the connection, i.e. about as much as is on the way at any time,
+    from the round-trip time and the throughput measured in the session, so
+    that chunks are small on fast local networks, for responsiveness, and large
+    on slow long-distance ones, for throughput. STREAM_CHUNK until both have
+    been measured, which needs protocol version 5 on both sides.
+
+    Returns:
+        int: Size of the chunk, between MIN_CHUNK and MAX_CHUNK.
+    """
+    if session["rtt"] is None or session["throughput"] is None:
+        return STREAM_CHUNK
+    return max(MIN_CHUNK, min(MAX_CHUNK, int(session["rtt"] * session["throughput"])))
+
+
+def measure_throughput(size: int, secs: float) -> None:
+    """
+    Update the throughput of the session with a chunk that took some time to
+    send, as a moving average so that single chunks that went into buffers
+    instead of over the connection don't count for much.
+
+    Args:
+        size (int): Bytes sent.
+        secs (float): Seconds sending them took.
+    """
+    rate = size / max(secs, 1e-6)
+    session["throughput"] = rate if session["throughput"] is None else 0.8 * session["throughput"] + 0.2 * rate
+
+
+def send_range(out_fd: int, in_fd: int, offset: int, count: int, fname: str) -> None:
+    """
+    Send part of a file to a file descriptor with sendfile(), keeping to the
+    rate limit, if any.
+
+    Args:
+        out_fd (int): File descriptor to send to.
+        in_fd (int): File descriptor of the file.
+        offset (int): Start of the part in the file.
+        count (int): Size of the part.
+        fname (str): Name of the file, for errors.
+
+    Raises:
+        ValueError: If the file ends before the part does.
+    """
+    sent = 0
+    while sent < count:
+        # with a rate limit, send in chunks so that it is kept throughout
+        n = os.sendfile(out_fd, in_fd, offset + sent,
+                        count - sent if session["rate"] is None else min(count - sent, STREAM_CHUNK))
+        if n == 0:
+            raise ConnectionLost(f"Tried to send {count} bytes of {fname}, but sent only {sent}, aborting...")
+        sent += n
+        if session["rate"] is not None:
+            session["rate"].take(n)
+
+
+def stream_fd(stream: IO[bytes]) -> int | None:
+    """
+    Get the file descriptor of a stream to send files to with sendfile(), if
+    that is possible.
+
+    Args:
+        stream: Writable stream.
+
+    Returns:
+        int: The file descriptor, or None if the stream has none or the
+             platform isn't Linux.
+    """
+    if not sys.platform.startswith("linux") or not hasattr(os, "sendfile"):
+        return None
+    try:
+        fd = stream.fileno()
+    except (AttributeError, OSError):
+        return None
+    return fd if isinstance(fd, int) else None
+
+
+def recv_file(
+    fname: str,
+    stream: IO[bytes],
+    overwrite_raise: bool=True,
+    store: str | None = None
+) -> None:
+    """
+    Receive a file with a 4-byte length prefix from a stream and write it to
+    disk, validating its checksum. Streamed files are written to a temporary
+    file chunk by chunk as they come in and renamed when complete. If the file
+    already exists with different content, the received file is kept next to
+    it, see conflict_name().
+
+    Args:
+        fname (str): Destination file path.
+        stream: Readable stream.
+        overwrite_raise: Raise error if existing file would be overwritten.
+        store (str): Content-addressed store to hardlink the file to, if any.
+
+    Raises:
+        ValueError: If file to receive already exists with different content
+        or received file's checksum does not match expected, or a streamed
+        file is incomplete.
+    """
+    size_data = stream.read(4)
+    if size_data != STREAM_MARKER:
+        write_file(fname, read(stream, size_data), overwrite_raise, store)
+        return
+    size = struct.unpack("!Q", stream.read(8))[0]
+    current_stats.get().read += 12
+    tmp = fname + ".notmuch-sync-tmp"
+    Path(fname).parent.mkdir(parents=True, exist_ok=True)
+    sha = hashlib.new("sha256")
+    received = 0
+    try:
+        with open(tmp, "wb") as f:
+            while chunk := read(stream):
+                sha.update(chunk)
+                f.write(chunk)
+                received += len(chunk)
+                # chunks vary in size, log every MiB
+                if received >> 20 != (received - len(chunk)) >> 20:
+                    logger.debug("Received %s/%s bytes of %s.", received, size, fname)
+            f.flush()
+            os.fsync(f.fileno())
+        if received != size:
+            raise ConnectionLost(f"Receiving '{fname}', but got {received} of {size} bytes, aborting...")
+        # the existing file is rarely there and compared like in write_file(),
+        # i.e. in memory
+        if Path(fname).exists() and overwrite_raise:
+            sha_mine = digest(Path(tmp).read_bytes())
+            if digest(Path(fname).read_bytes()) != sha_mine:
+                qname = conflict_name(fname, sha_mine)
+                durable_replace(tmp, qname)
+                raise ConflictError(f"Receiving '{fname}', but already exists with different content, "
+                                    f"kept received file as '{qname}'!")
+    except BaseException:
+        Path(tmp).unlink(missing_ok=True)
+        raise
+    durable_replace(tmp, fname)
+    if store is not None:
+        store_file(store, fname, sha.hexdigest())
+
+
+def write_file(
+    fname: str,
+    content: bytes,
+    overwrite_raise: bool=True,
+    store: str | None = None
+) -> None:
+    """
+    Write received content to disk, validating its checksum if the file
+    already exists. The content is written to a temporary file that is synced
+    to disk and renamed when complete, so that an interrupted sync or a crash
+    never leaves a partial file. If the file already exists with different
+    content, the content is written next to it instead, see conflict_name().
+
+    Args:
+        fname (str): Destination file path.
+        content (bytes): Content to write.
+        overwrite_raise: Raise error if existing file would be overwritten.
+        store (str): Content-addressed store to hardlink the file to instead of
+        writing it, if any; files are written if that isn't possible.
+
+    Raises:
+        ValueError: If file to write already exists with different content.
+    """
+    if Path(fname).exists() and overwrite_raise:
+        sha_mine = digest(content)
+        sha_exists = digest(Path(fname).read_bytes())
+        if sha_exists != sha_mine:
+            qname = conflict_name(fname, sha_mine)
+            with open(qname + ".notmuch-sync-tmp", "wb") as f:
+                f.write(content)
+                f.flush()
+                os.fsync(f.fileno())
+            durable_replace(qname + ".notmuch-sync-tmp", qname)
+            raise ConflictError(f"Receiving '{fname}', but already exists with different content, "
+                                f"kept received file as '{qname}'!")
+    Path(fname).parent.mkdir(parents=True, exist_ok=True)
+    if store is not None and link_stored(store, fname, content):
+        return
+    with open(fname + ".notmuch-sync-tmp", "wb") as f:
+        f.write(content)
+        f.flush()
+        os.fsync(f.fileno())
+    durable_replace(fname + ".notmuch-sync-tmp", fname)
+
+
+def durable_replace(tmp: str, fname: str) -> None:
+    """
+    Rename a completely written file into place and sync the directory it is
+    in, so that the rename survives a crash; a file is thus either there with
+    its full contents or not at all.
+
+    Args:
+        tmp (str): File to rename, synced to disk already.
+        fname (str): New name.
+    """
+    os.replace(tmp, fname)
+    fsync_dir(os.path.dirname(fname))
+
+
+def fsync_dir(path: str) -> None:
+    """
+    Sync a directory to disk, i.e. the names of the files in it. Does nothing
+    where directories can't be opened, e.g. on Windows.
+
+    Args:
+        path (str): Directory, the current directory if empty.
+    """
+    try:
+        fd = os.open(path or ".", os.O_RDONLY)
+    except OSError:
+        return
+    try:
+        os.fsync(fd)
+    except OSError as e:
+        logger.debug("Not syncing directory %s: %s", path, e)
+    finally:
+        os.close(fd)
+
+
+def conflict_name(fname: str, sha: str) -> str:
+    """
+    Get the name to keep a received file under that conflicts with an existing
+    file of the same name, i.e. the name with ".conflict-" and the start of
+    the digest of the received content appended.
+
+    Args:
+        fname (str): Name of the existing file.
+        sha (str): Digest of the received content.
+
+    Returns:
+        str: Name to keep the received file under.
+    """
+    return f"{fname}.conflict-{sha[:16]}"
+
+
+def link_stored(store: str, fname: str, content: bytes) -> bool:
+    """
+    Put content into the content-addressed store, if it isn't there already,
+    and make the file a hardlink to it, replacing the file if it exists.
+
+    Args:
+        store (str): Directory of the store.
+        fname (str): File to link.
+        content (bytes): Contents of the file.
+
+    Returns:
+        bool: Whether the file was linked; False if hardlinks aren't possible,
+        e.g. because the store is on a different file system.
+    """
+    sha = hashlib.new("sha256", content).hexdigest()
+    spath = os.path.join(store, sha[:2], sha)
+    try:
+        if not os.path.exists(spath):
+            Path(spath).parent.mkdir(parents=True, exist_ok=True)
+            with open(spath + ".tmp", "wb") as f:
+                f.write(content)
+                f.flush()
+                os.fsync(f.fileno())
+            durable_replace(spath + ".tmp", spath)
+        if os.path.exists(fname):
+            if os.path.samefile(spath, fname):
+                return True
+            os.link(spath, fname + ".notmuch-sync-tmp")
+            durable_replace(fname + ".notmuch-sync-tmp", fname)
+        else:
+            os.link(spath, fname)
+            fsync_dir(os.path.dirname(fname))
+    except OSError as e:
+        logger.debug("Not linking %s to store: %s", fname, e)
+        return False
+    return True
+
+
+def store_file(store: str, fname: str, sha: str) -> bool:
+    """
+    Put a file that has been written already into the content-addressed store,
+    or make it a hardlink to the file in the store with the same contents.
+
+    Args:
+        store (str): Directory of the store.
+        fname (str): File to store.
+        sha (str): SHA256 digest of the contents of the file.
+
+    Returns:
+        bool: Whether the file is in the store, see link_stored().
+    """
+    spath = os.path.join(store, sha[:2], sha)
+    try:
+        if os.path.exists(spath):
+            os.link(spath, fname + ".notmuch-sync-tmp")
+            durable_replace(fname + ".notmuch-sync-tmp", fname)
+        else:
+            Path(spath).parent.mkdir(parents=True, exist_ok=True)
+            os.link(fname, spath)
+            fsync_dir(os.path.dirname(spath))
+    except OSError as e:
+        logger.debug("Not linking %s to store: %s", fname, e)
+        return False
+    return True
+
+
+def copy_file(src: str, dst: str) -> None:
+    """
+    Copy a file, as hardlinks to the same file in the content-addressed store
+    if one is used.
+
+    Args:
+        src (str): File to copy.
+        dst (str): Destination.
+    """
+    Path(dst).parent.mkdir(parents=True, exist_ok=True)
+    if session["store"] is not None:
+        content = Path(src).read_bytes()
+        if link_stored(session["store"], src, content) and link_stored(session["store"], dst, content):
+            return
+    shutil.copy(src, dst)
+
+
+def init_store(db: notmuch2.Database, prefix: str) -> None:
+    """
+    Use the content-addressed store in the .notmuch directory for the files
+    written by this sync. If the store doesn't exist yet, all files in the
+    notmuch database are put into it first, so that duplicates share their
+    contents.
+
+    Args:
+        db: An open notmuch2.Database object.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+    """
+    store = os.path.join(prefix, ".notmuch", "notmuch-sync-store")
+    if not os.path.exists(store):
+        logger.info("Putting all files into store %s...", store)
+        os.makedirs(store)
+        for msg in db.messages("*"):
+            for f in msg.filenames():
+                if os.path.exists(f):
+                    link_stored(store, str(f), Path(f).read_bytes())
+    session["store"] = store
+
+
+def prune_store(store: str) -> int:
+    """
+    Remove files from the content-addressed store that no file links to
+    anymore.
+
+    Args:
+        store (str): Directory of the store.
+
+    Returns:
+        int: Number of removed files.
+    """
+    removed = 0
+    for sub in Path(store).iterdir():
+        for f in sub.iterdir():
+            if f.stat().st_nlink == 1:
+                logger.debug("Removing unused %s from store.", f)
+                f.unlink()
+                removed += 1
+        if not any(sub.iterdir()):
+            sub.rmdir()
+    return removed
+
+
+def weak_checksum(data: bytes) -> Tuple[int, int]:
+    """
+    Compute the two halves of the rsync-style rolling checksum of a block.
+
+    Args:
+        data (bytes): The block.
+
+    Returns:
+        tuple: (sum of the bytes, sum of the bytes weighted by their distance
+        to the end of the block), both modulo 2^16.
+    """
+    n = len(data)
+    return (sum(data) & 0xFFFF, sum((n - i) * x for i, x in enumerate(data)) & 0xFFFF)
+
+
+def block_signatures(data: bytes, block: int = DELTA_BLOCK) -> Dict[str, List[Any]]:
+    """
+    Compute the signatures of the complete blocks of a file for the other side
+    to send a delta against.
+
+    Args:
+        data (bytes): Contents of the file.
+        block (int): Block size.
+
+    Returns:
+        dict: Weak (rolling) and strong checksums of the blocks, in order.
+    """
+    sigs: Dict[str, List[Any]] = {"weak": [], "strong": []}
+    for start in range(0, len(data) - block + 1, block):
+        chunk = data[start:start + block]
+        a, b = weak_checksum(chunk)
+        sigs["weak"].append(a | b << 16)
+        sigs["strong"].append(hashlib.blake2b(chunk, digest_size=8).hexdigest())
+    return sigs
+
+
+def make_delta(data: bytes, sigs: Dict[str, List[Any]], block: int = DELTA_BLOCK) -> bytes:
+    """
+    Encode a file as a delta to a file of the other side with the given block
+    signatures: the SHA256 of the file, followed by "C" and two 4-byte unsigned
+    ints for runs of blocks of the other side's file to copy (first block and
+    count) and "L" and a 4-byte unsigned int length for literal data.
+
+    Args:
+        data (bytes): Contents of the file.
+        sigs (dict): Block signatures of the other side's file, see
+                     block_signatures().
+        block (int): Block size.
+
+    Returns:
+        bytes: The delta.
+    """
+    lookup: Dict[int, Dict[str, int]] = {}
+    for idx, (weak, strong) in enumerate(zip(sigs["weak"], sigs["strong"])):
+        lookup.setdefault(weak, {}).setdefault(strong, idx)
+    out = [hashlib.sha256(data).digest()]
+    copy = None
+    literal = pos = 0
+
+    def _flush(end):
+        nonlocal copy
+        if copy is not None:
+            out.append(b"C" + struct.pack("!II", *copy))
+            copy = None
+        if end > literal:
+            out.append(b"L" + struct.pack("!I", end - literal) + data[literal:end])
+
+    if len(data) >= block:
+        a, b = weak_checksum(data[:block])
+    while lookup and pos + block <= len(data):
+        # the strong checksum is only computed if the weak one matches
+        strong = lookup.get(a | b << 16)
+        match = strong.get(hashlib.blake2b(data[pos:pos + block], digest_size=8).hexdigest()) if strong else None
+        if match is not None:
+            if copy is not None and literal == pos and copy[0] + copy[1] == match:
+                copy = (copy[0], copy[1] + 1)
+            else:
+                _flush(pos)
+                copy = (match, 1)
+            pos += block
+            literal = pos
+            if pos + block <= len(data):
+                a, b = weak_checksum(data[pos:pos + block])
+            continue
+        if pos + block < len(data):
+            a = (a - data[pos] + data[pos + block]) & 0xFFFF
+            b = (b - block * data[pos] + a) & 0xFFFF
+        pos += 1
+    _flush(len(data))
+    return b"".join(out)
+
+
+def apply_delta(basis: bytes, delta: bytes, block: int = DELTA_BLOCK) -> bytes:
+    """
+    Reconstruct a file from a delta to a local file, see make_delta().
+
+    Args:
+        basis (bytes): Contents of the local file the delta is against.
+        delta (bytes): The delta.
+        block (int): Block size.
+
+    Returns:
+        bytes: Contents of the file.
+
+    Raises:
+        ProtocolError: If the delta is malformed.
+        SyncError: If the reconstructed file doesn't match its checksum, e.g.
+        because the local file changed in the meantime.
+    """
+    out = []
+    pos = 32
+    while pos < len(delta):
+        op = delta[pos:pos + 1]
+        if op == b"C":
+            start, count = struct.unpack("!II", delta[pos + 1:pos + 9])
+            out.append(basis[start * block:(start + count) * block])
+            pos += 9
+        elif op == b"L":
+            size = struct.unpack("!I", delta[pos + 1:pos + 5])[0]
+            out.append(delta[pos + 5:pos + 5 + size])
+            pos += 5 + size
+        else:
+            raise ProtocolError(f"Unexpected operation {op!r} in delta, aborting...")
+    data = b"".join(out)
+    if hashlib.sha256(data).digest() != delta[:32]:
+        raise SyncError("File reconstructed from delta doesn't match its checksum.")
+    return data
+
+
+def delta_basis(dbw: notmuch2.Database, mid: str) -> str | None:
+    """
+    Find a local file of a message to receive a missing file of it as a delta
+    to.
+
+    Args:
+        dbw: An open notmuch2.Database object.
+        mid (str): Message ID.
+
+    Returns:
+        str: Path of the largest file of the message between DELTA_MIN and
+        DELTA_MAX bytes, or None if there is none.
+    """
+    try:
+        msg = dbw.find(mid)
+        if msg.ghost:
+            return None
+        sizes = {str(f): os.path.getsize(f) for f in msg.filenames() if os.path.exists(f)}
+    except LookupError:
+        return None
+    sizes = {f: size for f, size in sizes.items() if DELTA_MIN <= size <= DELTA_MAX}
+    return max(sizes, key=lambda f: sizes[f]) if sizes else None
+
+
+def send_delta(fname: str, sigs: Dict[str, List[Any]], stream: IO[bytes], flush: bool = True) -> None:
+    """
+    Send a file as a delta to a file of the other side, or in full if it is
+    larger than DELTA_MAX.
+
+    Args:
+        fname (str): Path to the file to send.
+        sigs (dict): Block signatures of the other side's file.
+        stream: Writable stream.
+        flush (bool): Whether to flush the stream afterwards.
+    """
+    if os.path.getsize(fname) > DELTA_MAX:
+        write(DELTA_FULL, stream, False)
+        send_file(fname, stream, flush)
+        return
+    write(DELTA_MARK, stream, False)
+    write(make_delta(Path(fname).read_bytes(), sigs), stream, flush)
+
+
+def recv_delta(fname: str, basis: str, stream: IO[bytes], store: str | None = None) -> None:
+    """
+    Receive a file sent with send_delta() and write it to disk.
+
+    Args:
+        fname (str): Destination file path.
+        basis (str): Path of the local file the signatures were sent of.
+        stream: Readable stream.
+        store (str): Content-addressed store to hardlink the file to, if any.
+
+    Raises:
+        ValueError: If the delta doesn't match, or the file to receive already
+        exists with different content.
+    """
+    marker = read(stream)
+    if marker == DELTA_FULL:
+        recv_file(fname, stream, store=store)
+        return
+    if marker != DELTA_MARK:
+        raise ProtocolError(f"Expected file or delta for '{fname}' from remote, aborting...")
+    delta = read(stream)
+    content = apply_delta(Path(basis).read_bytes(), delta)
+    logger.debug("Received %s as delta of %s bytes to %s.", fname, len(delta), basis)
+    write_file(fname, content, store=store)
+
+
+def find_duplicates(prefix: str, fnames: List[str]) -> List[int | None]:
+    """
+    Find files with identical contents, e.g. mailing list mails delivered to
+    several folders. Only files of the same size are compared.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        fnames (list): Files to check.
+
+    Returns:
+        list: For each file, the index of the first earlier file with identical
+        contents, or None.
+    """
+    dups: List[int | None] = [None] * len(fnames)
+    if len(fnames) < 2:
+        return dups
+    sizes: Dict[int, List[int]] = {}
+    for idx, fname in enumerate(fnames):
+        sizes.setdefault(os.path.getsize(os.path.join(prefix, fname)), []).append(idx)
+    for idxs in sizes.values():
+        if len(idxs) < 2:
+            continue
+        first: Dict[str, int] = {}
+        for idx in idxs:
+            sha = hashlib.new("sha256", Path(os.path.join(prefix, fnames[idx])).read_bytes()).hexdigest()
+            if sha in first:
+                dups[idx] = first[sha]
+            else:
+                first[sha] = idx
+    return dups
+
+
+def format_bytes(size: int) -> str:
+    """
+    Format a number of bytes for humans, e.g. "1.5 MiB".
+
+    Args:
+        size (int): Number of bytes.
+
+    Returns:
+        str: Formatted size.
+    """
+    for unit in ["B", "KiB", "MiB", "GiB"]:
+        if size < 1024 or unit == "GiB":
+            break
+        size /= 1024
+    return f"{size} B" if unit == "B" else f"{size:.1f} {unit}"
+
+
+class Progress:
+    """
+    Progress of a file transfer in both directions, shown as a single line on
+    a terminal that is redrawn as files are received and sent. Bytes are taken
+    from the stats of the session.
+    """
+    WIDTH = 20
+
+    def __init__(self, recv_total: int, send_total: int, stream: IO[str] | None = None):
+        self.recv_total, self.send_total = recv_total, send_total
+        self.recv, self.send = 0, 0
+        self.stream = stream or sys.stderr
+        self.stats = current_stats.get()
+        self.start = (self.stats.read, self.stats.write)
+        self.drawn = 0.0
+        self.lock = threading.Lock()
+
+    def line(self) -> str:
+        """
+        Format the progress.
+
+        Returns:
+            str: Progress bar and files and bytes received and sent.
+        """
+        total = self.recv_total + self.send_total
+        done = int(self.WIDTH * (self.recv + self.send) / total) if total > 0 else self.WIDTH
+        return (f"[{'#' * done}{'-' * (self.WIDTH - done)}] "
+                f"received {self.recv}/{self.recv_total} files ({format_bytes(self.stats.read - self.start[0])}), "
+                f"sent {self.send}/{self.send_total} files ({format_bytes(self.stats.write - self.start[1])})")
+
+    def update(self, recv: int = 0, send: int = 0) -> None:
+        """
+        Count received and sent files and redraw, at most ten times a second.
+
+        Args:
+            recv (int): Number of files received.
+            send (int): Number of files sent.
+        """
+        with self.lock:
+            self.recv += recv
+            self.send += send
+            now = time.monotonic()
+            if now - self.drawn >= 0.1:
+                self.drawn = now
+                self.stream.write("\r" + self.line())
+                self.stream.flush()
+
+    def close(self) -> None:
+        """
+        Draw the final progress and end the line.
+        """
+        self.stream.write("\r" + self.line() + "\n")
+        self.stream.flush()
+
+
+def sync_files(
+    dbw: notmuch2.Database,
+    prefix: str,
+    missing: Dict[str, Dict[str, Any]],
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None,
+    protected: List[str] | None = None,
+    receive_only: bool = False,
+    diff_format: str = "text",
+    lenient: bool = False,
+    new_tag: str | None = None,
+    index: Dict[str, List[Any]] | None = None,
+    jobs: int | None = None,
+    rewrite_tag: str | None = None,
+    include: List[str] | None = None,
+    exclude: List[str] | None = None,
+    deadline: float | None = None,
+    checkpoint: str | None = None,
+    progress: bool = False,
+    delta: bool = False
+) -> Tuple[int, int]:
+    """
+    Synchronize files that are missing locally or remotely. Files with
+    identical contents are only sent once and copied on the receiving side.
+    If an index of digests is given, files that exist with the same contents
+    anywhere on the receiving side are copied there instead of being sent.
+    Received files of new messages are renamed if their maildir flags don't
+    match the synced tags. Received files that conflict with existing files of
+    the same name are kept next to them, skipped, and counted in the stats.
+    Received files are recorded in the checkpoint file,
+    if given, until they have been added to the database, so that a sync that
+    is interrupted before doesn't request them again. With delta, files of
+    messages the receiving side has another file of are sent as differences to
+    that file.
+
+    Args:
+        dbw: An open writable notmuch2.Database object.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        missing (dict): Mapping of missing files by message ID.
+        from_stream: Stream to read file names and files from.
+        to_stream: Stream to send file names and files to.
+        protected (list): Globs of tags that must not be added to new messages.
+        receive_only: Whether this side only receives changes, i.e. rejects
+        any file requests from the remote.
+        diff_format (str): Format of logged tags of new messages with debug
+        logging, "text" or "patch".
+        lenient: Whether to skip received files that can't be written instead
+        of aborting.
+        new_tag (str): Tag to add to all new messages in addition to their
+        tags on the other side.
+        index (dict): Digests of all local files, see index_digests(); must be
+        given on both sides or neither.
+        jobs (int): Number of worker threads to digest files to send with.
+        rewrite_tag (str): Tag to add to new messages whose body is the same as
+        that of a message with a different ID in the index, i.e. that are likely
+        the same mail with a rewritten message ID.
+        include (list): Globs of file names of which missing files must match
+        at least one to be requested, if any.
+        exclude (list): Globs of file names that missing files must not match
+        to be requested.
+        deadline (float): time.monotonic() after which no more windows of
+        files are exchanged; the number of files not received is recorded in
+        the stats.
+        checkpoint (str): File to record received files in.
+        progress: Whether to show the progress of the transfer on stderr.
+        delta: Whether to send files as deltas where possible; must be given on
+        both sides or neither.
+
+    Returns:
+        tuple: (number of added messages, number of added files)
+
+    Raises:
+        ValueError: If this side is receive-only and the remote requested files,
+        or a received file can't be written and lenient is not set.
+    """
+    files = {}
+    files["mine"] = [ {"name": f, "id": mid} for mid in missing for f in missing[mid]["files"]
+                      if included(f, include, exclude) ]
+    changes = {"files": len(files["mine"]), "messages": 0}
+    # files received completely by an interrupted sync, only to be added to the
+    # database
+    resumed = []
+    if checkpoint is not None:
+        try:
+            received = set(Path(checkpoint).read_text(encoding="utf-8").splitlines())
+        except OSError:
+            received = set()
+        resumed = [f for f in files["mine"] if f["name"] in received and os.path.exists(os.path.join(prefix, f["name"]))]
+        if len(resumed) > 0:
+            logger.info("Not requesting %s files received by an interrupted sync.", len(resumed))
+            names = {f["name"] for f in resumed}
+            files["mine"] = [f for f in files["mine"] if f["name"] not in names]
+
+    def _send_fnames():
+        logger.info("Sending file names missing on local...")
+        write(json.dumps([f["name"] for f in files["mine"]]).encode("utf-8"), to_stream)
+
+    def _recv_fnames():
+        logger.info("Receiving file names missing on remote...")
+        files["theirs"] = json.loads(read(from_stream).decode("utf-8"))
+
+    run_async(_send_fnames, _recv_fnames)
+
+    if receive_only and len(files["theirs"]) > 0:
+        raise ProtocolError(f"Remote requested {len(files['theirs'])} files from receive-only side, aborting...")
+
+    logger.info("Missing file names synced.")
+    if session["dry_run"]:
+        # both sides stop here, nothing is transferred
+        for f in files["mine"]:
+            dry_run(f"receive {os.path.join(prefix, f['name'])}")
+        return (0, 0)
+
+    def _send_dups():
+        files["dups_theirs"] = find_duplicates(prefix, files["theirs"])
+        write(json.dumps(files["dups_theirs"]).encode("utf-8"), to_stream)
+
+    def _recv_dups():
+        files["dups_mine"] = json.loads(read(from_stream).decode("utf-8"))
+
+    run_async(_send_dups, _recv_dups)
+
+    # files the receiving side has locally under other names
+    files["local_mine"] = {}
+    files["local_theirs"] = set()
+    if index is not None:
+        def _send_digests():
+            idxs = [idx for idx, dup in enumerate(files["dups_theirs"]) if dup is None]
+            shas = digest_files([os.path.join(prefix, files["theirs"][idx]) for idx in idxs], jobs, index)
+            write(json.dumps(dict(zip(idxs, shas))).encode("utf-8"), to_stream)
+
+        def _recv_digests():
+            shas = json.loads(read(from_stream).decode("utf-8"))
+            by_digest: Dict[str, List[str]] = {}
+            for fname, entry in index.items():
+                by_digest.setdefault(entry[3], []).append(fname)
+            for idx, sha in shas.items():
+                src = find_digest(index, by_digest, sha)
+                if src is not None:
+                    files["local_mine"][int(idx)] = src
+
+        run_async(_send_digests, _recv_digests)
+
+        def _send_local():
+            write(json.dumps(sorted(files["local_mine"])).encode("utf-8"), to_stream)
+
+        def _recv_local():
+            files["local_theirs"] = set(json.loads(read(from_stream).decode("utf-8")))
+
+        run_async(_send_local, _recv_local)
+
+    # local files to receive requested files as deltas to, and the block
+    # signatures of those of the other side
+    files["basis"] = {}
+    files["sigs_theirs"] = {}
+    if delta:
+        for idx, f in enumerate(files["mine"]):
+            if idx not in files["local_mine"] and files["dups_mine"][idx] is None:
+                basis = delta_basis(dbw, f["id"])
+                if basis is not None:
+                    files["basis"][idx] = basis
+
+        def _send_sigs():
+            logger.info("Sending block signatures of %s files...", len(files["basis"]))
+            sigs = {idx: block_signatures(Path(basis).read_bytes()) for idx, basis in files["basis"].items()}
+            write(json.dumps(sigs).encode("utf-8"), to_stream)
+
+        def _recv_sigs():
+            sigs = json.loads(read(from_stream).decode("utf-8"))
+            files["sigs_theirs"] = {int(idx): sig for idx, sig in sigs.items()}
+
+        run_async(_send_sigs, _recv_sigs)
+
+    def _send_files(start):
+        dups = files["dups_theirs"]
+        for idx in range(start, min(start + FILES_WINDOW, len(files["theirs"]))):
+            fname = files["theirs"][idx]
+            if idx in files["local_theirs"]:
+                logger.info("%s/%s Not sending %s, exists on remote.", idx + 1, len(files["theirs"]),
+                            fname)
+            elif dups[idx] is None and idx in files["sigs_theirs"]:
+                logger.info("%s/%s Sending %s as delta...", idx + 1, len(files["theirs"]),
+                            fname)
+                send_delta(os.path.join(prefix, fname), files["sigs_theirs"][idx], to_stream, False)
+            elif dups[idx] is None:
+                logger.info("%s/%s Sending %s...", idx + 1, len(files["theirs"]),
+                            fname)
+                send_file(os.path.join(prefix, fname), to_stream, False)
+            else:
+                logger.info("%s/%s Not sending %s, same as %s.", idx + 1, len(files["theirs"]),
+                            fname, files["theirs"][dups[idx]])
+            if bar is not None:
+                bar.update(send=1)
+        if to_stream is not None:
+            to_stream.flush()
+
+    skipped: set[int] = set()
+
+    def _recv_files(start):
+        dups = files["dups_mine"]
+        for idx in range(start, min(start + FILES_WINDOW, len(files["mine"]))):
+            f = files["mine"][idx]
+            dst = os.path.join(prefix, f["name"])
+            try:
+                if idx in files["local_mine"]:
+                    src = files["local_mine"][idx]
+                    logger.info("%s/%s Copying %s to %s, same contents...", idx + 1, len(files["mine"]),
+                                src, f["name"])
+                    write_file(dst, Path(src).read_bytes(), store=session["store"])
+                elif dups[idx] is None and idx in files["basis"]:
+                    logger.info("%s/%s Receiving %s as delta...", idx + 1, len(files["mine"]), f["name"])
+                    recv_delta(dst, files["basis"][idx], from_stream, store=session["store"])
+                elif dups[idx] is None:
+                    logger.info("%s/%s Receiving %s...", idx + 1, len(files["mine"]), f["name"])
+                    recv_file(dst, from_stream, store=session["store"])
+                elif dups[idx] in skipped:
+                    skipped.add(idx)
+                    logger.warning("Skipping %s, same as skipped %s.", f["name"], files["mine"][dups[idx]]["name"])
+                else:
+                    src = files["mine"][dups[idx]]["name"]
+                    logger.info("%s/%s Copying %s to %s...", idx + 1, len(files["mine"]), src, f["name"])
+                    write_file(dst, Path(os.path.join(prefix, src)).read_bytes(), store=session["store"])
+            except ConflictError as e:
+                skipped.add(idx)
+                current_stats.get().conflicts += 1
+                logger.warning("%s Skipping.", e)
+            except (ProtocolError, ConnectionLost):
+                # the stream is out of step, skipping the file can't help
+                raise
+            except ValueError as e:
+                if not lenient:
+                    raise
+                skipped.add(idx)
+                logger.warning("%s Skipping.", e)
+            if bar is not None:
+                bar.update(recv=1)
+
+    stop = {"mine": False, "theirs": False}
+
+    def _send_ack():
+        stop["mine"] = deadline is not None and time.monotonic() > deadline
+        write(FILES_STOP if stop["mine"] else FILES_ACK, to_stream)
+
+    def _recv_ack():
+        ack = read(from_stream)
+        if ack not in (FILES_ACK, FILES_STOP):
+            raise ProtocolError("Expected acknowledgement of received files from remote, aborting...")
+        stop["theirs"] = ack == FILES_STOP
+
+    # files are exchanged in windows of FILES_WINDOW files that both sides
+    # acknowledge having written before the next window is sent, so that a fast
+    # sender can't get arbitrarily far ahead of a slow receiver; either side
+    # can stop after a window when it is out of time
+    done = 0
+    bar = Progress(len(files["mine"]), len(files["theirs"])) if progress and (files["mine"] or files["theirs"]) else None
+    for start in range(0, max(len(files["mine"]), len(files["theirs"])), FILES_WINDOW):
+        run_async(lambda start=start: _send_files(start), lambda start=start: _recv_files(start))
+        if checkpoint is not None and start < len(files["mine"]):
+            with open(checkpoint, "a", encoding="utf-8") as f:
+                f.writelines(files["mine"][idx]["name"] + "\n"
+                             for idx in range(start, min(start + FILES_WINDOW, len(files["mine"])))
+                             if idx not in skipped)
+        run_async(_send_ack, _recv_ack)
+        done = start + FILES_WINDOW
+        if stop["mine"] or stop["theirs"]:
+            break
+    if bar is not None:
+        bar.close()
+    remaining = max(0, len(files["mine"]) - done)
+    if remaining > 0:
+        logger.warning("Out of time, %s of %s files not received, they will be synced next time.",
+                       remaining, len(files["mine"]))
+        skipped.update(range(done, len(files["mine"])))
+        current_stats.get().remaining = remaining
+    # the other side may have files left even if this side doesn't
+    current_stats.get().incomplete = done < max(len(files["mine"]), len(files["theirs"]))
+    changes["files"] -= len(skipped)
+
+    by_body: Dict[str, List[str]] = {}
+    if rewrite_tag and index is not None:
+        for fname, entry in index.items():
+            if len(entry) > 4 and entry[4] is not None:
+                by_body.setdefault(entry[4], []).append(fname)
+
+    with heartbeat(to_stream), AtomicBatches(dbw) as batches:
+        for idx, f in enumerate(files["mine"] + resumed):
+            if idx in skipped:
+                continue
+            dst = os.path.join(prefix, f["name"])
+            logger.info("Adding %s to DB.", dst)
+            msg, dup = dbw.add(dst)
+            journal("add", file=dst, id=f["id"], new=not dup)
+            count_folders(prefix, [dst], "files")
+            if not dup:
+                changes["messages"] += 1
+                current_stats.get().message_ids.add(msg.messageid)
+                count_folders(prefix, [dst], "messages")
+                # the tags of the remote, plus those of --tag-new-synced and
+                # --flag-rewrites, all set at once below
+                tags = set(missing[f["id"]]["tags"])
+                if protected:
+                    tags = protect_tags(msg.messageid, tags, set(), protected)
+                if new_tag:
+                    tags.add(new_tag)
+                if rewrite_tag and index is not None:
+                    bd = body_digest(Path(dst).read_bytes())
+                    src = find_digest(index, by_body, bd) if bd else None
+                    if src is not None:
+                        logger.warning("%s has the same body as %s, possibly the same mail with a different message ID.",
+                                       msg.messageid, dbw.get(src).messageid)
+                        tags.add(rewrite_tag)
+                with msg.frozen():
+                    if logger.isEnabledFor(logging.DEBUG):
+                        logger.debug("%s", tag_diff(msg.messageid, set(), tags, diff_format))
+                    else:
+                        logger.info("Setting tags %s for received %s.",
+                                    sorted(tags), msg.messageid)
+                    msg.tags.clear()
+                    for tag in sorted(tags):
+                        msg.tags.add(tag)
+                    if "orset" in missing[f["id"]]:
+                        set_orset(msg, missing[f["id"]]["orset"])
+                    if "times" in missing[f["id"]]:
+                        set_times(msg, missing[f["id"]]["times"])
+                    if "properties" in missing[f["id"]]:
+                        set_properties(msg, missing[f["id"]]["properties"])
+                    # the synced tags are authoritative, rename the file if its
+                    # flags say otherwise so that notmuch doesn't change the tags
+                    # back the next time it looks at the flags
+                    reconcile_flags(msg)
+            elif session["flags"] and not flags_match(dst, msg):
+                # another file of a message that is here already
+                logger.info("Maildir flags of %s don't match tags %s, renaming.", f["name"], sorted(msg.tags))
+                with msg.frozen():
+                    reconcile_flags(msg)
+            batches.done()
+
+    if checkpoint is not None:
+        Path(checkpoint).unlink(missing_ok=True)
+    logger.info("Missing files synced.")
+
+    return (changes["messages"], changes["files"])
+
+
+def sync_renames(
+    dbw: notmuch2.Database,
+    prefix: str,
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None
+) -> int:
+    """
+    Tell the other side which files this side renamed during the sync to make
+    their maildir flags match their tags, and rename the files the other side
+    renamed in the same way, so that both sides have the same file names
+    without waiting for the next sync. Files that aren't here under their old
+    name, are here under their new name already (e.g. because this side
+    renamed them as well), or whose new name isn't a maildir flag rename of the
+    old one are left alone.
+
+    Args:
+        dbw: An open writable notmuch2.Database object.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        from_stream: Stream to read renamed files from.
+        to_stream: Stream to send renamed files to.
+
+    Returns:
+        int: Number of files renamed on this side.
+    """
+    renames: Dict[str, List[List[str]]] = {
+        "mine": [[old.removeprefix(prefix), new.removeprefix(prefix)] for old, new in current_stats.get().renamed],
+        "theirs": []
+    }
+
+    def _send_renames():
+        logger.info("Sending %s files renamed to match their tags...", len(renames["mine"]))
+        write(json.dumps(renames["mine"]).encode("utf-8"), to_stream)
+
+    def _recv_renames():
+        logger.info("Receiving files renamed to match their tags...")
+        renames["theirs"] = json.loads(read(from_stream).decode("utf-8"))
+
+    run_async(_send_renames, _recv_renames)
+
+    changes = 0
+    with AtomicBatches(dbw) as batches:
+        for old, new in renames["theirs"]:
+            if any(os.path.isabs(f) or os.pardir in Path(f).parts for f in (old, new)) or not maildir_rename(old, new):
+                logger.warning("Not renaming %s to %s, not a maildir flag rename.", old, new)
+                continue
+            src, dst = os.path.join(prefix, old), os.path.join(prefix, new)
+            if not os.path.exists(src) or os.path.lexists(dst):
+                logger.debug("Not renaming %s to %s like remote.", src, dst)
+                continue
+            changes += 1
+            if dry_run(f"move {src} to {dst}"):
+                continue
+            logger.info("Renaming %s to %s like remote.", src, dst)
+            Path(dst).parent.mkdir(parents=True, exist_ok=True)
+            os.rename(src, dst)
+            msg, _ = dbw.add(dst)
+            dbw.remove(src)
+            journal("move", **{"from": src, "to": dst, "id": msg.messageid})
+            batches.done()
+    logger.info("Renamed files synced.")
+
+    return changes
+
+
+def get_ids(prefix: str) -> List[str]:
+    """
+    Get all message IDs from the notmuch database, using Xapian directly (much
+    faster).
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+
+    Returns:
+        list: All message IDs.
+    """
+    db = xapian.Database(os.path.join(prefix, ".notmuch", "xapian"))
+    message_ids = []
+
+    logger.info("Getting all message IDs from DB...")
+    ghosts = {p.docid for p in db.postlist("Tghost")} # type: ignore[attr-defined]
+    all_docs = set(range(1, db.get_lastdocid() + 1))
+    for doc_id in all_docs - ghosts:
+        try:
+            doc = db.get_document(doc_id)
+            value = doc.get_value(1)
+            if value:
+                message_ids.append(value.decode("utf-8"))
+        except xapian.DocNotFoundError:
+            pass
+        except RuntimeError:
+            # not entirely sure why, but this seems to happen sometimes
+            continue
+
+    db.close()
+
+    return message_ids
+
+
+def get_counts(prefix: str) -> Dict[str, Any]:
+    """
+    Get the number of messages, files, and messages per maildir folder from the
+    notmuch database, using Xapian directly (only looks at term statistics, no
+    documents). Folders are as in notmuch "folder:" searches, without any
+    cur/new.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+
+    Returns:
+        dict: Number of messages, number of files, and number of messages per
+        folder.
+    """
+    db = xapian.Database(os.path.join(prefix, ".notmuch", "xapian"))
+
+    logger.info("Getting message and file counts from DB...")
+    counts: Dict[str, Any] = {"messages": db.get_termfreq("Tmail"),
+                              "files": sum(1 for _ in db.allterms("XFDIRENTRY")),
+                              "folders": {}}
+    for t in db.allterms("XFOLDER:"):
+        name = t.term.decode("utf-8")[len("XFOLDER:"):]
+        if os.path.basename(name) not in ("cur", "new"):
+            counts["folders"][name or "."] = t.termfreq
+
+    db.close()
+
+    return counts
+
+
+def check_counts(counts_mine: Dict[str, Any], counts_theirs: Dict[str, Any]) -> bool:
+    """
+    Compare message and file counts of both sides after a sync and warn about
+    any differences.
+
+    Args:
+        counts_mine (dict): Local counts as returned by get_counts().
+        counts_theirs (dict): Remote counts as returned by get_counts().
+
+    Returns:
+        bool: Whether the counts are the same.
+    """
+    diffs = []
+    for what in ("messages", "files"):
+        if counts_mine[what] != counts_theirs[what]:
+            diffs.append(f"{counts_mine[what]} {what} local, {counts_theirs[what]} {what} remote")
+    for name in sorted(set(counts_mine["folders"]) | set(counts_theirs["folders"])):
+        mine = counts_mine["folders"].get(name, 0)
+        theirs = counts_theirs["folders"].get(name, 0)
+        if mine != theirs:
+            diffs.append(f"{mine} messages local, {theirs} messages remote in folder {name}")
+    for diff in diffs:
+        logger.warning("DATABASES DIFFER AFTER SYNC: %s.", diff)
+    return len(diffs) == 0
+
+
+def get_spot_check(prefix: str, mids: List[str]) -> Dict[str, Dict[str, Any]]:
+    """
+    Get tags and digests of all files of the given messages.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        mids (list): Message IDs to check.
+
+    Returns:
+        dict: Mapping of message IDs to tags and digests of files, for all
+        message IDs that exist in the database.
+    """
+    info = {}
+    with notmuch2.Database() as db:
+        for mid in mids:
+            try:
+                msg = db.find(mid)
+                if msg.ghost:
+                    continue
+                info[mid] = {"tags": sorted(msg.tags),
+                             "files": {str(f).removeprefix(prefix): digest(Path(f).read_bytes()) for f in msg.filenames()}}
+            except LookupError:
+                pass
+    return info
+
+
+def spot_check_local(
+    prefix: str,
+    percent: float,
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None
+) -> int:
+    """
+    Randomly sample the given percentage of all messages, compare their tags
+    and file digests to the ones on the remote, and warn about any mismatches.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        percent (float): Percentage of messages to check.
+        from_stream: Stream to read from the remote.
+        to_stream: Stream to write to the remote.
+
+    Returns:
+        int: Number of messages with mismatches.
+    """
+    ids = get_ids(prefix)
+    sample = random.sample(ids, min(len(ids), max(1, round(len(ids) * percent / 100))))
+    logger.info("Spot-checking %s messages...", len(sample))
+    write(json.dumps(sample).encode("utf-8"), to_stream)
+    mine = get_spot_check(prefix, sample)
+    theirs = json.loads(read(from_stream).decode("utf-8"))
+
+    mismatches = 0
+    for mid in sample:
+        if mid not in mine and mid not in theirs:
+            continue
+        if mid not in theirs:
+            logger.warning("Spot check: %s missing on remote.", mid)
+        elif mid not in mine:
+            logger.warning("Spot check: %s missing on local.", mid)
+        else:
+            ok = True
+            if mine[mid]["tags"] != theirs[mid]["tags"]:
+                ok = False
+                logger.warning("Spot check: tags of %s differ, %s local, %s remote.",
+                               mid, mine[mid]["tags"], theirs[mid]["tags"])
+            if sorted(mine[mid]["files"]) != sorted(theirs[mid]["files"]):
+                ok = False
+                logger.warning("Spot check: files of %s differ, %s local, %s remote.",
+                               mid, sorted(mine[mid]["files"]), sorted(theirs[mid]["files"]))
+            for f in sorted(set(mine[mid]["files"]) & set(theirs[mid]["files"])):
+                if mine[mid]["files"][f] != theirs[mid]["files"][f]:
+                    ok = False
+                    logger.warning("Spot check: contents of %s differ.", f)
+            if ok:
+                continue
+        mismatches += 1
+
+    logger.warning("Spot-checked %s messages, %s mismatches.", len(sample), mismatches)
+    return mismatches
+
+
+def spot_check_remote(
+    prefix: str,
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None
+) -> None:
+    """
+    Send tags and file digests of the messages sampled by the local for
+    comparison.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        from_stream: Stream to read from the local.
+        to_stream: Stream to write to the local.
+    """
+    sample = json.loads(read(from_stream).decode("utf-8"))
+    write(json.dumps(get_spot_check(prefix, sample)).encode("utf-8"), to_stream)
+
+
+# Separate methods for local and remote to avoid sending all IDs both ways --
+# have local figure out what needs to be deleted on both sides
+def sync_deletes_local(
+    prefix: str,
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None,
+    no_check: bool = False,
+    receive_only: bool = False,
+    receive_only_theirs: bool = False,
+    confirm: bool = False,
+    yes: bool = False
+) -> int:
+    """
+    Synchronize deletions for the local database and instruct remote to delete
+    messages/files as needed. With confirm, asks whether to go ahead before
+    deleting anything; if not, nothing is deleted on either side.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        from_stream: Stream to read from the remote.
+        to_stream: Stream to write to the remote.
+        no_check: Delete message not present on other side even if it doesn't
+        have the 'deleted' tag.
+        receive_only: Whether this side only receives changes, i.e. does not
+        instruct the remote to delete anything.
+        receive_only_theirs: Whether the remote only receives changes, i.e.
+        nothing is deleted locally.
+        confirm (bool): Whether to ask for confirmation before deleting.
+        yes (bool): Whether to go ahead without asking.
+
+    Returns:
+        int: Number of deletions performed.
+    """
+    ids = {}
+    dels = {'a': 0}
+
+    def _get_ids():
+        with heartbeat(to_stream):
+            ids["mine"] = get_ids(prefix)
+
+    def _recv_ids():
+        logger.info("Receiving all message IDs from remote...")
+        ids["theirs"] = json.loads(read(from_stream).decode("utf-8"))
+
+    run_async(_get_ids, _recv_ids)
+
+    logger.info("Message IDs synced.")
+
+    to_del = set() if receive_only_theirs else set(ids["mine"]) - set(ids["theirs"])
+    to_del_remote = [] if receive_only else list(set(ids["theirs"]) - set(ids["mine"]))
+    if confirm and (to_del or to_del_remote):
+        what = "" if no_check else " if tagged 'deleted'"
+        if not ask_confirm([f"{'local:':8}{len(to_del)} messages not on remote to delete{what}",
+                            f"{'remote:':8}{len(to_del_remote)} messages not on local to delete{what}"], yes):
+            logger.warning("Not deleting messages, not confirmed.")
+            to_del, to_del_remote = set(), []
+
+    def _send_del_ids():
+        logger.debug("Remote IDs to be deleted %s.", to_del_remote)
+        logger.info("Sending message IDs to be deleted to remote...")
+        write(json.dumps(to_del_remote).encode("utf-8"), to_stream)
+
+    def _recv_del_ids():
+        logger.debug("Local IDs to be deleted %s.", to_del)
+        with notmuch2.Database(mode=notmuch2.Database.MODE.READ_WRITE) as dbw:
+            for mid in to_del:
+                try:
+                    msg = dbw.find(mid)
+                    if msg.ghost:
+                        continue
+                    if "deleted" in msg.tags or no_check:
+                        dels["a"] += 1
+                        logger.info("Removing %s from DB and deleting files.", mid)
+                        fnames = [str(f) for f in msg.filenames()]
+                        count_folders(prefix, fnames, "deleted")
+                        if dry_run(f"delete {mid} ({', '.join(fnames)})"):
+                            continue
+                        tags = sorted(msg.tags)
+                        for f in fnames:
+                            logger.debug("Removing %s.", f)
+                            dbw.remove(f)
+                            delete_file(f, prefix, mid, tags)
+                    else:
+                        # not there on remote, but no "deleted" tag -- assume
+                        # that something went wrong and set tags again to make
+                        # it show up in next changeset to be synced back to
+                        # remote
+                        logger.info("%s set to be removed, but not tagged 'deleted'!", mid)
+                        if dry_run(f"mark {mid} as changed, deleted on remote but not tagged 'deleted'"):
+                            continue
+                        with msg.frozen():
+                            tmp = "".join(msg.tags)
+                            msg.tags.add(tmp)
+                            msg.tags.discard(tmp)
+                except LookupError:
+                    # already deleted? doesn't matter
+                    pass
+
+    run_async(_send_del_ids, _recv_del_ids)
+
+    return dels["a"]
+
+
+def sync_deletes_remote(
+    prefix: str,
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None,
+    no_check: bool = False
+) -> int:
+    """
+    Receive instructions from local to delete messages/files from the remote database.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        from_stream: Stream to read from the local.
+        to_stream: Stream to write to the local.
+        no_check: Delete message not present on other side even if it doesn't
+        have the 'deleted' tag.
+
+    Returns:
+        int: Number of deletions performed.
+    """
+    dels = 0
+    with heartbeat(to_stream):
+        ids = get_ids(prefix)
+    write(json.dumps(ids).encode("utf-8"), to_stream)
+
+    to_del = json.loads(read(from_stream).decode("utf-8"))
+    with notmuch2.Database(mode=notmuch2.Database.MODE.READ_WRITE) as dbw:
+        for mid in to_del:
+            try:
+                msg = dbw.find(mid)
+                if msg.ghost:
+                    continue
+                if "deleted" in msg.tags or no_check:
+                    dels += 1
+                    fnames = [str(f) for f in msg.filenames()]
+                    count_folders(prefix, fnames, "deleted")
+                    if dry_run(f"delete {mid} ({', '.join(fnames)})"):
+                        continue
+                    tags = sorted(msg.tags)
+                    for f in fnames:
+                        dbw.remove(f)
+                        delete_file(f, prefix, mid, tags)
+                else:
+                    # not on local, but no "deleted" tag -- assume that
+                    # something went wrong and set tags again to make it
+                    # show up in next changeset to be synced back to local
+                    if dry_run(f"mark {mid} as changed, deleted on local but not tagged 'deleted'"):
+                        continue
+                    with msg.frozen():
+                        tmp = "".join(msg.tags)
+                        msg.tags.add(tmp)
+                        msg.tags.discard(tmp)
+            except LookupError:
+                # already deleted? doesn't matter
+                pass
+    return dels
+
+
+def get_folders(prefix: str) -> List[str]:
+    """
+    Get all maildir folders under the mail root, i.e. directories with a "cur"
+    subdirectory, relative to the mail root. Hidden directories (including
+    .notmuch) are not considered.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+
+    Returns:
+        list: Maildir folders, "." for the mail root itself.
+    """
+    folders = []
+    for root, dirs, _ in os.walk(prefix):
+        dirs[:] = sorted(d for d in dirs if not d.startswith("."))
+        if "cur" in dirs:
+            folders.append(os.path.relpath(root, prefix))
+            dirs[:] = [d for d in dirs if d not in ("cur", "new", "tmp")]
+    return folders
+
+
+def empty_folder(path: str) -> bool:
+    """
+    Check whether a maildir folder has no mail and no subfolders, i.e. contains
+    only empty cur/new/tmp directories and mbsync state files.
+
+    Args:
+        path (str): Path of the folder.
+
+    Returns:
+        bool: Whether the folder can be removed without losing anything.
+    """
+    for entry in os.scandir(path):
+        if entry.name in ("cur", "new", "tmp") and entry.is_dir(follow_symlinks=False):
+            if any(os.scandir(entry.path)):
+                return False
+        elif not entry.name.startswith((".uidvalidity", ".mbsyncstate")):
+            return False
+    return True
+
+
+def sync_folders(
+    prefix: str,
     fname: str,
-    stream: IO[bytes],
-    overwrite_raise: bool=True
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None,
+    receive_only_theirs: bool = False
+) -> int:
+    """
+    Remove maildir folders that the other side has removed. A folder is removed
+    if both sides had it at the end of the last sync, the other side doesn't
+    have it anymore, and it is empty on this side, i.e. all its messages have
+    been deleted. Folders present on both sides are recorded for the next sync.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        fname (str): File the folders present on both sides are recorded in.
+        from_stream: Stream to read from the other side.
+        to_stream: Stream to write to the other side.
+        receive_only_theirs: Whether the other side only receives changes, i.e.
+        nothing is removed on this side.
+
+    Returns:
+        int: Number of removed folders.
+    """
+    folders: Dict[str, Any] = {"mine": get_folders(prefix)}
+
+    def _send_folders():
+        logger.info("Sending maildir folders...")
+        write(json.dumps(folders["mine"]).encode("utf-8"), to_stream)
+
+    def _recv_folders():
+        logger.info("Receiving maildir folders...")
+        folders["theirs"] = set(json.loads(read(from_stream).decode("utf-8")))
+
+    run_async(_send_folders, _recv_folders)
+
+    try:
+        synced = set(json.loads(Path(fname).read_text(encoding="utf-8")))
+    except (OSError, ValueError):
+        synced = set()
+
+    removed = 0
+    for folder in folders["mine"]:
+        if folder == "." or folder in folders["theirs"] or folder not in synced or receive_only_theirs:
+            continue
+        path = os.path.join(prefix, folder)
+        if empty_folder(path):
+            logger.info("Removing folder %s, removed on other side.", path)
+            if not dry_run(f"remove folder {path}"):
+                shutil.rmtree(path)
+            removed += 1
+        else:
+            logger.info("Not removing folder %s removed on other side, not empty.", path)
+
+    if not session["dry_run"]:
+        Path(fname).write_text(json.dumps(sorted(set(folders["mine"]) & folders["theirs"])), encoding="utf-8")
+    return removed
+
+
+def delete_file(fname: str, prefix: str, mid: str | None = None, tags: List[str] | None = None) -> None:
+    """
+    Delete a file, or, if there is a trash directory (session["trash"]), move it
+    to the directory for the current day in it, under the same path as in the
+    mail directory, and record where it came from in the manifest there, so
+    that it can be restored. If changes are journaled, the file is kept next
+    to the journal instead if there is no trash directory, and its deletion is
+    journaled.
+
+    Args:
+        fname (str): Path of the file.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        mid (str): ID of the message of the file, for the manifest.
+        tags (list): Tags of the message, for the manifest.
+    """
+    if session["trash"] is not None:
+        base = os.path.join(os.path.expanduser(session["trash"]), time.strftime("%Y-%m-%d"))
+    elif session["journal"] is not None:
+        base = os.path.join(os.path.dirname(session["journal"].name), JOURNAL_FILES)
+    else:
+        Path(fname).unlink()
+        return
+    dst = os.path.join(base, os.path.relpath(fname, prefix))
+    # the same file may be deleted more than once a day, e.g. after re-syncing
+    n = 1
+    while os.path.lexists(dst):
+        dst = os.path.join(base, os.path.relpath(fname, prefix)) + f".{n}"
+        n += 1
+    logger.debug("Moving %s to %s.", fname, dst)
+    os.makedirs(os.path.dirname(dst), exist_ok=True)
+    shutil.move(fname, dst)
+    if session["trash"] is not None:
+        with open(os.path.join(base, TRASH_MANIFEST), "a", encoding="utf-8") as f:
+            f.write(json.dumps({"time": int(time.time()), "file": fname, "trash": dst, "id": mid, "tags": tags}) + "\n")
+    journal("delete", file=fname, trash=dst, id=mid, tags=tags)
+
+
+def journal(op: str, **entry: Any) -> None:
+    """
+    Record a change to the notmuch database or the mail files in the journal of
+    the current sync (session["journal"]), if any, so that it can be undone.
+
+    Args:
+        op (str): Kind of change: "tags", "add", "move", or "delete".
+        entry: What changed, see undo_sync().
+    """
+    if session["journal"] is not None:
+        session["journal"].write(json.dumps({"op": op} | entry) + "\n")
+        session["journal"].flush()
+
+
+def start_journal(state_dir: str) -> IO[str]:
+    """
+    Start the journal of a sync, replacing that of the previous one and
+    removing the files it kept.
+
+    Args:
+        state_dir (str): Directory with sync state files.
+
+    Returns:
+        The journal, to be closed at the end of the sync.
+    """
+    os.makedirs(state_dir, exist_ok=True)
+    shutil.rmtree(os.path.join(state_dir, JOURNAL_FILES), ignore_errors=True)
+    f = open(os.path.join(state_dir, JOURNAL), "w", encoding="utf-8") # pylint: disable=consider-using-with
+    f.write(json.dumps({"op": "sync", "time": int(time.time()), "session": session["id"]}) + "\n")
+    return f
+
+
+def undo_sync(state_dir: str | None = None) -> int:
+    """
+    Undo the changes of the last sync on the local side as recorded in its
+    journal, in reverse order: restore tags and properties of messages whose
+    tags and properties are still the ones set by the sync, remove received files, move moved files back, and
+    restore deleted files. Changes made since the sync are kept; changes that
+    can't be undone are skipped with a warning. The journal is removed
+    afterwards, so that a sync can only be undone once.
+
+    Args:
+        state_dir (str): Directory to keep sync state in instead of .notmuch.
+
+    Returns:
+        int: Number of undone changes.
+
+    Raises:
+        StateError: If there is no journal.
+    """
+    undone = 0
+    # files renamed to match restored tags are counted, but not reported
+    current_stats.set(SyncStats())
+    with notmuch2.Database(mode=notmuch2.Database.MODE.READ_WRITE) as dbw:
+        prefix = os.path.join(str(dbw.default_path()), '')
+        fname = os.path.join(get_state_dir(prefix, dbw.revision().uuid.decode(),
+                                           state_dir or dbw.config.get("notmuch-sync.state_dir")), JOURNAL)
+        try:
+            entries = [json.loads(line) for line in Path(fname).read_text(encoding="utf-8").splitlines()]
+        except (OSError, ValueError) as e:
+            raise StateError(f"No journal of a sync to undo in {fname} ({e}), aborting...") from e
+        with lock_session(prefix, "local"):
+            for entry in reversed(entries):
+                try:
+                    if entry["op"] == "tags":
+                        msg = dbw.find(entry["id"])
+                        if sorted(msg.tags) != entry["after"]:
+                            logger.warning("Tags of %s changed since the sync, not restoring them.", entry["id"])
+                            continue
+                        logger.info("Restoring tags %s of %s.", entry["before"], entry["id"])
+                        with msg.frozen():
+                            msg.tags.clear()
+                            for tag in entry["before"]:
+                                msg.tags.add(tag)
+                            reconcile_flags(msg)
+                    elif entry["op"] == "properties":
+                        msg = dbw.find(entry["id"])
+                        if get_properties(msg) != entry["after"]:
+                            logger.warning("Properties of %s changed since the sync, not restoring them.", entry["id"])
+                            continue
+                        logger.info("Restoring properties %s of %s.", entry["before"], entry["id"])
+                        set_properties(msg, entry["before"])
+                    elif entry["op"] == "add":
+                        # received files of new messages may have been renamed
+                        # to match their tags since
+                        fnames = [entry["file"]]
+                        if entry["new"]:
+                            fnames = [str(f) for f in dbw.find(entry["id"]).filenames()]
+                        for f in fnames:
+                            logger.info("Removing received %s.", f)
+                            dbw.remove(f)
+                            Path(f).unlink()
+                    elif entry["op"] == "move":
+                        if os.path.lexists(entry["from"]) or not os.path.exists(entry["to"]):
+                            logger.warning("Can't move %s back to %s.", entry["to"], entry["from"])
+                            continue
+                        logger.info("Moving %s back to %s.", entry["to"], entry["from"])
+                        Path(entry["from"]).parent.mkdir(parents=True, exist_ok=True)
+                        shutil.move(entry["to"], entry["from"])
+                        dbw.add(entry["from"])
+                        dbw.remove(entry["to"])
+                    elif entry["op"] == "delete":
+                        if os.path.lexists(entry["file"]) or not os.path.exists(entry["trash"]):
+                            logger.warning("Can't restore %s from %s.", entry["file"], entry["trash"])
+                            continue
+                        logger.info("Restoring deleted %s.", entry["file"])
+                        Path(entry["file"]).parent.mkdir(parents=True, exist_ok=True)
+                        shutil.move(entry["trash"], entry["file"])
+                        msg, dup = dbw.add(entry["file"])
+                        if not dup and entry.get("tags") is not None:
+                            with msg.frozen():
+                                msg.tags.clear()
+                                for tag in entry["tags"]:
+                                    msg.tags.add(tag)
+                    else:
+                        continue
+                    undone += 1
+                except (LookupError, OSError) as e:
+                    logger.warning("Can't undo %s: %s", entry, e)
+        Path(fname).unlink()
+    return undone
+
+
+def purge_trash(trash: str, older_than: float) -> int:
+    """
+    Remove the directories of files moved to the trash directory on days that
+    ended more than the given time ago.
+
+    Args:
+        trash (str): Trash directory.
+        older_than (float): Age in seconds.
+
+    Returns:
+        int: Number of removed directories.
+    """
+    removed = 0
+    trash = os.path.expanduser(trash)
+    if not os.path.isdir(trash):
+        return 0
+    for day in sorted(os.listdir(trash)):
+        try:
+            end = time.mktime(time.strptime(day, "%Y-%m-%d")) + 86400
+        except ValueError:
+            # not one of ours
+            continue
+        if end < time.time() - older_than:
+            logger.info("Removing %s from trash.", day)
+            shutil.rmtree(os.path.join(trash, day))
+            removed += 1
+    return removed
+
+
+def prune_empty_dirs(prefix: str) -> int:
+    """
+    Remove empty directories under the mail root, e.g. left behind after
+    deleting messages. The cur/new/tmp directories of maildir folders are kept.
+    Hidden directories (including .notmuch) are not considered.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+
+    Returns:
+        int: Number of removed directories.
+    """
+    removed = 0
+    for root, dirs, _ in os.walk(prefix, topdown=False):
+        if any(part.startswith(".") for part in Path(os.path.relpath(root, prefix)).parts):
+            continue
+        for d in dirs:
+            path = os.path.join(root, d)
+            if d in ("cur", "new", "tmp") and os.path.isdir(os.path.join(root, "cur")):
+                continue
+            if d.startswith(".") or os.path.islink(path) or any(os.scandir(path)):
+                continue
+            logger.info("Removing empty directory %s.", path)
+            if not dry_run(f"remove empty directory {path}"):
+                os.rmdir(path)
+            removed += 1
+    return removed
+
+
+def sync_mbsync_local(
+    prefix: str,
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None,
+    receive_only: bool = False,
+    receive_only_theirs: bool = False
+) -> None:
+    """
+    Synchronize local mbsync files with remote.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        from_stream: Stream to read from the remote.
+        to_stream: Stream to write to the remote.
+        receive_only: Whether this side only receives changes, i.e. does not
+        send any files.
+        receive_only_theirs: Whether the remote only receives changes, i.e.
+        does not send any files.
+    """
+    mbsync = {}
+
+    def _get_mbsync():
+        logger.info("Getting local mbsync file stats...")
+        mbsync["mine"] = { str(f).removeprefix(prefix): f.stat().st_mtime
+                           for pat in [".uidvalidity", ".mbsyncstate"]
+                           for f in Path(prefix).rglob(pat) }
+
+    def _recv_mbsync():
+        logger.info("Receiving mbsync file stats from remote...")
+        mbsync["theirs"] = json.loads(read(from_stream).decode("utf-8"))
+
+    run_async(_get_mbsync, _recv_mbsync)
+
+    logger.info("mbsync file stats synced.")
+
+    pull = [ f for f in mbsync["mine"].keys()
+            if (f in mbsync["theirs"] and mbsync["theirs"][f] > mbsync["mine"][f]) ]
+    pull += list(set(mbsync["theirs"].keys()) - set(mbsync["mine"].keys()))
+    if receive_only_theirs:
+        pull = []
+    if session["dry_run"]:
+        pull = [f for f in pull if not dry_run(f"receive mbsync file {os.path.join(prefix, f)}")]
+    logger.debug("Local mbsync files to be updated from remote %s.", pull)
+    write(json.dumps(pull).encode("utf-8"), to_stream)
+
+    def _send_mbsync_files():
+        push = [ f for f in mbsync["theirs"].keys()
+                if (f in mbsync["mine"] and mbsync["mine"][f] > mbsync["theirs"][f]) ]
+        push += list(set(mbsync["mine"].keys()) - set(mbsync["theirs"].keys()))
+        if receive_only:
+            push = []
+        if session["dry_run"]:
+            push = [f for f in push if not dry_run(f"send mbsync file {os.path.join(prefix, f)}")]
+
+        logger.debug("mbsync files to update on remote %s.", push)
+        logger.info("Sending %s mbsync files to remote...", len(push))
+        write(json.dumps(push).encode("utf-8"), to_stream, False)
+        for idx, f in enumerate(push):
+            logger.debug("%s/%s Sending mbsync file %s to remote...", idx + 1,
+                         len(push), f)
+            to_stream.write(struct.pack("!d", mbsync["mine"][f]))
+            current_stats.get().write += 8
+            send_file(os.path.join(prefix, f), to_stream, False)
+        to_stream.flush()
+
+    def _recv_mbsync_files():
+        logger.info("Receiving %s mbsync files from remote...", len(pull))
+        for idx, f in enumerate(pull):
+            logger.debug("%s/%s Receiving mbsync file %s from remote...",
+                         idx + 1, len(pull), f)
+            mtime_data = from_stream.read(8)
+            current_stats.get().read += 8
+            mtime = struct.unpack("!d", mtime_data)[0]
+            fname = os.path.join(prefix, f)
+            recv_file(fname, from_stream, overwrite_raise=False)
+            os.utime(fname, (mtime, mtime))
+
+    run_async(_send_mbsync_files, _recv_mbsync_files)
+
+    logger.info("mbsync files synced.")
+
+
+def sync_mbsync_remote(
+    prefix: str,
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None,
+    receive_only: bool = False
 ) -> None:
     """
-    Receive a file with a 4-byte length prefix from a stream and write it to
-    disk, validating its checksum.
+    Synchronize remote mbsync files with local.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        from_stream: Stream to read from the remote.
+        to_stream: Stream to write to the remote.
+        receive_only: Whether this side only receives changes, i.e. rejects
+        any file requests from the local side.
+
+    Raises:
+        ProtocolError: If this side is receive-only and files were requested.
+    """
+    mbsync = { str(f).removeprefix(prefix): f.stat().st_mtime
+               for pat in [".uidvalidity", ".mbsyncstate"]
+               for f in Path(prefix).rglob(pat) }
+    write(json.dumps(mbsync).encode("utf-8"), to_stream)
+    push = json.loads(read(from_stream).decode("utf-8"))
+    if receive_only and len(push) > 0:
+        raise ProtocolError(f"Local requested {len(push)} mbsync files from receive-only side, aborting...")
+
+    def _send_mbsync_files():
+        for f in push:
+            fname = os.path.join(prefix, f)
+            to_stream.write(struct.pack("!d", Path(fname).stat().st_mtime))
+            current_stats.get().write += 8
+            send_file(fname, to_stream, False)
+        to_stream.flush()
+
+    def _recv_mbsync_files():
+        pull = json.loads(read(from_stream).decode("utf-8"))
+        for f in pull:
+            mtime_data = from_stream.read(8)
+            current_stats.get().read += 8
+            mtime = struct.unpack("!d", mtime_data)[0]
+            fname = os.path.join(prefix, f)
+            recv_file(fname, from_stream, overwrite_raise=False)
+            os.utime(fname, (mtime, mtime))
+
+    run_async(_send_mbsync_files, _recv_mbsync_files)
+
+
+def find_orphans(db: notmuch2.Database, prefix: str) -> List[str]:
+    """
+    Find files under the mail root that are not files of any message in the
+    notmuch database, e.g. leftovers from crashes or aborted transfers. Hidden
+    files and directories (including .notmuch) are not considered.
+
+    Args:
+        db: An open notmuch2.Database object.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+
+    Returns:
+        list: Paths of orphaned files.
+    """
+    logger.info("Getting all files from DB...")
+    known = {str(f) for msg in db.messages("*") for f in msg.filenames()}
+    orphans = []
+    logger.info("Looking for orphaned files...")
+    for root, dirs, fnames in os.walk(prefix):
+        dirs[:] = sorted(d for d in dirs if not d.startswith("."))
+        for fname in sorted(fnames):
+            path = os.path.join(root, fname)
+            if not fname.startswith(".") and path not in known:
+                orphans.append(path)
+    return orphans
+
+
+def handle_orphans(action: str) -> int:
+    """
+    Report, remove, or index files under the mail root that are not in the
+    notmuch database.
+
+    Args:
+        action (str): "report", "remove", or "index".
+
+    Returns:
+        int: Number of orphaned files.
+    """
+    with notmuch2.Database(mode=notmuch2.Database.MODE.READ_WRITE) as dbw:
+        prefix = os.path.join(str(dbw.default_path()), '')
+        orphans = find_orphans(dbw, prefix)
+        for fname in orphans:
+            if action == "remove":
+                logger.warning("Removing orphaned file %s.", fname)
+                Path(fname).unlink()
+            elif action == "index":
+                logger.warning("Adding orphaned file %s to DB.", fname)
+                try:
+                    dbw.add(fname)
+                except notmuch2.FileNotEmailError:
+                    logger.warning("Not adding %s, not an email.", fname)
+            else:
+                logger.warning("Orphaned file %s.", fname)
+    logger.warning("%s orphaned files.", len(orphans))
+    return len(orphans)
+
+
+def apply_mode(args: argparse.Namespace) -> None:
+    """
+    Set defaults for options that are bundled in the operating modes. "strict"
+    aborts on anything unexpected: more than 20% of messages changed, files
+    not in the notmuch database, or differences after syncing, and fails if
+    received files conflicted with existing ones. "lenient" skips and reports
+    what it can: received files that can't be written are skipped, and files
+    not in the notmuch database are indexed.
+    Explicitly given options take precedence.
+
+    Args:
+        args: Parsed command-line arguments.
+    """
+    if args.mode == "strict":
+        if args.max_change is None:
+            args.max_change = 0.2
+        if args.unindexed is None:
+            args.unindexed = "warn"
+        args.fail_on_conflict = True
+    elif args.mode == "lenient":
+        if args.unindexed is None:
+            args.unindexed = "index"
+
+
+def config_path() -> str:
+    """
+    Get the default location of the configuration file,
+    $XDG_CONFIG_HOME/notmuch-sync/config.toml (~/.config/notmuch-sync/config.toml
+    if XDG_CONFIG_HOME is not set).
+
+    Returns:
+        str: Path of the configuration file.
+    """
+    base = os.environ.get("XDG_CONFIG_HOME") or os.path.join(os.path.expanduser("~"), ".config")
+    return os.path.join(base, "notmuch-sync", "config.toml")
+
+
+def load_config(fname: str, profile: str) -> Dict[str, Any]:
+    """
+    Read the options of a profile from a TOML configuration file. Options at the
+    top level of the file apply to all profiles, options in the table
+    [profiles.<profile>] to that profile only and take precedence.
+
+    Args:
+        fname (str): Path of the configuration file.
+        profile (str): Name of the profile.
+
+    Returns:
+        dict: Options of the profile by name as in the configuration file.
+
+    Raises:
+        argparse.ArgumentTypeError: If the file cannot be read or parsed, or does not define the
+            profile.
+    """
+    if tomllib is None:
+        raise argparse.ArgumentTypeError("Reading configuration files needs Python 3.11 or later or the tomli module.")
+    try:
+        with open(fname, "rb") as f:
+            config = tomllib.load(f)
+    except OSError as e:
+        raise argparse.ArgumentTypeError(f"Cannot read configuration file {fname}: {e.strerror}.") from e
+    except tomllib.TOMLDecodeError as e:
+        raise argparse.ArgumentTypeError(f"Cannot parse configuration file {fname}: {e}.") from e
+
+    profiles = config.pop("profiles", {})
+    if not isinstance(profiles, dict) or not isinstance(profiles.get(profile), dict):
+        raise argparse.ArgumentTypeError(f"Profile '{profile}' not defined in configuration file {fname}.")
+    options = {k: v for k, v in config.items() if not isinstance(v, dict)}
+    options.update(profiles[profile])
+    return options
+
+
+def config_defaults(parser: argparse.ArgumentParser, options: Dict[str, Any]) -> Dict[str, Any]:
+    """
+    Convert options from a configuration file to defaults for the command-line
+    arguments. Options are named like the long command-line flags, with dashes
+    or underscores, and values are checked and converted like the arguments of
+    the flags: flags without arguments take booleans, flags that can be given
+    multiple times a value or a list.
+
+    Args:
+        parser: Parser of the command-line arguments.
+        options (dict): Options by name as in the configuration file.
+
+    Returns:
+        dict: Values by argument destination, to be passed to
+            parser.set_defaults().
+
+    Raises:
+        argparse.ArgumentTypeError: If an option is not known or has an invalid value.
+    """
+    actions = {}
+    for action in parser._actions: # pylint: disable=protected-access
+        for opt in action.option_strings:
+            if opt.startswith("--") and action.dest not in ["help", "config", "profile"]:
+                actions[opt[2:]] = action
+
+    def convert(key: str, action: argparse.Action, value: Any) -> Any:
+        if action.type not in [None, int, float, str] and isinstance(value, (int, float)) and not isinstance(value, bool):
+            # numbers are checked like on the command line, e.g. --jobs 0
+            value = str(value)
+        if isinstance(value, str):
+            try:
+                value = action.type(value) if action.type else value
+            except (argparse.ArgumentTypeError, ValueError) as e:
+                raise argparse.ArgumentTypeError(f"Invalid value '{value}' for option '{key}' in configuration file: {e}") from e
+        elif not (action.type in [int, float] and isinstance(value, (int, float)) and not isinstance(value, bool)):
+            raise argparse.ArgumentTypeError(f"Invalid value '{value}' for option '{key}' in configuration file.")
+        if action.choices and value not in action.choices:
+            raise argparse.ArgumentTypeError(f"Invalid value '{value}' for option '{key}' in configuration file, choose from {', '.join(action.choices)}.")
+        return value
+
+    defaults = {}
+    for key, value in options.items():
+        action = actions.get(key.replace("_", "-"))
+        if action is None:
+            raise argparse.ArgumentTypeError(f"Unknown option '{key}' in configuration file.")
+        if action.nargs == 0:
+            # count (-v) takes a number, the other flags true or false
+            kind = int if action.const is None else bool
+            if not isinstance(value, kind) or (kind is int and isinstance(value, bool)):
+                raise argparse.ArgumentTypeError(f"Invalid value '{value}' for option '{key}' in configuration file.")
+            defaults[action.dest] = value
+        elif isinstance(action.default, list):
+            defaults[action.dest] = [convert(key, action, v) for v in (value if isinstance(value, list) else [value])]
+        else:
+            defaults[action.dest] = convert(key, action, value)
+    return defaults
+
+
+def lock_session(prefix: str, client: str | None = None, wait: float = 0) -> IO[str]:
+    """
+    Lock the notmuch database against other syncs for the duration of a
+    session, waiting for another sync to finish for up to the given time. The
+    lock file records the client holding the lock.
+
+    Args:
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        client (str): Address of the client, taken from SSH_CLIENT if not
+                      given.
+        wait (float): Seconds to wait for another sync to finish.
+
+    Returns:
+        Open lock file, closing it releases the lock.
+
+    Raises:
+        BusyError: If another sync is still in progress after waiting.
+    """
+    f = open(os.path.join(prefix, ".notmuch", "notmuch-sync.lock"), "a+", encoding="utf-8")
+    deadline = time.monotonic() + wait
+    waiting = False
+    while True:
+        try:
+            fcntl.flock(f, fcntl.LOCK_EX | fcntl.LOCK_NB)
+            break
+        except BlockingIOError as e:
+            if time.monotonic() >= deadline:
+                f.seek(0)
+                holder = f.read().strip()
+                f.close()
+                raise BusyError(f"Busy, another sync in progress from {holder or 'unknown client'}, aborting...") from e
+            if not waiting:
+                logger.info("Waiting for another sync to finish...")
+                waiting = True
+            time.sleep(min(1, max(0, deadline - time.monotonic())))
+    f.seek(0)
+    f.truncate()
+    f.write(f"{client or os.environ.get('SSH_CLIENT', 'local').split(' ')[0]} (pid {os.getpid()})")
+    f.flush()
+    return f
+
+
+def check_side(
+    dbw: notmuch2.Database,
+    prefix: str,
+    uuid_theirs: str,
+    state_dir: str | None = None
+) -> Dict[str, Any]:
+    """
+    Check whether this side is ready to sync with the other side: the notmuch
+    database is open for writing, no other sync is in progress, and the sync
+    state file for the other side, if any, belongs to the database.
 
     Args:
-        fname (str): Destination file path.
-        stream: Readable stream.
-        overwrite_raise: Raise error if existing file would be overwritten.
+        dbw: An open writable notmuch2.Database object.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        uuid_theirs (str): UUID of the database on the other side.
+        state_dir (str): Directory to keep sync state in instead of .notmuch.
 
-    Raises:
-        ValueError: If file to receive already exists or received file's
-        checksum does not match expected.
+    Returns:
+        dict: Versions, database path, UUID, and revision, revision of the last
+        sync with the other side, free disk space, and problems found.
     """
-    content = read(stream)
-    if Path(fname).exists() and overwrite_raise:
-        sha_mine = digest(content)
-        sha_exists = digest(Path(fname).read_bytes())
-        if sha_exists != sha_mine:
-            raise ValueError(f"Receiving '{fname}', but already exists with different content!")
-    Path(fname).parent.mkdir(parents=True, exist_ok=True)
-    with open(fname, "wb") as f:
-        f.write(content)
+    revision = dbw.revision()
+    uuid_mine = revision.uuid.decode()
+    try:
+        version = importlib.metadata.version("notmuch-sync")
+    except importlib.metadata.PackageNotFoundError:
+        version = "unknown"
+    report: Dict[str, Any] = {"check": True, "version": version, "protocol": PROTOCOL_VERSION,
+                              "path": prefix, "uuid": uuid_mine, "revision": revision.rev,
+                              "last_sync": -1, "free": shutil.disk_usage(prefix).free, "problems": []}
+    try:
+        lock_session(prefix).close()
+    except ValueError as e:
+        report["problems"].append(str(e))
+    fname = os.path.join(get_state_dir(prefix, uuid_mine, state_dir), "notmuch-sync-" + uuid_theirs)
+    try:
+        report["last_sync"] = last_sync_revision(fname, revision)
+    except ValueError as e:
+        report["problems"].append(str(e))
+    return report
 
 
-def sync_files(
+def run_check(
     dbw: notmuch2.Database,
     prefix: str,
-    missing: Dict[str, Dict[str, Any]],
     from_stream: IO[bytes] | None,
-    to_stream: IO[bytes] | None
-) -> Tuple[int, int]:
+    to_stream: IO[bytes] | None,
+    state_dir: str | None = None
+) -> Tuple[Dict[str, Any], Dict[str, Any]]:
     """
-    Synchronize files that are missing locally or remotely.
+    Check both sides instead of syncing: do the handshake and exchange the
+    results of check_side().
 
     Args:
         dbw: An open writable notmuch2.Database object.
         prefix (str): Prefix path for filenames (notmuch config database.path).
-        missing (dict): Mapping of missing files by message ID.
-        from_stream: Stream to read file names and files from.
-        to_stream: Stream to send file names and files to.
+        from_stream: Stream to read from the other side.
+        to_stream: Stream to write to the other side.
+        state_dir (str): Directory to keep sync state in instead of .notmuch.
 
     Returns:
-        tuple: (number of added messages, number of added files)
+        tuple: Results of the checks on this side and the other side.
+
+    Raises:
+        ValueError: If the other side doesn't speak the protocol or isn't
+        checking.
     """
-    files = {}
-    files["mine"] = [ {"name": f, "id": mid} for mid in missing for f in missing[mid]["files"] ]
-    changes = {"files": len(files["mine"]), "messages": 0}
+    reports = {}
+    uuid_theirs = exchange_uuids(dbw.revision().uuid.decode(), from_stream, to_stream)
+    reports["mine"] = check_side(dbw, prefix, uuid_theirs, state_dir)
 
-    def _send_fnames():
-        logger.info("Sending file names missing on local...")
-        write(json.dumps([f["name"] for f in files["mine"]]).encode("utf-8"), to_stream)
+    def _send_report():
+        write(json.dumps(reports["mine"]).encode("utf-8"), to_stream)
 
-    def _recv_fnames():
-        logger.info("Receiving file names missing on remote...")
-        files["theirs"] = json.loads(read(from_stream).decode("utf-8"))
+    def _recv_report():
+        reports["theirs"] = json.loads(read(from_stream).decode("utf-8"))
 
-    run_async(_send_fnames, _recv_fnames)
+    run_async(_send_report, _recv_report)
+    if not reports["theirs"].get("check"):
+        raise ProtocolError("Other side is syncing instead of checking, aborting (give --check on both sides "
+                         "when using --remote-cmd)...")
+    return (reports["mine"], reports["theirs"])
 
-    logger.info("Missing file names synced.")
 
-    def _send_files():
-        for idx, fname in enumerate(files["theirs"]):
-            logger.info("%s/%s Sending %s...", idx + 1, len(files["theirs"]),
-                        fname)
-            send_file(os.path.join(prefix, fname), to_stream)
+def format_check(report: Dict[str, Any], side: str) -> List[str]:
+    """
+    Format the results of check_side() for the user.
 
-    def _recv_files():
-        for idx, f in enumerate(files["mine"]):
-            logger.info("%s/%s Receiving %s...", idx + 1, len(files["mine"]), f["name"])
-            dst = os.path.join(prefix, f["name"])
-            recv_file(dst, from_stream)
+    Args:
+        report (dict): Results of the checks.
+        side (str): Name of the side, e.g. "local".
 
-        for idx, f in enumerate(files["mine"]):
-            dst = os.path.join(prefix, f["name"])
-            logger.info("Adding %s to DB.", dst)
-            msg, dup = dbw.add(dst)
-            if not dup:
-                changes["messages"] += 1
-                with msg.frozen():
-                    logger.info("Setting tags %s for received %s.",
-                                sorted(missing[f["id"]]["tags"]),
-                                msg.messageid)
-                    msg.tags.clear()
-                    for tag in missing[f["id"]]["tags"]:
-                        msg.tags.add(tag)
+    Returns:
+        list: Lines of output.
+    """
+    last_sync = "never synced" if report["last_sync"] < 0 else f"last sync at revision {report['last_sync']}"
+    lines = [f"notmuch-sync {report['version']}, protocol version {report['protocol']}",
+             f"notmuch database {report['path']} writable, UUID {report['uuid']}, revision {report['revision']}",
+             f"{last_sync}, {format_bytes(report['free'])} free"]
+    lines += [f"problem: {problem}" for problem in report["problems"]]
+    return [f"{side + ':':8}{line}" for line in lines]
 
-    run_async(_send_files, _recv_files)
 
-    logger.info("Missing files synced.")
+def get_verify(
+    db: notmuch2.Database,
+    prefix: str,
+    include: List[str] | None = None,
+    exclude: List[str] | None = None,
+    jobs: int | None = None,
+    cache: Dict[str, List[Any]] | None = None
+) -> Dict[str, Dict[str, Any]]:
+    """
+    Get tags and digests of all files of all messages, leaving out files that
+    aren't synced because of include/exclude globs and messages without any
+    other files.
 
-    return (changes["messages"], changes["files"])
+    Args:
+        db: An open notmuch2.Database object.
+        prefix (str): Prefix path for filenames (notmuch config database.path).
+        include (list): Globs of file names of which files must match at least
+        one, if any.
+        exclude (list): Globs of file names that files must not match.
+        jobs (int): Number of worker threads to digest files with.
+        cache (dict): Digests of files from previous syncs, see digest_files().
 
+    Returns:
+        dict: Mapping of message IDs to tags and digests of files.
+    """
+    info = {}
+    fnames = []
+    for msg in db.messages("*"):
+        files = [str(f) for f in msg.filenames() if included(str(f).removeprefix(prefix), include, exclude)]
+        if files:
+            info[msg.messageid] = {"tags": sorted(msg.tags), "files": files}
+            fnames += files
+    logger.info("Digesting %s files of %s messages...", len(fnames), len(info))
+    shas = dict(zip(fnames, digest_files(fnames, jobs, cache)))
+    for entry in info.values():
+        entry["files"] = {f.removeprefix(prefix): shas[f] for f in entry["files"]}
+    return info
+
+
+def verify_diff(mine: Dict[str, Dict[str, Any]], theirs: Dict[str, Dict[str, Any]]) -> List[str]:
+    """
+    Compare the messages, tags, and files of both sides as returned by
+    get_verify().
 
-def get_ids(prefix: str) -> List[str]:
+    Args:
+        mine (dict): Messages on the local side.
+        theirs (dict): Messages on the remote.
+
+    Returns:
+        list: Differences, one per line.
     """
-    Get all message IDs from the notmuch database, using Xapian directly (much
-    faster).
+    diffs = []
+    for mid in sorted(set(mine) | set(theirs)):
+        if mid not in theirs:
+            diffs.append(f"{mid}: missing on remote")
+            continue
+        if mid not in mine:
+            diffs.append(f"{mid}: missing on local")
+            continue
+        for what in ("tags", "files"):
+            only_mine = sorted(set(mine[mid][what]) - set(theirs[mid][what]))
+            only_theirs = sorted(set(theirs[mid][what]) - set(mine[mid][what]))
+            if only_mine or only_theirs:
+                diffs.append(f"{mid}: {what} differ, local only: {' '.join(only_mine) or '-'}, "
+                             f"remote only: {' '.join(only_theirs) or '-'}")
+        for f in sorted(set(mine[mid]["files"]) & set(theirs[mid]["files"])):
+            if mine[mid]["files"][f] != theirs[mid]["files"][f]:
+                diffs.append(f"{mid}: contents of {f} differ")
+    return diffs
+
+
+def run_verify(
+    db: notmuch2.Database,
+    prefix: str,
+    from_stream: IO[bytes] | None,
+    to_stream: IO[bytes] | None,
+    arbiter: bool = False,
+    include: List[str] | None = None,
+    exclude: List[str] | None = None,
+    jobs: int | None = None,
+    checksum: bool = False,
+    state_dir: str | None = None
+) -> List[str]:
+    """
+    Verify both sides instead of syncing: do the handshake, and have the remote
+    send tags and digests of all files of all messages to the local side, which
+    compares them to its own. Nothing is changed on either side, except that
+    the digests of files are cached for later syncs. A remote that wasn't given
+    --verify, e.g. one served with --listen, switches to verifying when it sees
+    that the local side does, see verify_messages().
 
     Args:
+        db: An open notmuch2.Database object.
         prefix (str): Prefix path for filenames (notmuch config database.path).
+        from_stream: Stream to read from the other side.
+        to_stream: Stream to write to the other side.
+        arbiter (bool): Whether this is the remote, which only sends.
+        include (list): Globs of file names of which files must match at least
+        one, if any.
+        exclude (list): Globs of file names that files must not match.
+        jobs (int): Number of worker threads to digest files with.
+        checksum (bool): Whether to hash all files instead of reusing the
+        digests of unchanged files from previous syncs.
+        state_dir (str): Directory to keep sync state in instead of .notmuch.
 
     Returns:
-        list: All message IDs.
+        list: Differences found, see verify_diff(); always empty on the remote.
+
+    Raises:
+        ProtocolError: If the other side doesn't speak the protocol or isn't
+        verifying.
     """
-    db = xapian.Database(os.path.join(prefix, ".notmuch", "xapian"))
-    message_ids = []
+    uuid_mine = db.revision().uuid.decode()
+    exchange_uuids(uuid_mine, from_stream, to_stream)
+    headers = {}
 
-    logger.info("Getting all message IDs from DB...")
-    ghosts = {p.docid for p in db.postlist("Tghost")} # type: ignore[attr-defined]
-    all_docs = set(range(1, db.get_lastdocid() + 1))
-    for doc_id in all_docs - ghosts:
-        try:
-            doc = db.get_document(doc_id)
-            value = doc.get_value(1)
-            if value:
-                message_ids.append(value.decode("utf-8"))
-        except xapian.DocNotFoundError:
-            pass
-        except RuntimeError:
-            # not entirely sure why, but this seems to happen sometimes
-            continue
+    def _send_header():
+        write(json.dumps({"verify": True}).encode("utf-8"), to_stream)
 
-    db.close()
+    def _recv_header():
+        headers["theirs"] = json.loads(read(from_stream).decode("utf-8"))
 
-    return message_ids
+    run_async(_send_header, _recv_header)
+    if not isinstance(headers["theirs"], dict) or \
+            not (headers["theirs"].get("verify") or (not arbiter and headers["theirs"].get("verifies"))):
+        raise ProtocolError("Other side is syncing instead of verifying, aborting (give --verify on both sides "
+                            "when using --remote-cmd)...")
+    return verify_messages(db, prefix, from_stream, to_stream, arbiter, include, exclude, jobs, checksum, state_dir)
 
 
-# Separate methods for local and remote to avoid sending all IDs both ways --
-# have local figure out what needs to be deleted on both sides
-def sync_deletes_local(
+def verify_messages(
+    db: notmuch2.Database,
     prefix: str,
     from_stream: IO[bytes] | None,
     to_stream: IO[bytes] | None,
-    no_check: bool = False
-) -> int:
+    arbiter: bool = False,
+    include: List[str] | None = None,
+    exclude: List[str] | None = None,
+    jobs: int | None = None,
+    checksum: bool = False,
+    state_dir: str | None = None
+) -> List[str]:
     """
-    Synchronize deletions for the local database and instruct remote to delete
-    messages/files as needed.
+    Verify both sides after the handshake, see run_verify(); the remote also
+    calls this directly when it was syncing and the local side turns out to be
+    verifying.
 
     Args:
+        db: An open notmuch2.Database object.
         prefix (str): Prefix path for filenames (notmuch config database.path).
-        from_stream: Stream to read from the remote.
-        to_stream: Stream to write to the remote.
-        no_check: Delete message not present on other side even if it doesn't
-        have the 'deleted' tag.
+        from_stream: Stream to read from the other side.
+        to_stream: Stream to write to the other side.
+        arbiter (bool): Whether this is the remote, which only sends.
+        include (list): Globs of file names of which files must match at least
+        one, if any.
+        exclude (list): Globs of file names that files must not match.
+        jobs (int): Number of worker threads to digest files with.
+        checksum (bool): Whether to hash all files instead of reusing the
+        digests of unchanged files from previous syncs.
+        state_dir (str): Directory to keep sync state in instead of .notmuch.
 
     Returns:
-        int: Number of deletions performed.
+        list: Differences found, see verify_diff(); always empty on the remote.
     """
-    ids = {}
-    dels = {'a': 0}
+    uuid_mine = db.revision().uuid.decode()
+    digests_fname = os.path.join(get_state_dir(prefix, uuid_mine, state_dir), "notmuch-sync-digests")
+    cache = None if checksum else load_digests(digests_fname)
+    mine = get_verify(db, prefix, include, exclude, jobs, cache)
+    if cache is not None and os.path.isdir(os.path.dirname(digests_fname)):
+        save_digests(digests_fname, cache)
+    if arbiter:
+        logger.info("Sending %s messages to verify...", len(mine))
+        write_changes(mine, to_stream)
+        return []
+    logger.info("Receiving messages to verify...")
+    theirs = read_changes(from_stream)
+    diffs = verify_diff(mine, theirs)
+    logger.warning("Verified %s messages, %s differences.", len(set(mine) | set(theirs)), len(diffs))
+    return diffs
+
+
+def sync_remote(
+    args: argparse.Namespace,
+    from_stream: IO[bytes] | None = None,
+    to_stream: IO[bytes] | None = None,
+    client: str | None = None
+) -> None:
+    """
+    Run synchronization in remote mode, communicating with the local side over
+    stdin/stdout or the given streams. The database is locked against syncs
+    with other clients for the duration of the session.
 
-    def _get_ids():
-        ids["mine"] = get_ids(prefix)
+    Args:
+        args: Parsed command-line arguments.
+        from_stream: Stream to read from the local side, stdin if not given.
+        to_stream: Stream to write to the local side, stdout if not given.
+        client (str): Address of the local side, for the lock file.
+    """
+    from_stream = from_stream or sys.stdin.buffer
+    to_stream = to_stream or sys.stdout.buffer
+    policies = parse_tag_policies(args.tag_policy, False, args.tag_conflict, args.tag_times)
+    property_policies = parse_property_policies(args.property_policy, False)
+    # nothing of a previous client applies, the session ID comes from this one
+    start_session(compress="zlib" if args.compress not in (None, "none") else "", crc=args.frame_checksum,
+                  dry_run=args.dry_run, rate=RateLimit(args.bwlimit) if args.bwlimit else None,
+                  batch=args.batch_size)
+    stats = SyncStats()
+    current_stats.set(stats)
+    hooks = not (args.check or args.verify or args.preview or args.dry_run)
+    if hooks:
+        prepare_sync(args, remote=True)
+    with notmuch2.Database() as db:
+        prefix = os.path.join(str(db.default_path()), '')
+        session["trash"] = db.config.get("notmuch-sync.trash_dir")
+        session["flags"] = db.config.get("maildir.synchronize_flags", "true") != "false"
+    if args.check:
+        with notmuch2.Database(mode=notmuch2.Database.MODE.READ_WRITE) as dbw:
+            run_check(dbw, prefix, from_stream, to_stream, dbw.config.get("notmuch-sync.state_dir"))
+        return
+    with lock_session(prefix, client, args.wait_lock):
+        if args.verify:
+            with notmuch2.Database() as db:
+                run_verify(db, prefix, from_stream, to_stream, True, args.include_pattern, args.exclude_pattern,
+                           args.jobs, args.checksum, db.config.get("notmuch-sync.state_dir"))
+            return
+        with notmuch2.Database(mode=notmuch2.Database.MODE.READ_WRITE) as dbw:
+            try:
+                changes_mine, changes_theirs, tchanges, sync_fname, info = initial_sync(
+                    dbw, prefix, from_stream, to_stream, arbiter=True, orset=args.orset,
+                    tag_times=args.tag_times, policies=policies, protected=args.protect_tags,
+                    receive_only=args.receive_only, max_change=None if args.force else args.max_change,
+                    state_dir=dbw.config.get("notmuch-sync.state_dir"), compress=session["compress"] and args.compress,
+                    preview=args.preview, phases=protocol_phases(args),
+                    patterns={"include": args.include_pattern, "exclude": args.exclude_pattern}, confirm=args.confirm,
+                    properties=args.properties, property_policies=property_policies)
+            except VerifyRequested:
+                verify_messages(dbw, prefix, from_stream, to_stream, True, args.include_pattern,
+                                args.exclude_pattern, args.jobs, args.checksum,
+                                dbw.config.get("notmuch-sync.state_dir"))
+                return
+            if args.preview:
+                write(json.dumps(preview_changes(dbw, prefix, changes_mine, changes_theirs, info["decisions"],
+                                                 args.protect_tags, args.include_pattern,
+                                                 args.exclude_pattern)).encode("utf-8"), to_stream)
+                if read(from_stream) != GOODBYE:
+                    raise ProtocolError("Expected goodbye from local, aborting...")
+                return
+            if args.unindexed:
+                with heartbeat(to_stream):
+                    unindexed = check_unindexed(dbw, prefix, changes_mine, changes_theirs, args.unindexed == "index")
+                if unindexed > 0 and args.mode == "strict":
+                    raise SyncError(f"{unindexed} files not in notmuch database, aborting...")
+            if args.dedup and not args.dry_run:
+                init_store(dbw, prefix)
+            digests_fname = os.path.join(os.path.dirname(sync_fname), "notmuch-sync-digests")
+            cache = load_digests(digests_fname) if args.hash_index or not args.checksum else None
+            digests = None if args.checksum else cache
+            if args.hash_index:
+                with heartbeat(to_stream):
+                    index_digests(dbw, cache, args.jobs)
+            missing, fchanges, dfchanges = get_missing_files(dbw, prefix, changes_mine, changes_theirs, from_stream, to_stream, move_on_change=False,
+                                                             receive_only=args.receive_only, jobs=args.jobs, digests=digests,
+                                                             include=args.include_pattern, exclude=args.exclude_pattern)
+            rmessages, rfiles = sync_files(dbw, prefix, missing, from_stream, to_stream, args.protect_tags, args.receive_only,
+                                           lenient=args.mode == "lenient", index=cache if args.hash_index else None,
+                                           jobs=args.jobs, include=args.include_pattern, exclude=args.exclude_pattern,
+                                           checkpoint=sync_fname + ".partial", delta=args.delta)
+            if session["protocol"] >= 3:
+                fchanges += sync_renames(dbw, prefix, from_stream, to_stream)
+            if stats.incomplete:
+                # not recording the sync makes the next one pick up the messages
+                # with files that haven't been synced again
+                logger.warning("Not recording sync, some files are left to sync.")
+            elif not args.dry_run:
+                record_sync(sync_fname, dbw.revision(), info["vector"])
+                record_tags(sync_fname + "-tags", dbw, list(changes_mine.keys() | changes_theirs.keys()))
+            if cache is not None and not args.dry_run:
+                save_digests(digests_fname, cache)
+
+        dchanges = 0
+        # messages that haven't been received yet would look deleted
+        if args.delete and not stats.incomplete:
+            dchanges = sync_deletes_remote(prefix, from_stream, to_stream, args.delete_no_check)
+            if args.delete_folders:
+                sync_folders(prefix, sync_fname + "-folders", from_stream, to_stream, info["receive_only"])
+        if args.prune_empty_dirs:
+            prune_empty_dirs(prefix)
+        if session["store"] is not None:
+            prune_store(session["store"])
+        if args.mbsync and not stats.incomplete:
+            sync_mbsync_remote(prefix, from_stream, to_stream, args.receive_only)
+        if args.check_counts:
+            write(json.dumps(get_counts(prefix)).encode("utf-8"), to_stream)
+        if args.spot_check:
+            spot_check_remote(prefix, from_stream, to_stream)
+        stats.messages, stats.files, stats.moved = rmessages, rfiles, fchanges
+        stats.deleted_files, stats.tags, stats.deleted_messages = dfchanges, tchanges, dchanges
+        remote_stats = {"version": STATS_VERSION} | stats.changes()
+        if args.dry_run:
+            remote_stats["actions"] = stats.actions
+        # before sending the stats so that the local side learns if it fails
+        if args.post_hook and hooks:
+            run_hook(args.post_hook, "post-sync hook", summary_json(stats), remote=True)
+        write(json.dumps(remote_stats).encode("utf-8"), to_stream)
+        # wait for the local side to acknowledge the stats before closing, so
+        # that neither side goes away while the other one is still reading
+        if read(from_stream) != GOODBYE:
+            raise ProtocolError("Expected goodbye from local, aborting...")
+
+
+def protocol_phases(args: argparse.Namespace) -> List[str]:
+    """
+    Get the options that add optional parts to the protocol, which both sides
+    must agree on to stay in lockstep.
 
-    def _recv_ids():
-        logger.info("Receiving all message IDs from remote...")
-        ids["theirs"] = json.loads(read(from_stream).decode("utf-8"))
+    Args:
+        args: Parsed command-line arguments.
 
-    run_async(_get_ids, _recv_ids)
+    Returns:
+        list: Names of the options given.
+    """
+    phases = {"compress": args.compress not in (None, "none"), "delete": args.delete,
+              "delete-folders": args.delete and args.delete_folders, "mbsync": args.mbsync,
+              "check-counts": args.check_counts, "spot-check": bool(args.spot_check),
+              "hash-index": args.hash_index, "delta": args.delta, "confirm": args.confirm, "preview": args.preview,
+              "dry-run": args.dry_run, "frame-checksum": args.frame_checksum, "properties": args.properties}
+    return [name for name, given in phases.items() if given]
 
-    logger.info("Message IDs synced.")
 
-    def _send_del_ids():
-        to_del_remote = list(set(ids["theirs"]) - set(ids["mine"]))
-        logger.debug("Remote IDs to be deleted %s.", to_del_remote)
-        logger.info("Sending message IDs to be deleted to remote...")
-        write(json.dumps(to_del_remote).encode("utf-8"), to_stream)
+def split_ssh_cmd(client: str, ssh_cmd: str) -> List[str]:
+    """
+    Split the SSH command into arguments according to the conventions of the
+    SSH client. plink is usually run on Windows, where backslashes in paths
+    are not escapes and arguments are quoted with double quotes only.
 
-    def _recv_del_ids():
-        to_del = set(ids["mine"]) - set(ids["theirs"])
-        logger.debug("Local IDs to be deleted %s.", to_del)
-        with notmuch2.Database(mode=notmuch2.Database.MODE.READ_WRITE) as dbw:
-            for mid in to_del:
-                try:
-                    msg = dbw.find(mid)
-                    if msg.ghost:
-                        continue
-                    if "deleted" in msg.tags or no_check:
-                        dels["a"] += 1
-                        logger.info("Removing %s from DB and deleting files.", mid)
-                        for f in msg.filenames():
-                            logger.debug("Removing %s.", f)
-                            dbw.remove(f)
-                            Path(f).unlink()
-                    else:
-                        # not there on remote, but no "deleted" tag -- assume
-                        # that something went wrong and set tags again to make
-                        # it show up in next changeset to be synced back to
-                        # remote
-                        logger.info("%s set to be removed, but not tagged 'deleted'!", mid)
-                        with msg.frozen():
-                            tmp = "".join(msg.tags)
-                            msg.tags.add(tmp)
-                            msg.tags.discard(tmp)
-                except LookupError:
-                    # already deleted? doesn't matter
-                    pass
+    Args:
+        client (str): SSH client, one of SSH_CLIENTS.
+        ssh_cmd (str): SSH command.
 
-    run_async(_send_del_ids, _recv_del_ids)
+    Returns:
+        list: Arguments of the SSH command.
+    """
+    if client == "plink":
+        return [arg[1:-1] if len(arg) > 1 and arg[0] == arg[-1] == '"' else arg
+                for arg in shlex.split(ssh_cmd, posix=False)]
+    return shlex.split(ssh_cmd)
 
-    return dels["a"]
 
+def read_ssh_config(host: str, fname: str = "~/.ssh/config") -> Dict[str, str]:
+    """
+    Get the options for a host from an OpenSSH client configuration file. As
+    with OpenSSH, the first value of each option from all matching Host blocks
+    applies. Match blocks and Include are not supported.
 
-def sync_deletes_remote(
-    prefix: str,
-    from_stream: IO[bytes] | None,
-    to_stream: IO[bytes] | None,
-    no_check: bool = False
-) -> int:
+    Args:
+        host (str): Host name or alias as given on the commandline.
+        fname (str): Configuration file.
+
+    Returns:
+        dict: Options in lower case mapped to their values.
     """
-    Receive instructions from local to delete messages/files from the remote database.
+    opts: Dict[str, str] = {}
+    try:
+        lines = Path(os.path.expanduser(fname)).read_text(encoding="utf-8").splitlines()
+    except OSError:
+        return opts
+    matches = True
+    for line in lines:
+        parts = line.strip().replace("=", " ", 1).split(None, 1)
+        if len(parts) < 2 or parts[0].startswith("#"):
+            continue
+        key, value = parts[0].lower(), parts[1].strip().strip('"')
+        if key == "host":
+            patterns = value.split()
+            matches = (any(fnmatch.fnmatch(host, p) for p in patterns if not p.startswith("!")) and
+                       not any(fnmatch.fnmatch(host, p[1:]) for p in patterns if p.startswith("!")))
+        elif key == "match":
+            matches = False
+        elif matches:
+            opts.setdefault(key, value)
+    return opts
+
+
+def ssh_destination(client: str, remote: str, user: str | None) -> List[str]:
+    """
+    Get the SSH arguments to connect to the remote. OpenSSH reads its
+    configuration itself; for other clients, host name, user, port, identity
+    file, and jump host are taken from ~/.ssh/config and passed as arguments
+    so that aliases and other settings from there work the same.
 
     Args:
-        prefix (str): Prefix path for filenames (notmuch config database.path).
-        from_stream: Stream to read from the local.
-        to_stream: Stream to write to the local.
-        no_check: Delete message not present on other side even if it doesn't
-        have the 'deleted' tag.
+        client (str): SSH client, one of SSH_CLIENTS.
+        remote (str): Remote host as given on the commandline.
+        user (str): SSH user as given on the commandline, if any.
 
     Returns:
-        int: Number of deletions performed.
+        list: Arguments for the SSH client, the last one being the destination.
     """
-    dels = 0
-    ids = get_ids(prefix)
-    write(json.dumps(ids).encode("utf-8"), to_stream)
+    if client == "openssh":
+        return [(f"{user}@" if user else "") + remote]
+    opts = read_ssh_config(remote)
+    args = []
+    if "port" in opts:
+        args += ["-P" if client == "plink" else "-p", opts["port"]]
+    if "identityfile" in opts:
+        args += ["-i", os.path.expanduser(opts["identityfile"])]
+    user = user or opts.get("user")
+    dest = (f"{user}@" if user else "") + opts.get("hostname", remote).replace("%h", remote)
+    jump = opts.get("proxyjump", "none")
+    if jump != "none":
+        if client == "plink":
+            args += ["-proxycmd", f"plink -batch -nc %host:%port {jump}"]
+        else:
+            # dropbear's multi-hop syntax
+            dest = f"{jump},{dest}"
+    return args + [dest]
 
-    to_del = json.loads(read(from_stream).decode("utf-8"))
-    with notmuch2.Database(mode=notmuch2.Database.MODE.READ_WRITE) as dbw:
-        for mid in to_del:
-            try:
-                msg = dbw.find(mid)
-                if msg.ghost:
-                    continue
-                if "deleted" in msg.tags or no_check:
-                    dels += 1
-                    for f in msg.filenames():
-                        dbw.remove(f)
-                        Path(f).unlink()
-                else:
-                    # not on local, but no "deleted" tag -- assume that
-                    # something went wrong and set tags again to make it
-                    # show up in next changeset to be synced back to local
-                    with msg.frozen():
-                        tmp = "".join(msg.tags)
-                        msg.tags.add(tmp)
-                        msg.tags.discard(tmp)
-            except LookupError:
-                # already deleted? doesn't matter
-                pass
-    return dels
 
+def ssh_master(ssh_cmd: List[str], host: str, persist: str = "10") -> str:
+    """
+    Connect to the remote with SSH attached to the terminal, so that SSH can
+    ask for passwords and second factors, and keep the connection open for a
+    few seconds as master connection for the sync, which cannot ask for
+    anything because its input and output are used for the sync.
+
+    Args:
+        ssh_cmd (list): SSH command to use.
+        host (str): Host to connect to, optionally with user.
+        persist (str): How long to keep the connection open after the last
+                       sync, "yes" to keep it open until it is stopped.
+
+    Returns:
+        str: Control path of the master connection to pass to SSH.
+
+    Raises:
+        ConnectionLost: If connecting fails.
+    """
+    control = os.path.join(tempfile.gettempdir(), f"notmuch-sync-{os.getpid()}-%C")
+    logger.info("Connecting to remote for authentication...")
+    res = subprocess.run(ssh_cmd + ["-o", "ControlMaster=yes", "-o", f"ControlPersist={persist}",
+                                    "-o", f"ControlPath={control}", host, "true"], check=False)
+    if res.returncode != 0:
+        raise ConnectionLost(f"Connecting to {host} for authentication failed with exit code {res.returncode}, aborting...")
+    return control
+
+
+def parse_address(spec: str) -> Tuple[str, int]:
+    """
+    Parse a network address of the form "host:port", with IPv6 addresses in
+    brackets, e.g. "[::1]:4711".
+
+    Args:
+        spec (str): Address.
+
+    Returns:
+        tuple: Host and port.
+
+    Raises:
+        argparse.ArgumentTypeError: If the address is not valid.
+    """
+    host, _, port = spec.rpartition(":")
+    if host.startswith("[") and host.endswith("]"):
+        host = host[1:-1]
+    try:
+        num = int(port)
+    except ValueError as e:
+        raise argparse.ArgumentTypeError(f"invalid address '{spec}', must be <host>:<port>") from e
+    if not host or not 0 < num < 65536:
+        raise argparse.ArgumentTypeError(f"invalid address '{spec}', must be <host>:<port>")
+    return (host, num)
+
+
+class SocketStream(io.RawIOBase):
+    """
+    Raw stream over a (TLS) socket. Unlike socket.makefile(), it has no file
+    descriptor, so that files are never sent with sendfile() past TLS.
+    """
+    def __init__(self, sock: socket.socket):
+        super().__init__()
+        self.sock = sock
+
+    def readable(self) -> bool:
+        return True
+
+    def writable(self) -> bool:
+        return True
+
+    def readinto(self, b) -> int: # type: ignore[override]
+        return self.sock.recv_into(b)
+
+    def write(self, b) -> int: # type: ignore[override]
+        return self.sock.send(b)
+
+    def close(self) -> None:
+        if not self.closed:
+            self.sock.close()
+        super().close()
+
+
+def socket_streams(sock: socket.socket) -> Tuple[IO[bytes], IO[bytes]]:
+    """
+    Get buffered streams to read from and write to a socket.
+
+    Args:
+        sock: Connected socket.
 
-def sync_mbsync_local(
-    prefix: str,
-    from_stream: IO[bytes] | None,
-    to_stream: IO[bytes] | None
-) -> None:
+    Returns:
+        tuple: Stream to read from and stream to write to; closing both closes
+        the socket.
     """
-    Synchronize local mbsync files with remote.
+    raw = SocketStream(sock)
+    return (io.BufferedReader(raw), io.BufferedWriter(raw)) # type: ignore[return-value]
+
+
+def tls_context(server: bool, cert: str | None, key: str | None, peer: str | None) -> ssl.SSLContext:
+    """
+    Set up TLS for a direct connection between the two sides. Both sides
+    authenticate with their certificate and only accept the certificates of
+    the other side given (or certificates issued by them), so that
+    self-signed certificates can be exchanged beforehand; host names are not
+    checked.
 
     Args:
-        prefix (str): Prefix path for filenames (notmuch config database.path).
-        from_stream: Stream to read from the remote.
-        to_stream: Stream to write to the remote.
+        server (bool): Whether this is the side accepting connections.
+        cert (str): File with the certificate of this side, and its key if
+                    key is not given.
+        key (str): File with the private key of this side.
+        peer (str): File with the certificates of the other side to accept.
+
+    Returns:
+        ssl.SSLContext: Context to wrap sockets with.
+
+    Raises:
+        SyncError: If certificate or peer certificates are not given or
+        cannot be loaded.
     """
-    mbsync = {}
+    if not cert or not peer:
+        raise SyncError("Direct connections need --tls-cert and --tls-peer, aborting...")
+    ctx = ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER if server else ssl.PROTOCOL_TLS_CLIENT)
+    ctx.minimum_version = ssl.TLSVersion.TLSv1_2
+    ctx.check_hostname = False
+    ctx.verify_mode = ssl.CERT_REQUIRED
+    # accept the certificates of the other side themselves, not just CAs
+    ctx.verify_flags |= ssl.VERIFY_X509_PARTIAL_CHAIN
+    try:
+        ctx.load_cert_chain(cert, key)
+        ctx.load_verify_locations(peer)
+    except (OSError, ssl.SSLError) as e:
+        raise SyncError(f"Cannot load TLS certificates: {e}, aborting...") from e
+    return ctx
 
-    def _get_mbsync():
-        logger.info("Getting local mbsync file stats...")
-        mbsync["mine"] = { str(f).removeprefix(prefix): f.stat().st_mtime
-                           for pat in [".uidvalidity", ".mbsyncstate"]
-                           for f in Path(prefix).rglob(pat) }
 
-    def _recv_mbsync():
-        logger.info("Receiving mbsync file stats from remote...")
-        mbsync["theirs"] = json.loads(read(from_stream).decode("utf-8"))
+def tls_connect(address: Tuple[str, int], ctx: ssl.SSLContext) -> Tuple[IO[bytes], IO[bytes]]:
+    """
+    Connect to a remote listening with --listen.
 
-    run_async(_get_mbsync, _recv_mbsync)
+    Args:
+        address (tuple): Host and port to connect to.
+        ctx (ssl.SSLContext): TLS context, see tls_context().
 
-    logger.info("mbsync file stats synced.")
+    Returns:
+        tuple: Stream to read from and stream to write to the remote.
 
-    pull = [ f for f in mbsync["mine"].keys()
-            if (f in mbsync["theirs"] and mbsync["theirs"][f] > mbsync["mine"][f]) ]
-    pull += list(set(mbsync["theirs"].keys()) - set(mbsync["mine"].keys()))
-    logger.debug("Local mbsync files to be updated from remote %s.", pull)
-    write(json.dumps(pull).encode("utf-8"), to_stream)
+    Raises:
+        ValueError: If connecting or the TLS handshake fails.
+    """
+    logger.info("Connecting to %s:%s...", *address)
+    try:
+        sock = socket.create_connection(address)
+    except OSError as e:
+        raise ConnectionLost(f"Connecting to {address[0]}:{address[1]} failed: {e}, aborting...") from e
+    try:
+        tls = ctx.wrap_socket(sock)
+    except (OSError, ssl.SSLError) as e:
+        sock.close()
+        raise ConnectionLost(f"TLS handshake with {address[0]}:{address[1]} failed: {e}, aborting...") from e
+    return socket_streams(tls)
 
-    def _send_mbsync_files():
-        push = [ f for f in mbsync["theirs"].keys()
-                if (f in mbsync["mine"] and mbsync["mine"][f] > mbsync["theirs"][f]) ]
-        push += list(set(mbsync["mine"].keys()) - set(mbsync["theirs"].keys()))
 
-        logger.debug("mbsync files to update on remote %s.", push)
-        logger.info("Sending %s mbsync files to remote...", len(push))
-        write(json.dumps(push).encode("utf-8"), to_stream)
-        for idx, f in enumerate(push):
-            logger.debug("%s/%s Sending mbsync file %s to remote...", idx + 1,
-                         len(push), f)
-            to_stream.write(struct.pack("!d", mbsync["mine"][f]))
-            to_stream.flush()
-            transfer["write"] += 8
-            send_file(os.path.join(prefix, f), to_stream)
+def unix_connect(path: str) -> Tuple[IO[bytes], IO[bytes]]:
+    """
+    Connect to a remote listening with --listen --socket.
 
-    def _recv_mbsync_files():
-        logger.info("Receiving %s mbsync files from remote...", len(pull))
-        for idx, f in enumerate(pull):
-            logger.debug("%s/%s Receiving mbsync file %s from remote...",
-                         idx + 1, len(pull), f)
-            mtime_data = from_stream.read(8)
-            transfer["read"] += 8
-            mtime = struct.unpack("!d", mtime_data)[0]
-            fname = os.path.join(prefix, f)
-            recv_file(fname, from_stream, overwrite_raise=False)
-            os.utime(fname, (mtime, mtime))
+    Args:
+        path (str): Path of the Unix domain socket to connect to.
 
-    run_async(_send_mbsync_files, _recv_mbsync_files)
+    Returns:
+        tuple: Stream to read from and stream to write to the remote.
 
-    logger.info("mbsync files synced.")
+    Raises:
+        ValueError: If connecting fails.
+    """
+    logger.info("Connecting to %s...", path)
+    sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    try:
+        sock.connect(path)
+    except OSError as e:
+        sock.close()
+        raise ConnectionLost(f"Connecting to {path} failed: {e}, aborting...") from e
+    return socket_streams(sock)
 
 
-def sync_mbsync_remote(
-    prefix: str,
-    from_stream: IO[bytes] | None,
-    to_stream: IO[bytes] | None
-) -> None:
+def unix_server(path: str) -> socket.socket:
     """
-    Synchronize remote mbsync files with local.
+    Listen on a Unix domain socket that only the user can connect to. A socket
+    left over from an earlier run is replaced, unless something is still
+    listening on it.
 
     Args:
-        prefix (str): Prefix path for filenames (notmuch config database.path).
-        from_stream: Stream to read from the remote.
-        to_stream: Stream to write to the remote.
+        path (str): Path of the socket.
+
+    Returns:
+        socket.socket: Listening socket.
+
+    Raises:
+        SyncError: If the path exists and isn't a stale socket.
     """
-    mbsync = { str(f).removeprefix(prefix): f.stat().st_mtime
-               for pat in [".uidvalidity", ".mbsyncstate"]
-               for f in Path(prefix).rglob(pat) }
-    write(json.dumps(mbsync).encode("utf-8"), to_stream)
-    push = json.loads(read(from_stream).decode("utf-8"))
+    if os.path.exists(path):
+        if not stat.S_ISSOCK(os.stat(path).st_mode):
+            raise SyncError(f"{path} exists and is not a socket, aborting...")
+        try:
+            from_stream, to_stream = unix_connect(path)
+        except ValueError:
+            os.unlink(path)
+        else:
+            to_stream.close()
+            from_stream.close()
+            raise SyncError(f"Something is listening on {path} already, aborting...")
+    server = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    umask = os.umask(0o177)
+    try:
+        server.bind(path)
+    finally:
+        os.umask(umask)
+    server.listen()
+    return server
 
-    def _send_mbsync_files():
-        for f in push:
-            fname = os.path.join(prefix, f)
-            to_stream.write(struct.pack("!d", Path(fname).stat().st_mtime))
-            to_stream.flush()
-            transfer["write"] += 8
-            send_file(fname, to_stream)
 
-    def _recv_mbsync_files():
-        pull = json.loads(read(from_stream).decode("utf-8"))
-        for f in pull:
-            mtime_data = from_stream.read(8)
-            transfer["read"] += 8
-            mtime = struct.unpack("!d", mtime_data)[0]
-            fname = os.path.join(prefix, f)
-            recv_file(fname, from_stream, overwrite_raise=False)
-            os.utime(fname, (mtime, mtime))
+def serve(args: argparse.Namespace) -> None:
+    """
+    Listen for direct connections from local sides with --connect or --socket
+    and run a remote sync for each of them in turn, until interrupted. Failed
+    syncs are logged and reported to the local side.
 
-    run_async(_send_mbsync_files, _recv_mbsync_files)
+    Args:
+        args: Parsed command-line arguments.
+    """
+    if args.socket:
+        if args.listen is not True:
+            raise SyncError("Give either an address to --listen or --socket, aborting...")
+        ctx = None
+        server = unix_server(args.socket)
+        where = args.socket
+    else:
+        if args.listen is True:
+            raise SyncError("--listen needs an address without --socket, aborting...")
+        ctx = tls_context(True, args.tls_cert, args.tls_key, args.tls_peer)
+        host, port = args.listen
+        family = socket.AF_INET6 if ":" in host else socket.AF_INET
+        server = socket.create_server((host, port), family=family)
+        where = f"{host}:{port}"
+    try:
+        logger.warning("Listening on %s.", where)
+        while True:
+            conn, addr = server.accept()
+            # peers on Unix domain sockets have no address
+            peer = addr[0] if ctx else "local"
+            logger.info("Connection from %s.", peer)
+            if ctx:
+                try:
+                    conn = ctx.wrap_socket(conn, server_side=True)
+                except (OSError, ssl.SSLError) as e:
+                    logger.error("TLS handshake with %s failed: %s", peer, e)
+                    conn.close()
+                    continue
+            from_stream, to_stream = socket_streams(conn)
+            try:
+                sync_remote(args, from_stream, to_stream, peer)
+                logger.info("Sync with %s done.", peer)
+            except Exception as e: # pylint: disable=broad-exception-caught
+                logger.error("Sync with %s failed: %s", peer, e)
+                if not isinstance(e, RemoteError):
+                    with contextlib.suppress(OSError):
+                        write_error(e, to_stream)
+            finally:
+                with contextlib.suppress(OSError):
+                    to_stream.close()
+                from_stream.close()
+    finally:
+        server.close()
+        if args.socket:
+            with contextlib.suppress(OSError):
+                os.unlink(args.socket)
+
+
+def summary_lines(stats: SyncStats, remote_stats: Dict[str, Any]) -> List[str]:
+    """
+    Format the summary of a sync for the user.
+
+    Args:
+        stats (SyncStats): Statistics of the local side.
+        remote_stats (dict): Changes on the remote side, see SyncStats.changes().
+
+    Returns:
+        list: Lines of the summary.
+    """
+    lines = ["local:  {} new messages,\t{} new files,\t{} files copied/moved,\t{} files deleted,\t{} messages with tag changes,\t{} messages deleted".format(
+                 stats.messages, stats.files, stats.moved, stats.deleted_files, stats.tags, stats.deleted_messages),
+             "remote: {} new messages,\t{} new files,\t{} files copied/moved,\t{} files deleted,\t{} messages with tag changes,\t{} messages deleted".format(
+                 *(remote_stats.get(key, 0) for key in ("messages", "files", "moved", "deleted_files", "tags", "deleted_messages")))]
+    for side, side_folders in (("local: ", stats.folders), ("remote:", remote_stats.get("folders", {}))):
+        for name in sorted(side_folders):
+            lines.append(f"{side} {name}:\t{side_folders[name]['messages']} new messages,\t"
+                         f"{side_folders[name]['files']} new files,\t{side_folders[name]['deleted']} messages deleted")
+    if stats.incomplete:
+        lines.append(f"Out of time: {stats.remaining} files left to receive locally, "
+                     f"{remote_stats.get('remaining', 0)} on remote; run again to sync them.")
+    if stats.conflicts or remote_stats.get("conflicts", 0):
+        lines.append(f"Conflicts: {stats.conflicts} received files skipped locally, "
+                     f"{remote_stats.get('conflicts', 0)} on remote, because they exist with different content.")
+    lines.append(f"{stats.read}/{stats.write} bytes received from/sent to remote.")
+    return lines
+
+
+def summary_json(
+    stats: SyncStats | None,
+    remote_stats: Dict[str, Any] | None = None,
+    error: str | None = None
+) -> Dict[str, Any]:
+    """
+    Get the summary of a sync, or the error it failed with, for scripts.
+
+    Args:
+        stats (SyncStats): Statistics of the local side, None on error.
+        remote_stats (dict): Changes on the remote side.
+        error (str): Error the sync failed with, if any.
+
+    Returns:
+        dict: Time, status, and error or whether the sync is incomplete, bytes
+        transferred, and changes on both sides.
+    """
+    summary: Dict[str, Any] = {"time": int(time.time()), "status": "error" if error else "ok"}
+    if error:
+        summary["error"] = error
+    else:
+        summary["incomplete"] = stats.incomplete
+        summary["read"], summary["write"] = stats.read, stats.write
+        summary["local"] = stats.changes()
+        summary["remote"] = {key: val for key, val in (remote_stats or {}).items() if key != "version"}
+    return summary
 
 
-def sync_remote(args: argparse.Namespace) -> None:
+def run_hook(cmd: str, name: str, summary: Dict[str, Any] | None = None, remote: bool = False) -> None:
     """
-    Run synchronization in remote mode.
+    Run a hook command, with the summary of the sync as JSON on stdin if given
+    (and nothing otherwise, so that it can't read from the connection to the
+    other side). On the remote, its output is discarded, as stdout is the
+    connection to the local side and anything on stderr is taken as an error
+    by the local side, except for the error output of a failed command, which
+    is part of the error.
+
+    Args:
+        cmd (str): Command to run.
+        name (str): Name of the hook for messages, e.g. "pre-sync hook".
+        summary (dict): Summary of the sync, see summary_json().
+        remote (bool): Whether this is the remote side.
+
+    Raises:
+        SyncError: If the command fails.
+    """
+    logger.info("Running %s...", name)
+    res = subprocess.run(shlex.split(cmd), check=False, text=True,
+                         input="" if summary is None else json.dumps(summary),
+                         stdout=subprocess.DEVNULL if remote else None,
+                         stderr=subprocess.PIPE if remote else None)
+    if res.returncode != 0:
+        output = f" ({res.stderr.strip()})" if remote and res.stderr.strip() else ""
+        raise SyncError(f"{name[:1].upper()}{name[1:]} failed with exit code {res.returncode}{output}, aborting...")
+
+
+def prepare_sync(args: argparse.Namespace, remote: bool = False) -> None:
+    """
+    Run the pre-sync hook, the command to fetch mail, and notmuch new to index
+    new mail, as far as given, before syncing.
 
     Args:
         args: Parsed command-line arguments.
+        remote (bool): Whether this is the remote side.
+
+    Raises:
+        SyncError: If any of the commands fails.
     """
-    with notmuch2.Database(mode=notmuch2.Database.MODE.READ_WRITE) as dbw:
-        prefix = os.path.join(str(dbw.default_path()), '')
-        changes_mine, changes_theirs, tchanges, sync_fname = initial_sync(dbw, prefix, sys.stdin.buffer, sys.stdout.buffer)
-        missing, fchanges, dfchanges = get_missing_files(dbw, prefix, changes_mine, changes_theirs, sys.stdin.buffer, sys.stdout.buffer, move_on_change=False)
-        rmessages, rfiles = sync_files(dbw, prefix, missing, sys.stdin.buffer, sys.stdout.buffer)
-        record_sync(sync_fname, dbw.revision())
+    if args.pre_hook:
+        run_hook(args.pre_hook, "pre-sync hook", remote=remote)
+    if args.pull_cmd:
+        run_hook(args.pull_cmd, "pull command", remote=remote)
+    if args.index in (["remote", "both"] if remote else ["local", "both"]):
+        run_hook("notmuch new", "notmuch new", remote=remote)
 
-    dchanges = 0
-    if args.delete:
-        dchanges = sync_deletes_remote(prefix, sys.stdin.buffer, sys.stdout.buffer, args.delete_no_check)
-    if args.mbsync:
-        sync_mbsync_remote(prefix, sys.stdin.buffer, sys.stdout.buffer)
-    sys.stdout.buffer.write(struct.pack("!IIIIII", tchanges, fchanges, dfchanges,
-                                        rmessages, dchanges, rfiles))
-    sys.stdout.buffer.flush()
+
+def write_summary(
+    fname: str,
+    summary_format: str,
+    stats: SyncStats | None,
+    remote_stats: Dict[str, Any] | None = None,
+    error: str | None = None
+) -> None:
+    """
+    Write the summary of a sync, or the error it failed with, to a file for
+    status bars and monitoring scripts. The file is replaced atomically, so
+    that readers never see a partially written summary.
+
+    Args:
+        fname (str): File to write to.
+        summary_format (str): "text" for the lines logged at the end of a sync,
+                              "json" for the changes on both sides.
+        stats (SyncStats): Statistics of the local side, None on error.
+        remote_stats (dict): Changes on the remote side.
+        error (str): Error the sync failed with, if any.
+    """
+    if summary_format == "json":
+        text = json.dumps(summary_json(stats, remote_stats, error)) + "\n"
+    elif error:
+        text = f"error: {error}\n"
+    else:
+        text = "".join(line + "\n" for line in summary_lines(stats, remote_stats or {}))
+    tmp = fname + ".notmuch-sync-tmp"
+    Path(tmp).write_text(text, encoding="utf-8")
+    os.replace(tmp, fname)
 
 
-def sync_local(args: argparse.Namespace) -> None:
+class RemoteStderr(threading.Thread):
+    """
+    Thread reading the error output of the remote command as it comes, so that
+    the remote never blocks on a full pipe, logging each line at debug level
+    and keeping all of it to report at the end.
     """
-    Run synchronization in local mode, communicating with the remote over SSH or
-    a custom command.
+    def __init__(self, stream: IO[bytes]):
+        super().__init__(daemon=True)
+        self.stream = stream
+        self.data = bytearray()
+
+    def run(self) -> None:
+        for line in iter(self.stream.readline, b""):
+            logger.debug("Remote: %s", line.decode("utf-8", "replace").rstrip())
+            self.data += line
+
+    def output(self, timeout: float = 5) -> bytes:
+        """
+        Wait for the remote to close its error output and get all of it.
+
+        Args:
+            timeout (float): Seconds to wait at most.
+
+        Returns:
+            bytes: Error output of the remote so far.
+        """
+        self.join(timeout)
+        if not self.is_alive():
+            self.stream.close()
+        return bytes(self.data)
+
+
+def sync_local(
+    args: argparse.Namespace,
+    control: str | None = None,
+    from_stream: IO[bytes] | None = None,
+    to_stream: IO[bytes] | None = None
+) -> SyncStats:
+    """
+    Run synchronization in local mode, communicating with the remote over SSH, a
+    custom command, a direct connection, or the given streams. Applications
+    embedding notmuch-sync can pass streams over their own channels, with
+    sync_remote() getting the other ends; both are closed at the end. Options
+    are not passed on to the remote in this case.
 
     Args:
         args: Parsed command-line arguments.
+        control (str): Control path of an OpenSSH master connection to the
+                       remote to use, if any.
+        from_stream: Stream to read from the remote instead of connecting.
+        to_stream: Stream to write to the remote instead of connecting.
+
+    Returns:
+        SyncStats: Statistics of the session on the local side.
     """
-    if args.remote_cmd:
+    if (args.preview or args.dry_run) and (args.orset or args.tag_times or args.confirm):
+        raise SyncError(f"--{'preview' if args.preview else 'dry-run'} doesn't work with --orset, --tag-times, or "
+                         "--confirm, aborting...")
+    hooks = not (args.check or args.verify or args.preview or args.dry_run)
+    if hooks:
+        prepare_sync(args)
+    deadline = time.monotonic() + args.time_limit if args.time_limit else None
+    policies = parse_tag_policies(args.tag_policy, True, args.tag_conflict, args.tag_times)
+    property_policies = parse_property_policies(args.property_policy, True)
+    if from_stream is not None or args.socket:
+        cmd = None
+    elif args.connect:
+        cmd = None
+        ctx = tls_context(False, args.tls_cert, args.tls_key, args.tls_peer)
+    elif args.remote_cmd:
         cmd = shlex.split(args.remote_cmd)
     else:
-        rargs = [(f"{args.user}@" if args.user else "") + args.remote, f"{args.path}"]
+        rargs = [f"{args.path}"]
         if args.delete:
             rargs.append("--delete")
         if args.delete_no_check:
             rargs.append("--delete-no-check")
+        if args.delete_folders:
+            rargs.append("--delete-folders")
+        if args.prune_empty_dirs:
+            rargs.append("--prune-empty-dirs")
         if args.mbsync:
             rargs.append("--mbsync")
-        cmd = shlex.split(args.ssh_cmd) + rargs
-
+        if args.check_counts:
+            rargs.append("--check-counts")
+        if args.spot_check:
+            rargs += ["--spot-check", str(args.spot_check)]
+        if args.unindexed:
+            rargs += ["--unindexed", args.unindexed]
+        if args.orset:
+            rargs.append("--orset")
+        if args.tag_times:
+            rargs.append("--tag-times")
+        for policy in args.tag_policy:
+            rargs += ["--tag-policy", shlex.quote(policy)]
+        if args.tag_conflict:
+            rargs += ["--tag-conflict", args.tag_conflict]
+        if args.properties:
+            rargs.append("--properties")
+        for policy in args.property_policy:
+            rargs += ["--property-policy", shlex.quote(policy)]
+        for pat in args.include_pattern:
+            rargs += ["--include-pattern", shlex.quote(pat)]
+        for pat in args.exclude_pattern:
+            rargs += ["--exclude-pattern", shlex.quote(pat)]
+        if args.max_change is not None:
+            rargs += ["--max-change", str(args.max_change)]
+        if args.force:
+            rargs.append("--force")
+        if args.mode:
+            rargs += ["--mode", args.mode]
+        if args.checksum:
+            rargs.append("--checksum")
+        # flagging rewritten messages needs the index on both sides
+        if args.hash_index or args.flag_rewrites:
+            args.hash_index = True
+            rargs.append("--hash-index")
+        if args.dedup:
+            rargs.append("--dedup")
+        if args.delta:
+            rargs.append("--delta")
+        if args.compress:
+            rargs += ["--compress", args.compress]
+        if args.frame_checksum:
+            rargs.append("--frame-checksum")
+        if args.preview:
+            rargs.append("--preview")
+        if args.dry_run:
+            rargs.append("--dry-run")
+        if args.bwlimit:
+            rargs += ["--bwlimit", str(args.bwlimit / 1024)]
+        if args.check:
+            rargs.append("--check")
+        if args.verify:
+            rargs.append("--verify")
+        if args.confirm:
+            rargs.append("--confirm")
+        if args.wait_lock:
+            rargs += ["--wait-lock", str(args.wait_lock)]
+        if args.remote_pre_hook:
+            rargs += ["--pre-hook", shlex.quote(args.remote_pre_hook)]
+        if args.remote_post_hook:
+            rargs += ["--post-hook", shlex.quote(args.remote_post_hook)]
+        if args.remote_pull_cmd:
+            rargs += ["--pull-cmd", shlex.quote(args.remote_pull_cmd)]
+        if args.index in ["remote", "both"]:
+            rargs += ["--index", "remote"]
+        ssh_cmd = split_ssh_cmd(args.ssh_client, args.ssh_cmd or SSH_CLIENTS[args.ssh_client])
+        dest = ssh_destination(args.ssh_client, args.remote, args.user)
+        cmd = ssh_cmd + dest + rargs
+        if args.interactive_auth and control is None:
+            if args.ssh_client != "openssh":
+                raise SyncError("--interactive-auth only works with --ssh-client openssh, aborting...")
+            control = ssh_master(ssh_cmd, dest[-1])
+        if control is not None:
+            cmd = ssh_cmd + ["-o", f"ControlPath={control}"] + dest + rargs
+
+    start_session(id=str(uuid.uuid4()), compress="zlib" if args.compress not in (None, "none") else "",
+                  crc=args.frame_checksum, dry_run=args.dry_run,
+                  rate=RateLimit(args.bwlimit) if args.bwlimit else None, batch=args.batch_size)
+    stats = SyncStats()
+    current_stats.set(stats)
     logger.info("Connecting to remote...")
     logger.debug("Command to connect to remote: %s", cmd)
 
-    with subprocess.Popen(
-                cmd,
-                stdin=subprocess.PIPE,
-                stdout=subprocess.PIPE,
-                stderr=subprocess.PIPE
-            ) as proc:
-        to_remote = proc.stdin
-        from_remote = proc.stdout
-        err_remote = proc.stderr
+    with contextlib.ExitStack() as stack:
+        if not args.check:
+            # --check looks at the lock itself
+            with notmuch2.Database() as db:
+                prefix = os.path.join(str(db.default_path()), '')
+            stack.enter_context(lock_session(prefix, "local", args.wait_lock))
+        if cmd is None:
+            if from_stream is not None:
+                from_remote, to_remote = from_stream, to_stream
+            elif args.socket:
+                from_remote, to_remote = unix_connect(args.socket)
+            else:
+                from_remote, to_remote = tls_connect(args.connect, ctx)
+            err_remote: RemoteStderr | None = None
+        else:
+            proc = stack.enter_context(subprocess.Popen(
+                        cmd,
+                        stdin=subprocess.PIPE,
+                        stdout=subprocess.PIPE,
+                        stderr=subprocess.PIPE
+                    ))
+            to_remote = proc.stdin
+            from_remote = proc.stdout
+            err_remote = RemoteStderr(proc.stderr)
+            err_remote.start()
+        if args.timeout and from_stream is None:
+            # a read that times out is ended by cutting the connection
+            if cmd is not None:
+                abort = proc.kill
+            else:
+                sock = from_remote.raw.sock # type: ignore[attr-defined]
+                abort = lambda: sock.shutdown(socket.SHUT_RDWR)
+            from_remote = ReadTimeout(from_remote, args.timeout, abort) # type: ignore[assignment]
 
         data = b''
+        anomalies = 0
         try:
             with notmuch2.Database(mode=notmuch2.Database.MODE.READ_WRITE) as dbw:
                 prefix = os.path.join(str(dbw.default_path()), '')
-                changes_mine, changes_theirs, tchanges, sync_fname = initial_sync(dbw, prefix, from_remote, to_remote)
-                missing, fchanges, dfchanges = get_missing_files(dbw, prefix, changes_mine, changes_theirs, from_remote, to_remote, move_on_change=True)
+                session["trash"] = args.trash_dir or dbw.config.get("notmuch-sync.trash_dir")
+                session["flags"] = dbw.config.get("maildir.synchronize_flags", "true") != "false"
+                if not (args.check or args.verify or args.preview or args.dry_run):
+                    session["journal"] = stack.enter_context(start_journal(get_state_dir(
+                        prefix, dbw.revision().uuid.decode(), args.state_dir or dbw.config.get("notmuch-sync.state_dir"))))
+                    stack.callback(session.update, journal=None)
+                if args.check:
+                    reports = run_check(dbw, prefix, from_remote, to_remote,
+                                        args.state_dir or dbw.config.get("notmuch-sync.state_dir"))
+                    sys.stdout.write("".join(line + "\n" for line in format_check(reports[0], "local") +
+                                             format_check(reports[1], "remote")))
+                    sys.stdout.flush()
+                    if reports[0]["problems"] or reports[1]["problems"]:
+                        stats.status = 1
+                    return stats
+                if args.verify:
+                    diffs = run_verify(dbw, prefix, from_remote, to_remote, False, args.include_pattern,
+                                       args.exclude_pattern, args.jobs, args.checksum,
+                                       args.state_dir or dbw.config.get("notmuch-sync.state_dir"))
+                    sys.stdout.write("".join(line + "\n" for line in diffs))
+                    sys.stdout.flush()
+                    if diffs:
+                        stats.status = 1
+                    return stats
+                changes_mine, changes_theirs, tchanges, sync_fname, info = initial_sync(
+                    dbw, prefix, from_remote, to_remote, orset=args.orset, tag_times=args.tag_times,
+                    policies=policies, protected=args.protect_tags, receive_only=args.receive_only,
+                    max_change=None if args.force else args.max_change, diff_format=args.diff_format,
+                    state_dir=args.state_dir or dbw.config.get("notmuch-sync.state_dir"),
+                    compress=session["compress"] and args.compress, preview=args.preview,
+                    phases=protocol_phases(args),
+                    patterns={"include": args.include_pattern, "exclude": args.exclude_pattern},
+                    confirm=args.confirm, yes=args.yes, properties=args.properties,
+                    property_policies=property_policies)
+                if args.preview:
+                    preview_mine = preview_changes(dbw, prefix, changes_mine, changes_theirs, info["decisions"],
+                                                   args.protect_tags, args.include_pattern, args.exclude_pattern)
+                    preview_theirs = json.loads(read(from_remote).decode("utf-8"))
+                    write(GOODBYE, to_remote)
+                    sys.stdout.write(format_preview(preview_mine, "local", args.diff_format) +
+                                     format_preview(preview_theirs, "remote", args.diff_format))
+                    sys.stdout.flush()
+                    return stats
+                if args.unindexed:
+                    with heartbeat(to_remote):
+                        unindexed = check_unindexed(dbw, prefix, changes_mine, changes_theirs, args.unindexed == "index")
+                    if unindexed > 0 and args.mode == "strict":
+                        raise SyncError(f"{unindexed} files not in notmuch database, aborting...")
+                if args.dedup and not args.dry_run:
+                    init_store(dbw, prefix)
+                digests_fname = os.path.join(os.path.dirname(sync_fname), "notmuch-sync-digests")
+                cache = load_digests(digests_fname) if args.hash_index or not args.checksum else None
+                digests = None if args.checksum else cache
+                if args.hash_index:
+                    with heartbeat(to_remote):
+                        index_digests(dbw, cache, args.jobs, args.flag_rewrites is not None)
+                missing, fchanges, dfchanges = get_missing_files(dbw, prefix, changes_mine, changes_theirs, from_remote, to_remote, move_on_change=True,
+                                                                 receive_only=args.receive_only, jobs=args.jobs, digests=digests,
+                                                                 include=args.include_pattern, exclude=args.exclude_pattern)
                 logger.debug("Missing files %s.", missing)
-                rmessages, rfiles = sync_files(dbw, prefix, missing, from_remote, to_remote)
-                record_sync(sync_fname, dbw.revision())
+                rmessages, rfiles = sync_files(dbw, prefix, missing, from_remote, to_remote, args.protect_tags, args.receive_only,
+                                               args.diff_format, args.mode == "lenient", args.tag_new_synced,
+                                               cache if args.hash_index else None, args.jobs, args.flag_rewrites,
+                                               args.include_pattern, args.exclude_pattern, deadline, sync_fname + ".partial",
+                                               sys.stderr.isatty() and not args.quiet and args.verbose == 0, args.delta)
+                if session["protocol"] >= 3:
+                    fchanges += sync_renames(dbw, prefix, from_remote, to_remote)
+                if stats.incomplete:
+                    # not recording the sync makes the next one pick up the messages
+                    # with files that haven't been synced again
+                    logger.warning("Not recording sync, some files are left to sync.")
+                elif not args.dry_run:
+                    record_sync(sync_fname, dbw.revision(), info["vector"])
+                    record_tags(sync_fname + "-tags", dbw, list(changes_mine.keys() | changes_theirs.keys()))
+                if cache is not None and not args.dry_run:
+                    save_digests(digests_fname, cache)
 
             dchanges = 0
-            if args.delete:
-                dchanges = sync_deletes_local(prefix, from_remote, to_remote, args.delete_no_check)
-            if args.mbsync:
-                sync_mbsync_local(prefix, from_remote, to_remote)
+            # messages that haven't been received yet would look deleted
+            if args.delete and not stats.incomplete:
+                dchanges = sync_deletes_local(prefix, from_remote, to_remote, args.delete_no_check,
+                                              args.receive_only, info["receive_only"], args.confirm, args.yes)
+                if args.delete_folders:
+                    sync_folders(prefix, sync_fname + "-folders", from_remote, to_remote, info["receive_only"])
+            if args.prune_empty_dirs:
+                prune_empty_dirs(prefix)
+            if session["store"] is not None:
+                prune_store(session["store"])
+            if args.mbsync and not stats.incomplete:
+                sync_mbsync_local(prefix, from_remote, to_remote, args.receive_only, info["receive_only"])
+            if args.check_counts:
+                logger.info("Checking message and file counts...")
+                if not check_counts(get_counts(prefix), json.loads(read(from_remote).decode("utf-8"))):
+                    anomalies += 1
+            if args.spot_check:
+                anomalies += spot_check_local(prefix, args.spot_check, from_remote, to_remote)
 
             logger.info("Getting change numbers from remote...")
             if from_remote is not None:
-                remote_changes = struct.unpack("!IIIIII", from_remote.read(6 * 4))
-                transfer["read"] += 6 * 4
+                remote_stats = json.loads(read(from_remote).decode("utf-8"))
+                if remote_stats.get("version", 0) > STATS_VERSION:
+                    logger.info("Remote stats version %s newer than %s, ignoring unknown fields.",
+                                remote_stats["version"], STATS_VERSION)
+                write(GOODBYE, to_remote)
             else:
-                remote_changes = (0,0,0,0,0,0)
+                remote_stats = {}
+        except BrokenPipeError:
+            # the remote aborted while we were sending, see if it told us why
+            if from_remote is not None and from_remote.read(4) == ERROR_MARKER:
+                current_stats.get().read += 4
+                read_error(from_remote)
+            raise
+        except (RemoteError, ConnectionLost):
+            raise
+        except Exception as e:
+            # tell the remote what went wrong instead of just going away
+            if to_remote is not None:
+                with contextlib.suppress(OSError, ValueError):
+                    write_error(e, to_remote)
+            raise
         finally:
-            ready, _, exc = select([err_remote], [], [], 0)
-            if err_remote is not None and ready and not exc:
-                data = err_remote.read()
-                # getting zero data on EOF
-                if len(data) > 0:
-                    logger.error("Remote error: %s", data)
-
             if to_remote is not None:
                 to_remote.close()
             if from_remote is not None:
                 from_remote.close()
             if err_remote is not None:
-                err_remote.close()
-
-    logger.warning("local:  %s new messages,\t%s new files,\t%s files copied/moved,\t%s files deleted,\t%s messages with tag changes,\t%s messages deleted", rmessages, rfiles, fchanges, dfchanges, tchanges, dchanges)
-    logger.warning("remote: %s new messages,\t%s new files,\t%s files copied/moved,\t%s files deleted,\t%s messages with tag changes,\t%s messages deleted", remote_changes[3], remote_changes[5], remote_changes[1], remote_changes[2], remote_changes[0], remote_changes[4])
-    logger.warning("%s/%s bytes received from/sent to remote.", transfer["read"], transfer["write"])
+                # closing stdin makes the remote exit if it hasn't already
+                data = err_remote.output()
+                if len(data) > 0:
+                    logger.error("Remote error: %s", data.decode("utf-8", "replace").strip())
+
+    stats.messages, stats.files, stats.moved = rmessages, rfiles, fchanges
+    stats.deleted_files, stats.tags, stats.deleted_messages = dfchanges, tchanges, dchanges
+    for line in summary_lines(stats, remote_stats):
+        logger.warning("%s", line)
+    if args.dry_run:
+        sys.stdout.write("".join(f"{side} would {action}\n" for side, actions in
+                                 (("local: ", stats.actions), ("remote:", remote_stats.get("actions", [])))
+                                 for action in actions))
+        sys.stdout.flush()
+    elif args.summary_file:
+        write_summary(args.summary_file, args.summary_format, stats, remote_stats)
+
+    if args.post_sync_cmd and stats.message_ids:
+        logger.info("Running post-sync command for %s new or changed messages...", len(stats.message_ids))
+        res = subprocess.run(shlex.split(args.post_sync_cmd), check=False, text=True,
+                             input="".join(f"{mid}\n" for mid in sorted(stats.message_ids)))
+        if res.returncode != 0:
+            logger.error("Post-sync command failed with exit code %s.", res.returncode)
+            stats.status = 1
 
     if len(data) > 0:
         # error output from remote
-        sys.exit(1)
-
-
-def main() -> None:
+        stats.status = 1
+    if anomalies > 0 and args.mode == "strict" and not args.dry_run:
+        logger.error("Sides differ after sync, failing because of --mode strict.")
+        stats.status = 1
+    if args.fail_on_conflict and (stats.conflicts or remote_stats.get("conflicts", 0)) and not args.dry_run:
+        logger.error("Received files conflict with existing files, failing because of --fail-on-conflict.")
+        stats.status = ConflictError.exit_code
+    if args.post_hook and hooks:
+        run_hook(args.post_hook, "post-sync hook", summary_json(stats, remote_stats))
+    return stats
+
+
+def arg_parser() -> argparse.ArgumentParser:
     """
-    Entry point for the command-line interface. Parses arguments and dispatches
-    to local or remote sync.
+    Build the parser of the command-line arguments.
+
+    Returns:
+        argparse.ArgumentParser: Parser of the command-line arguments.
     """
     parser = argparse.ArgumentParser()
-    parser.add_argument("-r", "--remote", type=str, help="remote host to connect to")
+    parser.add_argument("-r", "--remote", type=str, action="append", default=[], help="remote host to connect to; can be given multiple times to sync with each in turn, with a table of the changes from each at the end")
     parser.add_argument("-u", "--user", type=str, help="SSH user to use")
     parser.add_argument("-v", "--verbose", action="count", default=0, help="increases verbosity, up to twice (ignored on remote)")
     parser.add_argument("-q", "--quiet", action="store_true", help="do not print any output, overrides --verbose")
-    parser.add_argument("-s", "--ssh-cmd", type=str, default="ssh -CTaxq", help="SSH command to use (default 'ssh -CTaxq')")
+    parser.add_argument("-s", "--ssh-cmd", type=str, help="SSH command to use (default depends on --ssh-client, 'ssh -CTaxq' for OpenSSH)")
+    parser.add_argument("--ssh-client", type=str, choices=list(SSH_CLIENTS), default="openssh", help="SSH client --ssh-cmd is, for its default command and argument quoting: OpenSSH, PuTTY's plink (e.g. on Windows), or dropbear's dbclient (default openssh)")
     parser.add_argument("-m", "--mbsync", action="store_true", help="sync mbsync files (.mbsyncstate, .uidvalidity)")
     parser.add_argument("-p", "--path", type=str, default=os.path.basename(sys.argv[0]), help="path to notmuch-sync on remote server")
     parser.add_argument("-c", "--remote-cmd", type=str, help="command to run to sync; overrides --remote, --user, --ssh-cmd, --path; mostly used for testing")
     parser.add_argument("-d", "--delete", action="store_true", help="sync deleted messages (requires listing all messages in notmuch database, potentially expensive)")
     parser.add_argument("-x", "--delete-no-check", action="store_true", help="delete missing messages even if they don't have the 'deleted' tag (requires --delete) -- potentially unsafe")
-    args = parser.parse_args()
+    parser.add_argument("--delete-folders", action="store_true", help="remove maildir folders that were removed on the other side and have no mail left after syncing deletions (requires --delete)")
+    parser.add_argument("--prune-empty-dirs", action="store_true", help="remove directories under the mail directory that are empty after syncing, except cur/new/tmp of maildir folders")
+    parser.add_argument("--check-counts", action="store_true", help="compare the number of messages, files, and messages per folder on both sides after syncing and warn if they differ")
+    parser.add_argument("--spot-check", type=parse_percent, metavar="PERCENT", help="after syncing, compare tags and file contents of a random sample of PERCENT of all messages on both sides and warn about mismatches")
+    merge = parser.add_mutually_exclusive_group()
+    merge.add_argument("--orset", action="store_true", help="merge tags as observed-remove sets stored in message properties, which removes tags removed on either side (needs to be used for all syncs between all copies)")
+    merge.add_argument("--tag-times", action="store_true", help="merge tags by taking the most recent change of each tag, with change times stored in message properties (needs to be used for all syncs between all copies)")
+    parser.add_argument("--receive-only", action="store_true", help="only receive changes from the other side and never send local changes; can be given on the remote as well")
+    parser.add_argument("--protect-tags", type=str, action="append", default=[], metavar="TAG", help="never add or remove tags matching glob TAG because of changes from the remote; can be given multiple times")
+    parser.add_argument("--tag-policy", type=str, action="append", default=[], metavar="TAG:POLICY", help="merge tags matching glob TAG of messages changed on both sides with POLICY (union, prefer-local, prefer-remote, or newest, which needs --tag-times); can be given multiple times, first match applies")
+    parser.add_argument("--tag-conflict", type=str, choices=["union", "prefer-local", "prefer-remote", "newest"], help="merge all tags of messages changed on both sides that match no --tag-policy with this policy (default union, newest with --tag-times)")
+    parser.add_argument("--properties", action="store_true", help="sync message properties (except notmuch's index.* and notmuch-sync's own) along with tags; properties of messages changed on both sides are merged like tags without --orset or --tag-times")
+    parser.add_argument("--property-policy", type=str, action="append", default=[], metavar="KEY:POLICY", help="merge properties with keys matching glob KEY of messages changed on both sides with POLICY (union, prefer-local, or prefer-remote); can be given multiple times, first match applies")
+    parser.add_argument("--include-pattern", type=str, action="append", default=[], metavar="GLOB", help="only transfer (or copy/move locally) files whose path relative to the mail directory matches glob GLOB; can be given multiple times, files matching any of them are transferred")
+    parser.add_argument("--exclude-pattern", "--exclude", type=str, action="append", default=[], metavar="GLOB", help="never transfer (or copy/move locally) files whose path relative to the mail directory matches glob GLOB, e.g. '*.gz' or 'Spam/*' (also given as --exclude); can be given multiple times, takes precedence over --include-pattern")
+    parser.add_argument("--tag-new-synced", type=str, metavar="TAG", help="add TAG to all messages received from the remote that are new here, e.g. to review them; the tag is synced to the remote the next time the tags of the message change")
+    parser.add_argument("--summary-file", metavar="PATH", help="after each sync, write the summary of the changes on both sides (or the error the sync failed with) to PATH, replacing it atomically, for status bars and monitoring scripts")
+    parser.add_argument("--summary-format", type=str, choices=["json", "text"], default="json", help="format of the summary written with --summary-file, 'json' with the time of the sync, status, and changes on both sides, or 'text' with the lines shown at the end of a sync (default 'json')")
+    parser.add_argument("--post-sync-cmd", type=str, metavar="CMD", help="after syncing, run CMD with the IDs of all messages that are new or had their tags changed here, one per line, on stdin, e.g. to auto-tag only those (not run if there are none)")
+    parser.add_argument("--pre-hook", type=str, metavar="CMD", help="before syncing, run CMD, e.g. to fetch mail and run notmuch new, and abort if it fails")
+    parser.add_argument("--post-hook", type=str, metavar="CMD", help="after a successful sync, run CMD with the summary of the sync as JSON (as in --summary-format json) on stdin, e.g. to refresh an email client or send a notification")
+    parser.add_argument("--remote-pre-hook", type=str, metavar="CMD", help="like --pre-hook, but run CMD on the remote; its output is discarded")
+    parser.add_argument("--remote-post-hook", type=str, metavar="CMD", help="like --post-hook, but run CMD on the remote with the summary of the changes there on stdin; its output is discarded")
+    parser.add_argument("--pull-cmd", type=str, metavar="CMD", help="before syncing (after --pre-hook), run CMD to fetch mail, e.g. 'mbsync -a', and abort if it fails")
+    parser.add_argument("--remote-pull-cmd", type=str, metavar="CMD", help="like --pull-cmd, but run CMD on the remote; its output is discarded")
+    parser.add_argument("--index", nargs="?", const="both", choices=["local", "remote", "both"], help="before syncing (after --pull-cmd), run notmuch new on the local side, the remote, or both (default) to index new mail")
+    parser.add_argument("--time-limit", type=parse_duration, metavar="DURATION", help="stop transferring files after DURATION (e.g. 600, 10m, or 1h) and report how many are left, e.g. on expensive connections; the files being transferred are finished and the rest synced the next time, deletions and mbsync files are not synced in this case")
+    parser.add_argument("--wait-lock", type=parse_duration, default=0, metavar="DURATION", help="if another sync with the notmuch database on either side is in progress, wait for up to DURATION for it to finish instead of failing right away, e.g. for overlapping syncs from cron jobs")
+    parser.add_argument("--retries", type=int, default=0, metavar="N", help="if a sync fails because the notmuch database on either side is locked (e.g. by notmuch new), another sync is in progress, or the connection fails, try again up to N times, waiting --retry-delay before the first retry and twice as long before each further one")
+    parser.add_argument("--timeout", type=parse_duration, metavar="DURATION", help="give up on the remote with exit code 4 if it sends nothing for DURATION while this side waits for it (both sides send heartbeats while they are busy)")
+    parser.add_argument("--retry-delay", type=parse_duration, default=10, metavar="DURATION", help="with --retries, wait DURATION before trying again the first time (default 10s)")
+    parser.add_argument("--max-change", type=float, metavar="FRACTION", help="abort if the remote changes would alter tags or files of more than FRACTION (e.g. 0.2) of all messages; not checked on the first sync")
+    parser.add_argument("--force", action="store_true", help="sync even if more than --max-change of all messages would change")
+    parser.add_argument("--diff-format", type=str, choices=["text", "patch"], default="text", help="format of per-message tag changes shown with -vv; 'patch' uses notmuch batch tagging syntax that can be applied with 'notmuch tag --batch' (default 'text')")
+    parser.add_argument("--unindexed", type=str, choices=["warn", "index"], help="look for files that are not in the notmuch database in all folders with changes before syncing files and warn about or index them")
+    parser.add_argument("--state-dir", type=str, metavar="DIR", help="keep sync state in DIR instead of the .notmuch directory of the notmuch database (default notmuch config notmuch-sync.state_dir); set that option in the notmuch config on the remote")
+    parser.add_argument("--mode", type=str, choices=["strict", "lenient"], help="'strict' aborts on anything unexpected (implies --max-change 0.2, --unindexed warn, and --fail-on-conflict, fails if --check-counts or --spot-check find differences), 'lenient' skips and reports (skips received files that can't be written, implies --unindexed index)")
+    parser.add_argument("--fail-on-conflict", action="store_true", help="exit with code 2 after syncing if received files were skipped on either side because files with the same name and different content exist there")
+    parser.add_argument("-j", "--jobs", type=parse_count, metavar="N", help="number of threads to read and hash files with when determining moved/copied files (default number of CPUs); applies to this side only")
+    parser.add_argument("--batch-size", type=lambda spec: parse_count(spec, 0), default=ATOMIC_BATCH, metavar="N", help=f"change tags and add received messages in atomic notmuch transactions of N messages (default {ATOMIC_BATCH}, 0 for a single transaction), so that fewer, larger commits are made and interrupted syncs leave no partial changes of messages; applies to this side only")
+    parser.add_argument("--checksum", action="store_true", help="hash all files when determining moved/copied files instead of reusing the digests of files with unchanged size, mtime, and inode from previous syncs")
+    parser.add_argument("--hash-index", action="store_true", help="keep digests of all files and copy files to receive that exist with the same contents anywhere on the receiving side instead of transferring them, e.g. when messages were moved across folders; the first sync with this option reads all files")
+    parser.add_argument("--flag-rewrites", metavar="TAG", help="add TAG to messages received from the remote whose body is the same as that of another local message, i.e. that are likely the same mail with a different message ID (e.g. after re-delivery or rewriting by a mailing list), and warn about them; implies --hash-index")
+    parser.add_argument("--delta", action="store_true", help="send files of messages the receiving side has another file of, e.g. after a header was changed, as differences to that file instead of in full; costs CPU time on both sides")
+    parser.add_argument("--dedup", action="store_true", help="store the contents of files only once in the .notmuch directory, with all files with the same contents hardlinks to it, so that duplicates take no extra space and copies are cheap; the first sync with this option puts all existing files into the store")
+    parser.add_argument("--compress", nargs="?", const="zlib", type=parse_compress, metavar="ALGORITHM[:LEVEL]", help="compress data sent to/received from the remote, except data that doesn't compress well like compressed attachments (use with an SSH command without -C), with zlib (default, levels 1-9, also accepted as gzip), zstd (levels 1-22), lz4, or none; zstd and lz4 need the zstandard and lz4 Python modules on both sides and fall back to zlib otherwise")
+    parser.add_argument("--frame-checksum", action="store_true", help="follow each frame sent to/received from the remote with a CRC32 checksum and abort if it doesn't match, to detect data corrupted in transit, e.g. by a misbehaving SSH wrapper; large files are no longer sent with sendfile()")
+    parser.add_argument("--bwlimit", type=parse_bwlimit, metavar="KIBPS", help="limit the rate data is sent at on both sides to KIBPS KiB/s each, e.g. for large syncs over slow or metered connections")
+    parser.add_argument("--connect", type=parse_address, metavar="HOST:PORT", help="connect to a remote listening with --listen at HOST:PORT directly over TLS instead of with SSH (needs --tls-cert and --tls-peer); options are not passed on to the remote")
+    parser.add_argument("--listen", nargs="?", const=True, type=parse_address, metavar="HOST:PORT", help="instead of syncing, listen at HOST:PORT (or on --socket) for direct TLS connections from local sides with --connect (or --socket) and sync with each in turn, e.g. on a NAS without shell access (needs --tls-cert and --tls-peer and the same options as the local sides)")
+    parser.add_argument("--socket", type=str, metavar="PATH", help="connect to a remote listening with --listen --socket on the Unix domain socket at PATH instead of with SSH, e.g. for another notmuch database on the same machine or in a container with the socket bind-mounted; only the user can connect to the socket, and options are not passed on to the remote")
+    parser.add_argument("--tls-cert", type=str, metavar="PATH", help="certificate of this side for --connect and --listen, in PEM format, including the private key unless --tls-key is given")
+    parser.add_argument("--tls-key", type=str, metavar="PATH", help="private key for --tls-cert in PEM format")
+    parser.add_argument("--tls-peer", type=str, metavar="PATH", help="certificates of the other side(s) to accept for --connect and --listen (or of the CA that issued them), in PEM format")
+    parser.add_argument("--interactive-auth", action="store_true", help="connect to the remote with SSH on the terminal first to allow it to ask for passwords or second factors, then sync over that connection (OpenSSH only)")
+    parser.add_argument("--check", action="store_true", help="instead of syncing, check that both sides speak the same protocol, can open their notmuch database for writing, aren't busy with another sync, and have consistent sync state, and show the versions, databases, and free disk space of both sides")
+    parser.add_argument("--verify", action="store_true", help="instead of syncing, compare the messages, tags, and file contents of both sides and list any differences on stdout, without changing anything; exits with 1 if there are differences; hashes all files whose digests aren't cached from previous syncs")
+    parser.add_argument("--confirm", action="store_true", help="show how many messages will get tag changes and files will be removed on each side, with the most common tag changes, before applying anything, and again before deleting messages with --delete, and ask whether to go ahead; the remote waits for the answer")
+    parser.add_argument("--yes", action="store_true", help="go ahead without asking with --confirm, e.g. in scripts")
+    parser.add_argument("--dry-run", action="store_true", help="go through a whole sync with the remote, but instead of changing anything on either side, print the tag changes, file copies/moves and deletions, and file transfers it would make; nothing is recorded for the next sync")
+    parser.add_argument("--preview", action="store_true", help="instead of syncing, print the changes a sync would make on both sides as a unified diff, with the files to add and remove per folder and the tag changes per message (in the format given by --diff-format); files are determined by name, deletions are not included, and nothing is changed")
+    parser.add_argument("--watch", action="store_true", help="keep running and sync whenever the local notmuch database changes (e.g. after 'notmuch new' or tagging), and at least every --watch-interval to get changes on the remote; with OpenSSH, the connection to the remote is kept open")
+    parser.add_argument("--watch-debounce", type=parse_duration, default=10, metavar="DURATION", help="with --watch, wait until the notmuch database hasn't changed for DURATION before syncing (default 10s)")
+    parser.add_argument("--watch-interval", type=parse_duration, default=900, metavar="DURATION", help="with --watch, sync at least every DURATION (default 15m)")
+    parser.add_argument("--verify-every", type=parse_duration, metavar="DURATION", help="with --watch, also verify both sides as with --verify after a sync when DURATION (e.g. 7d) has passed since the last verification with the remote, listing any differences on stdout; regular syncs stay incremental")
+    parser.add_argument("--verify-at", type=parse_time_of_day, metavar="HH:MM", help="with --verify-every, verify at the first HH:MM (local time) after the verification is due, e.g. at night")
+    parser.add_argument("--trash-dir", type=str, metavar="DIR", help="move deleted files to a directory for the day in DIR instead of deleting them, with a manifest of where they came from (default notmuch config notmuch-sync.trash_dir); set that option in the notmuch config on the remote")
+    parser.add_argument("--purge-trash", type=parse_duration, metavar="DURATION", help="instead of syncing, remove files moved to the --trash-dir more than DURATION (e.g. 30d) ago")
+    parser.add_argument("--undo", action="store_true", help="instead of syncing, undo the changes the last sync made on this side (tags, received, moved, and deleted files), except where changed since; the changes are synced to the remote by the next sync")
+    parser.add_argument("--orphans", type=str, choices=["report", "remove", "index"], help="instead of syncing, find files under the mail root that are not in the notmuch database and report, remove, or index them")
+    parser.add_argument("--profile", type=str, action="append", default=[], metavar="NAME", help="read options from profile NAME in the configuration file, with options given on the command line taking precedence (added to for options that can be given multiple times, except --remote); can be given multiple times to sync with the remotes of each profile in turn")
+    parser.add_argument("--config", type=str, metavar="PATH", help="configuration file to read --profile from (default $XDG_CONFIG_HOME/notmuch-sync/config.toml)")
+    return parser
+
+
+def parse_runs(argv: List[str] | None = None) -> List[argparse.Namespace]:
+    """
+    Parse the command-line arguments into one set of arguments for each sync to
+    run, i.e. for each remote of each profile. Remotes given on the command line
+    replace those of the profiles.
+
+    Args:
+        argv (list): Command-line arguments, sys.argv if not given.
+
+    Returns:
+        list: Parsed arguments for each sync, with a single remote (or None).
+    """
+    parser = arg_parser()
+    args = parser.parse_args(argv)
+    if args.config and not args.profile:
+        parser.error("--config requires --profile")
+    runs = []
+    for profile in args.profile or [None]:
+        run = args
+        if profile:
+            parser = arg_parser()
+            try:
+                parser.set_defaults(**config_defaults(parser, load_config(args.config or config_path(), profile)))
+            except argparse.ArgumentTypeError as e:
+                parser.error(str(e))
+            run = parser.parse_args(argv)
+            run.remote = args.remote or run.remote
+        for remote in run.remote or [None]:
+            runs.append(argparse.Namespace(**{**vars(run), "remote": remote}))
+    for run in runs:
+        apply_mode(run)
+    return runs
+
+
+def remote_label(args: argparse.Namespace) -> str:
+    """
+    Get the name of the remote to sync with, for the user.
+
+    Args:
+        args: Parsed command-line arguments.
+
+    Returns:
+        str: Remote host, command, or address to connect to.
+    """
+    if args.socket:
+        return args.socket
+    if args.connect:
+        return "{}:{}".format(*args.connect)
+    return args.remote or args.remote_cmd
+
+
+def remotes_table(results: List[Tuple[str, SyncStats | None]]) -> List[str]:
+    """
+    Format the changes here from syncing with several remotes as a table, one
+    row per remote.
+
+    Args:
+        results (list): Remote and local statistics of the sync with it, None
+                        if it failed.
+
+    Returns:
+        list: Lines of the table.
+    """
+    rows = [["remote", "status", "new messages", "new files", "copied/moved", "tag changes",
+             "deleted", "received", "sent"]]
+    for remote, stats in results:
+        if stats is None:
+            rows.append([remote, "failed"])
+        else:
+            rows.append([remote, "incomplete" if stats.incomplete else "ok"] +
+                        [str(val) for val in (stats.messages, stats.files, stats.moved, stats.tags,
+                                              stats.deleted_messages, stats.read, stats.write)])
+    widths = [max(len(row[i]) for row in rows if i < len(row)) for i in range(len(rows[0]))]
+    return ["  ".join(val.ljust(width) for val, width in zip(row, widths)).rstrip() for row in rows]
 
-    if args.remote or args.remote_cmd:
+
+def retryable(e: BaseException) -> bool:
+    """
+    Check whether trying again later may get past an error, e.g. because the
+    notmuch database on either side was locked by another process like
+    notmuch new, or the connection broke off.
+
+    Args:
+        e: The error.
+
+    Returns:
+        bool: Whether the error is transient.
+    """
+    if isinstance(e, RemoteError):
+        return e.retryable or e.kind == "XapianError"
+    if isinstance(e, SyncError):
+        return e.retryable
+    return isinstance(e, (notmuch2.XapianError, ConnectionError))
+
+
+def run_local(args: argparse.Namespace, control: str | None = None) -> SyncStats:
+    """
+    Run a local sync, trying again up to --retries times with exponentially
+    increasing delays if it fails with a transient error, and writing the error
+    it finally fails with to the summary file, if any.
+
+    Args:
+        args: Parsed command-line arguments.
+        control (str): Control path of an OpenSSH master connection to the
+                       remote to use, if any.
+
+    Returns:
+        SyncStats: Statistics of the session on the local side.
+    """
+    delay = args.retry_delay
+    attempt = 0
+    while True:
+        try:
+            return sync_local(args, control)
+        except Exception as e:
+            if attempt < args.retries and retryable(e):
+                attempt += 1
+                logger.warning("Sync failed: %s, trying again in %ss (%s of %s)...",
+                               e, delay, attempt, args.retries)
+                time.sleep(delay)
+                delay *= 2
+                continue
+            if args.summary_file and not args.dry_run:
+                error = f"Remote error: {e}" if isinstance(e, RemoteError) else str(e)
+                write_summary(args.summary_file, args.summary_format, None, error=error)
+            raise
+
+
+def dir_state(path: str) -> List[Tuple[str, int, int]]:
+    """
+    Get the names, sizes, and modification times of the files in a directory,
+    to tell whether anything in it changed.
+
+    Args:
+        path (str): Directory.
+
+    Returns:
+        list: Name, size, and modification time in nanoseconds of each file.
+    """
+    with os.scandir(path) as entries:
+        return sorted((entry.name, entry.stat().st_size, entry.stat().st_mtime_ns) for entry in entries)
+
+
+def wait_for_changes(path: str, interval: float, debounce: float, poll: float = 1) -> bool:
+    """
+    Wait until the files in a directory change and then don't change anymore
+    for a while, or until some time has passed.
+
+    Args:
+        path (str): Directory to watch.
+        interval (float): Seconds to wait for changes at most.
+        debounce (float): Seconds without further changes to wait for after a
+                          change.
+        poll (float): Seconds between checks of the directory.
+
+    Returns:
+        bool: Whether the directory changed.
+    """
+    start = time.monotonic()
+    state = dir_state(path)
+    changed = None
+    while True:
+        now = time.monotonic()
+        if changed is not None and now - changed >= debounce:
+            return True
+        if changed is None and now - start >= interval:
+            return False
+        time.sleep(poll)
+        new = dir_state(path)
+        if new != state:
+            state, changed = new, time.monotonic()
+
+
+def next_verify(last: float, every: float, at: Tuple[int, int] | None = None) -> float:
+    """
+    Get the time the next scheduled verification is due, which is the given
+    interval after the last one, or the first time after that at the given
+    time of day.
+
+    Args:
+        last (float): Time of the last verification.
+        every (float): Seconds between verifications.
+        at (tuple): Hour and minute (local time) to verify at, if any.
+
+    Returns:
+        float: Time the next verification is due.
+    """
+    due = last + every
+    if at is None:
+        return due
+    day = time.localtime(due)
+    start = time.mktime((day.tm_year, day.tm_mon, day.tm_mday, at[0], at[1], 0, 0, 0, -1))
+    if start < due:
+        start = time.mktime((day.tm_year, day.tm_mon, day.tm_mday + 1, at[0], at[1], 0, 0, 0, -1))
+    return start
+
+
+def watch(runs: List[argparse.Namespace]) -> None:
+    """
+    Sync whenever the local notmuch database changes, or at least at regular
+    intervals to pick up changes on the remotes, until interrupted. With
+    OpenSSH, the connection to each remote is kept open between syncs. With
+    --verify-every, both sides are verified after a sync whenever that is due;
+    the times of the last verifications are kept with the sync state, so that
+    the schedule carries over restarts.
+
+    Args:
+        runs (list): Parsed command-line arguments for each remote.
+    """
+    args = runs[0]
+    if args.dry_run or args.preview:
+        raise SyncError(f"--watch doesn't work with --{'preview' if args.preview else 'dry-run'}, aborting...")
+    with notmuch2.Database() as db:
+        prefix = str(db.default_path())
+        xapian_dir = os.path.join(prefix, ".notmuch", "xapian")
+        verified_fname = os.path.join(get_state_dir(prefix, db.revision().uuid.decode(),
+                                                    args.state_dir or db.config.get("notmuch-sync.state_dir")),
+                                      "notmuch-sync-verified") if args.verify_every else ""
+    # first verification at the next time due from now
+    started = time.time() - (args.verify_every or 0)
+    verified: Dict[str, float] = {}
+    if verified_fname:
+        with contextlib.suppress(OSError, ValueError):
+            verified = json.loads(Path(verified_fname).read_text(encoding="utf-8"))
+
+    masters = []
+    try:
+        controls = []
+        for run in runs:
+            control = None
+            if run.ssh_client == "openssh" and not run.remote_cmd and not run.connect and not run.socket:
+                ssh_cmd = split_ssh_cmd(run.ssh_client, run.ssh_cmd or SSH_CLIENTS[run.ssh_client])
+                host = ssh_destination(run.ssh_client, run.remote, run.user)[-1]
+                control = ssh_master(ssh_cmd, host, persist="yes")
+                masters.append(ssh_cmd + ["-o", f"ControlPath={control}", "-O", "exit", host])
+            controls.append(control)
+
+        while True:
+            for run, control in zip(runs, controls):
+                remote = remote_label(run)
+                try:
+                    run_local(run, control)
+                except Exception as e: # pylint: disable=broad-exception-caught
+                    # try again the next time
+                    logger.error("Sync with %s failed: %s", remote, e)
+                    continue
+                if not run.verify_every or \
+                        time.time() < next_verify(verified.get(remote, started), run.verify_every, run.verify_at):
+                    continue
+                logger.warning("Verifying with %s...", remote)
+                try:
+                    run_local(argparse.Namespace(**{**vars(run), "verify": True}), control)
+                except Exception as e: # pylint: disable=broad-exception-caught
+                    logger.error("Verification with %s failed: %s", remote, e)
+                    continue
+                verified[remote] = time.time()
+                with contextlib.suppress(OSError):
+                    Path(verified_fname).write_text(json.dumps(verified), encoding="utf-8")
+            logger.info("Waiting for changes...")
+            wait_for_changes(xapian_dir, args.watch_interval, args.watch_debounce)
+    except KeyboardInterrupt:
+        logger.info("Stopping.")
+    finally:
+        for cmd in masters:
+            subprocess.run(cmd, check=False, stderr=subprocess.DEVNULL)
+
+
+def main() -> None:
+    """
+    Entry point for the command-line interface. Parses arguments and dispatches
+    to local or remote sync.
+    """
+    runs = parse_runs()
+    args = runs[0]
+
+    if args.remote or args.remote_cmd or args.connect or args.socket or args.listen or args.orphans or args.purge_trash or args.undo:
         if args.verbose == 1:
             logger.setLevel(level=logging.INFO)
         elif args.verbose == 2:
@@ -918,10 +6662,66 @@ def main() -> None:
 
         if args.quiet:
             logger.disabled = True
-        sync_local(args)
+        try:
+            if args.orphans:
+                handle_orphans(args.orphans)
+            elif args.undo:
+                logger.warning("Undid %s changes.", undo_sync(args.state_dir))
+            elif args.purge_trash:
+                with notmuch2.Database() as db:
+                    trash = args.trash_dir or db.config.get("notmuch-sync.trash_dir")
+                if trash is None:
+                    logger.error("--purge-trash needs --trash-dir or notmuch-sync.trash_dir in the notmuch config.")
+                    sys.exit(SyncError.exit_code)
+                logger.warning("Removed %s days of files from trash.", purge_trash(trash, args.purge_trash))
+            elif args.listen:
+                try:
+                    serve(args)
+                except KeyboardInterrupt:
+                    logger.info("Stopping.")
+            elif args.watch:
+                watch(runs)
+            elif len(runs) == 1:
+                sys.exit(run_local(args).status)
+            else:
+                results: List[Tuple[str, SyncStats | None]] = []
+                codes = []
+                for run in runs:
+                    remote = remote_label(run)
+                    logger.warning("Syncing with %s...", remote)
+                    try:
+                        stats = run_local(run)
+                        results.append((remote, stats))
+                        codes.append(stats.status)
+                    except RemoteError as e:
+                        logger.error("Remote error: %s", e)
+                        results.append((remote, None))
+                        codes.append(e.exit_code)
+                    except Exception as e: # pylint: disable=broad-exception-caught
+                        # carry on with the other remotes
+                        logger.error("Sync with %s failed: %s", remote, e)
+                        results.append((remote, None))
+                        codes.append(e.exit_code if isinstance(e, SyncError) else SyncError.exit_code)
+                for line in remotes_table(results):
+                    logger.warning("%s", line)
+                sys.exit(max(codes))
+        except RemoteError as e:
+            logger.error("Remote error: %s", e)
+            sys.exit(e.exit_code)
+        except SyncError as e:
+            logger.error("%s", e)
+            sys.exit(e.exit_code)
     else:
         logger.disabled = True
-        sync_remote(args)
+        try:
+            sync_remote(args)
+        except Exception as e:
+            # tell the local side what went wrong instead of just going away,
+            # unless it told us
+            if not isinstance(e, RemoteError):
+                with contextlib.suppress(OSError):
+                    write_error(e, sys.stdout.buffer)
+            sys.exit(1)
 
 
 if __name__ == "__main__":
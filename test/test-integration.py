@@ -15,6 +15,11 @@ def write_conf(path):
     return conf_path
 
 
+def sync_state(f):
+    # revision and UUID, without the version vector
+    return " ".join(f.read().split(' ')[:2])
+
+
 def sync(shell, local_conf, remote_conf, verbose=False, delete=False, mbsync=False):
     args = ["./src/notmuch_sync.py", "--remote-cmd", f"bash -c 'NOTMUCH_CONFIG={remote_conf} ./src/notmuch_sync.py {"--delete" if delete else ""} {"--mbsync" if mbsync else ""}'"]
     if verbose:
@@ -53,20 +58,20 @@ def test_sync(shell):
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             assert os.path.exists(local_sync_file)
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"5 {lsum[1]}"
+                assert sync_state(f) == f"5 {lsum[1]}"
 
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             assert os.path.exists(remote_sync_file)
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"5 {rsum[1]}"
+                assert sync_state(f) == f"5 {rsum[1]}"
 
             out = sync(shell, local_conf, remote_conf).split('\n')
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[0]
             assert "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"5 {lsum[1]}"
+                assert sync_state(f) == f"5 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"5 {rsum[1]}"
+                assert sync_state(f) == f"5 {rsum[1]}"
 
             lsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": local_conf}).stdout.split('\t')
             assert lsum[2] == "5\n"
@@ -97,11 +102,11 @@ def test_sync_tags(shell):
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             assert os.path.exists(local_sync_file)
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"5 {lsum[1]}"
+                assert sync_state(f) == f"5 {lsum[1]}"
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             assert os.path.exists(remote_sync_file)
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"5 {rsum[1]}"
+                assert sync_state(f) == f"5 {rsum[1]}"
 
             assert shell.run("notmuch", "tag", "+local", "id:874llc2bkp.fsf@curie.anarc.at",
                              env={"NOTMUCH_CONFIG": local_conf}).returncode == 0
@@ -144,14 +149,14 @@ def test_sync_tags(shell):
                              env={"NOTMUCH_CONFIG": remote_conf}).data == ["remote", "unread"]
 
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"11 {lsum[1]}"
+                assert sync_state(f) == f"9 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"11 {rsum[1]}"
+                assert sync_state(f) == f"9 {rsum[1]}"
 
             lsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": local_conf}).stdout.split('\t')
-            assert lsum[2] == "11\n"
+            assert lsum[2] == "9\n"
             rsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": remote_conf}).stdout.split('\t')
-            assert rsum[2] == "11\n"
+            assert rsum[2] == "9\n"
 
             out = sync(shell, local_conf, remote_conf).split('\n')
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[0]
@@ -186,34 +191,44 @@ def test_sync_tags_files_verbose(shell):
 
             out = sync(shell, local_conf, remote_conf, verbose=True).split('\n')
             assert 'Connecting to remote...' in out[0]
-            assert 'Sending UUID' in out[1]
-            assert 'Receiving UUID...' in out[2]
-            assert 'UUIDs synced.' in out[3]
-            assert 'Computing local changes...' in out[4]
-            assert 'Previous sync revision -1, current revision 7.' in out[5]
+            assert 'Sending protocol version 5 and UUID' in out[1]
+            assert 'Receiving protocol version and UUID...' in out[2]
+            assert 'UUIDs synced, using protocol version 5.' in out[3]
+            assert 'Sending sync information...' in out[4]
+            assert 'Receiving sync information...' in out[5]
+            assert 'Computing local changes...' in out[6]
+            assert 'Previous sync revision -1, current revision 7.' in out[7]
             assert any('Sending local changes...' in o for o in out)
             assert any('Receiving remote changes...' in o for o in out)
-            assert 'Changes synced.' in out[8]
+            assert 'Changes synced.' in out[10]
+            assert 'Receiving merge decisions from remote...' in out[11]
             assert any("Setting tags ['local', 'remote'] for 87d1dajhgf.fsf@example.net." in o for o in out)
             assert any("Setting tags ['attachment', 'local', 'remote'] for 20111101080303.30A10409E@asxas.net." in o for o in out)
-            assert 'Tags synced.' in out[11]
-            assert any('Sending file names missing on local...' in o for o in out)
-            assert any('Receiving file names missing on remote...' in o for o in out)
+            assert 'Tags synced.' in out[14]
             assert any('Requesting 0 hashes from remote...' in o for o in out)
             assert any('Receiving hash requests from remote...' in o for o in out)
             assert any('Hashing 0 requested files and sending to remote...' in o for o in out)
+            assert any('Hashing 0 local files...' in o for o in out)
             assert any('Receiving hashes from remote...' in o for o in out)
-            assert 'Missing file names synced.' in out[18]
+            assert any('Sending file names missing on local...' in o for o in out)
+            assert any('Receiving file names missing on remote...' in o for o in out)
+            assert 'Missing file names synced.' in out[22]
             assert any('1/1 Sending mails/simple.eml...' in o for o in out)
             assert any('1/1 Receiving mails/attachment.eml...' in o for o in out)
             assert any(f'Adding {local}/mails/attachment.eml to DB.' in o for o in out)
             assert any("Setting tags ['attachment', 'remote'] for received 874llc2bkp.fsf@curie.anarc.at." in o for o in out)
-            assert 'Missing files synced.' in out[23]
-            assert 'Writing last sync revision 11.' in out[24]
-            assert 'Getting change numbers from remote...' in out[25]
-            assert 'local:  1 new messages,\t1 new files,\t0 files copied/moved,\t0 files deleted,\t2 messages with tag changes,\t0 messages deleted' in out[26]
-            assert 'remote: 1 new messages,\t1 new files,\t0 files copied/moved,\t0 files deleted,\t2 messages with tag changes,\t0 messages deleted' in out[27]
-            assert '9098/4288 bytes received from/sent to remote.' in out[28]
+            assert 'Missing files synced.' in out[27]
+            assert any('Sending 0 files renamed to match their tags...' in o for o in out)
+            assert any('Receiving files renamed to match their tags...' in o for o in out)
+            assert 'Renamed files synced.' in out[30]
+            assert 'Writing last sync revision 9.' in out[31]
+            assert 'Recording tags of all messages.' in out[32]
+            assert 'Getting change numbers from remote...' in out[33]
+            assert 'local:  1 new messages,\t1 new files,\t0 files copied/moved,\t0 files deleted,\t2 messages with tag changes,\t0 messages deleted' in out[34]
+            assert 'remote: 1 new messages,\t1 new files,\t0 files copied/moved,\t0 files deleted,\t2 messages with tag changes,\t0 messages deleted' in out[35]
+            assert 'local:  mails:\t1 new messages,\t1 new files,\t0 messages deleted' in out[36]
+            assert 'remote: mails:\t1 new messages,\t1 new files,\t0 messages deleted' in out[37]
+            assert '9695/4537 bytes received from/sent to remote.' in out[38]
 
 
 def test_sync_tags_files(shell):
@@ -282,27 +297,27 @@ def test_sync_tags_files(shell):
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             assert os.path.exists(local_sync_file)
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {lsum[1]}"
+                assert sync_state(f) == f"6 {lsum[1]}"
 
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             assert os.path.exists(remote_sync_file)
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {rsum[1]}"
+                assert sync_state(f) == f"5 {rsum[1]}"
 
             # we record the last sync before transferring files and
             # adding/tagging them, so the revision after finished sync is higher
             lsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": local_conf}).stdout.split('\t')
-            assert lsum[2] == "9\n"
+            assert lsum[2] == "6\n"
             rsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": remote_conf}).stdout.split('\t')
-            assert rsum[2] == "9\n"
+            assert rsum[2] == "5\n"
 
             out = sync(shell, local_conf, remote_conf).split('\n')
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[0]
             assert "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {lsum[1]}"
+                assert sync_state(f) == f"6 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {rsum[1]}"
+                assert sync_state(f) == f"5 {rsum[1]}"
 
 
 def test_sync_tags_files_copied(shell):
@@ -367,27 +382,27 @@ def test_sync_tags_files_copied(shell):
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             assert os.path.exists(local_sync_file)
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"10 {lsum[1]}"
+                assert sync_state(f) == f"7 {lsum[1]}"
 
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             assert os.path.exists(remote_sync_file)
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"10 {rsum[1]}"
+                assert sync_state(f) == f"9 {rsum[1]}"
 
             # we record the last sync before transferring files and
             # adding/tagging them, so the revision after finished sync is higher
             lsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": local_conf}).stdout.split('\t')
-            assert lsum[2] == "10\n"
+            assert lsum[2] == "7\n"
             rsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": remote_conf}).stdout.split('\t')
-            assert rsum[2] == "10\n"
+            assert rsum[2] == "9\n"
 
             out = sync(shell, local_conf, remote_conf).split('\n')
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[0]
             assert "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"10 {lsum[1]}"
+                assert sync_state(f) == f"7 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"10 {rsum[1]}"
+                assert sync_state(f) == f"9 {rsum[1]}"
 
 
 def test_sync_tags_files_moved(shell):
@@ -452,51 +467,51 @@ def test_sync_tags_files_moved(shell):
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             assert os.path.exists(local_sync_file)
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {lsum[1]}"
+                assert sync_state(f) == f"6 {lsum[1]}"
 
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             assert os.path.exists(remote_sync_file)
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {rsum[1]}"
+                assert sync_state(f) == f"8 {rsum[1]}"
 
             # we record the last sync before transferring files and
             # adding/tagging them, so the revision after finished sync is higher
             lsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": local_conf}).stdout.split('\t')
-            assert lsum[2] == "9\n"
+            assert lsum[2] == "6\n"
             rsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": remote_conf}).stdout.split('\t')
-            assert rsum[2] == "9\n"
+            assert rsum[2] == "8\n"
 
             shutil.move(os.path.join(local, "mails", "html-only.eml"), os.path.join(local, "mails", "html-only1.eml"))
             assert shell.run("notmuch", "new", env={"NOTMUCH_CONFIG": local_conf}).returncode == 0
             lsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": local_conf}).stdout.split('\t')
-            assert lsum[2] == "11\n"
+            assert lsum[2] == "8\n"
             assert not Path(os.path.join(remote, "mails", "html-only1.eml")).exists()
 
             out = sync(shell, local_conf, remote_conf).split('\n')
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[0]
             assert "remote: 0 new messages,\t0 new files,\t1 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"11 {lsum[1]}"
+                assert sync_state(f) == f"8 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"11 {rsum[1]}"
+                assert sync_state(f) == f"10 {rsum[1]}"
 
             assert not Path(os.path.join(remote, "mails", "html-only.eml")).exists()
             assert Path(os.path.join(remote, "mails", "html-only1.eml")).exists()
 
             lsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": local_conf}).stdout.split('\t')
             assert lsum[0] == "4"
-            assert lsum[2] == "11\n"
+            assert lsum[2] == "8\n"
             rsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": remote_conf}).stdout.split('\t')
             assert rsum[0] == "4"
-            assert rsum[2] == "11\n"
+            assert rsum[2] == "10\n"
 
             out = sync(shell, local_conf, remote_conf).split('\n')
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[0]
             assert "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"11 {lsum[1]}"
+                assert sync_state(f) == f"8 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"11 {rsum[1]}"
+                assert sync_state(f) == f"10 {rsum[1]}"
 
 
 def test_sync_tags_files_moved_twice(shell):
@@ -564,27 +579,27 @@ def test_sync_tags_files_moved_twice(shell):
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             assert os.path.exists(local_sync_file)
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"11 {lsum[1]}"
+                assert sync_state(f) == f"8 {lsum[1]}"
 
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             assert os.path.exists(remote_sync_file)
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {rsum[1]}"
+                assert sync_state(f) == f"8 {rsum[1]}"
 
             # we record the last sync before transferring files and
             # adding/tagging them, so the revision after finished sync is higher
             lsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": local_conf}).stdout.split('\t')
-            assert lsum[2] == "11\n"
+            assert lsum[2] == "8\n"
             rsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": remote_conf}).stdout.split('\t')
-            assert rsum[2] == "9\n"
+            assert rsum[2] == "8\n"
 
             out = sync(shell, local_conf, remote_conf).split('\n')
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[0]
             assert "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"11 {lsum[1]}"
+                assert sync_state(f) == f"8 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {rsum[1]}"
+                assert sync_state(f) == f"8 {rsum[1]}"
 
 
 def test_sync_tags_files_none_remote(shell):
@@ -639,27 +654,27 @@ def test_sync_tags_files_none_remote(shell):
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             assert os.path.exists(local_sync_file)
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {lsum[1]}"
+                assert sync_state(f) == f"9 {lsum[1]}"
 
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             assert os.path.exists(remote_sync_file)
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {rsum[1]}"
+                assert sync_state(f) == f"1 {rsum[1]}"
 
             # we record the last sync before transferring files and
             # adding/tagging them, so the revision after finished sync is higher
             lsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": local_conf}).stdout.split('\t')
             assert lsum[2] == "9\n"
             rsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": remote_conf}).stdout.split('\t')
-            assert rsum[2] == "9\n"
+            assert rsum[2] == "1\n"
 
             out = sync(shell, local_conf, remote_conf).split('\n')
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[0]
             assert "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {lsum[1]}"
+                assert sync_state(f) == f"9 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {rsum[1]}"
+                assert sync_state(f) == f"1 {rsum[1]}"
 
 
 def test_sync_files_deleted(shell):
@@ -686,12 +701,12 @@ def test_sync_files_deleted(shell):
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             assert os.path.exists(local_sync_file)
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"5 {lsum[1]}"
+                assert sync_state(f) == f"5 {lsum[1]}"
 
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             assert os.path.exists(remote_sync_file)
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"5 {rsum[1]}"
+                assert sync_state(f) == f"5 {rsum[1]}"
 
             Path.unlink(os.path.join(remote, "mails", "html-only1.eml"))
             assert shell.run("notmuch", "new", env={"NOTMUCH_CONFIG": remote_conf}).returncode == 0
@@ -709,9 +724,9 @@ def test_sync_files_deleted(shell):
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t1 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[0]
             assert "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {lsum[1]}"
+                assert sync_state(f) == f"6 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {rsum[1]}"
+                assert sync_state(f) == f"6 {rsum[1]}"
 
             assert shell.run("notmuch", "search", "--output=files", "--format=json", "id:87d1dajhgf.fsf@example.net",
                              env={"NOTMUCH_CONFIG": local_conf}).data == [os.path.join(local, "mails", "html-only.eml")]
@@ -752,12 +767,12 @@ def test_sync_message_deleted_local(shell):
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             assert os.path.exists(local_sync_file)
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {lsum[1]}"
+                assert sync_state(f) == f"6 {lsum[1]}"
 
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             assert os.path.exists(remote_sync_file)
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {rsum[1]}"
+                assert sync_state(f) == f"6 {rsum[1]}"
 
             Path.unlink(os.path.join(local, "mails", "simple.eml"))
             assert shell.run("notmuch", "new", env={"NOTMUCH_CONFIG": local_conf}).returncode == 0
@@ -775,9 +790,9 @@ def test_sync_message_deleted_local(shell):
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[0]
             assert "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t1 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {lsum[1]}"
+                assert sync_state(f) == f"6 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {rsum[1]}"
+                assert sync_state(f) == f"6 {rsum[1]}"
 
             assert shell.run("notmuch", "search", "--format=json", "id:1258848661-4660-2-git-send-email-stefan@datenfreihafen.org",
                              env={"NOTMUCH_CONFIG": local_conf}).data == []
@@ -836,10 +851,10 @@ def test_sync_message_deleted_local_failsafe(shell):
             assert "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {lsum[1]}"
+                assert sync_state(f) == f"6 {lsum[1]}"
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {rsum[1]}"
+                assert sync_state(f) == f"6 {rsum[1]}"
 
             assert shell.run("notmuch", "search", "--output=files", "--format=json", "id:1258848661-4660-2-git-send-email-stefan@datenfreihafen.org",
                              env={"NOTMUCH_CONFIG": local_conf}).data == [os.path.join(local, "mails", "simple.eml")]
@@ -882,12 +897,12 @@ def test_sync_message_deleted_remote(shell):
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             assert os.path.exists(local_sync_file)
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {lsum[1]}"
+                assert sync_state(f) == f"6 {lsum[1]}"
 
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             assert os.path.exists(remote_sync_file)
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {rsum[1]}"
+                assert sync_state(f) == f"6 {rsum[1]}"
 
             Path.unlink(os.path.join(remote, "mails", "simple.eml"))
             assert shell.run("notmuch", "new", env={"NOTMUCH_CONFIG": remote_conf}).returncode == 0
@@ -905,9 +920,9 @@ def test_sync_message_deleted_remote(shell):
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t1 messages deleted" in out[0]
             assert "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {lsum[1]}"
+                assert sync_state(f) == f"6 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {rsum[1]}"
+                assert sync_state(f) == f"6 {rsum[1]}"
 
             assert shell.run("notmuch", "search", "--format=json", "id:1258848661-4660-2-git-send-email-stefan@datenfreihafen.org",
                              env={"NOTMUCH_CONFIG": local_conf}).data == []
@@ -966,10 +981,10 @@ def test_sync_message_deleted_remote_failsafe(shell):
             assert "remote: 1 new messages,\t1 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {lsum[1]}"
+                assert sync_state(f) == f"6 {lsum[1]}"
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"6 {rsum[1]}"
+                assert sync_state(f) == f"6 {rsum[1]}"
 
             assert shell.run("notmuch", "search", "--output=files", "--format=json", "id:1258848661-4660-2-git-send-email-stefan@datenfreihafen.org",
                              env={"NOTMUCH_CONFIG": local_conf}).data == [os.path.join(local, "mails", "simple.eml")]
@@ -1013,12 +1028,12 @@ def test_sync_message_deleted_multiple_and_back(shell):
             local_sync_file = os.path.join(local, ".notmuch", f"notmuch-sync-{rsum[1]}")
             assert os.path.exists(local_sync_file)
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"7 {lsum[1]}"
+                assert sync_state(f) == f"7 {lsum[1]}"
 
             remote_sync_file = os.path.join(remote, ".notmuch", f"notmuch-sync-{lsum[1]}")
             assert os.path.exists(remote_sync_file)
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"7 {rsum[1]}"
+                assert sync_state(f) == f"7 {rsum[1]}"
 
             Path.unlink(os.path.join(local, "mails", "attachment.eml"))
             Path.unlink(os.path.join(remote, "mails", "simple.eml"))
@@ -1039,9 +1054,9 @@ def test_sync_message_deleted_multiple_and_back(shell):
             assert "local:  0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t1 messages deleted" in out[0]
             assert "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t1 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"7 {lsum[1]}"
+                assert sync_state(f) == f"7 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"7 {rsum[1]}"
+                assert sync_state(f) == f"7 {rsum[1]}"
 
             assert shell.run("notmuch", "search", "--output=files", "--format=json", "id:874llc2bkp.fsf@curie.anarc.at",
                              env={"NOTMUCH_CONFIG": remote_conf}).data == []
@@ -1080,9 +1095,9 @@ def test_sync_message_deleted_multiple_and_back(shell):
             assert "local:  1 new messages,\t1 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[0]
             assert "remote: 1 new messages,\t1 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted" in out[1]
             with open(local_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"10 {lsum[1]}"
+                assert sync_state(f) == f"9 {lsum[1]}"
             with open(remote_sync_file, "r", encoding="utf-8") as f:
-                assert f.read() == f"9 {rsum[1]}"
+                assert sync_state(f) == f"9 {rsum[1]}"
 
             assert shell.run("notmuch", "search", "--output=files", "--format=json", "id:874llc2bkp.fsf@curie.anarc.at",
                              env={"NOTMUCH_CONFIG": local_conf}).data == [os.path.join(local, "mails", "attachment.eml")]
@@ -1099,7 +1114,7 @@ def test_sync_message_deleted_multiple_and_back(shell):
 
             lsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": local_conf}).stdout.split('\t')
             assert lsum[0] == "4"
-            assert lsum[2] == "10\n"
+            assert lsum[2] == "9\n"
             rsum = shell.run("notmuch", "count", "--lastmod", env={"NOTMUCH_CONFIG": remote_conf}).stdout.split('\t')
             assert rsum[0] == "4"
             assert rsum[2] == "9\n"
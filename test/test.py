@@ -1,18 +1,63 @@
 import pytest
+import argparse
+import contextvars
 import os
+import shutil
+import socket
+import ssl
+import subprocess
 import sys
+import threading
 import io
 import json
+import logging
 import stat
 import struct
-from unittest.mock import MagicMock, PropertyMock, call, mock_open, patch
+import time
+import zlib
+from unittest.mock import ANY, MagicMock, PropertyMock, call, mock_open, patch
 from tempfile import NamedTemporaryFile, TemporaryDirectory, gettempdir
+from pathlib import Path
 
 import notmuch2
 
 import src.notmuch_sync as ns
 
 prefix = gettempdir() + os.sep
+ACK = b"\x00\x00\x00\x03ack"
+
+
+@pytest.fixture(autouse=True)
+def reset_session():
+    """
+    Restore the session and stats after each test, so that state set by one
+    test (e.g. a mock trash directory) doesn't leak into the next.
+    """
+    saved = dict(ns.session)
+    token = ns.current_stats.set(ns.SyncStats())
+    yield
+    ns.session.clear()
+    ns.session.update(saved)
+    ns.current_stats.reset(token)
+
+
+def frame(data):
+    tmp = json.dumps(data).encode("utf-8")
+    return struct.pack("!I", len(tmp)) + tmp
+
+
+def handshake(uuid):
+    # followed by the empty frames that measure the round-trip time
+    return ns.HANDSHAKE + struct.pack("!I", ns.PROTOCOL_VERSION) + uuid.encode("utf-8") + struct.pack("!I", 0) * 2
+
+
+def info_frame(vector={}, receive_only=False, session="", verifies=False):
+    return frame({"vector": vector, "receive_only": receive_only, "session": session} | ({"verifies": True} if verifies else {}))
+
+
+def changes_frames(changes):
+    return (frame(changes) if changes else b"") + b"\x00\x00\x00\x00"
+
 
 def test_changes():
     mm = lambda: None
@@ -83,7 +128,7 @@ def test_changes_changed_uuid():
         f.flush()
         with pytest.raises(ValueError) as pwe:
             ns.get_changes(db, rev, prefix, f.name)
-        assert pwe.type == ValueError
+        assert pwe.type == ns.StateError
         assert str(pwe.value) == "Last sync with UUID abc, but notmuch DB has UUID 00000000-0000-0000-0000-000000000000, aborting..."
 
 
@@ -98,7 +143,7 @@ def test_changes_later_rev():
         f.flush()
         with pytest.raises(ValueError) as pwe:
             ns.get_changes(db, rev, prefix, f.name)
-        assert pwe.type == ValueError
+        assert pwe.type == ns.StateError
         assert str(pwe.value) == "Last sync revision 123 larger than current DB revision 122, aborting..."
 
 
@@ -113,33 +158,529 @@ def test_changes_corrupted_file():
         f.flush()
         with pytest.raises(ValueError) as pwe:
             ns.get_changes(db, rev, prefix, f.name)
-        assert pwe.type == ValueError
+        assert pwe.type == ns.StateError
         assert str(pwe.value) == f"Sync state file '{f.name}' corrupted, delete to sync from scratch."
 
 
+def test_changes_orset():
+    mm = MagicMock()
+    mm.messageid = "foo"
+    mm.tags = ["foo", "bar"]
+    mm.filenames = MagicMock(return_value=[])
+    mm.properties.getall = MagicMock(side_effect=[iter([("notmuch-sync-add", "a:1 foo"),
+                                                        ("notmuch-sync-add", "a:1 baz")]),
+                                                  iter([("notmuch-sync-rm", "a:0 bar")])])
+
+    db = lambda: None
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.messages = MagicMock(return_value=[mm])
+
+    f = NamedTemporaryFile(mode="r", prefix="notmuch-sync-test-tmp-")
+    f.close()
+    changes = ns.get_changes(db, rev, prefix, f.name, orset=True)
+    orset = {"add": ["00000000-0000-0000-0000-000000000000:123 bar", "a:1 foo"],
+             "rm": ["a:0 bar", "a:1 baz"]}
+    assert changes == {"foo": {"tags": ["foo", "bar"], "files": [], "orset": orset}}
+    assert mm.properties.add.mock_calls == [
+        call("notmuch-sync-add", "00000000-0000-0000-0000-000000000000:123 bar"),
+        call("notmuch-sync-add", "a:1 foo"),
+        call("notmuch-sync-rm", "a:0 bar"),
+        call("notmuch-sync-rm", "a:1 baz")
+    ]
+
+
+def test_changes_tag_times():
+    mm = MagicMock()
+    mm.messageid = "foo"
+    mm.tags = ["foo", "bar"]
+    mm.filenames = MagicMock(return_value=[])
+    mm.properties.getall = MagicMock(return_value=iter([("notmuch-sync-time", "100 +foo"),
+                                                        ("notmuch-sync-time", "100 -bar"),
+                                                        ("notmuch-sync-time", "100 +baz")]))
+
+    db = lambda: None
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.messages = MagicMock(return_value=[mm])
+
+    f = NamedTemporaryFile(mode="r", prefix="notmuch-sync-test-tmp-")
+    f.close()
+    with patch("time.time", return_value=200.5):
+        changes = ns.get_changes(db, rev, prefix, f.name, tag_times=True)
+    times = {"foo": [100, True], "bar": [200, True], "baz": [200, False]}
+    assert changes == {"foo": {"tags": ["foo", "bar"], "files": [], "times": times}}
+    assert mm.properties.add.mock_calls == [
+        call("notmuch-sync-time", "200 +bar"),
+        call("notmuch-sync-time", "200 -baz"),
+        call("notmuch-sync-time", "100 +foo")
+    ]
+
+
+def test_write_read_changes():
+    changes = {"foo": {"tags": ["inbox"], "files": ["a"]}, "bar": {"tags": ["inbox"], "files": ["b"]},
+               "baz": {"tags": [], "files": ["c"]}}
+    stream = io.BytesIO()
+    with patch.object(ns, "CHANGES_CHUNK", 2):
+        ns.write_changes(changes, stream)
+    assert stream.getvalue() == (frame({"foo": changes["foo"], "bar": changes["bar"]}) +
+                                 frame({"baz": changes["baz"]}) + b"\x00\x00\x00\x00")
+    stream.seek(0)
+    assert ns.read_changes(stream) == changes
+
+    stream = io.BytesIO()
+    ns.write_changes({}, stream)
+    assert stream.getvalue() == b"\x00\x00\x00\x00"
+    stream.seek(0)
+    assert ns.read_changes(stream) == {}
+
+
+def test_write_read_changes_msgpack(monkeypatch):
+    fake = MagicMock()
+    fake.packb = lambda obj: json.dumps(obj).encode("utf-8")
+    fake.unpackb = lambda data, object_hook: json.loads(data, object_hook=object_hook)
+    monkeypatch.setitem(ns.session, "encoding", "msgpack")
+    changes = {"foo": {"tags": ["inbox"], "files": ["a"]}, "bar": {"tags": ["inbox"], "files": ["b"]}}
+    stream = io.BytesIO()
+    with patch.object(ns, "msgpack", fake):
+        ns.write_changes(changes, stream)
+        stream.seek(0)
+        read = ns.read_changes(stream)
+    assert read == changes
+    assert read["foo"]["tags"][0] is read["bar"]["tags"][0]
+
+
+def test_intern_tags():
+    data = json.dumps({"foo": {"tags": ["inbox", "unread"], "files": ["a"]},
+                       "bar": {"tags": ["inbox"], "files": ["b"]}})
+    changes = json.loads(data, object_hook=ns.intern_tags)
+    assert changes == json.loads(data)
+    assert changes["foo"]["tags"][0] is changes["bar"]["tags"][0]
+    assert ns.intern_tags({"tags": {"foo": ["bar"]}}) == {"tags": {"foo": ["bar"]}}
+
+
+def test_get_set_properties():
+    m = MagicMock()
+    m.properties.getall.return_value = [("session-key", "1:abc"), ("index.decryption", "success"),
+                                        (ns.TAG_TIME, "100 +inbox"), ("foo", "b"), ("foo", "a")]
+    assert ns.get_properties(m) == {"session-key": ["1:abc"], "foo": ["a", "b"]}
+    ns.set_properties(m, {"foo": ["b", "a"], "bar": ["c"]})
+    assert m.properties.__delitem__.mock_calls == [call("bar"), call("session-key")]
+    assert m.properties.add.mock_calls == [call("bar", "c")]
+
+
+def test_merge_properties():
+    mine = {"foo": {"tags": [], "properties": {"a": ["1"], "b": ["1"], "c": ["1"]}}, "bar": {"tags": []}}
+    theirs = {"foo": {"tags": [], "properties": {"a": ["2"], "b": ["1"], "d": ["2"]}},
+              "bar": {"tags": [], "properties": {}}, "baz": {"tags": [], "properties": {"a": ["1"]}}}
+    assert ns.merge_properties(mine, theirs) == {"foo": {"a": ["1", "2"], "b": ["1"], "c": ["1"], "d": ["2"]}}
+    assert ns.merge_properties(mine, theirs, "theirs") == {"foo": {"a": ["2"], "b": ["1"], "d": ["2"]}}
+    policies = ns.parse_property_policies(["a:prefer-local", "c*:union"], True)
+    assert policies == [("a", "mine"), ("c*", "union")]
+    assert ns.merge_properties(mine, theirs, "theirs", policies) == {"foo": {"a": ["1"], "b": ["1"], "c": ["1"],
+                                                                             "d": ["2"]}}
+    assert ns.parse_property_policies(["a:prefer-local"], False) == [("a", "theirs")]
+    with pytest.raises(ns.SyncError) as pwe:
+        ns.parse_property_policies(["a:newest"], True)
+    assert str(pwe.value) == "Invalid property merge policy 'a:newest', must be <key>:<union|prefer-local|prefer-remote>."
+
+
+def test_merge_times():
+    mine = {"foo": [100, True], "bar": [200, False], "baz": [100, False]}
+    theirs = {"foo": [150, False], "bar": [100, True], "baz": [100, True], "foobar": [50, True]}
+    merged = ns.merge_times(mine, theirs)
+    assert merged == {"foo": [150, False], "bar": [200, False], "baz": [100, True], "foobar": [50, True]}
+    assert merged == ns.merge_times(theirs, mine)
+    assert ns.times_tags(merged) == {"baz", "foobar"}
+    assert ns.merge_tags({"m": {"tags": [], "times": mine}}, {"m": {"tags": [], "times": theirs}}) == \
+        {"m": ["baz", "foobar"]}
+    # newest for some tags, union for the rest
+    policies = ns.parse_tag_policies(["f*:newest"], True, "union", True)
+    assert ns.merge_tags({"m": {"tags": ["foo"], "times": mine}},
+                         {"m": {"tags": ["bar", "baz", "foobar"], "times": theirs}}, policies=policies) == \
+        {"m": ["bar", "baz", "foobar"]}
+
+
+def test_merge_orsets():
+    mine = {"add": ["a:1 foo", "a:2 bar"], "rm": ["a:0 baz"]}
+    theirs = {"add": ["a:1 foo", "b:1 baz"], "rm": ["a:2 bar"]}
+    merged = ns.merge_orsets(mine, theirs)
+    assert merged == {"add": ["a:1 foo", "b:1 baz"], "rm": ["a:0 baz", "a:2 bar"]}
+    assert merged == ns.merge_orsets(theirs, mine)
+    assert merged == ns.merge_orsets(merged, mine)
+    assert ns.orset_tags(merged) == {"foo", "baz"}
+
+
 def test_initial_sync():
     db = lambda: None
+    db.atomic = MagicMock()
     rev = lambda: None
     rev.rev = 123
     rev.uuid = b'00000000-0000-0000-0000-000000000000'
     db.revision = MagicMock(return_value=rev)
 
     fname = os.path.join(gettempdir(), ".notmuch", "notmuch-sync-00000000-0000-0000-0000-000000000001")
-    with patch.object(ns, "get_changes", return_value=[]) as gc:
-        istream = io.BytesIO(b"00000000-0000-0000-0000-000000000001\x00\x00\x00\x02[]")
+    with patch.object(ns, "get_changes", return_value={}) as gc, \
+         patch.object(ns, "get_vector", return_value={"00000000-0000-0000-0000-000000000000": 123}) as gv:
+        dec = json.dumps({"stamp": "00000000-0000-0000-0000-000000000001:42", "tags": {}}).encode("utf-8")
+        vec = json.dumps({"vector": {"00000000-0000-0000-0000-000000000001": 42}, "receive_only": False,
+                          "session": ""}).encode("utf-8")
+        istream = io.BytesIO(handshake("00000000-0000-0000-0000-000000000001") + struct.pack("!I", len(vec)) + vec +
+                             changes_frames({}) + struct.pack("!I", len(dec)) + dec)
         ostream = io.BytesIO()
-        mine, theirs, nchanges, syncname = ns.initial_sync(db, prefix, istream, ostream)
-        assert mine == []
-        assert theirs == []
+        mine, theirs, nchanges, syncname, info = ns.initial_sync(db, prefix, istream, ostream)
+        assert mine == {}
+        assert theirs == {}
         assert nchanges == 0
         assert syncname == fname
-        assert b"00000000-0000-0000-0000-000000000000\x00\x00\x00\x02[]" == ostream.getvalue()
+        assert info == {"vector": {"00000000-0000-0000-0000-000000000000": 123,
+                                   "00000000-0000-0000-0000-000000000001": 42},
+                        "receive_only": False}
+        vec = json.dumps({"vector": {"00000000-0000-0000-0000-000000000000": 123},
+                          "receive_only": False, "session": ""}).encode("utf-8")
+        assert (handshake("00000000-0000-0000-0000-000000000000") + struct.pack("!I", len(vec)) + vec +
+                changes_frames({})) == ostream.getvalue()
 
-        gc.assert_called_once_with(db, rev, prefix, fname)
+        gc.assert_called_once_with(db, rev, prefix, fname, False, False, False)
+        gv.assert_called_once_with(prefix, rev, None)
 
     assert db.revision.call_count == 1
 
 
+def test_initial_sync_arbiter():
+    db = lambda: None
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.revision = MagicMock(return_value=rev)
+
+    mine = {"foo": {"tags": ["tag1"], "files": ["foofile"]}}
+    theirs = {"foo": {"tags": ["tag2"], "files": ["foofile"]},
+              "bar": {"tags": ["tag3"], "files": ["barfile"]}}
+    with patch.object(ns, "get_changes", return_value=mine), patch.object(ns, "get_vector", return_value={}):
+        with patch.object(ns, "sync_tags", return_value=1) as st:
+            tmp = json.dumps(theirs).encode("utf-8")
+            istream = io.BytesIO(handshake("00000000-0000-0000-0000-000000000001") + info_frame() +
+                                 changes_frames(theirs))
+            ostream = io.BytesIO()
+            _, _, nchanges, _, _ = ns.initial_sync(db, prefix, istream, ostream, arbiter=True)
+            assert nchanges == 1
+            st.assert_called_once_with(db, mine, theirs, {"foo": ["tag1", "tag2"]}, None, "text", {})
+
+    dec = json.dumps({"stamp": "00000000-0000-0000-0000-000000000000:123",
+                      "tags": {"foo": ["tag1", "tag2"]}}).encode("utf-8")
+    assert (handshake("00000000-0000-0000-0000-000000000000") + info_frame(verifies=True) + changes_frames(mine) +
+            struct.pack("!I", len(dec)) + dec) == ostream.getvalue()
+
+
+def test_initial_sync_confirm():
+    db = lambda: None
+    db.atomic = MagicMock()
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.revision = MagicMock(return_value=rev)
+    db.count_messages = MagicMock(return_value=10)
+
+    theirs = {"foo": {"tags": ["tag2"], "files": ["foofile"]}}
+    preview = {"tags": {"foo": [["tag1"], ["tag2"]]}, "folders": {}}
+    summary = {"tags": 1, "removed": 0, "common": [["+tag2", 1], ["-tag1", 1]]}
+    dec = frame({"stamp": "00000000-0000-0000-0000-000000000001:42", "tags": {}})
+    with patch.object(ns, "get_changes", return_value={}), patch.object(ns, "get_vector", return_value={}), \
+         patch.object(ns, "preview_changes", return_value=preview), patch.object(ns, "sync_tags", return_value=1) as st:
+        with patch.object(ns, "ask_confirm", return_value=False) as ac:
+            istream = io.BytesIO(handshake("00000000-0000-0000-0000-000000000001") + info_frame() +
+                                 changes_frames(theirs) + dec + frame({"tags": 0, "removed": 0, "common": []}))
+            ostream = io.BytesIO()
+            with pytest.raises(ns.SyncError, match="Changes not confirmed"):
+                ns.initial_sync(db, prefix, istream, ostream, confirm=True, yes=True)
+            ac.assert_called_once_with(["local:  1 messages with tag changes, 0 files to remove",
+                                        "local:    +tag2 on 1 messages", "local:    -tag1 on 1 messages",
+                                        "remote: 0 messages with tag changes, 0 files to remove"], True)
+            assert ostream.getvalue().endswith(struct.pack("!I", 2) + ns.CONFIRM_NO)
+            st.assert_not_called()
+
+        istream = io.BytesIO(handshake("00000000-0000-0000-0000-000000000001") + info_frame() +
+                             changes_frames(theirs) + struct.pack("!I", 3) + ns.CONFIRM_YES)
+        ostream = io.BytesIO()
+        _, _, nchanges, _, _ = ns.initial_sync(db, prefix, istream, ostream, arbiter=True, confirm=True)
+        assert nchanges == 1
+        assert ostream.getvalue().endswith(frame(summary))
+
+        istream = io.BytesIO(handshake("00000000-0000-0000-0000-000000000001") + info_frame() +
+                             changes_frames(theirs) + struct.pack("!I", 2) + ns.CONFIRM_NO)
+        with pytest.raises(ns.SyncError, match="Changes not confirmed on local"):
+            ns.initial_sync(db, prefix, istream, io.BytesIO(), arbiter=True, confirm=True)
+        assert st.call_count == 1
+
+
+def test_ask_confirm(monkeypatch):
+    stdin = io.StringIO("y\nn\n")
+    monkeypatch.setattr(sys, "stdin", stdin)
+    assert ns.ask_confirm(["foo"], yes=True)
+    with pytest.raises(ns.SyncError, match="No terminal to ask for confirmation on"):
+        ns.ask_confirm(["foo"])
+    stdin.isatty = lambda: True
+    assert ns.ask_confirm(["foo"])
+    assert not ns.ask_confirm(["foo"])
+
+
+def test_initial_sync_session():
+    db = lambda: None
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.revision = MagicMock(return_value=rev)
+
+    with patch.object(ns, "get_changes", return_value={}), patch.object(ns, "get_vector", return_value={}), \
+         patch.object(ns, "sync_tags", return_value=0), patch.dict(ns.session, {"id": ""}):
+        istream = io.BytesIO(handshake("00000000-0000-0000-0000-000000000001") + info_frame(session="foo") + changes_frames({}))
+        ostream = io.BytesIO()
+        ns.initial_sync(db, prefix, istream, ostream, arbiter=True)
+        assert ns.session["id"] == "foo"
+        assert ostream.getvalue().startswith(handshake("00000000-0000-0000-0000-000000000000") + info_frame(verifies=True))
+
+        record = logging.makeLogRecord({"msg": "foo"})
+        assert ns.add_session(record)
+        assert record.session == "[foo] "
+
+        # the next client brings its own session ID
+        istream = io.BytesIO(handshake("00000000-0000-0000-0000-000000000002") + info_frame(session="bar") + changes_frames({}))
+        ns.initial_sync(db, prefix, istream, io.BytesIO(), arbiter=True)
+        assert ns.session["id"] == "bar"
+
+    record = logging.makeLogRecord({"msg": "foo"})
+    assert ns.add_session(record)
+    assert record.session == ""
+
+
+def test_initial_sync_arbiter_stale():
+    db = lambda: None
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.revision = MagicMock(return_value=rev)
+
+    mine = {"foo": {"tags": ["tag1"], "files": ["foofile"]}}
+    theirs = {"foo": {"tags": ["tag2"], "files": ["foofile"]}}
+    vector = {"00000000-0000-0000-0000-000000000000": 123, "00000000-0000-0000-0000-000000000002": 7}
+    with patch.object(ns, "get_changes", return_value=mine), patch.object(ns, "get_vector", return_value=vector):
+        with patch.object(ns, "sync_tags", return_value=1) as st:
+            vec = json.dumps({"vector": {"00000000-0000-0000-0000-000000000002": 5},
+                              "receive_only": False, "session": ""}).encode("utf-8")
+            istream = io.BytesIO(handshake("00000000-0000-0000-0000-000000000001") + struct.pack("!I", len(vec)) + vec +
+                                 changes_frames(theirs))
+            ostream = io.BytesIO()
+            ns.initial_sync(db, prefix, istream, ostream, arbiter=True)
+            st.assert_called_once_with(db, mine, theirs, {"foo": ["tag1"]}, None, "text", {})
+
+
+def test_merge_tags_policies():
+    mine = {"foo": {"tags": ["unread", "flagged", "inbox"]}}
+    theirs = {"foo": {"tags": ["trash", "todo"]}}
+    policies = [("unread", "mine"), ("trash", "theirs"), ("t*", "union"), ("inbox", "theirs")]
+    assert ns.merge_tags(mine, theirs, policies=policies) == {"foo": ["flagged", "todo", "trash", "unread"]}
+    assert ns.merge_tags(mine, theirs, "theirs", policies) == {"foo": ["todo", "trash", "unread"]}
+
+
+def test_parse_tag_policies():
+    specs = ["unread:prefer-local", "flagged:union", "gtd/*:prefer-remote"]
+    assert ns.parse_tag_policies(specs, True) == [("unread", "mine"), ("flagged", "union"), ("gtd/*", "theirs")]
+    assert ns.parse_tag_policies(specs, False) == [("unread", "theirs"), ("flagged", "union"), ("gtd/*", "mine")]
+    assert ns.parse_tag_policies(specs, True, "prefer-remote") == [("unread", "mine"), ("flagged", "union"),
+                                                                    ("gtd/*", "theirs"), ("*", "theirs")]
+    assert ns.parse_tag_policies(["unread:newest"], False, "union", True) == [("unread", "newest"), ("*", "union")]
+    with pytest.raises(ns.SyncError) as pwe:
+        ns.parse_tag_policies(["unread:latest"], True)
+    assert str(pwe.value) == "Invalid tag merge policy 'unread:latest', must be <tag>:<union|prefer-local|prefer-remote|newest>."
+    with pytest.raises(ns.SyncError) as pwe:
+        ns.parse_tag_policies([], True, "newest")
+    assert str(pwe.value) == "Tag merge policy '*:newest' needs --tag-times."
+
+
+def test_initial_sync_receive_only():
+    db = lambda: None
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.revision = MagicMock(return_value=rev)
+
+    theirs = {"foo": {"tags": ["tag2"], "files": ["foofile"]}}
+    with patch.object(ns, "get_changes") as gc, patch.object(ns, "get_vector", return_value={}):
+        with patch.object(ns, "sync_tags", return_value=1) as st:
+            istream = io.BytesIO(handshake("00000000-0000-0000-0000-000000000001") + info_frame() + changes_frames(theirs))
+            ostream = io.BytesIO()
+            mine, _, _, _, info = ns.initial_sync(db, prefix, istream, ostream, arbiter=True, receive_only=True)
+            assert mine == {}
+            assert info["receive_only"] is False
+            gc.assert_not_called()
+            st.assert_called_once_with(db, {}, theirs, {}, None, "text", {})
+
+    assert (handshake("00000000-0000-0000-0000-000000000000") + info_frame(receive_only=True, verifies=True) + changes_frames({}) +
+            frame({"stamp": "00000000-0000-0000-0000-000000000000:123", "tags": {}})) == ostream.getvalue()
+
+
+def test_check_changes():
+    db = lambda: None
+    db.count_messages = MagicMock(return_value=10)
+    changes = {"foo": {"tags": [], "files": []}, "bar": {"tags": [], "files": []},
+               "baz": {"tags": [], "files": []}}
+
+    with NamedTemporaryFile(mode="w+t", delete_on_close=False) as f:
+        ns.check_changes(db, changes, f.name)
+        ns.check_changes(db, changes, f.name, 0.3)
+        with pytest.raises(ns.SyncError) as pwe:
+            ns.check_changes(db, changes, f.name, 0.2)
+        assert str(pwe.value) == "Sync would change 3 of 10 messages, more than allowed by --max-change 0.2, aborting (use --force to sync anyway)..."
+        db.count_messages.assert_called_with("*")
+
+    # first sync
+    db.count_messages.reset_mock()
+    ns.check_changes(db, changes, f.name, 0.2)
+    db.count_messages.assert_not_called()
+
+
+def test_check_changes_exit_code(monkeypatch):
+    db = lambda: None
+    db.count_messages = MagicMock(return_value=10)
+    changes = {"foo": {"tags": [], "files": []}, "bar": {"tags": [], "files": []}}
+
+    monkeypatch.setattr(sys, "argv", ["notmuch-sync", "-r", "a"])
+    with NamedTemporaryFile(mode="w+t") as f, \
+         patch.object(ns, "run_local", side_effect=lambda args: ns.check_changes(db, changes, f.name, 0.1)), \
+         patch.object(ns.logger, "error") as err:
+        with pytest.raises(SystemExit) as pwe:
+            ns.main()
+        assert pwe.value.code == ns.SyncError.exit_code
+        err.assert_called_once_with("%s", ANY)
+
+
+def test_exit_status(monkeypatch):
+    monkeypatch.setattr(sys, "argv", ["notmuch-sync", "-r", "a"])
+    with patch.object(ns, "run_local", return_value=ns.SyncStats(status=ns.ConflictError.exit_code)), \
+         pytest.raises(SystemExit) as pwe:
+        ns.main()
+    assert pwe.value.code == 2
+
+    monkeypatch.setattr(sys, "argv", ["notmuch-sync", "-r", "a"])
+    with patch.object(ns, "run_local", return_value=ns.SyncStats()), pytest.raises(SystemExit) as pwe:
+        ns.main()
+    assert pwe.value.code == 0
+
+    monkeypatch.setattr(sys, "argv", ["notmuch-sync", "-r", "a", "-r", "b", "-r", "c"])
+    with patch.object(ns, "run_local", side_effect=[ns.SyncStats(status=1), ns.ProtocolError("foo"), ns.SyncStats()]), \
+         patch.object(ns.logger, "warning"), patch.object(ns.logger, "error"), pytest.raises(SystemExit) as pwe:
+        ns.main()
+    assert pwe.value.code == ns.ProtocolError.exit_code
+
+
+def test_get_vector():
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+
+    with TemporaryDirectory() as tmp:
+        os.mkdir(os.path.join(tmp, ".notmuch"))
+        assert ns.get_vector(tmp, rev) == {"00000000-0000-0000-0000-000000000000": 123}
+
+        with open(os.path.join(tmp, ".notmuch", "notmuch-sync-00000000-0000-0000-0000-000000000001"), "w") as f:
+            f.write('123 00000000-0000-0000-0000-000000000000 {"00000000-0000-0000-0000-000000000000":100,'
+                    '"00000000-0000-0000-0000-000000000001":5}')
+        with open(os.path.join(tmp, ".notmuch", "notmuch-sync-00000000-0000-0000-0000-000000000002"), "w") as f:
+            f.write('110 00000000-0000-0000-0000-000000000000 {"00000000-0000-0000-0000-000000000001":7,'
+                    '"00000000-0000-0000-0000-000000000002":3}')
+        assert ns.get_vector(tmp, rev) == {"00000000-0000-0000-0000-000000000000": 100,
+                                           "00000000-0000-0000-0000-000000000001": 7,
+                                           "00000000-0000-0000-0000-000000000002": 3}
+
+        rev.rev = 124
+        assert ns.get_vector(tmp, rev) == {"00000000-0000-0000-0000-000000000000": 124,
+                                           "00000000-0000-0000-0000-000000000001": 7,
+                                           "00000000-0000-0000-0000-000000000002": 3}
+
+
+def test_get_state_dir():
+    uuid = "00000000-0000-0000-0000-000000000000"
+    assert ns.get_state_dir(prefix, uuid) == os.path.join(prefix, ".notmuch")
+    assert ns.get_state_dir(prefix, uuid, "/var/lib/notmuch-sync") == "/var/lib/notmuch-sync/" + uuid
+
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = uuid.encode("utf-8")
+    with TemporaryDirectory() as tmp:
+        os.mkdir(os.path.join(tmp, uuid))
+        with open(os.path.join(tmp, uuid, "notmuch-sync-00000000-0000-0000-0000-000000000001"), "w") as f:
+            f.write(f'100 {uuid} {{"00000000-0000-0000-0000-000000000001":5}}')
+        assert ns.get_vector(prefix, rev, tmp) == {uuid: 123, "00000000-0000-0000-0000-000000000001": 5}
+
+
+def test_compare_vectors():
+    assert ns.compare_vectors({}, {}) is None
+    assert ns.compare_vectors({"a": 1, "b": 2}, {"a": 1, "b": 2}) is None
+    assert ns.compare_vectors({"a": 2, "b": 2}, {"a": 1, "b": 2}) == "mine"
+    assert ns.compare_vectors({"a": 1}, {"a": 1, "b": 2}) == "theirs"
+    assert ns.compare_vectors({"a": 2}, {"a": 1, "b": 2}) is None
+
+
+def test_merge_tags():
+    mine = {"foo": {"tags": ["tag1", "tag2"]}, "bar": {"tags": ["tag1"]}}
+    theirs = {"foo": {"tags": ["tag3", "tag1"]}, "foobar": {"tags": ["tag1"]}}
+    assert ns.merge_tags(mine, theirs) == {"foo": ["tag1", "tag2", "tag3"]}
+    assert ns.merge_tags({}, theirs) == {}
+    assert ns.merge_tags(mine, theirs, "mine") == {"foo": ["tag1", "tag2"]}
+    assert ns.merge_tags(mine, theirs, "theirs") == {"foo": ["tag1", "tag3"]}
+
+
+def test_merge_tags_base():
+    # tag2 removed on remote, tag3 added on local, tag4 removed on local and
+    # added on remote
+    mine = {"foo": {"tags": ["tag1", "tag2", "tag3"]}, "bar": {"tags": ["tag1"]}}
+    theirs = {"foo": {"tags": ["tag1", "tag4"]}, "bar": {"tags": ["tag2"]}}
+    base = {"foo": ["tag1", "tag2"]}
+    assert ns.merge_tags(mine, theirs, base=base) == {"foo": ["tag1", "tag3", "tag4"], "bar": ["tag1", "tag2"]}
+    assert ns.merge_tags(mine, theirs, base={"foo": ["tag1", "tag2", "tag4"]})["foo"] == ["tag1", "tag3"]
+    # policies and stale sides still apply
+    assert ns.merge_tags(mine, theirs, policies=[("tag2", "union")], base=base)["foo"] == ["tag1", "tag2", "tag3", "tag4"]
+    assert ns.merge_tags(mine, theirs, "theirs", base=base)["foo"] == ["tag1", "tag4"]
+
+
+def test_record_tags():
+    def message(mid, tags, ghost=False):
+        msg = lambda: None
+        msg.messageid = mid
+        msg.tags = set(tags)
+        msg.ghost = ghost
+        return msg
+
+    msgs = {"foo": message("foo", ["tag1"]), "bar": message("bar", ["tag2", "tag1"]), "baz": message("baz", [], True)}
+
+    def find(mid):
+        if mid not in msgs:
+            raise LookupError
+        return msgs[mid]
+
+    db = MagicMock()
+    db.messages.return_value = [msgs["foo"], msgs["bar"]]
+    db.find.side_effect = find
+    with TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "notmuch-sync-00000000-0000-0000-0000-000000000001-tags")
+        assert ns.load_tags(fname) is None
+        ns.record_tags(fname, db, ["foo"])
+        assert ns.load_tags(fname) == {"foo": ["tag1"], "bar": ["tag1", "tag2"]}
+        db.messages.assert_called_once_with("*")
+
+        msgs["foo"].tags = {"tag3"}
+        msgs["bar"].tags = set()
+        ns.record_tags(fname, db, ["foo", "baz", "qux"])
+        assert ns.load_tags(fname) == {"foo": ["tag3"], "bar": ["tag1", "tag2"]}
+        db.messages.assert_called_once()
+
+        Path(fname).write_bytes(b"garbage")
+        assert ns.load_tags(fname) is None
+
+
 def test_record_sync():
     rev = lambda: None
     rev.rev = 123
@@ -155,8 +696,42 @@ def test_record_sync():
         assert "123 00000000-0000-0000-0000-000000000000" == args[0]
 
 
+def test_record_sync_vector():
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+
+    fname = os.path.join(gettempdir(), ".notmuch", "notmuch-sync-00000000-0000-0000-0000-000000000001")
+    with patch("builtins.open", mock_open()) as o:
+        ns.record_sync(fname, rev, {"00000000-0000-0000-0000-000000000000": 120,
+                                    "00000000-0000-0000-0000-000000000001": 5})
+        hdl = o()
+        args = hdl.write.call_args.args
+        assert ('123 00000000-0000-0000-0000-000000000000 {"00000000-0000-0000-0000-000000000000":120,'
+                '"00000000-0000-0000-0000-000000000001":5}') == args[0]
+
+
+def test_atomic_batches(monkeypatch):
+    monkeypatch.setitem(ns.session, "batch", 2)
+    db = MagicMock()
+    with ns.AtomicBatches(db) as batches:
+        for _ in range(5):
+            batches.done()
+    # one transaction per two messages and one for the last
+    assert db.atomic.call_count == 3
+    assert db.atomic.return_value.__exit__.call_count == 3
+
+    monkeypatch.setitem(ns.session, "batch", 0)
+    db = MagicMock()
+    with ns.AtomicBatches(db) as batches:
+        for _ in range(5):
+            batches.done()
+    db.atomic.assert_called_once()
+
+
 def test_sync_tags_empty():
     db = lambda: None
+    db.atomic = MagicMock()
     changes = ns.sync_tags(db, {}, {})
     assert changes == 0
 
@@ -178,10 +753,15 @@ def test_sync_tags_only_theirs():
     type(m).tags = PropertyMock(return_value=mt)
 
     db = lambda: None
+
+    db.atomic = MagicMock()
     db.find = MagicMock(return_value=m)
 
+    stats = ns.SyncStats()
+    ns.current_stats.set(stats)
     changes = ns.sync_tags(db, {}, {"foo": {"tags": ["bar", "foobar"]}})
     assert changes == 1
+    assert stats.message_ids == {"foo"}
 
     db.find.assert_called_once_with("foo")
     m.frozen.assert_called_once()
@@ -198,6 +778,8 @@ def test_sync_tags_only_theirs_ghost():
     m.ghost = True
 
     db = lambda: None
+
+    db.atomic = MagicMock()
     db.find = MagicMock(return_value=m)
 
     changes = ns.sync_tags(db, {}, {"foo": {"tags": ["bar", "foobar"]}})
@@ -206,6 +788,54 @@ def test_sync_tags_only_theirs_ghost():
     db.find.assert_called_once_with("foo")
 
 
+def test_sync_tags_properties():
+    m = MagicMock()
+    m.ghost = False
+    type(m).tags = PropertyMock(return_value=["foo"])
+    m.properties.getall.return_value = [("foo", "a"), ("index.decryption", "success")]
+
+    db = lambda: None
+
+    db.atomic = MagicMock()
+    db.find = MagicMock(return_value=m)
+
+    stats = ns.SyncStats()
+    ns.current_stats.set(stats)
+    changes = ns.sync_tags(db, {}, {"foo": {"tags": ["foo"], "properties": {"foo": ["b"]}}})
+    assert changes == 1
+    assert stats.message_ids == {"foo"}
+    assert m.properties.__delitem__.mock_calls == [call("foo")]
+    assert m.properties.add.mock_calls == [call("foo", "b")]
+
+    m.properties.reset_mock()
+    changes = ns.sync_tags(db, {"foo": {"tags": ["foo"], "properties": {"foo": ["a"]}}},
+                           {"foo": {"tags": ["foo"], "properties": {"foo": ["b"]}}}, property_decisions={"foo": {}})
+    assert changes == 1
+    assert m.properties.__delitem__.mock_calls == [call("foo")]
+    assert m.properties.add.mock_calls == []
+
+    m.properties.reset_mock()
+    assert ns.sync_tags(db, {}, {"foo": {"tags": ["foo"], "properties": {"foo": ["a"]}}}) == 0
+    assert m.properties.add.mock_calls == []
+
+
+def test_sync_tags_properties_and_tags(monkeypatch):
+    monkeypatch.setitem(ns.session, "batch", 1)
+    m = MagicMock()
+    m.ghost = False
+    m.tags.__iter__.side_effect = lambda: iter(["foo"])
+    m.properties.getall.return_value = [("foo", "a")]
+
+    db = MagicMock()
+    db.find.return_value = m
+
+    # a message with changed properties and tags counts once, also in its batch
+    assert ns.sync_tags(db, {}, {"foo": {"tags": ["bar"], "properties": {"foo": ["b"]}}}) == 1
+    assert m.properties.add.mock_calls == [call("foo", "b")]
+    assert m.tags.add.mock_calls == [call("bar")]
+    assert db.atomic.call_count == 2
+
+
 def test_sync_tags_only_theirs_no_changes():
     m = MagicMock()
 
@@ -216,6 +846,8 @@ def test_sync_tags_only_theirs_no_changes():
     type(m).tags = PropertyMock(return_value=mt)
 
     db = lambda: None
+
+    db.atomic = MagicMock()
     db.find = MagicMock(return_value=m)
 
     changes = ns.sync_tags(db, {}, {"foo": {"tags": ["foo", "bar"]}})
@@ -226,6 +858,7 @@ def test_sync_tags_only_theirs_no_changes():
 
 def test_sync_tags_only_theirs_not_found():
     db = lambda: None
+    db.atomic = MagicMock()
     db.find = MagicMock()
     db.find.side_effect = LookupError()
 
@@ -237,6 +870,7 @@ def test_sync_tags_only_theirs_not_found():
 
 def test_sync_tags_only_mine():
     db = lambda: None
+    db.atomic = MagicMock()
     changes = ns.sync_tags(db, {"foo": {"tags": ["foo", "bar"]}}, {})
     assert changes == 0
 
@@ -258,6 +892,8 @@ def test_sync_tags_mine_theirs_no_overlap():
     type(m).tags = PropertyMock(return_value=mt)
 
     db = lambda: None
+
+    db.atomic = MagicMock()
     db.find = MagicMock(return_value=m)
 
     changes = ns.sync_tags(db, {"bar": {"tags": ["tag1", "tag2"]}}, {"foo": {"tags": ["bar", "foobar"]}})
@@ -273,73 +909,975 @@ def test_sync_tags_mine_theirs_no_overlap():
     mt.to_maildir_flags.assert_called_once()
 
 
-def test_sync_tags_mine_theirs_overlap():
-    m = MagicMock()
-    m.frozen = MagicMock()
-    m.frozen.__enter__.return_value = None
-    m.frozen.__exit__.return_value = False
-    m.ghost = False
+def test_sync_tags_mine_theirs_overlap():
+    m = MagicMock()
+    m.frozen = MagicMock()
+    m.frozen.__enter__.return_value = None
+    m.frozen.__exit__.return_value = False
+    m.ghost = False
+
+    mt = MagicMock(spec=list)
+    tags = ["foo", "bar"]
+    mt.__iter__.return_value = iter(tags)
+    mt.__len__.return_value = len(tags)
+    mt.clear = MagicMock()
+    mt.add = MagicMock()
+    mt.to_maildir_flags = MagicMock()
+    type(m).tags = PropertyMock(return_value=mt)
+
+    db = lambda: None
+
+    db.atomic = MagicMock()
+    db.find = MagicMock(return_value=m)
+
+    changes = ns.sync_tags(db, {"foo": {"tags": ["tag1", "tag2"]}}, {"foo": {"tags": ["bar", "foobar"]}})
+    assert changes == 1
+
+    db.find.assert_called_once_with("foo")
+    m.frozen.assert_called_once()
+    mt.clear.assert_called_once()
+    assert mt.add.mock_calls == [
+        call("bar"),
+        call("foobar"),
+        call("tag1"),
+        call("tag2")
+    ]
+    mt.to_maildir_flags.assert_called_once()
+
+
+def test_sync_tags_decisions():
+    m = MagicMock()
+    m.frozen = MagicMock()
+    m.frozen.__enter__.return_value = None
+    m.frozen.__exit__.return_value = False
+    m.ghost = False
+
+    mt = MagicMock(spec=list)
+    tags = ["foo", "bar"]
+    mt.__iter__.return_value = iter(tags)
+    mt.__len__.return_value = len(tags)
+    mt.clear = MagicMock()
+    mt.add = MagicMock()
+    mt.to_maildir_flags = MagicMock()
+    type(m).tags = PropertyMock(return_value=mt)
+
+    db = lambda: None
+
+    db.atomic = MagicMock()
+    db.find = MagicMock(return_value=m)
+
+    changes = ns.sync_tags(db, {"foo": {"tags": ["tag1", "tag2"]}}, {"foo": {"tags": ["bar", "foobar"]}},
+                           {"foo": ["tag1"]})
+    assert changes == 1
+
+    db.find.assert_called_once_with("foo")
+    mt.clear.assert_called_once()
+    assert mt.add.mock_calls == [
+        call("tag1")
+    ]
+    mt.to_maildir_flags.assert_called_once()
+
+
+def test_sync_tags_orset():
+    m = MagicMock()
+    m.ghost = False
+    m.properties.getall = MagicMock(side_effect=[iter([("notmuch-sync-add", "a:1 foo"),
+                                                       ("notmuch-sync-add", "a:1 bar")]),
+                                                 iter([])])
+
+    mt = MagicMock(spec=list)
+    tags = ["foo", "bar"]
+    mt.__iter__.return_value = iter(tags)
+    mt.__len__.return_value = len(tags)
+    mt.clear = MagicMock()
+    mt.add = MagicMock()
+    mt.to_maildir_flags = MagicMock()
+    type(m).tags = PropertyMock(return_value=mt)
+
+    db = lambda: None
+
+    db.atomic = MagicMock()
+    db.find = MagicMock(return_value=m)
+
+    changes = ns.sync_tags(db, {}, {"foo": {"tags": ["foo", "baz"],
+                                            "orset": {"add": ["a:1 foo", "b:1 baz"], "rm": ["a:1 bar"]}}})
+    assert changes == 1
+
+    assert mt.add.mock_calls == [
+        call("baz"),
+        call("foo")
+    ]
+    assert m.properties.add.mock_calls == [
+        call("notmuch-sync-add", "a:1 foo"),
+        call("notmuch-sync-add", "b:1 baz"),
+        call("notmuch-sync-rm", "a:1 bar")
+    ]
+
+
+def test_sync_tags_protected():
+    m = MagicMock()
+    m.frozen = MagicMock()
+    m.frozen.__enter__.return_value = None
+    m.frozen.__exit__.return_value = False
+    m.ghost = False
+
+    mt = MagicMock(spec=list)
+    tags = ["important", "gtd/next", "inbox"]
+    mt.__iter__.side_effect = lambda: iter(tags)
+    mt.__len__.return_value = len(tags)
+    mt.clear = MagicMock()
+    mt.add = MagicMock()
+    mt.to_maildir_flags = MagicMock()
+    type(m).tags = PropertyMock(return_value=mt)
+
+    db = lambda: None
+
+    db.atomic = MagicMock()
+    db.find = MagicMock(return_value=m)
+
+    changes = ns.sync_tags(db, {}, {"foo": {"tags": ["gtd/later", "unread"]}}, protected=["important", "gtd/*"])
+    assert changes == 1
+
+    mt.clear.assert_called_once()
+    assert mt.add.mock_calls == [
+        call("gtd/next"),
+        call("important"),
+        call("unread")
+    ]
+
+    mt.clear.reset_mock()
+    changes = ns.sync_tags(db, {}, {"foo": {"tags": ["gtd/next", "important", "inbox", "gtd/later"]}},
+                           protected=["important", "gtd/*"])
+    assert changes == 0
+    mt.clear.assert_not_called()
+
+
+def test_tag_diff():
+    assert ns.tag_diff("foo@bar", {"unread", "inbox"}, {"inbox", "flagged", "todo"}) == \
+        "Tag changes for foo@bar: +flagged +todo -unread."
+    assert ns.tag_diff("foo@bar", {"unread", "inbox"}, {"inbox", "flagged", "todo"}, "patch") == \
+        "+flagged +todo -unread -- id:foo@bar"
+    assert ns.tag_diff("a b/c%d", {"gtd/next"}, {"a b"}, "patch") == \
+        "+a%20b -gtd%2fnext -- id:a%20b%2fc%25d"
+
+
+def test_sync_tags_diff():
+    m = MagicMock()
+    m.frozen = MagicMock()
+    m.frozen.__enter__.return_value = None
+    m.frozen.__exit__.return_value = False
+    m.ghost = False
+
+    mt = MagicMock(spec=list)
+    tags = ["foo", "unread"]
+    mt.__iter__.side_effect = lambda: iter(tags)
+    mt.__len__.return_value = len(tags)
+    mt.clear = MagicMock()
+    mt.add = MagicMock()
+    mt.to_maildir_flags = MagicMock()
+    type(m).tags = PropertyMock(return_value=mt)
+
+    db = lambda: None
+
+    db.atomic = MagicMock()
+    db.find = MagicMock(return_value=m)
+
+    with patch.object(ns.logger, "isEnabledFor", return_value=True), \
+         patch.object(ns.logger, "debug") as debug, patch.object(ns.logger, "info") as info:
+        ns.sync_tags(db, {}, {"foo": {"tags": ["foo", "flagged"]}})
+        debug.assert_called_once_with("%s", "Tag changes for foo: +flagged -unread.")
+        debug.reset_mock()
+        ns.sync_tags(db, {}, {"foo": {"tags": ["foo", "flagged"]}}, diff_format="patch")
+        debug.assert_called_once_with("%s", "+flagged -unread -- id:foo")
+        info.assert_not_called()
+
+
+def test_dry_run():
+    stats = ns.SyncStats()
+    ns.current_stats.set(stats)
+    assert not ns.dry_run("delete foo")
+    with patch.dict(ns.session, dry_run=True):
+        assert ns.dry_run("delete foo")
+    assert stats.actions == ["delete foo"]
+    assert "actions" not in stats.changes()
+
+
+def test_sync_tags_dry_run():
+    m = MagicMock()
+    m.ghost = False
+    m.tags = ["foo", "unread"]
+    db = lambda: None
+    db.atomic = MagicMock()
+    db.find = MagicMock(return_value=m)
+
+    stats = ns.SyncStats()
+    ns.current_stats.set(stats)
+    with patch.dict(ns.session, dry_run=True):
+        assert ns.sync_tags(db, {}, {"foo": {"tags": ["foo", "flagged"]}}) == 1
+    m.frozen.assert_not_called()
+    assert stats.actions == ["change tags of foo: +flagged -unread"]
+    assert stats.message_ids == set()
+
+
+def test_preview_changes():
+    def _msg(tags, files):
+        m = MagicMock()
+        m.ghost = False
+        m.tags = tags
+        m.filenames.return_value = files
+        return m
+
+    prefix = "/mail/"
+    msgs = {"a": _msg(["inbox", "unread"], ["/mail/INBOX/cur/a:2,"]),
+            "b": _msg(["inbox"], ["/mail/INBOX/cur/b:2,", "/mail/Lists/cur/b:2,"]),
+            "c": _msg(["inbox"], ["/mail/INBOX/cur/c:2,"])}
+    db = lambda: None
+    db.find = MagicMock(side_effect=lambda mid: msgs[mid] if mid in msgs else (_ for _ in ()).throw(LookupError))
+    theirs = {"a": {"tags": ["inbox"], "files": ["INBOX/cur/a:2,S"]},
+              "b": {"tags": ["inbox"], "files": ["INBOX/cur/b:2,"]},
+              "c": {"tags": ["inbox", "todo"], "files": ["Archive/cur/c:2,"]},
+              "d": {"tags": ["inbox"], "files": ["INBOX/new/d", "Spam/cur/d"]}}
+    preview = ns.preview_changes(db, prefix, {"c": {}}, theirs, {"c": ["inbox", "todo", "flagged"]},
+                                 exclude=["Spam/*"])
+    assert preview["tags"] == {"a": [["inbox", "unread"], ["inbox"]],
+                               "c": [["inbox"], ["flagged", "inbox", "todo"]]}
+    assert preview["folders"] == {"INBOX": {"added": ["INBOX/cur/a:2,S", "INBOX/new/d"],
+                                            "removed": ["INBOX/cur/a:2,"]},
+                                  "Lists": {"added": [], "removed": ["Lists/cur/b:2,"]},
+                                  "Archive": {"added": ["Archive/cur/c:2,"], "removed": []}}
+    assert ns.preview_changes(db, prefix, {}, theirs, {}, ["unread"])["tags"] == \
+        {"c": [["inbox"], ["inbox", "todo"]]}
+
+
+def test_format_preview():
+    assert ns.format_preview({"tags": {}, "folders": {}}, "local") == ""
+    preview = {"tags": {"a": [["inbox", "unread"], ["flagged", "inbox"]]},
+               "folders": {"INBOX": {"added": ["INBOX/new/b"], "removed": ["INBOX/cur/a:2,"]}}}
+    assert ns.format_preview(preview, "local") == "\n".join([
+        "--- local", "+++ local after sync", "@@ folder INBOX -1 +1 @@", "-INBOX/cur/a:2,", "+INBOX/new/b",
+        "@@ id:a @@", "-unread", "+flagged"]) + "\n"
+    assert ns.format_preview(preview, "remote", "patch").endswith("@@ id:a @@\n+flagged -unread -- id:a\n")
+
+
+def test_sync_server(monkeypatch):
+    args = lambda: None
+    args.delete = False
+    args.mbsync = False
+    args.check_counts = False
+    args.spot_check = None
+    args.unindexed = None
+    args.mode = None
+    args.orset = False
+    args.tag_times = False
+    args.properties = False
+    args.property_policy = []
+    args.tag_policy = []
+    args.tag_conflict = None
+    args.protect_tags = []
+    args.receive_only = False
+    args.max_change = None
+    args.force = False
+    args.jobs = None
+    args.checksum = True
+    args.hash_index = False
+    args.delta = False
+    args.dedup = False
+    args.prune_empty_dirs = False
+    args.include_pattern = []
+    args.exclude_pattern = []
+    args.compress = None
+    args.frame_checksum = False
+    args.preview = False
+    args.dry_run = False
+    args.bwlimit = None
+    args.batch_size = 1000
+    args.check = False
+    args.verify = False
+    args.confirm = False
+    args.wait_lock = 0
+    args.pre_hook = None
+    args.post_hook = None
+    args.pull_cmd = None
+    args.index = None
+
+    db = lambda: None
+
+    db.atomic = MagicMock()
+    rev = lambda: None
+    rev.rev = 124
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.revision = MagicMock(return_value=rev)
+    db.default_path = MagicMock(return_value=gettempdir())
+    db.config = {}
+
+    mock_ctx = MagicMock()
+    mock_ctx.__enter__.return_value = db
+    mock_ctx.__exit__.return_value = False
+
+    fname = os.path.join(gettempdir(), ".notmuch", "notmuch-sync-00000000-0000-0000-0000-000000000001")
+    with patch("notmuch2.Database", return_value=mock_ctx):
+        with patch.object(ns, "get_changes", return_value={}) as gc, patch.object(ns, "get_vector", return_value={}), \
+             patch.object(ns, "lock_session") as ls, \
+             patch.object(ns, "record_tags") as rt:
+            with patch("builtins.open", mock_open()) as o:
+                mockio = io.BytesIO(handshake("00000000-0000-0000-0000-000000000001") + info_frame() + b'\x00\x00\x00\x00\x00\x00\x00\x02[]\x00\x00\x00\x02[]\x00\x00\x00\x02[]\x00\x00\x00\x02[]\x00\x00\x00\x02[]\x00\x00\x00\x03bye')
+                mockio.buffer = mockio
+                monkeypatch.setattr(sys, "stdin", mockio)
+                ns.sync_remote(args)
+                o.assert_called_once_with(fname, "w", encoding="utf-8")
+                rt.assert_called_once_with(fname + "-tags", db, [])
+                hdl = o()
+                hdl.write.assert_called_once()
+                args = hdl.write.call_args.args
+                assert "124 00000000-0000-0000-0000-000000000000 {}" == args[0]
+            gc.assert_called_once_with(db, rev, prefix, fname, False, False, False)
+            ls.assert_called_once_with(prefix, None, 0)
+
+    assert db.revision.call_count == 2
+    db.default_path.assert_called_once()
+
+
+def test_sync_remote_sessions():
+    db = lambda: None
+    db.default_path = MagicMock(return_value=gettempdir())
+    db.config = {}
+    mock_ctx = MagicMock()
+    mock_ctx.__enter__.return_value = db
+    mock_ctx.__exit__.return_value = False
+    sessions = []
+
+    def initial_sync(*args, **kwargs):
+        sessions.append(dict(ns.session))
+        # what a session picks up along the way
+        ns.session.update(id="foo", protocol=4, encoding="msgpack", store="/store", journal="journal")
+        raise ns.ProtocolError("stop")
+
+    first = ns.arg_parser().parse_args(["--bwlimit", "10", "--frame-checksum", "--dry-run", "--batch-size", "5"])
+    second = ns.arg_parser().parse_args([])
+    with patch("notmuch2.Database", return_value=mock_ctx), patch.object(ns, "lock_session"), \
+         patch.object(ns, "initial_sync", side_effect=initial_sync):
+        for args in (first, second):
+            with pytest.raises(ns.ProtocolError):
+                ns.sync_remote(args, io.BytesIO(), io.BytesIO())
+    assert sessions[0]["crc"] and sessions[0]["dry_run"] and sessions[0]["rate"] is not None
+    assert sessions[0]["batch"] == 5
+    assert sessions[1] == ns.SESSION_DEFAULTS | {"batch": 1000, "trash": None, "flags": True}
+
+
+def test_sync_remote_verify():
+    db = lambda: None
+    db.default_path = MagicMock(return_value=gettempdir())
+    db.config = {}
+    db.atomic = MagicMock()
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.revision = MagicMock(return_value=rev)
+    mock_ctx = MagicMock()
+    mock_ctx.__enter__.return_value = db
+    mock_ctx.__exit__.return_value = False
+
+    # a served remote switches to verifying when the local side verifies
+    with patch.object(ns, "get_vector", return_value={}), pytest.raises(ns.VerifyRequested):
+        ns.initial_sync(db, prefix, io.BytesIO(handshake("00000000-0000-0000-0000-000000000001") +
+                                               frame({"verify": True})), io.BytesIO(), arbiter=True)
+
+    args = ns.arg_parser().parse_args(["--exclude-pattern", "Spam/*"])
+    from_stream, to_stream = io.BytesIO(), io.BytesIO()
+    with patch("notmuch2.Database", return_value=mock_ctx), patch.object(ns, "lock_session"), \
+         patch.object(ns, "initial_sync", side_effect=ns.VerifyRequested) as init, \
+         patch.object(ns, "verify_messages") as vm:
+        ns.sync_remote(args, from_stream, to_stream)
+        init.assert_called_once()
+        vm.assert_called_once_with(db, os.path.join(gettempdir(), ""), from_stream, to_stream, True, [],
+                                   ["Spam/*"], None, False, None)
+
+
+def test_check_unindexed():
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        for d in ["INBOX/cur", "INBOX/new", "Sent/cur", "Other", "Untouched/cur"]:
+            os.makedirs(os.path.join(tmp, d))
+        for f in ["INBOX/cur/a", "INBOX/new/b", "Sent/cur/c", "Other/d", "Other/.e", "Untouched/cur/f"]:
+            Path(os.path.join(tmp, f)).touch()
+
+        indexed = {pre + "INBOX/cur/a", pre + "Sent/cur/c"}
+
+        def get(fname):
+            if fname not in indexed:
+                raise LookupError
+        db = lambda: None
+        db.get = MagicMock(side_effect=get)
+        db.add = MagicMock()
+
+        mine = {"foo": {"tags": [], "files": ["INBOX/cur/a"]}}
+        theirs = {"bar": {"tags": [], "files": ["Sent/cur/c", "Other/d", "Missing/cur/g"]}}
+        with patch.object(ns.logger, "warning") as warn:
+            assert 2 == ns.check_unindexed(db, pre, mine, theirs)
+            db.add.assert_not_called()
+            assert warn.mock_calls == [
+                call("File %s is not in the notmuch database, sync may be incomplete.", pre + "INBOX/new/b"),
+                call("File %s is not in the notmuch database, sync may be incomplete.", pre + "Other/d")
+            ]
+
+        assert 2 == ns.check_unindexed(db, pre, mine, theirs, True)
+        assert db.add.mock_calls == [call(pre + "INBOX/new/b"), call(pre + "Other/d")]
+
+
+def test_lock_session(monkeypatch):
+    monkeypatch.setenv("SSH_CLIENT", "192.0.2.1 4711 22")
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        os.mkdir(os.path.join(tmp, ".notmuch"))
+        with ns.lock_session(pre):
+            with open(os.path.join(tmp, ".notmuch", "notmuch-sync.lock"), encoding="utf-8") as f:
+                assert f.read() == f"192.0.2.1 (pid {os.getpid()})"
+            with pytest.raises(ValueError) as pwe:
+                ns.lock_session(pre)
+            assert str(pwe.value) == f"Busy, another sync in progress from 192.0.2.1 (pid {os.getpid()}), aborting..."
+        with ns.lock_session(pre):
+            pass
+        with ns.lock_session(pre, "198.51.100.1"):
+            with open(os.path.join(tmp, ".notmuch", "notmuch-sync.lock"), encoding="utf-8") as f:
+                assert f.read() == f"198.51.100.1 (pid {os.getpid()})"
+
+        # wait for the other sync to finish
+        lock = ns.lock_session(pre)
+        timer = threading.Timer(0.5, lock.close)
+        timer.start()
+        with ns.lock_session(pre, wait=5):
+            pass
+        timer.join()
+        lock = ns.lock_session(pre)
+        with pytest.raises(ns.BusyError):
+            ns.lock_session(pre, wait=0.5)
+        lock.close()
+
+
+def test_parse_address():
+    assert ns.parse_address("mail.example.com:4711") == ("mail.example.com", 4711)
+    assert ns.parse_address("[::1]:4711") == ("::1", 4711)
+    for spec in ["", "foo", ":4711", "foo:", "foo:bar", "foo:0", "foo:65536"]:
+        with pytest.raises(argparse.ArgumentTypeError):
+            ns.parse_address(spec)
+
+
+def test_remote_label():
+    args = argparse.Namespace(remote=None, remote_cmd="notmuch-sync", connect=None, socket=None)
+    assert ns.remote_label(args) == "notmuch-sync"
+    args.remote = "mail"
+    assert ns.remote_label(args) == "mail"
+    args.connect = ("::1", 4711)
+    assert ns.remote_label(args) == "::1:4711"
+    args.socket = "/run/notmuch-sync.sock"
+    assert ns.remote_label(args) == "/run/notmuch-sync.sock"
+
+
+def test_socket_streams():
+    a, b = socket.socketpair()
+    from_a, to_a = ns.socket_streams(a)
+    from_b, to_b = ns.socket_streams(b)
+    # no file descriptor to send files to with sendfile()
+    assert ns.stream_fd(to_a) is None
+    ns.write(b"foo" * 1000, to_a)
+    ns.write(b"bar", to_b)
+    assert ns.read(from_b) == b"foo" * 1000
+    assert ns.read(from_a) == b"bar"
+    to_a.close()
+    from_a.close()
+    assert from_b.read(4) == b""
+    to_b.close()
+    from_b.close()
+
+
+def test_tls_context():
+    with pytest.raises(ns.SyncError) as pwe:
+        ns.tls_context(False, None, None, "peer.pem")
+    assert str(pwe.value) == "Direct connections need --tls-cert and --tls-peer, aborting..."
+    with pytest.raises(ns.SyncError) as pwe:
+        ns.tls_context(True, "/nonexistent/cert.pem", None, "/nonexistent/peer.pem")
+    assert str(pwe.value).startswith("Cannot load TLS certificates: ")
+
+
+@pytest.mark.skipif(shutil.which("openssl") is None, reason="needs openssl to create certificates")
+def test_tls_connect():
+    with TemporaryDirectory() as tmp:
+        for name in ["server", "client", "other"]:
+            subprocess.run(["openssl", "req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1", "-subj", f"/CN={name}",
+                            "-keyout", os.path.join(tmp, f"{name}.key"), "-out", os.path.join(tmp, f"{name}.pem")],
+                           check=True, capture_output=True)
+        pem = lambda name: os.path.join(tmp, f"{name}.pem")
+        key = lambda name: os.path.join(tmp, f"{name}.key")
+        server_ctx = ns.tls_context(True, pem("server"), key("server"), pem("client"))
+
+        def _serve(listener, results):
+            ns.current_stats.set(ns.SyncStats())
+            conn, _ = listener.accept()
+            try:
+                from_stream, to_stream = ns.socket_streams(server_ctx.wrap_socket(conn, server_side=True))
+                ns.write(ns.read(from_stream) + b" back", to_stream)
+                to_stream.close()
+                from_stream.close()
+            except (OSError, ssl.SSLError) as e:
+                results.append(e)
+                conn.close()
+
+        for client, ok in [("client", True), ("other", False)]:
+            with socket.create_server(("127.0.0.1", 0)) as listener:
+                results = []
+                thread = threading.Thread(target=_serve, args=(listener, results))
+                thread.start()
+                ctx = ns.tls_context(False, pem(client), key(client), pem("server"))
+                try:
+                    from_stream, to_stream = ns.tls_connect(listener.getsockname(), ctx)
+                    ns.write(b"hello", to_stream)
+                    assert ns.read(from_stream) == b"hello back"
+                    to_stream.close()
+                    from_stream.close()
+                except (ValueError, OSError, struct.error):
+                    assert not ok
+                thread.join()
+                assert (len(results) == 0) == ok
+
+
+def test_unix_socket():
+    with TemporaryDirectory() as tmp:
+        path = os.path.join(tmp, "sync.sock")
+        with pytest.raises(ValueError) as pwe:
+            ns.unix_connect(path)
+        assert str(pwe.value).startswith(f"Connecting to {path} failed: ")
+
+        with open(path, "w", encoding="utf-8") as f:
+            f.write("foo")
+        with pytest.raises(ns.SyncError) as pwe:
+            ns.unix_server(path)
+        assert str(pwe.value) == f"{path} exists and is not a socket, aborting..."
+        os.unlink(path)
+
+        # stale socket from an earlier run
+        ns.unix_server(path).close()
+        server = ns.unix_server(path)
+        assert os.stat(path).st_mode & 0o777 == 0o600
+        with pytest.raises(ns.SyncError) as pwe:
+            ns.unix_server(path)
+        assert str(pwe.value) == f"Something is listening on {path} already, aborting..."
+        # the check connected once
+        server.accept()[0].close()
+
+        from_local, to_local = ns.unix_connect(path)
+        from_remote, to_remote = ns.socket_streams(server.accept()[0])
+        ns.write(b"hello", to_local)
+        ns.write(ns.read(from_remote) + b" back", to_remote)
+        assert ns.read(from_local) == b"hello back"
+        for stream in [to_local, from_local, to_remote, from_remote]:
+            stream.close()
+        server.close()
+
+
+def test_remote_stderr():
+    stream = io.BytesIO(b"Traceback:\nValueError: foo\n")
+    err = ns.RemoteStderr(stream)
+    with patch.object(ns.logger, "debug") as debug:
+        err.start()
+        assert err.output() == b"Traceback:\nValueError: foo\n"
+    assert [c.args[1] for c in debug.call_args_list] == ["Traceback:", "ValueError: foo"]
+    assert stream.closed
+
+    # the remote keeps writing more than fits into a pipe
+    r, w = os.pipe()
+    with open(r, "rb") as rf:
+        err = ns.RemoteStderr(rf)
+        err.start()
+        with open(w, "wb") as wf:
+            wf.write((b"x" * 1000 + b"\n") * 200)
+        assert len(err.output()) == 200200
+
+
+def test_check():
+    with TemporaryDirectory() as tmp:
+        prefix = os.path.join(tmp, "")
+        os.mkdir(os.path.join(tmp, ".notmuch"))
+        uuid_mine = "00000000-0000-0000-0000-000000000000"
+        uuid_theirs = "00000000-0000-0000-0000-000000000001"
+        rev = lambda: None
+        rev.rev = 124
+        rev.uuid = uuid_mine.encode("utf-8")
+        db = MagicMock()
+        db.revision.return_value = rev
+        theirs = {"check": True, "version": "0.0.3", "protocol": ns.PROTOCOL_VERSION, "path": "/mail/",
+                  "uuid": uuid_theirs, "revision": 10, "last_sync": -1, "free": 2048, "problems": []}
+
+        ostream = io.BytesIO()
+        mine, got = ns.run_check(db, prefix, io.BytesIO(handshake(uuid_theirs) + frame(theirs)), ostream)
+        assert got == theirs
+        assert mine["uuid"] == uuid_mine and mine["revision"] == 124 and mine["last_sync"] == -1
+        assert mine["problems"] == []
+        assert ostream.getvalue().startswith(handshake(uuid_mine))
+
+        with open(os.path.join(tmp, ".notmuch", "notmuch-sync-" + uuid_theirs), "w", encoding="utf-8") as f:
+            f.write("200 " + uuid_mine)
+        mine, _ = ns.run_check(db, prefix, io.BytesIO(handshake(uuid_theirs) + frame(theirs)), io.BytesIO())
+        assert mine["problems"] == ["Last sync revision 200 larger than current DB revision 124, aborting..."]
+        lines = ns.format_check(mine, "local")
+        assert lines[0] == f"local:  notmuch-sync {mine['version']}, protocol version {ns.PROTOCOL_VERSION}"
+        assert lines[2].startswith("local:  never synced, ")
+        assert lines[3] == "local:  problem: Last sync revision 200 larger than current DB revision 124, aborting..."
+        assert ns.format_check(theirs, "remote")[2] == "remote: never synced, 2.0 KiB free"
+
+        # the remote is syncing instead
+        with pytest.raises(ValueError) as pwe:
+            ns.run_check(db, prefix, io.BytesIO(handshake(uuid_theirs) + info_frame()), io.BytesIO())
+        assert str(pwe.value).startswith("Other side is syncing instead of checking, aborting")
+
+
+def test_verify():
+    with TemporaryDirectory() as tmp:
+        prefix = os.path.join(tmp, "")
+        os.mkdir(os.path.join(tmp, ".notmuch"))
+        for f, content in [("a", "mail one"), ("b", "mail two"), ("c", "mail three"), ("Spam/d", "spam")]:
+            Path(os.path.join(tmp, f)).parent.mkdir(exist_ok=True)
+            Path(os.path.join(tmp, f)).write_text(content)
+        uuid_mine = "00000000-0000-0000-0000-000000000000"
+        uuid_theirs = "00000000-0000-0000-0000-000000000001"
+        rev = lambda: None
+        rev.uuid = uuid_mine.encode("utf-8")
+
+        def message(mid, tags, files):
+            msg = lambda: None
+            msg.messageid = mid
+            msg.tags = set(tags)
+            msg.filenames = lambda: [Path(os.path.join(tmp, f)) for f in files]
+            return msg
+
+        db = MagicMock()
+        db.revision.return_value = rev
+        db.messages.return_value = [message("foo", ["inbox", "unread"], ["a"]), message("bar", ["inbox"], ["b", "c"]),
+                                    message("baz", ["spam"], ["Spam/d"])]
+
+        ostream = io.BytesIO()
+        assert [] == ns.run_verify(db, prefix, io.BytesIO(handshake(uuid_theirs) + frame({"verify": True})), ostream,
+                                   True, exclude=["Spam/*"])
+        mine = {"foo": {"tags": ["inbox", "unread"], "files": {"a": ns.digest(b"mail one")}},
+                "bar": {"tags": ["inbox"], "files": {"b": ns.digest(b"mail two"), "c": ns.digest(b"mail three")}}}
+        assert ostream.getvalue() == handshake(uuid_mine) + frame({"verify": True}) + frame(mine) + struct.pack("!I", 0)
+        assert 3 == len(ns.load_digests(os.path.join(tmp, ".notmuch", "notmuch-sync-digests")))
+
+        theirs = {"foo": {"tags": ["inbox"], "files": {"a": ns.digest(b"mail one"), "e": ns.digest(b"mail one")}},
+                  "bar": {"tags": ["inbox"], "files": {"b": ns.digest(b"mail 2"), "c": ns.digest(b"mail three")}},
+                  "qux": {"tags": [], "files": {"f": ns.digest(b"mail four")}}}
+        istream = io.BytesIO(handshake(uuid_theirs) + frame({"verify": True}) + frame(theirs) + struct.pack("!I", 0))
+        assert ns.run_verify(db, prefix, istream, io.BytesIO(), checksum=True, exclude=["Spam/*"]) == [
+            "bar: contents of b differ",
+            "foo: tags differ, local only: unread, remote only: -",
+            "foo: files differ, local only: -, remote only: e",
+            "qux: missing on local"]
+
+        # the remote is syncing instead
+        with pytest.raises(ns.ProtocolError) as pwe:
+            ns.run_verify(db, prefix, io.BytesIO(handshake(uuid_theirs) + info_frame()), io.BytesIO())
+        assert str(pwe.value).startswith("Other side is syncing instead of verifying, aborting")
+
+        # the remote was syncing, but switches to verifying
+        istream = io.BytesIO(handshake(uuid_theirs) + info_frame(verifies=True) + frame(theirs) + struct.pack("!I", 0))
+        assert len(ns.run_verify(db, prefix, istream, io.BytesIO(), checksum=True, exclude=["Spam/*"])) == 4
+
+
+def test_sync_local_streams():
+    args = ns.arg_parser().parse_args([])
+    db = MagicMock()
+    db.default_path.return_value = gettempdir()
+    db.config = {}
+    mock_ctx = MagicMock()
+    mock_ctx.__enter__.return_value = db
+    from_stream, to_stream = io.BytesIO(), io.BytesIO()
+    with patch("notmuch2.Database", return_value=mock_ctx), patch.object(ns.subprocess, "Popen") as popen, \
+         patch.object(ns, "lock_session") as ls, patch.object(ns, "start_journal") as sj, \
+         patch.object(ns, "initial_sync", side_effect=ValueError("foo")) as init, \
+         patch.object(ns, "write_error") as we:
+        with pytest.raises(ValueError):
+            ns.sync_local(args, None, from_stream, to_stream)
+        assert we.call_args.args[1] is to_stream
+        assert str(we.call_args.args[0]) == "foo"
+        popen.assert_not_called()
+        ls.assert_called_once_with(os.path.join(gettempdir(), ""), "local", 0)
+        sj.assert_called_once_with(os.path.join(gettempdir(), ".notmuch"))
+        assert ns.session["journal"] is None
+        assert init.call_args.args[2:4] == (from_stream, to_stream)
+    assert from_stream.closed and to_stream.closed
+
+
+def test_sync_local_status(monkeypatch):
+    args = ns.arg_parser().parse_args(["--check"])
+    db = MagicMock()
+    db.default_path.return_value = gettempdir()
+    db.config = {}
+    mock_ctx = MagicMock()
+    mock_ctx.__enter__.return_value = db
+    monkeypatch.setattr(sys, "stdout", io.StringIO())
+    reports = [{"problems": []}, {"problems": ["foo"]}]
+    with patch("notmuch2.Database", return_value=mock_ctx), patch.object(ns, "lock_session"), \
+         patch.object(ns, "run_check", return_value=reports), patch.object(ns, "format_check", return_value=[]):
+        assert ns.sync_local(args, None, io.BytesIO(), io.BytesIO()).status == 1
+        reports[1]["problems"].clear()
+        assert ns.sync_local(args, None, io.BytesIO(), io.BytesIO()).status == 0
+
+
+def test_serve_args():
+    args = argparse.Namespace(listen=("127.0.0.1", 4711), socket="/tmp/sync.sock")
+    with pytest.raises(ns.SyncError) as pwe:
+        ns.serve(args)
+    assert str(pwe.value) == "Give either an address to --listen or --socket, aborting..."
+    args = argparse.Namespace(listen=True, socket=None)
+    with pytest.raises(ns.SyncError) as pwe:
+        ns.serve(args)
+    assert str(pwe.value) == "--listen needs an address without --socket, aborting..."
+
+
+def test_error_frame():
+    stream = io.BytesIO()
+    ns.write_error(ValueError("Busy, aborting..."), stream)
+    assert stream.getvalue().startswith(b"\xff\xff\xff\xff")
+    stream.seek(0)
+    with pytest.raises(ns.RemoteError) as pwe:
+        ns.read(stream)
+    assert pwe.value.kind == "ValueError"
+    assert pwe.value.message == "Busy, aborting..."
+    assert str(pwe.value) == "ValueError: Busy, aborting..."
+    assert pwe.value.exit_code == ns.ConnectionLost.exit_code
+    assert not pwe.value.retryable
+
+    stream = io.BytesIO()
+    ns.write_error(ns.BusyError("Busy, aborting..."), stream)
+    stream.seek(0)
+    with pytest.raises(ns.RemoteError) as pwe:
+        ns.read(stream)
+    assert pwe.value.kind == "BusyError"
+    assert pwe.value.exit_code == ns.ConnectionLost.exit_code
+    assert pwe.value.retryable
+
+    stream = io.BytesIO()
+    ns.write_error(ns.ProtocolError("Unexpected frame, aborting..."), stream)
+    stream.seek(0)
+    with pytest.raises(ns.RemoteError) as pwe:
+        ns.read(stream)
+    assert pwe.value.exit_code == ns.ProtocolError.exit_code
+    assert not pwe.value.retryable
+
+
+def test_heartbeat(monkeypatch):
+    monkeypatch.setitem(ns.session, "protocol", 3)
+    stream = io.BytesIO()
+    with ns.heartbeat(stream, 0.01):
+        time.sleep(0.1)
+    assert stream.getvalue() == b""
+
+    monkeypatch.setitem(ns.session, "protocol", 4)
+    with ns.heartbeat(stream, 0.01):
+        time.sleep(0.1)
+    beats = len(stream.getvalue()) // 4
+    assert beats > 1
+    assert stream.getvalue() == ns.HEARTBEAT_MARKER * beats
+    stream.write(frame(["foo"]))
+    stream.seek(0)
+    assert ns.read(stream) == b'["foo"]'
+
+
+def test_read_timeout():
+    r, w = os.pipe()
+    with open(r, "rb") as from_stream:
+        stream = ns.ReadTimeout(from_stream, 0.2, lambda: os.close(w))
+        start = time.monotonic()
+        with pytest.raises(ns.ConnectionLost) as pwe:
+            ns.read(stream)
+        assert time.monotonic() - start >= 0.2
+        assert str(pwe.value) == "No data from remote for 0.2s, aborting..."
+        stream.close()
+        assert stream.closed
+
+
+def test_initial_sync_remote_error():
+    db = lambda: None
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.revision = MagicMock(return_value=rev)
+
+    istream = io.BytesIO()
+    ns.write_error(ValueError("Busy, another sync in progress from 192.0.2.1 (pid 42), aborting..."), istream)
+    istream.seek(0)
+    with pytest.raises(ns.RemoteError) as pwe:
+        ns.initial_sync(db, prefix, istream, io.BytesIO())
+    assert str(pwe.value) == "ValueError: Busy, another sync in progress from 192.0.2.1 (pid 42), aborting..."
+
+
+def test_initial_sync_closed():
+    db = lambda: None
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.revision = MagicMock(return_value=rev)
+
+    with pytest.raises(ValueError) as pwe:
+        ns.initial_sync(db, prefix, io.BytesIO(), io.BytesIO())
+    assert str(pwe.value).startswith("Remote closed the connection before sending its UUID, aborting")
+
+
+def test_initial_sync_handshake():
+    db = lambda: None
+    rev = lambda: None
+    rev.rev = 123
+    rev.uuid = b'00000000-0000-0000-0000-000000000000'
+    db.revision = MagicMock(return_value=rev)
+
+    with pytest.raises(ValueError) as pwe:
+        ns.initial_sync(db, prefix, io.BytesIO(b"Agent pid 105983\n" + handshake("00000000-0000-0000-0000-000000000001")),
+                        io.BytesIO())
+    assert str(pwe.value).startswith("Remote sent b'Agent pid 105983\\n")
+    assert "instead of the notmuch-sync handshake, aborting" in str(pwe.value)
+
+    old = ns.HANDSHAKE + struct.pack("!I", 0) + b"00000000-0000-0000-0000-000000000001"
+    with pytest.raises(ValueError) as pwe:
+        ns.initial_sync(db, prefix, io.BytesIO(old), io.BytesIO())
+    assert str(pwe.value) == "Remote speaks protocol version 0, but this side needs at least 1, aborting (update notmuch-sync on the remote)..."
+
+    newer = ns.HANDSHAKE + struct.pack("!I", ns.PROTOCOL_VERSION + 1) + b"00000000-0000-0000-0000-000000000001" + \
+        struct.pack("!I", 0) * 2
+    with patch.object(ns, "get_vector", return_value={}), patch.object(ns, "get_changes", return_value={}):
+        with pytest.raises(ValueError) as pwe:
+            ns.initial_sync(db, prefix, io.BytesIO(newer + frame({"vector": {}, "receive_only": False, "session": "",
+                                                                   "phases": ["delete", "mbsync"]})),
+                            io.BytesIO(), phases=["delete", "check-counts"])
+    assert ns.session["protocol"] == ns.PROTOCOL_VERSION
+    assert str(pwe.value) == "Local and remote side differ in options --check-counts, --mbsync, aborting (give the same options on both sides when using --remote-cmd)..."
+
+    with patch.object(ns, "get_vector", return_value={}), patch.object(ns, "get_changes", return_value={}):
+        with pytest.raises(ValueError) as pwe:
+            ns.initial_sync(db, prefix, io.BytesIO(newer + frame({"vector": {}, "receive_only": False, "session": "",
+                                                                   "patterns": {"include": [], "exclude": ["Spam/*"]}})),
+                            io.BytesIO(), patterns={"include": [], "exclude": ["Trash/*", "Spam/*"]})
+    assert str(pwe.value) == "Local and remote side differ in --include-pattern or --exclude-pattern, aborting (give the same options on both sides when using --remote-cmd)..."
+
+
+def test_protocol_phases():
+    args = argparse.Namespace(compress="none", delete=True, delete_folders=False, mbsync=True, check_counts=False,
+                              spot_check=None, hash_index=False, delta=False, confirm=False, preview=False,
+                              dry_run=True, frame_checksum=False, properties=False)
+    assert ns.protocol_phases(args) == ["delete", "mbsync", "dry-run"]
+    args.compress, args.delete, args.delete_folders, args.delta = "zstd", False, True, True
+    args.frame_checksum = True
+    assert ns.protocol_phases(args) == ["compress", "mbsync", "delta", "dry-run", "frame-checksum"]
+
 
-    mt = MagicMock(spec=list)
-    tags = ["foo", "bar"]
-    mt.__iter__.return_value = iter(tags)
-    mt.__len__.return_value = len(tags)
-    mt.clear = MagicMock()
-    mt.add = MagicMock()
-    mt.to_maildir_flags = MagicMock()
-    type(m).tags = PropertyMock(return_value=mt)
+def test_split_ssh_cmd():
+    assert ns.split_ssh_cmd("openssh", "ssh -CTaxq -o 'ProxyJump foo'") == ["ssh", "-CTaxq", "-o", "ProxyJump foo"]
+    assert ns.split_ssh_cmd("dropbear", ns.SSH_CLIENTS["dropbear"]) == ["dbclient", "-T"]
+    assert ns.split_ssh_cmd("plink", '"C:\\Program Files\\PuTTY\\plink.exe" -batch -i C:\\keys\\mail.ppk') == \
+        ["C:\\Program Files\\PuTTY\\plink.exe", "-batch", "-i", "C:\\keys\\mail.ppk"]
 
-    db = lambda: None
-    db.find = MagicMock(return_value=m)
 
-    changes = ns.sync_tags(db, {"foo": {"tags": ["tag1", "tag2"]}}, {"foo": {"tags": ["bar", "foobar"]}})
-    assert changes == 1
+def test_read_ssh_config():
+    with NamedTemporaryFile(mode="w+t", prefix="notmuch-sync-test-tmp-") as f:
+        f.write("""# mail server
+Host mail
+    HostName mail.example.com
+    Port 2222
+Host *.example.com !other.example.com
+    User = alice
+Match host foo
+    User bob
+Host *
+    User carol
+    IdentityFile ~/.ssh/id_ed25519
+""")
+        f.flush()
+        assert ns.read_ssh_config("mail", f.name) == {"hostname": "mail.example.com", "port": "2222",
+                                                      "user": "carol", "identityfile": "~/.ssh/id_ed25519"}
+        assert ns.read_ssh_config("foo.example.com", f.name)["user"] == "alice"
+        assert ns.read_ssh_config("other.example.com", f.name)["user"] == "carol"
+    assert ns.read_ssh_config("mail", os.path.join(gettempdir(), "notmuch-sync-no-such-file")) == {}
+
+
+def test_ssh_destination():
+    assert ns.ssh_destination("openssh", "mail", "alice") == ["alice@mail"]
+    assert ns.ssh_destination("openssh", "mail", None) == ["mail"]
+    opts = {"hostname": "%h.example.com", "port": "2222", "user": "carol", "proxyjump": "jump"}
+    with patch.object(ns, "read_ssh_config", return_value=opts) as rsc:
+        assert ns.ssh_destination("dropbear", "mail", None) == ["-p", "2222", "jump,carol@mail.example.com"]
+        rsc.assert_called_once_with("mail")
+        assert ns.ssh_destination("plink", "mail", "alice") == \
+            ["-P", "2222", "-proxycmd", "plink -batch -nc %host:%port jump", "alice@mail.example.com"]
+    with patch.object(ns, "read_ssh_config", return_value={}):
+        assert ns.ssh_destination("plink", "mail", None) == ["mail"]
+
+
+def test_ssh_master():
+    with patch("subprocess.run") as run:
+        run.return_value.returncode = 0
+        control = ns.ssh_master(["ssh", "-CTaxq"], "user@host")
+        assert control == os.path.join(gettempdir(), f"notmuch-sync-{os.getpid()}-%C")
+        run.assert_called_once_with(["ssh", "-CTaxq", "-o", "ControlMaster=yes", "-o", "ControlPersist=10",
+                                     "-o", f"ControlPath={control}", "user@host", "true"], check=False)
+
+        run.reset_mock()
+        ns.ssh_master(["ssh"], "host", persist="yes")
+        run.assert_called_once_with(["ssh", "-o", "ControlMaster=yes", "-o", "ControlPersist=yes",
+                                     "-o", f"ControlPath={control}", "host", "true"], check=False)
+
+        run.return_value.returncode = 255
+        with pytest.raises(ValueError) as pwe:
+            ns.ssh_master(["ssh"], "host")
+        assert str(pwe.value) == "Connecting to host for authentication failed with exit code 255, aborting..."
 
-    db.find.assert_called_once_with("foo")
-    m.frozen.assert_called_once()
-    mt.clear.assert_called_once()
-    assert mt.add.mock_calls == [
-        call("bar"),
-        call("foobar"),
-        call("tag1"),
-        call("tag2")
-    ]
-    mt.to_maildir_flags.assert_called_once()
 
+def test_run_async_remote_error():
+    def _send():
+        raise BrokenPipeError()
 
-def test_sync_server(monkeypatch):
-    args = lambda: None
-    args.delete = False
-    args.mbsync = False
+    def _recv():
+        raise ns.RemoteError("ValueError", "foo")
 
-    db = lambda: None
-    rev = lambda: None
-    rev.rev = 124
-    rev.uuid = b'00000000-0000-0000-0000-000000000000'
-    db.revision = MagicMock(return_value=rev)
-    db.default_path = MagicMock(return_value=gettempdir())
+    with pytest.raises(ns.RemoteError):
+        ns.run_async(_send, _recv)
+    with pytest.raises(ns.RemoteError):
+        ns.run_async(_recv, _send)
 
-    mock_ctx = MagicMock()
-    mock_ctx.__enter__.return_value = db
-    mock_ctx.__exit__.return_value = False
 
-    fname = os.path.join(gettempdir(), ".notmuch", "notmuch-sync-00000000-0000-0000-0000-000000000001")
-    with patch("notmuch2.Database", return_value=mock_ctx):
-        with patch.object(ns, "get_changes", return_value=[]) as gc:
-            with patch("builtins.open", mock_open()) as o:
-                mockio = io.BytesIO(b'00000000-0000-0000-0000-000000000001\x00\x00\x00\x02{}\x00\x00\x00\x02[]\x00\x00\x00\x02[]\x00\x00\x00\x02[]')
-                mockio.buffer = mockio
-                monkeypatch.setattr(sys, "stdin", mockio)
-                ns.sync_remote(args)
-                o.assert_called_once_with(fname, "w", encoding="utf-8")
-                hdl = o()
-                hdl.write.assert_called_once()
-                args = hdl.write.call_args.args
-                assert "124 00000000-0000-0000-0000-000000000000" == args[0]
-            gc.assert_called_once_with(db, rev, prefix, fname)
+def test_digest_files():
+    with TemporaryDirectory() as tmp:
+        fnames = []
+        for i in range(5):
+            fnames.append(os.path.join(tmp, str(i)))
+            Path(fnames[-1]).write_bytes(f"mail {i}".encode("utf-8"))
+        digests = [ns.digest(f"mail {i}".encode("utf-8")) for i in range(5)]
+        assert ns.digest_files(fnames, 2) == digests
+        assert ns.digest_files(fnames) == digests
+        assert ns.digest_files([]) == []
 
-    assert db.revision.call_count == 2
-    db.default_path.assert_called_once()
+
+def test_digest_files_cache():
+    with TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "mail")
+        Path(fname).write_bytes(b"mail one")
+        cache = {}
+        assert ns.digest_files([fname], cache=cache) == [ns.digest(b"mail one")]
+        st = os.stat(fname)
+        assert cache == {fname: [st.st_size, st.st_mtime_ns, st.st_ino, ns.digest(b"mail one")]}
+
+        # unchanged size, mtime, and inode -- digest not computed again
+        cache[fname][3] = "foo"
+        with patch.object(ns, "digest") as d:
+            assert ns.digest_files([fname], cache=cache) == ["foo"]
+            d.assert_not_called()
+        # no cache -- always computed
+        assert ns.digest_files([fname]) == [ns.digest(b"mail one")]
+
+        Path(fname).write_bytes(b"mail three")
+        assert ns.digest_files([fname], cache=cache) == [ns.digest(b"mail three")]
+
+        cache_fname = os.path.join(tmp, "notmuch-sync-digests")
+        assert ns.load_digests(cache_fname) == {}
+        ns.save_digests(cache_fname, cache | {os.path.join(tmp, "gone"): [1, 2, 3, "bar"]})
+        assert ns.load_digests(cache_fname) == cache
 
 
 def test_missing_files_empty():
@@ -427,7 +1965,7 @@ def test_missing_files_inconsistent_no_move():
                 assert db.remove.call_count == 0
                 assert db.find.mock_calls == [ call("foo"), call("foo") ]
 
-    assert m.filenames.call_count == 3
+    assert m.filenames.call_count == 2
 
 
 def test_missing_files_inconsistent_move():
@@ -459,7 +1997,7 @@ def test_missing_files_inconsistent_move():
                 sm.assert_called_once_with(f1.name, f2.name)
                 db.add.assert_called_once_with(f2.name)
                 db.remove.assert_called_once_with(f1.name)
-                assert m.filenames.call_count == 3
+                assert m.filenames.call_count == 2
 
     assert db.find.mock_calls == [ call("foo"), call("foo") ]
 
@@ -500,7 +2038,7 @@ def test_missing_files_multiple_dups():
                         assert sm.mock_calls == [ call(f1.name, f3.name), call(f2.name, f4.name) ]
                         assert db.add.mock_calls == [ call(f3.name), call(f4.name) ]
                         assert db.remove.mock_calls == [ call(f1.name), call(f2.name) ]
-                        assert m.filenames.call_count == 3
+                        assert m.filenames.call_count == 2
 
     assert db.find.mock_calls == [ call("foo"), call("foo") ]
 
@@ -540,7 +2078,7 @@ def test_missing_files_multiple_dups_copy_move():
                         assert sc.mock_calls == [ call(f2.name, f3.name) ]
                         assert db.add.mock_calls == [ call(f2.name), call(f3.name) ]
                         assert db.remove.mock_calls == [ call(f1.name) ]
-                        assert m.filenames.call_count == 3
+                        assert m.filenames.call_count == 2
 
     assert db.find.mock_calls == [ call("foo"), call("foo") ]
 
@@ -571,7 +2109,7 @@ def test_missing_files_moved():
                 sm.assert_called_once_with(f1.name, f2.name)
                 db.add.assert_called_once_with(f2.name)
                 db.remove.assert_called_once_with(f1.name)
-                assert m.filenames.call_count == 3
+                assert m.filenames.call_count == 2
 
     assert db.find.mock_calls == [ call("foo"), call("foo") ]
 
@@ -589,7 +2127,7 @@ def test_missing_files_copied():
     f.close()
     with patch("shutil.copy") as sc:
         with NamedTemporaryFile(mode="w+t", prefix="notmuch-sync-test-tmp-") as f1:
-            istream = io.BytesIO(b"\x00\x00\x00\x02[]\x00\x00\x00\x88[\"a983f58ef9ef755c4e5e3755f10cf3e08d9b189b388bcb59d29b56d35d7d6b9d\", \"a983f58ef9ef755c4e5e3755f10cf3e08d9b189b388bcb59d29b56d35d7d6b9d\"]")
+            istream = io.BytesIO(b"\x00\x00\x00\x02[]" + frame(["a983f58ef9ef755c4e5e3755f10cf3e08d9b189b388bcb59d29b56d35d7d6b9d"]))
             ostream = io.BytesIO()
             m.filenames = MagicMock(return_value=[f1.name])
             f1.write("mail one")
@@ -598,12 +2136,12 @@ def test_missing_files_copied():
             f1name = f1.name.removeprefix(prefix)
             changes = {"foo": {"tags": ["foo"], "files": [f1name, fname]}}
             assert ({}, 1, 0) == ns.get_missing_files(db, prefix, {}, changes, istream, ostream)
-            tmp = json.dumps([f1name, fname])
+            tmp = json.dumps([fname])
             assert struct.pack("!I", len(tmp)) + tmp.encode("utf-8") + b"\x00\x00\x00\x02[]" == ostream.getvalue()
 
             sc.assert_called_once_with(f1.name, f.name)
 
-    assert m.filenames.call_count == 3
+    assert m.filenames.call_count == 2
     assert db.find.mock_calls == [ call("foo"), call("foo") ]
     db.add.assert_called_once_with(f.name)
 
@@ -619,7 +2157,7 @@ def test_missing_files_added():
         with patch("shutil.move") as sm:
             with patch("pathlib.Path.unlink") as pu:
                 with NamedTemporaryFile(mode="w+t", prefix="notmuch-sync-test-tmp-") as f1:
-                    istream = io.BytesIO(b"\x00\x00\x00\x02[]\x00\x00\x00\x4B[\"a983f58ef9ef755c4e5e3755f10cf3e08d9b189b388bcb59d29b56d35d7d6b9d\", \"abc\"]")
+                    istream = io.BytesIO(b"\x00\x00\x00\x02[]" + frame(["abc"]))
                     ostream = io.BytesIO()
                     m.filenames = MagicMock(return_value=[f1.name])
                     f1.write("mail one")
@@ -628,7 +2166,7 @@ def test_missing_files_added():
                     changes = {"foo": {"tags": ["foo"], "files": [f1name, "bar"]}}
                     exp = {"foo": {"files": ["bar"]}}
                     assert (exp, 0, 0) == ns.get_missing_files(db, prefix, {}, changes, istream, ostream)
-                    tmp = json.dumps([f1name, "bar"])
+                    tmp = json.dumps(["bar"])
                     assert struct.pack("!I", len(tmp)) + tmp.encode("utf-8") + b"\x00\x00\x00\x02[]" == ostream.getvalue()
                     assert pu.call_count == 0
 
@@ -636,7 +2174,83 @@ def test_missing_files_added():
             assert sc.call_count == 0
 
     assert db.find.mock_calls == [ call("foo"), call("foo") ]
-    assert m.filenames.call_count == 3
+    assert m.filenames.call_count == 2
+
+
+def test_missing_files_flags_rename():
+    m = MagicMock()
+    m.ghost = False
+    db = lambda: None
+
+    db.find = MagicMock(return_value=m)
+    db.add = MagicMock()
+    db.remove = MagicMock()
+
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        os.mkdir(os.path.join(tmp, "cur"))
+        Path(os.path.join(tmp, "cur", "1234:2,RS")).write_text("mail one")
+        m.filenames = MagicMock(return_value=[os.path.join(tmp, "cur", "1234:2,RS")])
+        changes_theirs = {"foo": {"tags": ["foo"], "files": ["cur/1234:2,S"]}}
+        hashes = frame(["a983f58ef9ef755c4e5e3755f10cf3e08d9b189b388bcb59d29b56d35d7d6b9d"])
+        with patch("shutil.move") as sm:
+            for changes_mine in [{}, {"foo": {"tags": ["foo"], "files": ["cur/1234:2,RS"]}}]:
+                istream = io.BytesIO(frame([]) + hashes)
+                assert ({}, 0, 0) == ns.get_missing_files(db, pre, changes_mine, changes_theirs, istream, io.BytesIO(),
+                                                          move_on_change=True)
+            assert sm.call_count == 0
+        assert os.path.exists(os.path.join(tmp, "cur", "1234:2,RS"))
+    assert db.add.call_count == 0
+    assert db.remove.call_count == 0
+
+
+def test_flags_only_rename():
+    assert ns.flags_only_rename("foo/cur/1234:2,S", "foo/cur/1234:2,RS")
+    assert not ns.flags_only_rename("foo/cur/1234:2,S", "foo/cur/1234:2,S")
+    assert not ns.flags_only_rename("foo/cur/1234:2,S", "bar/cur/1234:2,S")
+    assert not ns.flags_only_rename("foo/cur/1234:2,S", "foo/cur/1235:2,S")
+    assert not ns.flags_only_rename("foo/new/1234", "foo/cur/1234:2,S")
+
+
+def test_included():
+    assert ns.included("foo/cur/1", None, None)
+    assert not ns.included("foo/cur/1.gz", [], ["*.gz"])
+    assert ns.included("foo/cur/1", ["foo/*"], ["*.gz"])
+    assert not ns.included("bar/cur/1", ["foo/*"], ["*.gz"])
+    assert not ns.included("foo/cur/1.gz", ["foo/*"], ["*.gz"])
+
+
+def test_missing_files_excluded():
+    m = MagicMock()
+    m.ghost = False
+    db = lambda: None
+
+    db.find = MagicMock(return_value=m)
+    db.add = MagicMock()
+    db.remove = MagicMock()
+
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        Path(os.path.join(tmp, "a")).write_text("mail one")
+        m.filenames = MagicMock(return_value=[os.path.join(tmp, "a")])
+        # moved to an excluded name on the remote -- neither moved nor deleted
+        changes_theirs = {"foo": {"tags": ["foo"], "files": ["a.gz"]}}
+        istream = io.BytesIO(frame([]) + frame([]))
+        ostream = io.BytesIO()
+        assert ({}, 0, 0) == ns.get_missing_files(db, pre, {}, changes_theirs, istream, ostream, exclude=["*.gz"])
+        assert frame([]) + frame([]) == ostream.getvalue()
+        assert os.path.exists(os.path.join(tmp, "a"))
+        assert db.add.call_count == 0
+        assert db.remove.call_count == 0
+
+        # no digests are requested for excluded files
+        changes_theirs = {"foo": {"tags": ["foo"], "files": ["b", "c.gz"]}}
+        istream = io.BytesIO(frame([]) + frame(["a983f58ef9ef755c4e5e3755f10cf3e08d9b189b388bcb59d29b56d35d7d6b9d"]))
+        ostream = io.BytesIO()
+        assert ({}, 1, 0) == ns.get_missing_files(db, pre, {}, changes_theirs, istream, ostream, exclude=["*.gz"])
+        assert frame(["b"]) + frame([]) == ostream.getvalue()
+        assert os.path.exists(os.path.join(tmp, "b"))
+        assert not os.path.exists(os.path.join(tmp, "a"))
 
 
 def test_missing_files_delete():
@@ -742,7 +2356,7 @@ def test_missing_files_copy_delete():
                         pu.assert_called_once()
 
     assert db.find.mock_calls == [ call("foo"), call("foo") ]
-    assert m.filenames.call_count == 3
+    assert m.filenames.call_count == 2
 
 
 def test_missing_files_delete_mismatch():
@@ -768,7 +2382,7 @@ def test_missing_files_delete_mismatch():
                 changes_theirs = {"foo": {"tags": ["foo"], "files": [f2name]}}
                 with pytest.raises(ValueError) as pwe:
                     ns.get_missing_files(db, prefix, {}, changes_theirs, istream, ostream)
-                assert pwe.type == ValueError
+                assert pwe.type == ns.ConflictError
                 assert str(pwe.value) == f"Message 'foo' has ['{f2name}'] on remote and different ['{f1.name.removeprefix(prefix)}'] locally!"
                 tmp = json.dumps([f2name])
                 assert struct.pack("!I", len(tmp)) + tmp.encode("utf-8") + b"\x00\x00\x00\x02[]" == ostream.getvalue()
@@ -777,7 +2391,7 @@ def test_missing_files_delete_mismatch():
                 assert pu.call_count == 0
 
     assert db.find.mock_calls == [ call("foo"), call("foo") ]
-    assert m.filenames.call_count == 3
+    assert m.filenames.call_count == 2
 
 
 def test_send_file():
@@ -791,22 +2405,282 @@ def test_send_file():
         assert b"\x00\x00\x00\x0email one\nmail\n" == out
 
 
+def test_send_file_sendfile():
+    with TemporaryDirectory() as tmp:
+        data = os.urandom(ns.COALESCE_SIZE + 42)
+        Path(os.path.join(tmp, "mail")).write_bytes(data)
+        with open(os.path.join(tmp, "out"), "wb") as out:
+            out.write(b"foo")
+            with patch("os.sendfile", wraps=os.sendfile) as sf, patch.dict(ns.session, protocol=1):
+                ns.send_file(os.path.join(tmp, "mail"), out)
+                assert sf.call_count >= 1
+        assert Path(os.path.join(tmp, "out")).read_bytes() == b"foo" + struct.pack("!I", len(data)) + data
+    assert ns.stream_fd(io.BytesIO()) is None
+
+
+def test_send_file_too_large():
+    with TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "mail")
+        Path(fname).write_bytes(b"x" * 20)
+        stream = io.BytesIO()
+        with patch.object(ns, "MAX_FRAME", 10), patch.dict(ns.session, protocol=1, compress=""):
+            with pytest.raises(ns.ProtocolError) as pwe:
+                ns.send_file(fname, stream)
+            assert str(pwe.value) == (f"'{fname}' has 20 bytes, but the other side speaks protocol version 1, which "
+                                      "can't receive files larger than 10 bytes (update notmuch-sync there), aborting...")
+            assert stream.getvalue() == b""
+            with pytest.raises(ns.ProtocolError) as pwe:
+                ns.write(b"x" * 11, stream)
+            assert str(pwe.value) == "Tried to write 11 bytes in one frame, but frames hold at most 10, aborting..."
+            assert stream.getvalue() == b""
+            ns.write(b"x" * 10, stream)
+            assert stream.getvalue() == b"\x00\x00\x00\x0a" + b"x" * 10
+
+
+def test_send_recv_file_stream():
+    with TemporaryDirectory() as tmp:
+        data = os.urandom(2 * ns.STREAM_CHUNK + 42)
+        Path(os.path.join(tmp, "mail")).write_bytes(data)
+        chunks = [data[:ns.STREAM_CHUNK], data[ns.STREAM_CHUNK:2 * ns.STREAM_CHUNK], data[2 * ns.STREAM_CHUNK:]]
+        streamed = ns.STREAM_MARKER + struct.pack("!Q", len(data)) + b"".join(
+            struct.pack("!I", len(c)) + c for c in chunks) + b"\x00\x00\x00\x00"
+        with patch.dict(ns.session, protocol=2):
+            stream = io.BytesIO()
+            ns.send_file(os.path.join(tmp, "mail"), stream)
+            assert stream.getvalue() == streamed
+            with open(os.path.join(tmp, "out"), "wb") as out:
+                with patch("os.sendfile", wraps=os.sendfile) as sf:
+                    ns.send_file(os.path.join(tmp, "mail"), out)
+                    assert sf.call_count == 3
+            assert Path(os.path.join(tmp, "out")).read_bytes() == streamed
+            # small files are still sent in one frame
+            Path(os.path.join(tmp, "small")).write_bytes(b"foo")
+            stream = io.BytesIO()
+            ns.send_file(os.path.join(tmp, "small"), stream)
+            assert stream.getvalue() == b"\x00\x00\x00\x03foo"
+
+        store = os.path.join(tmp, "store")
+        ns.recv_file(os.path.join(tmp, "a", "cur", "1"), io.BytesIO(streamed), store=store)
+        assert Path(os.path.join(tmp, "a", "cur", "1")).read_bytes() == data
+        assert os.stat(os.path.join(tmp, "a", "cur", "1")).st_nlink == 2
+        ns.recv_file(os.path.join(tmp, "a", "cur", "2"), io.BytesIO(streamed), store=store)
+        assert os.path.samefile(os.path.join(tmp, "a", "cur", "1"), os.path.join(tmp, "a", "cur", "2"))
+
+        with pytest.raises(ValueError) as pwe:
+            ns.recv_file(os.path.join(tmp, "a", "cur", "3"), io.BytesIO(streamed[:-4 - len(chunks[2]) - 4] + b"\x00\x00\x00\x00"))
+        assert str(pwe.value) == f"Receiving '{os.path.join(tmp, 'a', 'cur', '3')}', but got {2 * ns.STREAM_CHUNK} of {len(data)} bytes, aborting..."
+        with pytest.raises(ValueError) as pwe:
+            ns.recv_file(os.path.join(tmp, "mail"), io.BytesIO(streamed.replace(data[:10], b"x" * 10)))
+        qname = os.path.join(tmp, "mail.conflict-" + ns.digest(data.replace(data[:10], b"x" * 10))[:16])
+        assert str(pwe.value) == f"Receiving '{os.path.join(tmp, 'mail')}', but already exists with different content, kept received file as '{qname}'!"
+        assert Path(qname).read_bytes() == data.replace(data[:10], b"x" * 10)
+        assert sorted(os.listdir(os.path.join(tmp, "a", "cur"))) == ["1", "2"]
+        assert ".notmuch-sync-tmp" not in "".join(os.listdir(tmp))
+
+
+def test_stream_chunk():
+    with patch.dict(ns.session, rtt=None, throughput=None):
+        assert ns.stream_chunk() == ns.STREAM_CHUNK
+        ns.measure_throughput(1000, 0.001)
+        assert ns.session["throughput"] == 1e6
+        # no round-trip time with peers before protocol version 5
+        assert ns.stream_chunk() == ns.STREAM_CHUNK
+        ns.measure_throughput(1000, 0.0005)
+        assert round(ns.session["throughput"]) == 1200000
+
+    # fast local network, slow long-distance one, and the bounds
+    for rtt, throughput, size in [(0.0005, 100e6, 50000), (0.1, 10e6, 1000000), (0.0001, 1e6, ns.MIN_CHUNK),
+                                  (1, 100e6, ns.MAX_CHUNK)]:
+        with patch.dict(ns.session, rtt=rtt, throughput=throughput):
+            assert ns.stream_chunk() == size
+
+    with TemporaryDirectory() as tmp:
+        data = os.urandom(100000)
+        Path(os.path.join(tmp, "mail")).write_bytes(data)
+        stream = io.BytesIO()
+        with patch.dict(ns.session, protocol=5), \
+             patch.object(ns, "stream_chunk", side_effect=[ns.MIN_CHUNK, 3 * ns.MIN_CHUNK, ns.MAX_CHUNK]):
+            ns.send_file(os.path.join(tmp, "mail"), stream)
+        chunks = [data[:ns.MIN_CHUNK], data[ns.MIN_CHUNK:4 * ns.MIN_CHUNK], data[4 * ns.MIN_CHUNK:]]
+        assert stream.getvalue() == ns.STREAM_MARKER + struct.pack("!Q", len(data)) + b"".join(
+            struct.pack("!I", len(c)) + c for c in chunks) + b"\x00\x00\x00\x00"
+        ns.recv_file(os.path.join(tmp, "out"), io.BytesIO(stream.getvalue()))
+        assert Path(os.path.join(tmp, "out")).read_bytes() == data
+
+
+def test_exchange_uuids_rtt():
+    mine, theirs = "00000000-0000-0000-0000-000000000000", "00000000-0000-0000-0000-000000000001"
+    with patch.dict(ns.session, rtt=None):
+        ostream = io.BytesIO()
+        assert ns.exchange_uuids(mine, io.BytesIO(handshake(theirs)), ostream) == theirs
+        assert ostream.getvalue() == handshake(mine)
+        assert ns.session["rtt"] >= 0
+
+        # peers before protocol version 5 don't measure it
+        ns.session["rtt"] = None
+        ostream = io.BytesIO()
+        old = ns.HANDSHAKE + struct.pack("!I", 4) + theirs.encode("utf-8")
+        assert ns.exchange_uuids(mine, io.BytesIO(old), ostream) == theirs
+        assert ostream.getvalue() == ns.HANDSHAKE + struct.pack("!I", ns.PROTOCOL_VERSION) + mine.encode("utf-8")
+        assert (ns.session["protocol"], ns.session["rtt"]) == (4, None)
+
+
+def test_compress_frame():
+    text = b"Subject: foo\n" * 100
+    assert ns.compress_frame(text)[:1] == b"\x02"
+    assert ns.decompress_frame(ns.compress_frame(text)) == text
+    assert ns.decompress_frame(b"\x01" + zlib.compress(text)) == text
+    # preset dictionary helps with small frames
+    changes = json.dumps({"87d1dajhgf.fsf@example.net": {"tags": ["inbox", "unread"],
+                                                         "files": ["INBOX/cur/1234.5678:2,S"]}}).encode("utf-8")
+    assert len(ns.compress_frame(changes)) < 0.8 * len(zlib.compress(changes))
+    # too small and incompressible data are sent raw
+    assert ns.compress_frame(b"foo") == b"\x00foo"
+    noise = os.urandom(10000)
+    assert ns.compress_frame(noise) == b"\x00" + noise
+    assert ns.decompress_frame(b"\x00" + noise) == noise
+    with pytest.raises(ValueError) as pwe:
+        ns.decompress_frame(b"\x07foo")
+    assert str(pwe.value) == "Unknown frame compression b'\\x07', aborting..."
+
+
+def test_compress_frame_algorithms():
+    text = b"Subject: foo\n" * 100
+    zstd = MagicMock()
+    zstd.ZstdCompressor.return_value.compress.return_value = b"zstd"
+    zstd.ZstdDecompressor.return_value.decompress.return_value = text
+    lz4 = MagicMock()
+    lz4.compress.return_value = b"lz4"
+    lz4.decompress.return_value = text
+    with patch.object(ns, "zstandard", zstd), patch.object(ns, "lz4frame", lz4):
+        assert ns.compress_frame(text, "zstd:19") == b"\x03zstd"
+        zstd.ZstdCompressor.assert_called_once_with(level=19)
+        assert ns.decompress_frame(b"\x03zstd") == text
+        assert ns.compress_frame(text, "lz4") == b"\x04lz4"
+        assert ns.decompress_frame(b"\x04lz4") == text
+    with patch.object(ns, "zstandard", None):
+        with pytest.raises(ValueError) as pwe:
+            ns.decompress_frame(b"\x03zstd")
+        assert str(pwe.value) == "Unknown frame compression b'\\x03', aborting..."
+    assert len(ns.compress_frame(text, "zlib:1")) < len(text)
+
+
+def test_parse_compress():
+    assert ns.parse_compress("zlib") == "zlib"
+    assert ns.parse_compress("zstd:19") == "zstd:19"
+    assert ns.parse_compress("none") == "none"
+    assert ns.parse_compress("gzip") == "zlib"
+    assert ns.parse_compress("gzip:6") == "zlib:6"
+    with pytest.raises(argparse.ArgumentTypeError) as pwe:
+        ns.parse_compress("brotli")
+    assert str(pwe.value) == "unknown compression 'brotli', choose from none, zlib, zstd, lz4"
+    with pytest.raises(argparse.ArgumentTypeError) as pwe:
+        ns.parse_compress("zlib:12")
+    assert str(pwe.value) == "invalid level '12' for compression 'zlib'"
+    with pytest.raises(argparse.ArgumentTypeError):
+        ns.parse_compress("lz4:3")
+
+
+def test_negotiate_compression():
+    with patch.object(ns, "zstandard", MagicMock()), patch.object(ns, "lz4frame", None), \
+         patch.object(ns.logger, "warning") as warn:
+        assert ns.available_compression() == ["zlib", "zstd"]
+        assert ns.negotiate_compression("zstd:3", ["zlib", "zstd"]) == "zstd:3"
+        warn.assert_not_called()
+        assert ns.negotiate_compression("zstd:3", ["zlib"]) == "zlib"
+        warn.assert_called_once_with("%s compression not available on the other side, using zlib.", "zstd")
+        warn.reset_mock()
+        assert ns.negotiate_compression("lz4", ["zlib", "lz4"]) == "zlib"
+        warn.assert_called_once_with("%s compression not available here, using zlib.", "lz4")
+
+
+def test_write_read_compressed():
+    text = b"Subject: foo\n" * 100
+    stream = io.BytesIO()
+    with patch.dict(ns.session, compress="zlib:9"):
+        ns.write(text, stream)
+        assert len(stream.getvalue()) < len(text)
+        ns.write(b"foo", stream)
+        stream.seek(0)
+        assert ns.read(stream) == text
+        assert ns.read(stream) == b"foo"
+
+
+def test_write_read_checksum():
+    text = b"Subject: foo\n" * 10000
+    with patch.dict(ns.session, crc=True, compress=""):
+        stream = io.BytesIO()
+        ns.write(text, stream)
+        ns.write(b"foo", stream)
+        assert stream.getvalue()[-11:] == b"\x00\x00\x00\x03foo" + struct.pack("!I", zlib.crc32(b"foo"))
+        stream.seek(0)
+        assert ns.read(stream) == text
+        assert ns.read(stream) == b"foo"
+
+        with pytest.raises(ns.ProtocolError) as pwe:
+            ns.read(io.BytesIO(stream.getvalue().replace(b"foo\n", b"fob\n", 1)))
+        assert str(pwe.value) == f"Checksum of frame of {len(text)} bytes doesn't match, data was corrupted in transit, aborting..."
+        with pytest.raises(ns.ConnectionLost):
+            ns.read(io.BytesIO(b"\x00\x00\x00\x03foo\x00"))
+
+        # files are written through frames to be checksummed instead of with sendfile()
+        with TemporaryDirectory() as tmp:
+            Path(os.path.join(tmp, "mail")).write_bytes(text)
+            with open(os.path.join(tmp, "out"), "wb") as out, patch("os.sendfile") as sf, \
+                    patch.dict(ns.session, protocol=1):
+                ns.send_file(os.path.join(tmp, "mail"), out)
+                assert sf.call_count == 0
+            with open(os.path.join(tmp, "out"), "rb") as f:
+                assert ns.read(f) == text
+
+
+def test_write_coalesce():
+    stream = MagicMock()
+    stream.write.side_effect = len
+    ns.write(b"foo", stream, False)
+    stream.write.assert_called_once_with(b"\x00\x00\x00\x03foo")
+    stream.flush.assert_not_called()
+
+    stream.reset_mock()
+    data = b"x" * ns.COALESCE_SIZE
+    ns.write(data, stream)
+    assert stream.write.call_args_list == [call(struct.pack("!I", len(data))), call(data)]
+    stream.flush.assert_called_once()
+
+    stream.write.side_effect = lambda b: len(b) - 1
+    with pytest.raises(ValueError) as pwe:
+        ns.write(b"foo", stream)
+    assert str(pwe.value) == "Tried to write 7 bytes, but wrote only 6, aborting..."
+
 
 def test_recv_file():
     fname = "foo"
-    with patch("builtins.open", mock_open()) as o:
+    with patch("builtins.open", mock_open()) as o, patch("os.replace") as rep, patch("os.fsync"):
         stream = io.BytesIO(b"\x00\x00\x00\x0email one\nmail\n")
         ns.recv_file("foo", stream, "3d0ea99df44f734ef462d85bfeb1352edcb7af528f3386cdaa0939ac27cd8cb3")
-        o.assert_called_once_with("foo", "wb")
+        o.assert_called_once_with("foo.notmuch-sync-tmp", "wb")
+        rep.assert_called_once_with("foo.notmuch-sync-tmp", "foo")
         hdl = o()
         hdl.write.assert_called_once()
         args = hdl.write.call_args.args
         assert b"mail one\nmail\n" == args[0]
 
 
+def test_write_file_fsync():
+    with TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "a", "cur", "1")
+        with patch.object(ns.os, "fsync", wraps=os.fsync) as fs:
+            ns.write_file(fname, b"mail one\n")
+        assert Path(fname).read_bytes() == b"mail one\n"
+        # the file and its directory
+        assert fs.call_count == 2
+        assert os.listdir(os.path.dirname(fname)) == ["1"]
+
+
 def test_recv_file_exists():
     fname = "foo"
-    with patch("builtins.open", mock_open()) as o:
+    qname = "foo.conflict-" + ns.digest(b"mail one\nmail\n")[:16]
+    with patch("builtins.open", mock_open()) as o, patch.object(ns.os, "replace") as rep, patch.object(ns.os, "fsync"):
         with patch("pathlib.Path.exists") as pe:
             with patch("pathlib.Path.read_bytes") as prb:
                 pe.return_value = True
@@ -814,23 +2688,82 @@ def test_recv_file_exists():
                 stream = io.BytesIO(b"\x00\x00\x00\x0email one\nmail\n")
                 with pytest.raises(ValueError) as pwe:
                     ns.recv_file("foo", stream, "3d0ea99df44f734ef462d85bfeb1352edcb7af528f3386cdaa0939ac27cd8cb3")
-                assert pwe.type == ValueError
-                assert str(pwe.value) == "Receiving 'foo', but already exists with different content!"
+                assert pwe.type == ns.ConflictError
+                assert str(pwe.value) == f"Receiving 'foo', but already exists with different content, kept received file as '{qname}'!"
                 assert pe.call_count == 1
-                assert o.call_count == 0
+                o.assert_called_once_with(qname + ".notmuch-sync-tmp", "wb")
+                rep.assert_called_once_with(qname + ".notmuch-sync-tmp", qname)
+
+
+def test_recv_file_store():
+    with TemporaryDirectory() as tmp:
+        store = os.path.join(tmp, "store")
+        f1 = os.path.join(tmp, "a", "cur", "1")
+        f2 = os.path.join(tmp, "b", "cur", "2")
+        ns.recv_file(f1, io.BytesIO(frame("mail one")), store=store)
+        ns.write_file(f2, b'"mail one"', store=store)
+        assert os.path.samefile(f1, f2)
+        assert os.stat(f1).st_nlink == 3
+        assert b'"mail one"' == Path(f2).read_bytes()
+        # replacing a file with the same contents links it as well
+        Path(f2).unlink()
+        Path(f2).write_bytes(b'"mail one"')
+        ns.write_file(f2, b'"mail one"', store=store)
+        assert os.path.samefile(f1, f2)
+
+        with patch.dict(ns.session, {"store": store}):
+            ns.copy_file(f1, os.path.join(tmp, "c", "cur", "3"))
+        assert os.stat(f1).st_nlink == 4
+
+
+def test_prune_store():
+    with TemporaryDirectory() as tmp:
+        store = os.path.join(tmp, "store")
+        f1 = os.path.join(tmp, "1")
+        f2 = os.path.join(tmp, "2")
+        ns.write_file(f1, b"mail one", store=store)
+        ns.write_file(f2, b"mail two", store=store)
+        assert 0 == ns.prune_store(store)
+        Path(f2).unlink()
+        assert 1 == ns.prune_store(store)
+        assert 1 == len(list(Path(store).iterdir()))
+        assert os.path.exists(f1)
 
 
 def test_sync_files_nothing():
     db = lambda: None
-    istream = io.BytesIO(b"\x00\x00\x00\x02[]")
+    db.atomic = MagicMock()
+    istream = io.BytesIO(b"\x00\x00\x00\x02[]\x00\x00\x00\x02[]")
     ostream = io.BytesIO()
     assert (0, 0) == ns.sync_files(db, prefix, {}, istream, ostream)
     out = ostream.getvalue()
-    assert b"\x00\x00\x00\x02[]" == out
+    assert b"\x00\x00\x00\x02[]\x00\x00\x00\x02[]" == out
+
+
+def test_sync_files_receive_only():
+    db = lambda: None
+    istream = io.BytesIO(b"\x00\x00\x00\x07[\"foo\"]")
+    ostream = io.BytesIO()
+    with pytest.raises(ns.ProtocolError) as pwe:
+        ns.sync_files(db, prefix, {}, istream, ostream, receive_only=True)
+    assert str(pwe.value) == "Remote requested 1 files from receive-only side, aborting..."
+
+
+def test_sync_files_dry_run():
+    db = lambda: None
+    stats = ns.SyncStats()
+    ns.current_stats.set(stats)
+    istream = io.BytesIO(frame(["bar"]))
+    ostream = io.BytesIO()
+    with patch.dict(ns.session, dry_run=True):
+        assert (0, 0) == ns.sync_files(db, prefix, {"foo": {"files": ["foo"]}}, istream, ostream)
+    assert frame(["foo"]) == ostream.getvalue()
+    assert stats.actions == [f"receive {prefix}foo"]
 
 
 def test_sync_files_recv_add():
-    istream = io.BytesIO(b"\x00\x00\x00\x02[]\x00\x00\x00\x09mail one\n\x00\x00\x00\x09mail two\n")
+    istream = io.BytesIO(b"\x00\x00\x00\x02[]" + frame([None, None]) + b"\x00\x00\x00\x09mail one\n\x00\x00\x00\x09mail two\n" +
+                         ACK)
     ostream = io.BytesIO()
 
     # this is only to get filenames that are guaranteed to be unique
@@ -843,13 +2776,17 @@ def test_sync_files_recv_add():
     missing = {"foo": {"files": [f1name, f2name]}}
 
     db = lambda: None
+
+    db.atomic = MagicMock()
     db.add = MagicMock(return_value=(lambda: None, True))
 
-    with patch("builtins.open", mock_open()) as o:
+    with patch("builtins.open", mock_open()) as o, patch("os.replace") as rep, patch("os.fsync"):
         assert (0, 2) == ns.sync_files(db, prefix, missing, istream, ostream)
-        assert call(f1.name, "wb") in o.mock_calls
+        assert call(f1.name + ".notmuch-sync-tmp", "wb") in o.mock_calls
         assert call().write(b'mail one\n') in o.mock_calls
-        assert call(f2.name, "wb") in o.mock_calls
+        assert call(f2.name + ".notmuch-sync-tmp", "wb") in o.mock_calls
+        assert rep.mock_calls == [call(f1.name + ".notmuch-sync-tmp", f1.name),
+                                  call(f2.name + ".notmuch-sync-tmp", f2.name)]
         assert call().write(b'mail two\n') in o.mock_calls
         hdl = o()
         assert hdl.write.call_count == 2
@@ -859,11 +2796,70 @@ def test_sync_files_recv_add():
         call(f2.name)
     ]
     tmp = json.dumps([f1name, f2name])
-    assert struct.pack("!I", len(tmp)) + tmp.encode("utf-8") == ostream.getvalue()
+    assert struct.pack("!I", len(tmp)) + tmp.encode("utf-8") + frame([]) + ACK == ostream.getvalue()
+
+
+def test_format_bytes():
+    assert ns.format_bytes(0) == "0 B"
+    assert ns.format_bytes(1023) == "1023 B"
+    assert ns.format_bytes(1536) == "1.5 KiB"
+    assert ns.format_bytes(3 * 1024 * 1024) == "3.0 MiB"
+    assert ns.format_bytes(5 * 1024 ** 4) == "5120.0 GiB"
+
+
+def test_progress():
+    stats = ns.SyncStats(read=100, write=50)
+    token = ns.current_stats.set(stats)
+    try:
+        out = io.StringIO()
+        clock = [0]
+        with patch.object(ns.time, "monotonic", side_effect=lambda: clock[0]):
+            bar = ns.Progress(3, 1, out)
+            clock[0] = 1
+            stats.read += 2048
+            bar.update(recv=1)
+            assert out.getvalue() == "\r[#####---------------] received 1/3 files (2.0 KiB), sent 0/1 files (0 B)"
+            # not redrawn right away
+            bar.update(send=1)
+            assert out.getvalue().count("\r") == 1
+            clock[0] = 2
+            bar.update(recv=2)
+            bar.close()
+        assert out.getvalue().endswith("\r[####################] received 3/3 files (2.0 KiB), sent 1/1 files (0 B)\n")
+    finally:
+        ns.current_stats.reset(token)
+
+
+def test_sync_files_resume():
+    with TemporaryDirectory() as tmp:
+        tmpprefix = tmp + os.sep
+        checkpoint = os.path.join(tmp, "notmuch-sync-foo.partial")
+        missing = {"foo": {"files": ["a/cur/1", "a/cur/2"]}}
+        db = lambda: None
+        db.atomic = MagicMock()
+        db.add = MagicMock(side_effect=RuntimeError("interrupted"))
+
+        istream = io.BytesIO(frame([]) + frame([None, None]) + b"\x00\x00\x00\x09mail one\n\x00\x00\x00\x09mail two\n" + ACK)
+        with pytest.raises(RuntimeError):
+            ns.sync_files(db, tmpprefix, missing, istream, io.BytesIO(), checkpoint=checkpoint)
+        assert Path(checkpoint).read_text(encoding="utf-8") == "a/cur/1\na/cur/2\n"
+        Path(os.path.join(tmp, "a", "cur", "2")).unlink()
+
+        db.add = MagicMock(return_value=(lambda: None, True))
+        istream = io.BytesIO(frame([]) + frame([None]) + b"\x00\x00\x00\x09mail two\n" + ACK)
+        ostream = io.BytesIO()
+        with patch.object(sys, "stderr", io.StringIO()) as err:
+            assert (0, 2) == ns.sync_files(db, tmpprefix, missing, istream, ostream, checkpoint=checkpoint, progress=True)
+        assert "] received 1/1 files (" in err.getvalue() and err.getvalue().endswith("\n")
+        assert frame(["a/cur/2"]) + frame([]) + ACK == ostream.getvalue()
+        assert db.add.mock_calls == [call(os.path.join(tmp, "a", "cur", "2")), call(os.path.join(tmp, "a", "cur", "1"))]
+        assert not os.path.exists(checkpoint)
+        assert sorted(os.listdir(os.path.join(tmp, "a", "cur"))) == ["1", "2"]
 
 
 def test_sync_files_recv_new():
-    istream = io.BytesIO(b"\x00\x00\x00\x02[]\x00\x00\x00\x09mail one\n\x00\x00\x00\x09mail two\n")
+    istream = io.BytesIO(b"\x00\x00\x00\x02[]" + frame([None, None]) + b"\x00\x00\x00\x09mail one\n\x00\x00\x00\x09mail two\n" +
+                         ACK)
     ostream = io.BytesIO()
 
     # this is only to get filenames that are guaranteed to be unique
@@ -885,17 +2881,20 @@ def test_sync_files_recv_new():
     mt.__len__.return_value = 0
     mt.clear = MagicMock()
     mt.add = MagicMock()
+    mt.to_maildir_flags = MagicMock()
     type(m).tags = PropertyMock(return_value=mt)
 
     db = lambda: None
+
+    db.atomic = MagicMock()
     db.add = MagicMock()
     db.add.side_effect = [(m, False), (m, True)]
 
-    with patch("builtins.open", mock_open()) as o:
+    with patch("builtins.open", mock_open()) as o, patch("os.replace"), patch("os.fsync"):
         assert (1, 2) == ns.sync_files(db, prefix, missing, istream, ostream)
-        assert call(f1.name, "wb") in o.mock_calls
+        assert call(f1.name + ".notmuch-sync-tmp", "wb") in o.mock_calls
         assert call().write(b'mail one\n') in o.mock_calls
-        assert call(f2.name, "wb") in o.mock_calls
+        assert call(f2.name + ".notmuch-sync-tmp", "wb") in o.mock_calls
         assert call().write(b'mail two\n') in o.mock_calls
         hdl = o()
         assert hdl.write.call_count == 2
@@ -907,15 +2906,366 @@ def test_sync_files_recv_new():
     m.frozen.assert_called_once()
     mt.clear.assert_called_once()
     assert mt.add.mock_calls == [
-        call("foo"),
-        call("bar")
+        call("bar"),
+        call("foo")
     ]
+    mt.to_maildir_flags.assert_called_once()
     tmp = json.dumps([f1name, f2name])
-    assert struct.pack("!I", len(tmp)) + tmp.encode("utf-8") == ostream.getvalue()
+    assert struct.pack("!I", len(tmp)) + tmp.encode("utf-8") + frame([]) + ACK == ostream.getvalue()
+
+
+def test_sync_files_recv_new_tag():
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        missing = {"foo": {"tags": ["foo"], "files": ["a"]}}
+
+        m = MagicMock()
+        m.messageid = "foo"
+        mt = MagicMock()
+        type(m).tags = PropertyMock(return_value=mt)
+        db = lambda: None
+        db.atomic = MagicMock()
+        db.add = MagicMock(return_value=(m, False))
+
+        stats = ns.SyncStats()
+        ns.current_stats.set(stats)
+        istream = io.BytesIO(frame([]) + frame([None]) + b"\x00\x00\x00\x09mail one\n" + ACK)
+        assert (1, 1) == ns.sync_files(db, pre, missing, istream, io.BytesIO(), new_tag="from-server")
+        assert mt.add.mock_calls == [call("foo"), call("from-server")]
+        assert stats.message_ids == {"foo"}
+
+
+def test_maildir_flag_tags():
+    assert ns.maildir_flag_tags("foo/cur/1234:2,FS") == {"flagged"}
+    assert ns.maildir_flag_tags("foo/cur/1234:2,RP") == {"replied", "passed", "unread"}
+    assert ns.maildir_flag_tags("foo/cur/1234:2,") == {"unread"}
+    assert ns.maildir_flag_tags("foo/new/1234") is None
+    assert ns.maildir_flag_tags("foo/cur/1234") is None
+
+
+def test_find_digest():
+    with TemporaryDirectory() as tmp:
+        f1 = os.path.join(tmp, "a")
+        f2 = os.path.join(tmp, "b")
+        Path(f1).write_text("mail one")
+        Path(f2).write_text("mail two")
+        index = {}
+        ns.digest_files([f1, f2], cache=index)
+        by_digest = {index[f1][3]: [f1], index[f2][3]: [f2], "gone": [os.path.join(tmp, "c")]}
+        assert f1 == ns.find_digest(index, by_digest, ns.digest(b"mail one"))
+        assert None is ns.find_digest(index, by_digest, "gone")
+        Path(f2).write_text("mail three")
+        assert None is ns.find_digest(index, by_digest, ns.digest(b"mail two"))
+
+
+def test_sync_files_index_recv(monkeypatch):
+    monkeypatch.setitem(ns.session, "flags", False)
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        os.mkdir(os.path.join(tmp, "old"))
+        Path(os.path.join(tmp, "old", "b")).write_text("mail one")
+        index = {}
+        ns.digest_files([os.path.join(tmp, "old", "b")], cache=index)
+        missing = {"foo": {"tags": ["foo"], "files": ["new/a"]}}
+
+        db = lambda: None
+
+        db.atomic = MagicMock()
+        db.add = MagicMock(return_value=(lambda: None, True))
+
+        istream = io.BytesIO(frame([]) + frame([None]) + frame({"0": ns.digest(b"mail one")}) + frame([]) + ACK)
+        ostream = io.BytesIO()
+        assert (0, 1) == ns.sync_files(db, pre, missing, istream, ostream, index=index)
+        assert "mail one" == Path(os.path.join(tmp, "new", "a")).read_text()
+        assert frame(["new/a"]) + frame([]) + frame({}) + frame([0]) + ACK == ostream.getvalue()
+        db.add.assert_called_once_with(os.path.join(tmp, "new", "a"))
+
+
+def test_sync_files_recv_flags(monkeypatch):
+    class Tags(set):
+        def to_maildir_flags(self):
+            pass
+
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        missing = {"foo": {"tags": ["inbox"], "files": ["cur/a:2,", "cur/b:2,S"]}}
+
+        m = MagicMock()
+        m.messageid = "foo"
+        m.tags = Tags(["inbox"])
+        m.filenames.side_effect = [[Path(pre + "cur/a:2,"), Path(pre + "cur/b:2,S")],
+                                   [Path(pre + "cur/a:2,S"), Path(pre + "cur/b:2,S")]]
+
+        db = lambda: None
+
+        db.atomic = MagicMock()
+        db.add = MagicMock(return_value=(m, True))
+
+        journal = io.StringIO()
+        monkeypatch.setitem(ns.session, "journal", journal)
+        istream = io.BytesIO(frame([]) + frame([None, None]) +
+                             b"\x00\x00\x00\x08mail one\x00\x00\x00\x08mail two" + ACK)
+        assert (0, 2) == ns.sync_files(db, pre, missing, istream, io.BytesIO())
+        assert m.frozen.call_count == 1
+        assert [json.loads(line) for line in journal.getvalue().splitlines()][1] == \
+            {"op": "move", "from": pre + "cur/a:2,", "to": pre + "cur/a:2,S", "id": "foo"}
+
+        monkeypatch.setitem(ns.session, "flags", False)
+        m.frozen.reset_mock()
+        istream.seek(0)
+        ns.sync_files(db, pre, missing, istream, io.BytesIO())
+        m.frozen.assert_not_called()
+
+
+def test_maildir_rename():
+    assert ns.maildir_rename("INBOX/cur/a:2,", "INBOX/cur/a:2,S")
+    assert ns.maildir_rename("INBOX/new/a", "INBOX/cur/a:2,S")
+    assert not ns.maildir_rename("INBOX/cur/a:2,S", "INBOX/new/a")
+    assert not ns.maildir_rename("INBOX/new/a", "Other/cur/a:2,S")
+    assert not ns.maildir_rename("INBOX/cur/a:2,", "INBOX/cur/b:2,S")
+
+
+def test_sync_renames():
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        for f in ["INBOX/cur/a:2,", "INBOX/new/b", "INBOX/cur/c:2,S"]:
+            Path(pre + f).parent.mkdir(parents=True, exist_ok=True)
+            Path(pre + f).write_text(f)
+
+        msg = lambda: None
+        msg.messageid = "foo"
+        db = lambda: None
+        db.atomic = MagicMock()
+        db.add = MagicMock(return_value=(msg, True))
+        db.remove = MagicMock()
+
+        stats = ns.SyncStats(renamed=[(pre + "Other/cur/d:2,", pre + "Other/cur/d:2,F")])
+        ns.current_stats.set(stats)
+        istream = io.BytesIO(frame([["INBOX/cur/a:2,", "INBOX/cur/a:2,S"], ["INBOX/new/b", "INBOX/cur/b:2,"],
+                                    ["INBOX/cur/c:2,", "INBOX/cur/c:2,S"], ["../new/x", "../cur/x:2,S"],
+                                    ["INBOX/cur/gone:2,", "INBOX/cur/gone:2,S"]]))
+        ostream = io.BytesIO()
+        assert 2 == ns.sync_renames(db, pre, istream, ostream)
+        assert ostream.getvalue() == frame([["Other/cur/d:2,", "Other/cur/d:2,F"]])
+        assert sorted(os.listdir(pre + "INBOX/cur")) == ["a:2,S", "b:2,", "c:2,S"]
+        assert os.listdir(pre + "INBOX/new") == []
+        assert db.add.mock_calls == [call(pre + "INBOX/cur/a:2,S"), call(pre + "INBOX/cur/b:2,")]
+        assert db.remove.mock_calls == [call(pre + "INBOX/cur/a:2,"), call(pre + "INBOX/new/b")]
+
+
+def test_sync_files_index_send():
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        Path(os.path.join(tmp, "a")).write_text("mail one")
+        Path(os.path.join(tmp, "b")).write_text("mail two")
+        index = {}
+
+        db = lambda: None
+
+        db.atomic = MagicMock()
+        istream = io.BytesIO(frame(["a", "b"]) + frame([None, None]) + frame({}) + frame([0]) + ACK)
+        ostream = io.BytesIO()
+        assert (0, 0) == ns.sync_files(db, pre, {}, istream, ostream, index=index)
+        assert (frame([None, None]) + frame({"0": ns.digest(b"mail one"), "1": ns.digest(b"mail two")}) +
+                frame([]) + b"\x00\x00\x00\x08mail two" + ACK) == ostream.getvalue()[len(frame([])):]
+        assert 2 == len(index)
+
+
+def test_delta():
+    basis = b"".join(f"Received: from host{i}\n".encode() for i in range(500))
+    data = b"X-Label: new\n" + basis[:3000] + b"changed" + basis[3000:]
+    delta = ns.make_delta(data, ns.block_signatures(basis))
+    assert len(delta) < 3 * ns.DELTA_BLOCK
+    assert data == ns.apply_delta(basis, delta)
+    # nothing in common
+    assert b"foo" == ns.apply_delta(basis, ns.make_delta(b"foo", ns.block_signatures(basis)))
+    with pytest.raises(ns.SyncError):
+        ns.apply_delta(basis[1:], delta)
+    with pytest.raises(ns.ProtocolError):
+        ns.apply_delta(basis, delta[:32] + b"X")
+
+
+def test_sync_files_delta():
+    basis = b"".join(f"Received: from host{i}\n".encode() for i in range(500))
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        Path(os.path.join(tmp, "a")).write_bytes(basis)
+        Path(os.path.join(tmp, "c")).write_bytes(b"X-Label: new\n" + basis)
+        missing = {"foo": {"tags": ["foo"], "files": ["b"]}}
+
+        db = lambda: None
+
+        db.atomic = MagicMock()
+        msg = lambda: None
+        msg.ghost = False
+        msg.filenames = MagicMock(return_value=[Path(os.path.join(tmp, "a"))])
+        db.find = MagicMock(return_value=msg)
+        db.add = MagicMock(return_value=(lambda: None, True))
+
+        sigs = ns.block_signatures(basis)
+        delta = ns.make_delta(b"X-Label: old\n" + basis, sigs)
+        istream = io.BytesIO(frame(["c"]) + frame([None]) + frame({"0": sigs}) +
+                             struct.pack("!I", 5) + ns.DELTA_MARK + struct.pack("!I", len(delta)) + delta + ACK)
+        ostream = io.BytesIO()
+        assert (0, 1) == ns.sync_files(db, pre, missing, istream, ostream, delta=True)
+        assert b"X-Label: old\n" + basis == Path(os.path.join(tmp, "b")).read_bytes()
+        db.find.assert_called_once_with("foo")
+        out = ostream.getvalue()
+        assert out.startswith(frame(["b"]) + frame([None]) + frame({"0": sigs}) +
+                              struct.pack("!I", 5) + ns.DELTA_MARK)
+        assert out.endswith(ACK)
+        # the delta of c is mostly references to blocks of a
+        assert len(out) < len(basis) / 2
+
+
+def test_body_digest():
+    body = b"mail body\n" * 30
+    assert ns.body_digest(b"Message-ID: <foo>\n\n" + body) == ns.body_digest(b"Message-ID: <bar>\nX-Foo: bar\n\n" + body)
+    assert ns.body_digest(b"Message-ID: <foo>\n\n" + body) != ns.body_digest(b"Message-ID: <foo>\n\n" + body + b"foo")
+    assert ns.body_digest(b"Message-ID: <foo>\n\nThanks!\n") is None
+    assert ns.body_digest(b"Message-ID: <foo>\n") is None
+    crlf = body.replace(b"\n", b"\r\n")
+    assert ns.body_digest(b"Message-ID: <foo>\r\n\r\n" + crlf) == \
+        ns.body_digest(b"Message-ID: <bar>\r\nX-Foo: bar\r\n\r\n" + crlf)
+    assert ns.body_digest(b"Message-ID: <foo>\r\n\r\n" + crlf) is not None
+    assert ns.body_digest(b"Message-ID: <foo>\r\n\r\nThanks!\r\n") is None
+
+
+def test_sync_files_flag_rewrites():
+    body = b"mail body\n" * 30
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        Path(os.path.join(tmp, "b")).write_bytes(b"Message-ID: <bar>\n\n" + body)
+        index = {}
+        ns.digest_files([os.path.join(tmp, "b")], cache=index, body=True)
+        missing = {"foo": {"tags": ["foo"], "files": ["a"]}}
+
+        m = MagicMock()
+        m.messageid = "foo"
+        mt = MagicMock()
+        type(m).tags = PropertyMock(return_value=mt)
+        other = MagicMock()
+        other.messageid = "bar"
+        db = lambda: None
+        db.atomic = MagicMock()
+        db.add = MagicMock(return_value=(m, False))
+        db.get = MagicMock(return_value=other)
+
+        mail = b"Message-ID: <foo>\nList-Id: foo\n\n" + body
+        istream = io.BytesIO(frame([]) + frame([None]) + frame({"0": ns.digest(mail)}) + frame([]) +
+                             struct.pack("!I", len(mail)) + mail + ACK)
+        assert (1, 1) == ns.sync_files(db, pre, missing, istream, io.BytesIO(), index=index, rewrite_tag="rewritten")
+        db.get.assert_called_once_with(os.path.join(tmp, "b"))
+        assert mt.add.mock_calls == [call("foo"), call("rewritten")]
+
+
+def test_sync_files_excluded():
+    db = lambda: None
+    db.atomic = MagicMock()
+    missing = {"foo": {"tags": ["foo"], "files": ["a.gz", "b.gz"]}}
+    istream = io.BytesIO(frame([]) + frame([]))
+    ostream = io.BytesIO()
+    assert (0, 0) == ns.sync_files(db, prefix, missing, istream, ostream, exclude=["*.gz"])
+    assert frame([]) + frame([]) == ostream.getvalue()
+
+
+def test_sync_files_time_limit():
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        missing = {"foo": {"files": ["a", "b"]}}
+        db = lambda: None
+        db.atomic = MagicMock()
+        db.add = MagicMock(return_value=(lambda: None, True))
+
+        stats = ns.SyncStats()
+        ns.current_stats.set(stats)
+        istream = io.BytesIO(frame([]) + frame([None, None]) + b"\x00\x00\x00\x09mail one\n" + ACK)
+        ostream = io.BytesIO()
+        with patch.object(ns, "FILES_WINDOW", 1):
+            assert (0, 1) == ns.sync_files(db, pre, missing, istream, ostream, deadline=0)
+        assert ostream.getvalue().endswith(b"\x00\x00\x00\x04stop")
+        db.add.assert_called_once_with(os.path.join(tmp, "a"))
+        assert not os.path.exists(os.path.join(tmp, "b"))
+        assert (stats.remaining, stats.incomplete) == (1, True)
+
+
+def test_rate_limit():
+    clock = [100.0]
+
+    def sleep(secs):
+        clock[0] += secs
+
+    with patch.object(ns.time, "monotonic", side_effect=lambda: clock[0]), \
+         patch.object(ns.time, "sleep", side_effect=sleep) as sl:
+        limit = ns.RateLimit(1000)
+        # up to a second's worth at once
+        limit.take(500)
+        limit.take(500)
+        assert sl.call_count == 0
+        limit.take(1000)
+        sl.assert_called_once_with(1.0)
+        assert clock[0] == 101
+        # a pause doesn't allow more than a second's worth at once
+        clock[0] = 200
+        limit.take(3000)
+        assert clock[0] == 202
+
+        with patch.dict(ns.session, rate=limit):
+            stream = io.BytesIO()
+            ns.write(b"x" * 996, stream)
+            assert clock[0] == 203
+            assert len(stream.getvalue()) == 1000
+
+
+def test_parse_bwlimit():
+    assert ns.parse_bwlimit("100") == 102400
+    assert ns.parse_bwlimit("0.5") == 512
+    for spec in ["", "foo", "0", "-1", "nan", "inf"]:
+        with pytest.raises(argparse.ArgumentTypeError):
+            ns.parse_bwlimit(spec)
+
+
+def test_parse_duration():
+    assert ns.parse_duration("90") == 90
+    assert ns.parse_duration("10m") == 600
+    assert ns.parse_duration("1.5h") == 5400
+    assert ns.parse_duration("1d") == 86400
+    for spec in ["", "m", "foo", "-1h", "0", "nan", "infh"]:
+        with pytest.raises(argparse.ArgumentTypeError):
+            ns.parse_duration(spec)
+
+
+def test_parse_percent():
+    assert ns.parse_percent("5") == 5
+    assert ns.parse_percent("0.5") == 0.5
+    assert ns.parse_percent("100") == 100
+    for spec in ["", "foo", "0", "-1", "100.5", "nan", "inf"]:
+        with pytest.raises(argparse.ArgumentTypeError):
+            ns.parse_percent(spec)
+
+
+def test_parse_time_of_day():
+    assert ns.parse_time_of_day("03:00") == (3, 0)
+    assert ns.parse_time_of_day("23:59") == (23, 59)
+    for spec in ["", "3", "foo:00", "24:00", "12:60", "-1:00"]:
+        with pytest.raises(argparse.ArgumentTypeError):
+            ns.parse_time_of_day(spec)
+
+
+def test_parse_count():
+    assert ns.parse_count("4") == 4
+    assert ns.parse_count("0", 0) == 0
+    for spec, minimum in [("", 1), ("foo", 1), ("1.5", 1), ("0", 1), ("-1", 0)]:
+        with pytest.raises(argparse.ArgumentTypeError):
+            ns.parse_count(spec, minimum)
+    for argv in [["--spot-check", "-1"], ["--spot-check", "101"], ["-j", "0"], ["--batch-size", "-1"]]:
+        with pytest.raises(SystemExit):
+            ns.arg_parser().parse_args(argv)
 
 
 def test_sync_files_send():
     db = lambda: None
+    db.atomic = MagicMock()
     with NamedTemporaryFile(mode="w+t", prefix="notmuch-sync-test-tmp-") as f1:
         f1.write("mail one\n")
         f1.flush()
@@ -923,11 +3273,12 @@ def test_sync_files_send():
             f2.write("mail two\n")
             f2.flush()
             tmp = json.dumps([f1.name, f2.name]).encode("utf-8")
-            istream = io.BytesIO(struct.pack("!I", len(tmp)) + tmp)
+            istream = io.BytesIO(struct.pack("!I", len(tmp)) + tmp + frame([]) + ACK)
             ostream = io.BytesIO()
             assert (0, 0) == ns.sync_files(db, prefix, {}, istream, ostream)
             out = ostream.getvalue()
-            assert b"\x00\x00\x00\x02[]\x00\x00\x00\x09mail one\n\x00\x00\x00\x09mail two\n" == out
+            assert b"\x00\x00\x00\x02[]" + frame([None, None]) + \
+                b"\x00\x00\x00\x09mail one\n\x00\x00\x00\x09mail two\n" + ACK == out
 
 
 def test_sync_files_send_recv_add():
@@ -941,16 +3292,19 @@ def test_sync_files_send_recv_add():
     missing = {"foo": {"files": [f1name, f2name]}}
 
     db = lambda: None
+
+    db.atomic = MagicMock()
     db.add = MagicMock(return_value=(lambda: None, True))
 
-    with patch("builtins.open", mock_open(read_data=b"mail three\n")) as o:
+    with patch("builtins.open", mock_open(read_data=b"mail three\n")) as o, patch("os.replace"), patch("os.fsync"):
         tmp = json.dumps([f1.name]).encode("utf-8")
-        istream = io.BytesIO(struct.pack("!I", len(tmp)) + tmp + b"\x00\x00\x00\x09mail one\n\x00\x00\x00\x09mail two\n")
+        istream = io.BytesIO(struct.pack("!I", len(tmp)) + tmp + frame([None, None]) +
+                             b"\x00\x00\x00\x09mail one\n\x00\x00\x00\x09mail two\n" + ACK)
         ostream = io.BytesIO()
         assert (0, 2) == ns.sync_files(db, prefix, missing, istream, ostream)
-        assert call(f1.name, "wb") in o.mock_calls
+        assert call(f1.name + ".notmuch-sync-tmp", "wb") in o.mock_calls
         assert call().write(b'mail one\n') in o.mock_calls
-        assert call(f2.name, "wb") in o.mock_calls
+        assert call(f2.name + ".notmuch-sync-tmp", "wb") in o.mock_calls
         assert call().write(b'mail two\n') in o.mock_calls
         assert call(f1.name, "rb") in o.mock_calls
         assert call().write(b'mail one\n') in o.mock_calls
@@ -959,7 +3313,8 @@ def test_sync_files_send_recv_add():
         assert hdl.read.call_count == 1
 
         tmp = json.dumps([f1name, f2name])
-        assert struct.pack("!I", len(tmp)) + tmp.encode("utf-8") + b"\x00\x00\x00\x0bmail three\n" == ostream.getvalue()
+        assert struct.pack("!I", len(tmp)) + tmp.encode("utf-8") + frame([None]) + \
+            b"\x00\x00\x00\x0bmail three\n" + ACK == ostream.getvalue()
 
     assert db.add.mock_calls == [
         call(f1.name),
@@ -967,6 +3322,513 @@ def test_sync_files_send_recv_add():
     ]
 
 
+def test_sync_files_conflict():
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        with open(os.path.join(tmp, "a"), "w", encoding="utf-8") as f:
+            f.write("mail zero\n")
+        missing = {"foo": {"files": ["a", "b", "c"]}}
+
+        db = lambda: None
+
+        db.atomic = MagicMock()
+        db.add = MagicMock(return_value=(lambda: None, True))
+
+        istream = io.BytesIO(b"\x00\x00\x00\x02[]" + frame([None, 0, None]) +
+                             b"\x00\x00\x00\x09mail one\n\x00\x00\x00\x09mail two\n" + ACK)
+        stats = ns.SyncStats()
+        ns.current_stats.set(stats)
+        assert (0, 1) == ns.sync_files(db, pre, missing, istream, io.BytesIO())
+        assert stats.conflicts == 1
+        with open(os.path.join(tmp, "a"), "rb") as f:
+            assert f.read() == b"mail zero\n"
+        qname = os.path.join(tmp, "a.conflict-" + ns.digest(b"mail one\n")[:16])
+        with open(qname, "rb") as f:
+            assert f.read() == b"mail one\n"
+        assert not os.path.exists(os.path.join(tmp, "b"))
+        db.add.assert_called_once_with(pre + "c")
+
+
+def test_sync_files_lenient():
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        missing = {"foo": {"files": ["a", "b", "c"]}}
+
+        db = lambda: None
+
+        db.atomic = MagicMock()
+        db.add = MagicMock(return_value=(lambda: None, True))
+
+        istream = io.BytesIO(b"\x00\x00\x00\x02[]" + frame([None, 0, None]) +
+                             b"\x00\x00\x00\x09mail one\n\x00\x00\x00\x09mail two\n" + ACK)
+        real_recv_file = ns.recv_file
+
+        def recv_file(fname, stream, store=None):
+            if fname.endswith("a"):
+                stream.read(13)
+                raise ns.SyncError("Can't write a.")
+            real_recv_file(fname, stream, store=store)
+
+        with patch.object(ns, "recv_file", side_effect=recv_file):
+            with pytest.raises(ValueError) as pwe:
+                ns.sync_files(db, pre, missing, istream, io.BytesIO())
+            assert str(pwe.value) == "Can't write a."
+            db.add.assert_not_called()
+
+            istream.seek(0)
+            assert (0, 1) == ns.sync_files(db, pre, missing, istream, io.BytesIO(), lenient=True)
+        assert not os.path.exists(os.path.join(tmp, "a"))
+        assert not os.path.exists(os.path.join(tmp, "b"))
+        db.add.assert_called_once_with(pre + "c")
+
+        # errors of the stream itself are never skipped
+        istream = io.BytesIO(b"\x00\x00\x00\x02[]" + frame([None, 0, None]) + b"\x00\x00\x00\x09mail")
+        with pytest.raises(ns.ConnectionLost):
+            ns.sync_files(db, pre, {"foo": {"files": ["d", "e", "f"]}}, istream, io.BytesIO(), lenient=True)
+
+
+def test_apply_mode():
+    def args(**kwargs):
+        tmp = lambda: None
+        tmp.mode = None
+        tmp.max_change = None
+        tmp.unindexed = None
+        for k, v in kwargs.items():
+            setattr(tmp, k, v)
+        ns.apply_mode(tmp)
+        return (tmp.max_change, tmp.unindexed)
+
+    assert args() == (None, None)
+    assert args(mode="strict") == (0.2, "warn")
+    tmp = lambda: None
+    tmp.mode, tmp.max_change, tmp.unindexed, tmp.fail_on_conflict = "strict", None, None, False
+    ns.apply_mode(tmp)
+    assert tmp.fail_on_conflict
+    assert args(mode="strict", max_change=0.5, unindexed="index") == (0.5, "index")
+    assert args(mode="lenient") == (None, "index")
+    assert args(mode="lenient", unindexed="warn") == (None, "warn")
+
+
+def test_config_path():
+    with patch.dict(os.environ, {"XDG_CONFIG_HOME": "/foo"}):
+        assert ns.config_path() == os.path.join("/foo", "notmuch-sync", "config.toml")
+    with patch.dict(os.environ, {"XDG_CONFIG_HOME": "", "HOME": "/home/bar"}):
+        assert ns.config_path() == os.path.join("/home/bar", ".config", "notmuch-sync", "config.toml")
+
+
+def test_load_config():
+    with NamedTemporaryFile(mode="w+t", suffix=".toml") as f:
+        f.write("""user = "me"
+mbsync = true
+
+[profiles.laptop-to-server]
+remote = "server"
+user = "other"
+exclude-pattern = ["*.gz"]
+
+[profiles.desktop]
+remote = "desktop"
+""")
+        f.flush()
+        assert ns.load_config(f.name, "laptop-to-server") == {"user": "other", "mbsync": True,
+                                                              "remote": "server", "exclude-pattern": ["*.gz"]}
+        assert ns.load_config(f.name, "desktop") == {"user": "me", "mbsync": True, "remote": "desktop"}
+        with pytest.raises(argparse.ArgumentTypeError, match="Profile 'foo' not defined"):
+            ns.load_config(f.name, "foo")
+
+        f.seek(0)
+        f.truncate()
+        f.write("remote = \n")
+        f.flush()
+        with pytest.raises(argparse.ArgumentTypeError, match="Cannot parse configuration file"):
+            ns.load_config(f.name, "foo")
+
+    with pytest.raises(argparse.ArgumentTypeError, match="Cannot read configuration file"):
+        ns.load_config("/nonexistent/config.toml", "foo")
+
+
+def test_config_defaults():
+    parser = ns.arg_parser()
+    assert ns.config_defaults(parser, {"remote": "server", "ssh_cmd": "ssh -T", "mbsync": True,
+                                       "delete": False, "verbose": 2, "spot-check": 5,
+                                       "time-limit": "10m", "exclude-pattern": ["*.gz", "Trash/*"],
+                                       "compress": "zstd:3"}) == {
+        "remote": ["server"], "ssh_cmd": "ssh -T", "mbsync": True, "delete": False, "verbose": 2,
+        "spot_check": 5, "time_limit": 600, "exclude_pattern": ["*.gz", "Trash/*"], "compress": "zstd:3"}
+
+    for options, msg in [({"foo": 1}, "Unknown option 'foo'"),
+                         ({"profile": "foo"}, "Unknown option 'profile'"),
+                         ({"mbsync": "yes"}, "Invalid value 'yes' for option 'mbsync'"),
+                         ({"verbose": True}, "Invalid value 'True' for option 'verbose'"),
+                         ({"remote": 1}, "Invalid value '1' for option 'remote'"),
+                         ({"time-limit": "foo"}, "Invalid value 'foo' for option 'time-limit'"),
+                         ({"mode": "foo"}, "Invalid value 'foo' for option 'mode'"),
+                         ({"spot-check": 200}, "Invalid value '200' for option 'spot-check'"),
+                         ({"jobs": 0}, "Invalid value '0' for option 'jobs'"),
+                         ({"exclude-pattern": ["*.gz", 1]}, "Invalid value '1' for option 'exclude-pattern'")]:
+        with pytest.raises(argparse.ArgumentTypeError, match=msg):
+            ns.config_defaults(parser, options)
+
+    parser.set_defaults(**ns.config_defaults(parser, {"remote": "server", "user": "me",
+                                                      "exclude-pattern": ["*.gz"]}))
+    args = parser.parse_args(["-u", "other", "--exclude-pattern", "Trash/*"])
+    assert (args.remote, args.user, args.exclude_pattern) == (["server"], "other", ["*.gz", "Trash/*"])
+
+
+def test_parse_runs():
+    assert [(run.remote, run.remote_cmd) for run in ns.parse_runs([])] == [(None, None)]
+    assert [run.remote for run in ns.parse_runs(["-r", "a", "-d", "-r", "b"])] == ["a", "b"]
+    assert all(run.delete for run in ns.parse_runs(["-r", "a", "-d", "-r", "b"]))
+    assert [run.max_change for run in ns.parse_runs(["-r", "a", "--mode", "strict"])] == [0.2]
+
+    with NamedTemporaryFile(mode="w+t", suffix=".toml") as f:
+        f.write("""delete = true
+
+[profiles.server]
+remote = "server"
+mbsync = true
+
+[profiles.both]
+remote = ["server", "vps"]
+""")
+        f.flush()
+        runs = ns.parse_runs(["--config", f.name, "--profile", "server", "--profile", "both"])
+        assert [(run.remote, run.delete, run.mbsync) for run in runs] == [("server", True, True), ("server", True, False),
+                                                                          ("vps", True, False)]
+        runs = ns.parse_runs(["--config", f.name, "--profile", "both", "-r", "other"])
+        assert [run.remote for run in runs] == ["other"]
+
+        with pytest.raises(SystemExit):
+            ns.parse_runs(["--config", f.name])
+        with pytest.raises(SystemExit):
+            ns.parse_runs(["--config", f.name, "--profile", "foo"])
+
+
+def test_wait_for_changes():
+    with TemporaryDirectory() as tmp:
+        clock = [0]
+        changes = {}
+
+        def sleep(secs):
+            clock[0] += secs
+            if clock[0] in changes:
+                Path(tmp, changes[clock[0]]).write_text(str(clock[0]), encoding="utf-8")
+
+        with patch.object(ns.time, "monotonic", side_effect=lambda: clock[0]), \
+             patch.object(ns.time, "sleep", side_effect=sleep):
+            assert not ns.wait_for_changes(tmp, 10, 4)
+            assert clock[0] == 10
+
+            clock[0] = 0
+            changes.update({3: "a", 5: "b"})
+            assert ns.wait_for_changes(tmp, 10, 4)
+            assert clock[0] == 9
+
+            # changes keep the wait going past the interval until they stop
+            clock[0] = 0
+            changes.clear()
+            changes.update({8: "a", 11: "a"})
+            assert ns.wait_for_changes(tmp, 10, 4)
+            assert clock[0] == 15
+
+
+def test_watch():
+    db = lambda: None
+    db.default_path = MagicMock(return_value="/mail")
+    mock_ctx = MagicMock()
+    mock_ctx.__enter__.return_value = db
+    mock_ctx.__exit__.return_value = False
+
+    runs = ns.parse_runs(["-r", "a", "-r", "b", "-c", "true", "--watch", "--watch-interval", "1m"])
+    runs[0].remote_cmd = None
+    with patch("notmuch2.Database", return_value=mock_ctx), \
+         patch.object(ns, "ssh_master", return_value="/tmp/control") as sm, \
+         patch.object(ns, "run_local", side_effect=[ns.SyncStats(), ValueError("foo"), ns.SyncStats(status=1), ns.SyncStats()]) as rl, \
+         patch.object(ns, "wait_for_changes", side_effect=[True, KeyboardInterrupt]) as wfc, \
+         patch("subprocess.run") as run, patch.object(ns.logger, "error") as err:
+        ns.watch(runs)
+        sm.assert_called_once_with(["ssh", "-CTaxq"], "a", persist="yes")
+        assert rl.mock_calls == [call(runs[0], "/tmp/control"), call(runs[1], None)] * 2
+        err.assert_called_once_with("Sync with %s failed: %s", "b", ANY)
+        assert wfc.mock_calls == [call(os.path.join("/mail", ".notmuch", "xapian"), 60, 10)] * 2
+        run.assert_called_once_with(["ssh", "-CTaxq", "-o", "ControlPath=/tmp/control", "-O", "exit", "a"],
+                                    check=False, stderr=ns.subprocess.DEVNULL)
+
+    with pytest.raises(ns.SyncError, match="--watch doesn't work with --dry-run"):
+        ns.watch(ns.parse_runs(["-r", "a", "--watch", "--dry-run"]))
+
+
+def test_next_verify():
+    assert ns.next_verify(1000, 500) == 1500
+    last = time.mktime((2026, 3, 1, 12, 0, 0, 0, 0, -1))
+    assert ns.next_verify(last, 86400, (3, 0)) == time.mktime((2026, 3, 3, 3, 0, 0, 0, 0, -1))
+    assert ns.next_verify(last, 3600, (13, 30)) == time.mktime((2026, 3, 1, 13, 30, 0, 0, 0, -1))
+    assert ns.next_verify(last, 7 * 86400, (23, 59)) == time.mktime((2026, 3, 8, 23, 59, 0, 0, 0, -1))
+
+
+def test_watch_verify():
+    with TemporaryDirectory() as tmp:
+        rev = lambda: None
+        rev.uuid = b'00000000-0000-0000-0000-000000000000'
+        db = lambda: None
+        db.default_path = MagicMock(return_value=tmp)
+        db.revision = MagicMock(return_value=rev)
+        db.config = {}
+        mock_ctx = MagicMock()
+        mock_ctx.__enter__.return_value = db
+        mock_ctx.__exit__.return_value = False
+        fname = os.path.join(tmp, ".notmuch", "notmuch-sync-verified")
+        os.mkdir(os.path.join(tmp, ".notmuch"))
+
+        runs = ns.parse_runs(["-c", "true", "--watch", "--verify-every", "1d"])
+        with patch("notmuch2.Database", return_value=mock_ctx), \
+             patch.object(ns, "run_local", return_value=ns.SyncStats()) as rl, \
+             patch.object(ns, "wait_for_changes", side_effect=[True, KeyboardInterrupt]), \
+             patch.object(ns.logger, "warning"):
+            ns.watch(runs)
+            # verified after the first sync only, and the time recorded
+            assert [c.args[0].verify for c in rl.mock_calls] == [False, True, False]
+            verified = json.loads(Path(fname).read_text(encoding="utf-8"))
+            assert list(verified) == ["true"]
+
+            # not due yet after a restart
+            rl.reset_mock()
+            with patch.object(ns, "wait_for_changes", side_effect=[KeyboardInterrupt]):
+                ns.watch(runs)
+            assert [c.args[0].verify for c in rl.mock_calls] == [False]
+
+            # due, but not before the time of day
+            Path(fname).write_text(json.dumps({"true": time.time() - 86400 - 60}), encoding="utf-8")
+            at = time.localtime(time.time() + 7200)
+            runs = ns.parse_runs(["-c", "true", "--watch", "--verify-every", "1d", "--verify-at",
+                                  f"{at.tm_hour:02}:{at.tm_min:02}"])
+            rl.reset_mock()
+            with patch.object(ns, "wait_for_changes", side_effect=[KeyboardInterrupt]):
+                ns.watch(runs)
+            assert [c.args[0].verify for c in rl.mock_calls] == [False]
+
+
+def test_watch_exit_code(monkeypatch):
+    monkeypatch.setattr(sys, "argv", ["notmuch-sync", "-r", "a", "--watch", "--dry-run"])
+    with patch.object(ns.logger, "error") as err, pytest.raises(SystemExit) as pwe:
+        ns.main()
+    assert pwe.value.code == ns.SyncError.exit_code
+    err.assert_called_once_with("%s", ANY)
+
+
+def test_run_hook():
+    with TemporaryDirectory() as tmp:
+        out = os.path.join(tmp, "out")
+        summary = ns.summary_json(ns.SyncStats(messages=2), {"version": 1, "tags": 3})
+        assert summary["local"]["messages"] == 2
+        assert summary["remote"]["tags"] == 3
+        ns.run_hook(f"cp /dev/stdin {out}", "post-sync hook", summary)
+        with open(out, encoding="utf-8") as f:
+            assert json.load(f) == summary
+        ns.run_hook(f"cp /dev/stdin {out}", "pre-sync hook")
+        assert os.path.getsize(out) == 0
+    with patch.object(ns.subprocess, "run", return_value=MagicMock(returncode=0)) as run:
+        ns.run_hook("notmuch new", "notmuch new", remote=True)
+        run.assert_called_once_with(["notmuch", "new"], check=False, text=True, input="",
+                                    stdout=ns.subprocess.DEVNULL, stderr=ns.subprocess.PIPE)
+    with pytest.raises(ns.SyncError) as pwe:
+        ns.run_hook("false", "pre-sync hook")
+    assert str(pwe.value) == "Pre-sync hook failed with exit code 1, aborting..."
+    with pytest.raises(ns.SyncError) as pwe:
+        ns.run_hook("sh -c 'echo oops >&2; exit 3'", "pull command", remote=True)
+    assert str(pwe.value) == "Pull command failed with exit code 3 (oops), aborting..."
+
+
+def test_prepare_sync():
+    args = ns.arg_parser().parse_args(["--pre-hook", "true", "--pull-cmd", "mbsync -a", "--index"])
+    with patch.object(ns, "run_hook") as rh:
+        ns.prepare_sync(args)
+        assert [c.args for c in rh.call_args_list] == [("true", "pre-sync hook"), ("mbsync -a", "pull command"),
+                                                      ("notmuch new", "notmuch new")]
+    args = ns.arg_parser().parse_args(["--index", "remote"])
+    with patch.object(ns, "run_hook") as rh:
+        ns.prepare_sync(args)
+        rh.assert_not_called()
+        ns.prepare_sync(args, remote=True)
+        rh.assert_called_once_with("notmuch new", "notmuch new", remote=True)
+
+
+def test_retryable():
+    assert ns.retryable(ns.BusyError("Busy, aborting..."))
+    assert ns.retryable(ns.ConnectionLost("Tried to read 4 bytes, but read only 0, aborting..."))
+    assert ns.retryable(BrokenPipeError())
+    assert ns.retryable(ns.notmuch2.XapianError("Unable to get write lock"))
+    assert ns.retryable(ns.RemoteError("BusyError", "Busy, aborting..."))
+    assert ns.retryable(ns.RemoteError("XapianError", "Unable to get write lock"))
+    assert not ns.retryable(ns.RemoteError("ValueError", "foo"))
+    assert not ns.retryable(ns.StateError("Sync state file corrupted"))
+    assert not ns.retryable(ValueError("foo"))
+
+
+def test_run_local_retries():
+    args = ns.arg_parser().parse_args(["--retries", "2", "--retry-delay", "1"])
+    stats = ns.SyncStats()
+    with patch.object(ns, "sync_local", side_effect=[ns.BusyError("Busy"), ConnectionResetError(), stats]) as sl, \
+         patch.object(ns.time, "sleep") as sleep:
+        assert ns.run_local(args) is stats
+        assert sl.call_count == 3
+        assert [c.args for c in sleep.call_args_list] == [(1,), (2,)]
+
+    with patch.object(ns, "sync_local", side_effect=[ns.BusyError("Busy")] * 3) as sl, \
+         patch.object(ns.time, "sleep") as sleep:
+        with pytest.raises(ns.BusyError):
+            ns.run_local(args)
+        assert sl.call_count == 3
+
+    with patch.object(ns, "sync_local", side_effect=[ns.StateError("corrupted"), stats]) as sl, \
+         patch.object(ns.time, "sleep") as sleep:
+        with pytest.raises(ns.StateError):
+            ns.run_local(args)
+        sleep.assert_not_called()
+
+
+def test_remotes_table():
+    stats = ns.SyncStats(messages=2, files=3, tags=1, read=1024, write=512)
+    incomplete = ns.SyncStats(incomplete=True)
+    assert ns.remotes_table([("server", stats), ("vps.example.com", incomplete), ("foo", None)]) == [
+        "remote           status      new messages  new files  copied/moved  tag changes  deleted  received  sent",
+        "server           ok          2             3          0             1            0        1024      512",
+        "vps.example.com  incomplete  0             0          0             0            0        0         0",
+        "foo              failed"]
+
+
+def test_find_duplicates():
+    with TemporaryDirectory() as tmp:
+        for name, content in [("a", "mail one\n"), ("b", "mail two\n"), ("c", "mail one\n"),
+                              ("d", "mail three\n"), ("e", "mail one\n")]:
+            with open(os.path.join(tmp, name), "w", encoding="utf-8") as f:
+                f.write(content)
+        assert ns.find_duplicates(tmp, ["a", "b", "c", "d", "e"]) == [None, None, 0, None, 0]
+        assert ns.find_duplicates(tmp, ["b", "d"]) == [None, None]
+        assert ns.find_duplicates(tmp, ["a"]) == [None]
+        assert ns.find_duplicates(tmp, []) == []
+
+
+def test_sync_files_send_recv_dup():
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        for name in ["a", "b"]:
+            with open(os.path.join(tmp, name), "w", encoding="utf-8") as f:
+                f.write("mail one\n")
+        missing = {"foo": {"files": ["c", "d"]}}
+
+        db = lambda: None
+
+        db.atomic = MagicMock()
+        db.add = MagicMock(return_value=(lambda: None, True))
+
+        istream = io.BytesIO(frame(["a", "b"]) + frame([None, 0]) + b"\x00\x00\x00\x09mail two\n" + ACK)
+        ostream = io.BytesIO()
+        assert (0, 2) == ns.sync_files(db, pre, missing, istream, ostream)
+        assert frame(["c", "d"]) + frame([None, 0]) + b"\x00\x00\x00\x09mail one\n" + ACK == ostream.getvalue()
+        with open(os.path.join(tmp, "c"), "rb") as f:
+            assert f.read() == b"mail two\n"
+        with open(os.path.join(tmp, "d"), "rb") as f:
+            assert f.read() == b"mail two\n"
+
+        assert db.add.mock_calls == [
+            call(pre + "c"),
+            call(pre + "d")
+        ]
+
+
+def test_sync_files_window():
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        with open(os.path.join(tmp, "a"), "w", encoding="utf-8") as f:
+            f.write("mail one\n")
+        missing = {"foo": {"files": ["b", "c"]}}
+
+        db = lambda: None
+
+        db.atomic = MagicMock()
+        db.add = MagicMock(return_value=(lambda: None, True))
+
+        istream = io.BytesIO(frame(["a"]) + frame([None, None]) + b"\x00\x00\x00\x09mail two\n" + ACK +
+                             b"\x00\x00\x00\x0bmail three\n" + ACK)
+        ostream = io.BytesIO()
+        with patch.object(ns, "FILES_WINDOW", 1):
+            assert (0, 2) == ns.sync_files(db, pre, missing, istream, ostream)
+        assert frame(["b", "c"]) + frame([None]) + b"\x00\x00\x00\x09mail one\n" + ACK + ACK == ostream.getvalue()
+        with open(os.path.join(tmp, "c"), "rb") as f:
+            assert f.read() == b"mail three\n"
+
+        istream = io.BytesIO(frame(["a"]) + frame([None, None]) + b"\x00\x00\x00\x09mail two\n" +
+                             b"\x00\x00\x00\x03foo")
+        with patch.object(ns, "FILES_WINDOW", 1), pytest.raises(ValueError) as pwe:
+            ns.sync_files(db, pre, {"foo": {"files": ["d", "e"]}}, istream, io.BytesIO())
+        assert str(pwe.value) == "Expected acknowledgement of received files from remote, aborting..."
+
+
+def test_count_folders():
+    stats = ns.SyncStats()
+    ns.current_stats.set(stats)
+    ns.count_folders(prefix, [os.path.join(prefix, "INBOX", "cur", "foo:2,S"),
+                              os.path.join(prefix, "INBOX", "new", "bar"),
+                              os.path.join(prefix, "Archive", "2024", "baz"),
+                              os.path.join(prefix, "qux")], "deleted")
+    ns.count_folders(prefix, [os.path.join(prefix, "INBOX", "tmp", "foo")], "files")
+    assert stats.folders == {"INBOX": {"messages": 0, "files": 1, "deleted": 1},
+                             "Archive/2024": {"messages": 0, "files": 0, "deleted": 1},
+                             ".": {"messages": 0, "files": 0, "deleted": 1}}
+
+
+def test_current_stats_unset():
+    # counting outside of a session fails instead of going to shared counters
+    with pytest.raises(LookupError):
+        contextvars.Context().run(ns.write, b"foo", io.BytesIO())
+
+
+def test_sync_stats():
+    stats = ns.SyncStats()
+    ns.current_stats.set(stats)
+    ns.write(b"foo", io.BytesIO())
+    assert ns.read(io.BytesIO(b"\x00\x00\x00\x02{}")) == b"{}"
+    assert (stats.read, stats.write) == (6, 7)
+    assert stats.changes() == {"messages": 0, "files": 0, "moved": 0, "deleted_files": 0, "tags": 0,
+                               "deleted_messages": 0, "remaining": 0, "conflicts": 0, "folders": {}}
+
+
+def test_summary_lines():
+    stats = ns.SyncStats(read=10, write=20, messages=1, files=2, folders={"INBOX": {"messages": 1, "files": 2, "deleted": 0}})
+    assert ns.summary_lines(stats, {"tags": 3}) == [
+        "local:  1 new messages,\t2 new files,\t0 files copied/moved,\t0 files deleted,\t0 messages with tag changes,\t0 messages deleted",
+        "remote: 0 new messages,\t0 new files,\t0 files copied/moved,\t0 files deleted,\t3 messages with tag changes,\t0 messages deleted",
+        "local:  INBOX:\t1 new messages,\t2 new files,\t0 messages deleted",
+        "10/20 bytes received from/sent to remote."]
+    stats.incomplete = True
+    stats.remaining = 5
+    assert ns.summary_lines(stats, {"remaining": 1})[-2] == \
+        "Out of time: 5 files left to receive locally, 1 on remote; run again to sync them."
+    stats.conflicts = 2
+    assert ns.summary_lines(stats, {})[-2] == \
+        "Conflicts: 2 received files skipped locally, 0 on remote, because they exist with different content."
+
+
+def test_write_summary():
+    stats = ns.SyncStats(read=10, write=20, messages=1)
+    with TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "summary")
+        with patch.object(ns.time, "time", return_value=1700000000):
+            ns.write_summary(fname, "json", stats, {"version": 2, "tags": 3})
+        summary = json.loads(Path(fname).read_text(encoding="utf-8"))
+        assert summary == {"time": 1700000000, "status": "ok", "incomplete": False, "read": 10, "write": 20,
+                           "local": stats.changes(), "remote": {"tags": 3}}
+        ns.write_summary(fname, "text", stats, {"tags": 3})
+        assert Path(fname).read_text(encoding="utf-8") == "".join(l + "\n" for l in ns.summary_lines(stats, {"tags": 3}))
+        ns.write_summary(fname, "json", None, error="Remote error: foo")
+        summary = json.loads(Path(fname).read_text(encoding="utf-8"))
+        assert (summary["status"], summary["error"]) == ("error", "Remote error: foo")
+        ns.write_summary(fname, "text", None, error="foo")
+        assert Path(fname).read_text(encoding="utf-8") == "error: foo\n"
+        assert os.listdir(tmp) == ["summary"]
+
+
 def test_sync_deletes_local():
     m1 = lambda: None
     m1.messageid = "foo"
@@ -1000,6 +3862,79 @@ def test_sync_deletes_local():
     m2.filenames.assert_called_once()
 
 
+def test_sync_deletes_local_confirm():
+    db = lambda: None
+    db.remove = MagicMock()
+    db.find = MagicMock()
+
+    mock_ctx = MagicMock()
+    mock_ctx.__enter__.return_value = db
+    mock_ctx.__exit__.return_value = False
+
+    with patch("notmuch2.Database", return_value=mock_ctx), patch.object(ns, "get_ids", return_value=["foo", "bar"]), \
+         patch.object(ns, "ask_confirm", return_value=False) as ac:
+        istream = io.BytesIO(frame(["foo", "baz"]))
+        ostream = io.BytesIO()
+        assert 0 == ns.sync_deletes_local(prefix, istream, ostream, confirm=True)
+        assert frame([]) == ostream.getvalue()
+        ac.assert_called_once_with(["local:  1 messages not on remote to delete if tagged 'deleted'",
+                                    "remote: 1 messages not on local to delete if tagged 'deleted'"], False)
+    db.find.assert_not_called()
+
+
+def test_sync_deletes_local_dry_run():
+    m2 = lambda: None
+    m2.messageid = "bar"
+    m2.filenames = MagicMock(return_value=["barfile"])
+    m2.tags = ["deleted"]
+    m2.ghost = False
+
+    db = lambda: None
+    db.remove = MagicMock()
+    db.find = MagicMock(return_value=m2)
+
+    mock_ctx = MagicMock()
+    mock_ctx.__enter__.return_value = db
+    mock_ctx.__exit__.return_value = False
+
+    stats = ns.SyncStats()
+    ns.current_stats.set(stats)
+    with patch("notmuch2.Database", return_value=mock_ctx), patch.dict(ns.session, dry_run=True):
+        with patch("pathlib.Path.unlink") as pu:
+            with patch.object(ns, "get_ids", return_value=["foo", "bar"]):
+                istream = io.BytesIO(b"\x00\x00\x00\x07[\"foo\"]")
+                ostream = io.BytesIO()
+                assert 1 == ns.sync_deletes_local(prefix, istream, ostream)
+                assert b"\x00\x00\x00\x02[]" == ostream.getvalue()
+                pu.assert_not_called()
+    db.remove.assert_not_called()
+    assert stats.actions == ["delete bar (barfile)"]
+
+
+def test_sync_deletes_local_receive_only():
+    db = lambda: None
+    db.find = MagicMock()
+
+    mock_ctx = MagicMock()
+    mock_ctx.__enter__.return_value = db
+    mock_ctx.__exit__.return_value = False
+
+    with patch("notmuch2.Database", return_value=mock_ctx):
+        with patch.object(ns, "get_ids", return_value=["foo", "bar"]):
+            istream = io.BytesIO(b"\x00\x00\x00\x07[\"baz\"]")
+            ostream = io.BytesIO()
+            assert 0 == ns.sync_deletes_local(prefix, istream, ostream, receive_only=True)
+            assert b"\x00\x00\x00\x02[]" == ostream.getvalue()
+            assert db.find.call_count == 2
+
+            istream = io.BytesIO(b"\x00\x00\x00\x07[\"baz\"]")
+            ostream = io.BytesIO()
+            db.find.reset_mock()
+            assert 0 == ns.sync_deletes_local(prefix, istream, ostream, receive_only_theirs=True)
+            assert b"\x00\x00\x00\x07[\"baz\"]" == ostream.getvalue()
+            db.find.assert_not_called()
+
+
 def test_sync_deletes_local_no_deleted():
     m1 = lambda: None
     m1.messageid = "foo"
@@ -1324,6 +4259,154 @@ def test_sync_deletes_remote_none():
     assert db.remove.call_count == 0
 
 
+def make_maildir(path, *fnames):
+    for sub in ("cur", "new", "tmp"):
+        os.makedirs(os.path.join(path, sub))
+    for fname in fnames:
+        Path(os.path.join(path, fname)).write_text("mail")
+
+
+def test_get_folders():
+    with TemporaryDirectory() as tmp:
+        make_maildir(tmp)
+        make_maildir(os.path.join(tmp, "foo"))
+        make_maildir(os.path.join(tmp, "foo", "bar"))
+        make_maildir(os.path.join(tmp, ".notmuch", "baz"))
+        os.makedirs(os.path.join(tmp, "qux"))
+        assert ns.get_folders(tmp + os.sep) == [".", "foo", "foo/bar"]
+
+
+def test_sync_folders():
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        make_maildir(os.path.join(tmp, "gone"), ".uidvalidity")
+        make_maildir(os.path.join(tmp, "gone-full"), "cur/1:2,S")
+        make_maildir(os.path.join(tmp, "new-here"))
+        make_maildir(os.path.join(tmp, "both"))
+        fname = os.path.join(tmp, ".notmuch-sync-folders")
+        Path(fname).write_text(json.dumps(["both", "gone", "gone-full"]))
+
+        ostream = io.BytesIO()
+        assert 0 == ns.sync_folders(pre, fname, io.BytesIO(frame(["both"])), ostream, receive_only_theirs=True)
+        assert frame(["both", "gone", "gone-full", "new-here"]) == ostream.getvalue()
+        assert os.path.exists(os.path.join(tmp, "gone"))
+
+        Path(fname).write_text(json.dumps(["both", "gone", "gone-full"]))
+        assert 1 == ns.sync_folders(pre, fname, io.BytesIO(frame(["both", "foo"])), io.BytesIO())
+        assert not os.path.exists(os.path.join(tmp, "gone"))
+        assert os.path.exists(os.path.join(tmp, "gone-full", "cur", "1:2,S"))
+        assert os.path.exists(os.path.join(tmp, "new-here"))
+        assert ["both"] == json.loads(Path(fname).read_text())
+
+
+def test_delete_file(monkeypatch):
+    with TemporaryDirectory() as tmp:
+        pre = os.path.join(tmp, "mail", "")
+        os.makedirs(os.path.join(pre, "INBOX", "cur"))
+        fname = os.path.join(pre, "INBOX", "cur", "a")
+        Path(fname).write_text("mail one")
+        ns.delete_file(fname, pre)
+        assert not os.path.exists(fname)
+
+        trash = os.path.join(tmp, "trash")
+        monkeypatch.setitem(ns.session, "trash", trash)
+        day = os.path.join(trash, time.strftime("%Y-%m-%d"))
+        for content in ["mail one", "mail two"]:
+            Path(fname).write_text(content)
+            ns.delete_file(fname, pre, "foo", ["deleted", "inbox"])
+            assert not os.path.exists(fname)
+        assert "mail one" == Path(os.path.join(day, "INBOX", "cur", "a")).read_text()
+        assert "mail two" == Path(os.path.join(day, "INBOX", "cur", "a.1")).read_text()
+        manifest = [json.loads(line) for line in Path(os.path.join(day, ns.TRASH_MANIFEST)).read_text().splitlines()]
+        assert [(m["file"], m["trash"], m["id"], m["tags"]) for m in manifest] == [
+            (fname, os.path.join(day, "INBOX", "cur", "a"), "foo", ["deleted", "inbox"]),
+            (fname, os.path.join(day, "INBOX", "cur", "a.1"), "foo", ["deleted", "inbox"])]
+
+
+def test_purge_trash():
+    with TemporaryDirectory() as tmp:
+        assert 0 == ns.purge_trash(os.path.join(tmp, "nothing"), 86400)
+        today = time.strftime("%Y-%m-%d")
+        for day in ["2020-01-01", "2020-01-02", today, "other"]:
+            os.makedirs(os.path.join(tmp, day, "INBOX"))
+            Path(os.path.join(tmp, day, "INBOX", "a")).touch()
+        assert 2 == ns.purge_trash(tmp, 30 * 86400)
+        assert sorted(os.listdir(tmp)) == [today, "other"]
+        assert 0 == ns.purge_trash(tmp, 1)
+
+
+def test_undo_sync(monkeypatch):
+    class Tags(set):
+        def to_maildir_flags(self):
+            pass
+
+    with TemporaryDirectory() as tmp:
+        pre = os.path.join(tmp, "mail", "")
+        state = os.path.join(pre, ".notmuch")
+        for f in ["a", "new/b", "c"]:
+            Path(os.path.join(pre, f)).parent.mkdir(parents=True, exist_ok=True)
+            Path(os.path.join(pre, f)).write_text(f)
+
+        monkeypatch.setitem(ns.session, "journal", ns.start_journal(state))
+        ns.journal("add", file=pre + "a", id="foo", new=True)
+        ns.journal("move", **{"from": pre + "old/b", "to": pre + "new/b", "id": "foo"})
+        ns.delete_file(pre + "c", pre, "bar", ["deleted"])
+        ns.journal("tags", id="baz", before=["inbox"], after=["inbox", "x"])
+        ns.journal("tags", id="qux", before=["inbox"], after=["inbox", "x"])
+        ns.journal("properties", id="baz", before={"a": ["1"]}, after={"a": ["2"]})
+        ns.session["journal"].close()
+        assert not os.path.exists(pre + "c")
+        assert "c" == Path(state, ns.JOURNAL_FILES, "c").read_text()
+
+        msgs = {"foo": MagicMock(), "baz": MagicMock(), "qux": MagicMock()}
+        msgs["foo"].filenames.return_value = [Path(pre + "a")]
+        msgs["baz"].tags = Tags(["inbox", "x"])
+        msgs["qux"].tags = Tags(["inbox", "y"])
+        msgs["baz"].properties.getall.return_value = [("a", "2")]
+        restored = MagicMock()
+        restored.tags = Tags()
+        rev = lambda: None
+        rev.uuid = b"00000000-0000-0000-0000-000000000000"
+        db = MagicMock()
+        db.default_path.return_value = pre
+        db.revision.return_value = rev
+        db.config = {}
+        db.find.side_effect = lambda mid: msgs[mid]
+        db.add.return_value = (restored, False)
+        mock_ctx = MagicMock()
+        mock_ctx.__enter__.return_value = db
+        with patch("notmuch2.Database", return_value=mock_ctx), patch.object(ns, "lock_session") as ls:
+            assert 5 == ns.undo_sync()
+            ls.assert_called_once_with(pre, "local")
+        assert msgs["baz"].tags == {"inbox"}
+        assert msgs["baz"].properties.add.mock_calls == [call("a", "1")]
+        assert msgs["qux"].tags == {"inbox", "y"}
+        assert restored.tags == {"deleted"}
+        assert "c" == Path(pre + "c").read_text()
+        assert "new/b" == Path(pre + "old/b").read_text()
+        assert not os.path.exists(pre + "new/b") and not os.path.exists(pre + "a")
+        assert db.add.call_args_list == [call(pre + "c"), call(pre + "old/b")]
+        assert db.remove.call_args_list == [call(pre + "new/b"), call(pre + "a")]
+        assert not os.path.exists(os.path.join(state, ns.JOURNAL))
+
+        with patch("notmuch2.Database", return_value=mock_ctx), pytest.raises(ns.StateError):
+            ns.undo_sync()
+
+
+def test_prune_empty_dirs():
+    with TemporaryDirectory() as tmp:
+        make_maildir(os.path.join(tmp, "foo"))
+        os.makedirs(os.path.join(tmp, "bar", "baz", "quux"))
+        os.makedirs(os.path.join(tmp, "qux"))
+        Path(os.path.join(tmp, "qux", "file")).write_text("foo")
+        os.makedirs(os.path.join(tmp, ".notmuch", "empty"))
+        assert 3 == ns.prune_empty_dirs(tmp + os.sep)
+        assert not os.path.exists(os.path.join(tmp, "bar"))
+        assert sorted(os.listdir(os.path.join(tmp, "foo"))) == ["cur", "new", "tmp"]
+        assert os.path.exists(os.path.join(tmp, "qux"))
+        assert os.path.exists(os.path.join(tmp, ".notmuch", "empty"))
+
+
 def test_get_ids():
     p1 = lambda: None
     p1.docid = 1
@@ -1350,6 +4433,150 @@ def test_get_ids():
         db.close.assert_called_once()
 
 
+def test_get_counts():
+    def term(t, freq=1):
+        tmp = lambda: None
+        tmp.term = t
+        tmp.termfreq = freq
+        return tmp
+
+    db = lambda: None
+    db.get_termfreq = MagicMock(return_value=3)
+    db.allterms = MagicMock()
+    db.allterms.side_effect = [
+        [term(b"XFDIRENTRY:1:a"), term(b"XFDIRENTRY:1:b"), term(b"XFDIRENTRY:2:c"), term(b"XFDIRENTRY:3:d")],
+        [term(b"XFOLDER:", 1), term(b"XFOLDER:INBOX", 2), term(b"XFOLDER:INBOX/cur", 1),
+         term(b"XFOLDER:INBOX/new", 1)]
+    ]
+    db.close = MagicMock()
+
+    with patch("xapian.Database", return_value=db) as xdb:
+        assert ns.get_counts(prefix) == {"messages": 3, "files": 4, "folders": {".": 1, "INBOX": 2}}
+        xdb.assert_called_once_with(prefix + ".notmuch/xapian")
+        db.get_termfreq.assert_called_once_with("Tmail")
+        assert db.allterms.mock_calls == [call("XFDIRENTRY"), call("XFOLDER:")]
+        db.close.assert_called_once()
+
+
+def test_check_counts():
+    counts = {"messages": 3, "files": 4, "folders": {".": 1, "INBOX": 2}}
+    with patch.object(ns.logger, "warning") as warn:
+        assert ns.check_counts(counts, counts)
+        warn.assert_not_called()
+
+        assert not ns.check_counts(counts, {"messages": 3, "files": 5, "folders": {"INBOX": 2, "Sent": 1}})
+        assert warn.mock_calls == [
+            call("DATABASES DIFFER AFTER SYNC: %s.", "4 files local, 5 files remote"),
+            call("DATABASES DIFFER AFTER SYNC: %s.", "1 messages local, 0 messages remote in folder ."),
+            call("DATABASES DIFFER AFTER SYNC: %s.", "0 messages local, 1 messages remote in folder Sent")
+        ]
+
+
+def test_get_spot_check():
+    m = lambda: None
+    m.ghost = False
+    m.tags = ["foo", "bar"]
+    m.filenames = MagicMock(return_value=[prefix + "mails/foofile"])
+    db = lambda: None
+    db.find = MagicMock(side_effect=[m, LookupError])
+
+    mock_ctx = MagicMock()
+    mock_ctx.__enter__.return_value = db
+    mock_ctx.__exit__.return_value = False
+
+    with patch("notmuch2.Database", return_value=mock_ctx):
+        with patch("pathlib.Path.read_bytes", return_value=b"foo"):
+            assert ns.get_spot_check(prefix, ["foo", "bar"]) == \
+                {"foo": {"tags": ["bar", "foo"], "files": {"mails/foofile": ns.digest(b"foo")}}}
+    assert db.find.mock_calls == [call("foo"), call("bar")]
+
+
+def test_spot_check_local():
+    mine = {"a": {"tags": ["foo"], "files": {"a1": "x"}},
+            "b": {"tags": ["foo"], "files": {"b1": "x"}},
+            "c": {"tags": ["foo"], "files": {"c1": "x", "c2": "y"}},
+            "d": {"tags": ["foo"], "files": {"d1": "x"}}}
+    theirs = {"a": {"tags": ["foo"], "files": {"a1": "x"}},
+              "b": {"tags": ["bar"], "files": {"b1": "x"}},
+              "c": {"tags": ["foo"], "files": {"c1": "z"}},
+              "e": {"tags": ["foo"], "files": {"e1": "x"}}}
+    with patch.object(ns, "get_ids", return_value=["a", "b", "c", "d", "e", "f"]), \
+         patch.object(ns, "get_spot_check", return_value=mine) as gsc, \
+         patch("random.sample", return_value=["a", "b", "c", "d", "e", "f"]) as rs, \
+         patch.object(ns.logger, "warning") as warn:
+        istream = io.BytesIO(frame(theirs))
+        ostream = io.BytesIO()
+        assert 4 == ns.spot_check_local(prefix, 50, istream, ostream)
+        rs.assert_called_once_with(["a", "b", "c", "d", "e", "f"], 3)
+        gsc.assert_called_once_with(prefix, ["a", "b", "c", "d", "e", "f"])
+        assert ostream.getvalue() == frame(["a", "b", "c", "d", "e", "f"])
+        assert warn.mock_calls == [
+            call("Spot check: tags of %s differ, %s local, %s remote.", "b", ["foo"], ["bar"]),
+            call("Spot check: files of %s differ, %s local, %s remote.", "c", ["c1", "c2"], ["c1"]),
+            call("Spot check: contents of %s differ.", "c1"),
+            call("Spot check: %s missing on remote.", "d"),
+            call("Spot check: %s missing on local.", "e"),
+            call("Spot-checked %s messages, %s mismatches.", 6, 4)
+        ]
+
+
+def test_spot_check_remote():
+    info = {"a": {"tags": ["foo"], "files": {"a1": "x"}}}
+    with patch.object(ns, "get_spot_check", return_value=info) as gsc:
+        istream = io.BytesIO(frame(["a", "b"]))
+        ostream = io.BytesIO()
+        ns.spot_check_remote(prefix, istream, ostream)
+        gsc.assert_called_once_with(prefix, ["a", "b"])
+        assert ostream.getvalue() == frame(info)
+
+
+def test_find_orphans():
+    with TemporaryDirectory() as tmp:
+        pre = tmp + os.sep
+        for d in ["INBOX/cur", "INBOX/new", ".notmuch/xapian", ".hidden"]:
+            os.makedirs(os.path.join(tmp, d))
+        for f in ["INBOX/cur/a", "INBOX/cur/b", "INBOX/new/c", "d", ".mbsyncstate",
+                  ".notmuch/xapian/e", ".hidden/f"]:
+            Path(os.path.join(tmp, f)).touch()
+
+        m1 = lambda: None
+        m1.filenames = MagicMock(return_value=[pre + "INBOX/cur/a", pre + "INBOX/new/c"])
+        m2 = lambda: None
+        m2.filenames = MagicMock(return_value=[])
+        db = lambda: None
+        db.messages = MagicMock(return_value=[m1, m2])
+
+        assert ns.find_orphans(db, pre) == [pre + "d", pre + "INBOX/cur/b"]
+        db.messages.assert_called_once_with("*")
+
+
+def test_handle_orphans():
+    db = lambda: None
+    db.default_path = MagicMock(return_value=gettempdir())
+    db.add = MagicMock(side_effect=[None, notmuch2.FileNotEmailError])
+
+    mock_ctx = MagicMock()
+    mock_ctx.__enter__.return_value = db
+    mock_ctx.__exit__.return_value = False
+
+    with patch("notmuch2.Database", return_value=mock_ctx):
+        with patch.object(ns, "find_orphans", return_value=["foo", "bar"]) as fo:
+            with patch("pathlib.Path.unlink") as pu:
+                assert 2 == ns.handle_orphans("report")
+                fo.assert_called_once_with(db, prefix)
+                pu.assert_not_called()
+                db.add.assert_not_called()
+
+                assert 2 == ns.handle_orphans("remove")
+                assert pu.call_count == 2
+                db.add.assert_not_called()
+
+                pu.reset_mock()
+                assert 2 == ns.handle_orphans("index")
+                pu.assert_not_called()
+                assert db.add.mock_calls == [call("foo"), call("bar")]
+
+
 def test_sync_mbsync_local_nothing():
     def effect(*args, **kwargs):
         yield []
@@ -1396,10 +4623,10 @@ def test_sync_mbsync_local():
                 ps.side_effect = effect_stat()
                 with patch("pathlib.Path.mkdir") as pm:
                     with patch("os.utime") as ut:
-                        with patch("builtins.open", mock_open(read_data=b"a")) as o:
+                        with patch("builtins.open", mock_open(read_data=b"a")) as o, patch("os.replace"), patch("os.fsync"):
                             ns.sync_mbsync_local(tmpdir, istream, ostream)
                             assert call(tmpdir + ".uidvalidity", "rb") in o.mock_calls
-                            assert call(tmpdir + ".mbsyncstate", "wb") in o.mock_calls
+                            assert call(tmpdir + ".mbsyncstate" + ".notmuch-sync-tmp", "wb") in o.mock_calls
                             hdl = o()
                             hdl.read.assert_called_once()
                             hdl.write.assert_called_once()
@@ -1464,10 +4691,10 @@ def test_sync_mbsync_local_missing():
                 ps.side_effect = effect_stat()
                 with patch("pathlib.Path.mkdir") as pm:
                     with patch("os.utime") as ut:
-                        with patch("builtins.open", mock_open(read_data=b"a")) as o:
+                        with patch("builtins.open", mock_open(read_data=b"a")) as o, patch("os.replace"), patch("os.fsync"):
                             ns.sync_mbsync_local(tmpdir, istream, ostream)
                             assert call(tmpdir + ".uidvalidity", "rb") in o.mock_calls
-                            assert call(tmpdir + ".mbsyncstate", "wb") in o.mock_calls
+                            assert call(tmpdir + ".mbsyncstate" + ".notmuch-sync-tmp", "wb") in o.mock_calls
                             hdl = o()
                             hdl.read.assert_called_once()
                             hdl.write.assert_called_once()
@@ -1526,9 +4753,9 @@ def test_sync_mbsync_remote():
                 ps.side_effect = effect_stat()
                 with patch("pathlib.Path.mkdir") as pm:
                     with patch("os.utime") as ut:
-                        with patch("builtins.open", mock_open(read_data=b"b")) as o:
+                        with patch("builtins.open", mock_open(read_data=b"b")) as o, patch("os.replace"), patch("os.fsync"):
                             ns.sync_mbsync_remote(tmpdir, istream, ostream)
-                            assert call(tmpdir + ".uidvalidity", "wb") in o.mock_calls
+                            assert call(tmpdir + ".uidvalidity" + ".notmuch-sync-tmp", "wb") in o.mock_calls
                             assert call(tmpdir + ".mbsyncstate", "rb") in o.mock_calls
                             hdl = o()
                             hdl.read.assert_called_once()
@@ -1595,9 +4822,9 @@ def test_sync_mbsync_remote_missing():
                 ps.side_effect = effect_stat()
                 with patch("pathlib.Path.mkdir") as pm:
                     with patch("os.utime") as ut:
-                        with patch("builtins.open", mock_open(read_data=b"a")) as o:
+                        with patch("builtins.open", mock_open(read_data=b"a")) as o, patch("os.replace"), patch("os.fsync"):
                             ns.sync_mbsync_remote(tmpdir, istream, ostream)
-                            assert call(tmpdir + ".uidvalidity", "wb") in o.mock_calls
+                            assert call(tmpdir + ".uidvalidity" + ".notmuch-sync-tmp", "wb") in o.mock_calls
                             assert call(tmpdir + ".mbsyncstate", "rb") in o.mock_calls
                             hdl = o()
                             hdl.read.assert_called_once()